@@ -14,44 +14,289 @@
 //! [`LiquidityManager::get_and_clear_pending_events`] to receive events.
 //!
 //! [`LiquidityManager::get_and_clear_pending_events`]: crate::LiquidityManager::get_and_clear_pending_events
+//!
+//! Every event is assigned a monotonically increasing sequence number when it is enqueued, and
+//! events are always handed out in that order. This allows to detect gaps or reordering when
+//! consuming events from multiple threads.
+//!
+//! Pending events may be persisted via [`LiquidityManager::write_pending_events`] and replayed
+//! after a restart via [`LiquidityManager::restore_pending_events`].
+//!
+//! [`LiquidityManager::write_pending_events`]: crate::LiquidityManager::write_pending_events
+//! [`LiquidityManager::restore_pending_events`]: crate::LiquidityManager::restore_pending_events
 use crate::jit_channel;
+use crate::transport::msgs::RequestId;
+
+use bitcoin::secp256k1::PublicKey;
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{
+	_init_and_read_len_prefixed_tlv_fields, impl_writeable_tlv_based_enum, write_tlv_fields,
+};
+
 use std::collections::VecDeque;
+#[cfg(feature = "event-subscriptions")]
+use std::sync::mpsc;
+#[cfg(feature = "event-subscriptions")]
+use std::sync::Arc;
 use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct EventQueueState {
+	queue: VecDeque<(u64, Event)>,
+	next_sequence_number: u64,
+	last_consumed_sequence_number: Option<u64>,
+	paused: bool,
+	// Events generated while paused, which are enqueued once resumed.
+	held_events: Vec<Event>,
+}
 
 #[derive(Default)]
 pub(crate) struct EventQueue {
-	queue: Mutex<VecDeque<Event>>,
+	state: Mutex<EventQueueState>,
 	condvar: Condvar,
+	#[cfg(feature = "event-subscriptions")]
+	subscribers: Arc<Mutex<Vec<mpsc::Sender<Event>>>>,
 }
 
 impl EventQueue {
 	pub fn enqueue(&self, event: Event) {
 		{
-			let mut queue = self.queue.lock().unwrap();
-			queue.push_back(event);
+			let mut state = self.state.lock().unwrap();
+			if state.paused {
+				state.held_events.push(event);
+				return;
+			}
+			self.push_event(&mut state, event);
 		}
 
 		self.condvar.notify_one();
 	}
 
+	fn push_event(&self, state: &mut EventQueueState, event: Event) {
+		#[cfg(feature = "event-subscriptions")]
+		{
+			let mut subscribers = self.subscribers.lock().unwrap();
+			subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+		}
+
+		let sequence_number = state.next_sequence_number;
+		state.next_sequence_number += 1;
+		state.queue.push_back((sequence_number, event));
+	}
+
+	/// Holds back all events enqueued from now on until [`Self::resume`] is called.
+	pub fn pause(&self) {
+		self.state.lock().unwrap().paused = true;
+	}
+
+	/// Enqueues all events held back since [`Self::pause`] was called, in their original order.
+	pub fn resume(&self) {
+		let should_notify;
+		{
+			let mut state = self.state.lock().unwrap();
+			state.paused = false;
+			let held_events = std::mem::take(&mut state.held_events);
+			should_notify = !held_events.is_empty();
+			for event in held_events {
+				self.push_event(&mut state, event);
+			}
+		}
+
+		if should_notify {
+			self.condvar.notify_one();
+		}
+	}
+
 	pub fn wait_next_event(&self) -> Event {
-		let mut queue =
-			self.condvar.wait_while(self.queue.lock().unwrap(), |queue| queue.is_empty()).unwrap();
+		self.wait_next_event_with_sequence_number().1
+	}
 
-		let event = queue.pop_front().expect("non-empty queue");
-		let should_notify = !queue.is_empty();
+	pub fn wait_next_event_with_sequence_number(&self) -> (u64, Event) {
+		let mut state = self
+			.condvar
+			.wait_while(self.state.lock().unwrap(), |state| state.queue.is_empty())
+			.unwrap();
 
-		drop(queue);
+		let (sequence_number, event) = state.queue.pop_front().expect("non-empty queue");
+		state.last_consumed_sequence_number = Some(sequence_number);
+		let should_notify = !state.queue.is_empty();
+
+		drop(state);
 
 		if should_notify {
 			self.condvar.notify_one();
 		}
 
-		event
+		(sequence_number, event)
+	}
+
+	pub fn wait_next_event_timeout(&self, timeout: Duration) -> Option<Event> {
+		let (mut state, _) = self
+			.condvar
+			.wait_timeout_while(self.state.lock().unwrap(), timeout, |state| state.queue.is_empty())
+			.unwrap();
+
+		let (sequence_number, event) = state.queue.pop_front()?;
+		state.last_consumed_sequence_number = Some(sequence_number);
+		let should_notify = !state.queue.is_empty();
+
+		drop(state);
+
+		if should_notify {
+			self.condvar.notify_one();
+		}
+
+		Some(event)
 	}
 
 	pub fn get_and_clear_pending_events(&self) -> Vec<Event> {
-		self.queue.lock().unwrap().drain(..).collect()
+		self.get_and_clear_pending_events_with_sequence_numbers()
+			.into_iter()
+			.map(|(_, event)| event)
+			.collect()
+	}
+
+	pub fn get_and_clear_pending_events_with_sequence_numbers(&self) -> Vec<(u64, Event)> {
+		let mut state = self.state.lock().unwrap();
+		let events: Vec<(u64, Event)> = state.queue.drain(..).collect();
+		if let Some((sequence_number, _)) = events.last() {
+			state.last_consumed_sequence_number = Some(*sequence_number);
+		}
+		events
+	}
+
+	pub fn last_consumed_sequence_number(&self) -> Option<u64> {
+		self.state.lock().unwrap().last_consumed_sequence_number
+	}
+
+	#[cfg(feature = "event-subscriptions")]
+	pub fn subscribe(&self) -> EventSubscriber {
+		EventSubscriber::new(Arc::clone(&self.subscribers))
+	}
+
+	/// Restores the pending events of a previously persisted queue.
+	///
+	/// The restored events are handed out before any events that were enqueued in the meantime.
+	/// Subscribers are not notified about restored events.
+	pub fn restore(&self, restored: EventQueue) {
+		let restored = restored.state.into_inner().unwrap();
+		let should_notify;
+		{
+			let mut state = self.state.lock().unwrap();
+			let mut queue = restored.queue;
+			let mut next_sequence_number = restored.next_sequence_number;
+			for (_, event) in state.queue.drain(..) {
+				queue.push_back((next_sequence_number, event));
+				next_sequence_number += 1;
+			}
+			state.queue = queue;
+			state.next_sequence_number = next_sequence_number.max(state.next_sequence_number);
+			if state.last_consumed_sequence_number.is_none() {
+				state.last_consumed_sequence_number = restored.last_consumed_sequence_number;
+			}
+			should_notify = !state.queue.is_empty();
+		}
+
+		if should_notify {
+			self.condvar.notify_one();
+		}
+	}
+}
+
+impl Writeable for EventQueue {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let state = self.state.lock().unwrap();
+		// Queued events always carry consecutive sequence numbers ending right before
+		// `next_sequence_number`, so we don't need to persist them individually. Held events are
+		// persisted as if they had been enqueued already.
+		let events: Vec<Event> = state
+			.queue
+			.iter()
+			.map(|(_, event)| event)
+			.chain(state.held_events.iter())
+			.cloned()
+			.collect();
+		let next_sequence_number = state.next_sequence_number + state.held_events.len() as u64;
+		write_tlv_fields!(writer, {
+			(0, next_sequence_number, required),
+			(2, state.last_consumed_sequence_number, option),
+			(4, events, required_vec),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for EventQueue {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, next_sequence_number, required),
+			(2, last_consumed_sequence_number, option),
+			(4, events, required_vec),
+		});
+		let next_sequence_number: u64 = next_sequence_number.0.unwrap();
+		let first_sequence_number = next_sequence_number
+			.checked_sub(events.len() as u64)
+			.ok_or(DecodeError::InvalidValue)?;
+		let queue = events
+			.into_iter()
+			.enumerate()
+			.map(|(i, event)| (first_sequence_number + i as u64, event))
+			.collect();
+		let state = EventQueueState {
+			queue,
+			next_sequence_number,
+			last_consumed_sequence_number,
+			..Default::default()
+		};
+		Ok(Self { state: Mutex::new(state), ..Default::default() })
+	}
+}
+
+/// A subscription to all events enqueued after it was created, independent of any other consumer.
+///
+/// Cloning a subscriber creates a new subscription that receives all events enqueued after the
+/// clone was made.
+///
+/// Obtained via [`LiquidityManager::subscribe_events`].
+///
+/// [`LiquidityManager::subscribe_events`]: crate::LiquidityManager::subscribe_events
+#[cfg(feature = "event-subscriptions")]
+pub struct EventSubscriber {
+	receiver: mpsc::Receiver<Event>,
+	subscribers: Arc<Mutex<Vec<mpsc::Sender<Event>>>>,
+}
+
+#[cfg(feature = "event-subscriptions")]
+impl EventSubscriber {
+	fn new(subscribers: Arc<Mutex<Vec<mpsc::Sender<Event>>>>) -> Self {
+		let (sender, receiver) = mpsc::channel();
+		subscribers.lock().unwrap().push(sender);
+		Self { receiver, subscribers }
+	}
+
+	/// Returns the next event without blocking, if any.
+	pub fn try_next_event(&self) -> Option<Event> {
+		self.receiver.try_recv().ok()
+	}
+
+	/// Blocks until the next event is ready or `timeout` elapsed, whichever comes first.
+	pub fn next_event_timeout(&self, timeout: Duration) -> Option<Event> {
+		self.receiver.recv_timeout(timeout).ok()
+	}
+
+	/// Returns all events received so far without blocking.
+	pub fn pending_events(&self) -> Vec<Event> {
+		self.receiver.try_iter().collect()
+	}
+}
+
+#[cfg(feature = "event-subscriptions")]
+impl Clone for EventSubscriber {
+	fn clone(&self) -> Self {
+		Self::new(Arc::clone(&self.subscribers))
 	}
 }
 
@@ -60,4 +305,208 @@ impl EventQueue {
 pub enum Event {
 	/// An LSPS2 (JIT Channel) protocol event.
 	LSPS2(jit_channel::LSPS2Event),
+	/// A response was received that doesn't match any outstanding request, or that doesn't match
+	/// the method of the request with the same id.
+	///
+	/// Only generated if [`LiquidityProviderConfig::strict_request_matching`] is set.
+	///
+	/// [`LiquidityProviderConfig::strict_request_matching`]: crate::LiquidityProviderConfig::strict_request_matching
+	MismatchedResponse {
+		/// The node id of the peer that sent the response.
+		counterparty_node_id: PublicKey,
+		/// The id of the response.
+		request_id: RequestId,
+		/// The method of the outstanding request with the same id, if any.
+		expected_method: Option<String>,
+	},
+	/// A peer reached the misbehavior threshold, e.g., by repeatedly sending malformed or
+	/// contradictory messages.
+	///
+	/// Any negotiation state with the peer was dropped and further messages from it will be
+	/// refused.
+	///
+	/// Only generated if [`LiquidityProviderConfig::max_peer_misbehaviors`] is set.
+	///
+	/// [`LiquidityProviderConfig::max_peer_misbehaviors`]: crate::LiquidityProviderConfig::max_peer_misbehaviors
+	PeerMisbehaved {
+		/// The node id of the misbehaving peer.
+		counterparty_node_id: PublicKey,
+	},
+}
+
+impl_writeable_tlv_based_enum!(Event,
+	(0, MismatchedResponse) => {
+		(0, counterparty_node_id, required),
+		(2, request_id, required),
+		(4, expected_method, option),
+	},
+	(2, PeerMisbehaved) => {
+		(0, counterparty_node_id, required),
+	};
+	(4, LSPS2)
+);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jit_channel::LSPS2Event;
+	use crate::test_utils;
+
+	use std::sync::Arc;
+	use std::thread;
+
+	fn get_info_event(request_id: &str) -> Event {
+		let counterparty_node_id = test_utils::parse_pubkey(
+			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
+		)
+		.unwrap();
+		Event::LSPS2(LSPS2Event::GetInfo {
+			request_id: RequestId::new(request_id.to_string()).unwrap(),
+			counterparty_node_id,
+			version: 1,
+			token: None,
+		})
+	}
+
+	#[test]
+	fn events_are_drained_with_increasing_sequence_numbers() {
+		let event_queue = EventQueue::default();
+		assert_eq!(event_queue.last_consumed_sequence_number(), None);
+
+		event_queue.enqueue(get_info_event("a"));
+		event_queue.enqueue(get_info_event("b"));
+		assert_eq!(event_queue.wait_next_event_with_sequence_number(), (0, get_info_event("a")));
+		assert_eq!(event_queue.last_consumed_sequence_number(), Some(0));
+
+		event_queue.enqueue(get_info_event("c"));
+		event_queue.enqueue(get_info_event("d"));
+
+		let events = event_queue.get_and_clear_pending_events_with_sequence_numbers();
+		assert_eq!(
+			events,
+			vec![(1, get_info_event("b")), (2, get_info_event("c")), (3, get_info_event("d"))]
+		);
+		assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert_eq!(event_queue.last_consumed_sequence_number(), Some(3));
+
+		assert!(event_queue.get_and_clear_pending_events_with_sequence_numbers().is_empty());
+		assert_eq!(event_queue.last_consumed_sequence_number(), Some(3));
+	}
+
+	#[test]
+	#[cfg(feature = "event-subscriptions")]
+	fn all_subscribers_receive_every_event() {
+		let event_queue = EventQueue::default();
+		let first_subscriber = event_queue.subscribe();
+		let second_subscriber = first_subscriber.clone();
+
+		event_queue.enqueue(get_info_event("a"));
+		assert_eq!(first_subscriber.try_next_event(), Some(get_info_event("a")));
+		assert_eq!(second_subscriber.try_next_event(), Some(get_info_event("a")));
+		assert_eq!(first_subscriber.try_next_event(), None);
+
+		// The single-consumer drain API is unaffected.
+		assert_eq!(event_queue.get_and_clear_pending_events(), vec![get_info_event("a")]);
+
+		drop(first_subscriber);
+		event_queue.enqueue(get_info_event("b"));
+		assert_eq!(event_queue.subscribers.lock().unwrap().len(), 1);
+		assert_eq!(second_subscriber.pending_events(), vec![get_info_event("b")]);
+	}
+
+	#[test]
+	fn paused_events_are_held_back_until_resumed() {
+		let event_queue = EventQueue::default();
+		event_queue.enqueue(get_info_event("a"));
+		event_queue.pause();
+		event_queue.enqueue(get_info_event("b"));
+		event_queue.enqueue(get_info_event("c"));
+
+		assert_eq!(event_queue.get_and_clear_pending_events(), vec![get_info_event("a")]);
+		assert_eq!(event_queue.wait_next_event_timeout(Duration::from_millis(10)), None);
+
+		// Held events are persisted, too.
+		let restored: EventQueue = Readable::read(&mut &event_queue.encode()[..]).unwrap();
+		assert_eq!(
+			restored.get_and_clear_pending_events_with_sequence_numbers(),
+			vec![(1, get_info_event("b")), (2, get_info_event("c"))]
+		);
+
+		event_queue.resume();
+		assert_eq!(
+			event_queue.get_and_clear_pending_events_with_sequence_numbers(),
+			vec![(1, get_info_event("b")), (2, get_info_event("c"))]
+		);
+	}
+
+	#[test]
+	fn pending_events_survive_persistence_in_order() {
+		let counterparty_node_id = test_utils::parse_pubkey(
+			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
+		)
+		.unwrap();
+		let events = vec![
+			get_info_event("a"),
+			Event::LSPS2(LSPS2Event::ClaimPayment {
+				counterparty_node_id,
+				user_channel_id: 42,
+				label: Some("coffee".to_string()),
+			}),
+			Event::MismatchedResponse {
+				counterparty_node_id,
+				request_id: RequestId::new("b".to_string()).unwrap(),
+				expected_method: None,
+			},
+			Event::PeerMisbehaved { counterparty_node_id },
+		];
+
+		let event_queue = EventQueue::default();
+		event_queue.enqueue(get_info_event("consumed"));
+		assert_eq!(event_queue.wait_next_event(), get_info_event("consumed"));
+		for event in &events {
+			event_queue.enqueue(event.clone());
+		}
+
+		let encoded = event_queue.encode();
+		let restored: EventQueue = Readable::read(&mut &encoded[..]).unwrap();
+		assert_eq!(restored.last_consumed_sequence_number(), Some(0));
+
+		// Events enqueued before restoring are handed out after the restored ones.
+		let fresh_queue = EventQueue::default();
+		fresh_queue.enqueue(get_info_event("new"));
+		fresh_queue.restore(restored);
+
+		let mut expected: Vec<(u64, Event)> =
+			events.into_iter().enumerate().map(|(i, event)| (i as u64 + 1, event)).collect();
+		expected.push((5, get_info_event("new")));
+		assert_eq!(fresh_queue.get_and_clear_pending_events_with_sequence_numbers(), expected);
+
+		fresh_queue.enqueue(get_info_event("c"));
+		assert_eq!(fresh_queue.wait_next_event_with_sequence_number(), (6, get_info_event("c")));
+	}
+
+	#[test]
+	fn wait_next_event_timeout_returns_none_without_event() {
+		let event_queue = EventQueue::default();
+		assert_eq!(event_queue.wait_next_event_timeout(Duration::from_millis(10)), None);
+		assert_eq!(event_queue.last_consumed_sequence_number(), None);
+	}
+
+	#[test]
+	fn wait_next_event_timeout_returns_enqueued_event() {
+		let event_queue = Arc::new(EventQueue::default());
+
+		let enqueuing_queue = Arc::clone(&event_queue);
+		let handle = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(10));
+			enqueuing_queue.enqueue(get_info_event("a"));
+		});
+
+		assert_eq!(
+			event_queue.wait_next_event_timeout(Duration::from_secs(10)),
+			Some(get_info_event("a"))
+		);
+		assert_eq!(event_queue.last_consumed_sequence_number(), Some(0));
+		handle.join().unwrap();
+	}
 }
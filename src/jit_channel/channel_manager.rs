@@ -7,29 +7,44 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{BlockHash, Txid};
+use chrono::Utc;
 use lightning::chain;
-use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::transaction::{OutPoint, TransactionData};
+use lightning::events::HTLCDestination;
+use lightning::io;
 use lightning::ln::channelmanager::{ChannelManager, InterceptId};
 use lightning::ln::msgs::{
-	ChannelMessageHandler, ErrorAction, LightningError, OnionMessageHandler, RoutingMessageHandler,
+	ChannelMessageHandler, DecodeError, ErrorAction, LightningError, OnionMessageHandler,
+	RoutingMessageHandler,
 };
 use lightning::ln::peer_handler::{CustomMessageHandler, PeerManager, SocketDescriptor};
 use lightning::ln::ChannelId;
 use lightning::routing::router::Router;
 use lightning::sign::{EntropySource, NodeSigner, SignerProvider};
+use lightning::util::config::ChannelConfig;
 use lightning::util::errors::APIError;
 use lightning::util::logger::{Level, Logger};
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
 
 use crate::events::EventQueue;
-use crate::jit_channel::utils::{compute_opening_fee, is_valid_opening_fee_params};
+use crate::jit_channel::event::FailureReason;
+use crate::jit_channel::utils::{
+	compute_opening_fee, is_valid_opening_fee_params_at, promise_key_id, PromiseVersion,
+};
 use crate::jit_channel::LSPS2Event;
-use crate::transport::message_handler::ProtocolMessageHandler;
+use crate::snapshot::{JitChannelSnapshot, PeerSnapshot, RequestSnapshot};
+use crate::transport::message_handler::{check_promise_secret, ProtocolMessageHandler};
 use crate::transport::msgs::{LSPSMessage, RequestId};
 use crate::{events::Event, transport::msgs::ResponseError};
 use crate::{utils, JITChannelsConfig};
@@ -37,14 +52,147 @@ use crate::{utils, JITChannelsConfig};
 use crate::jit_channel::msgs::{
 	BuyRequest, BuyResponse, GetInfoRequest, GetInfoResponse, GetVersionsRequest,
 	GetVersionsResponse, JitChannelScid, LSPS2Message, LSPS2Request, LSPS2Response,
-	OpeningFeeParams, RawOpeningFeeParams, LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+	OpeningFeeParams, RawOpeningFeeParams, Token,
+	LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
 	LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
 	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
 	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+	LSPS2_BUY_REQUEST_TEMPORARY_FAILURE_ERROR_CODE,
+	LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE,
+	LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
 };
 
 const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
 
+/// The estimated weight of a channel funding transaction spending a single P2WPKH input to the
+/// P2WSH funding output and a P2WPKH change output.
+const ESTIMATED_FUNDING_TRANSACTION_WEIGHT: u64 = 612;
+
+/// The maximum `to_self_delay` LDK accepts for its own outputs, i.e., the largest
+/// `max_client_to_self_delay` an LDK-based client is able to honor.
+///
+/// Mirrors LDK's `MAX_LOCAL_BREAKDOWN_TIMEOUT`, which isn't exported.
+const MAX_CLIENT_TO_SELF_DELAY: u32 = 2 * 6 * 24 * 7;
+
+/// The minimum invoice expiry recommended by [`JITChannelManager::recommended_invoice_expiry_secs`],
+/// leaving the payer a chance to pay even if the opening fee params are about to expire.
+const MIN_INVOICE_EXPIRY_SECS: u32 = 60;
+
+/// The share of the channel value, in millionths, the LSP is required to keep as channel reserve.
+///
+/// Mirrors the default of LDK's `ChannelHandshakeConfig::their_channel_reserve_proportional_millionths`.
+const CHANNEL_RESERVE_PROPORTIONAL_MILLIONTHS: u64 = 10_000;
+
+/// The minimum channel reserve in satoshis.
+///
+/// Mirrors LDK's `MIN_THEIR_CHAN_RESERVE_SATOSHIS`, which isn't exported.
+const MIN_CHANNEL_RESERVE_SATOSHIS: u64 = 1000;
+
+/// The maximum number of processed intercepted HTLCs we remember, so that replays of
+/// [`Event::HTLCIntercepted`] events are recognized.
+///
+/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
+const MAX_PROCESSED_INTERCEPTS: usize = 1000;
+
+/// The maximum number of JIT channels whose terminal state, i.e., [`JitChannelState::Ready`] or
+/// [`JitChannelState::Failed`], we remember, so it can still be queried for a while.
+const MAX_TERMINAL_CHANNEL_STATES: usize = 1000;
+
+/// The maximum exponent of the exponential backoff between retries of requests lost to a
+/// disconnect.
+const MAX_TRANSPORT_RETRY_BACKOFF_EXPONENT: u8 = 10;
+
+/// Allocates the intercept scids handed out to clients by an LSP.
+///
+/// Implemented for [`ChannelManager`] via [`ChannelManager::get_intercept_scid`], which never runs
+/// out of scids. Tests may supply an implementation handing out predetermined scids instead.
+///
+/// [`ChannelManager::get_intercept_scid`]: lightning::ln::channelmanager::ChannelManager::get_intercept_scid
+pub trait ScidAllocator {
+	/// Returns a fresh intercept scid, or [`Option::None`] if no more scids are available.
+	fn allocate_scid(&self) -> Option<u64>;
+}
+
+impl<M: Deref, T: Deref, ES: Deref, NS: Deref, SP: Deref, F: Deref, R: Deref, L: Deref>
+	ScidAllocator for ChannelManager<M, T, ES, NS, SP, F, R, L>
+where
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	ES::Target: EntropySource,
+	NS::Target: NodeSigner,
+	SP::Target: SignerProvider,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	L::Target: Logger,
+{
+	fn allocate_scid(&self) -> Option<u64> {
+		Some(self.get_intercept_scid())
+	}
+}
+
+/// A source of the current time, consulted in place of the system clock whenever the current time
+/// matters, e.g., when checking whether opening fee params expired.
+///
+/// Allows to rely on a trustworthy clock, e.g., if the system clock is subject to skew.
+pub trait Clock {
+	/// Returns the time elapsed since the unix epoch.
+	fn duration_since_epoch(&self) -> Duration;
+}
+
+/// The outcome of handling a channel becoming ready, see [`LiquidityManager::channel_ready`].
+///
+/// [`LiquidityManager::channel_ready`]: crate::LiquidityManager::channel_ready
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelReadyOutcome {
+	/// The channel is a JIT channel we opened and the intercepted payment was forwarded over it.
+	Forwarded,
+	/// The channel is not a JIT channel we opened, e.g., as it was opened normally, and was
+	/// ignored.
+	Ignored,
+}
+
+/// The strategy an LSP uses to size the channels it opens for JIT channel payments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelSizingStrategy {
+	/// The channel is sized to exactly fit the intercepted payment.
+	Exact,
+	/// The channel is sized to fit the intercepted payment plus the given amount of additional
+	/// liquidity.
+	Additional {
+		/// The additional liquidity in msat.
+		liquidity_msat: u64,
+	},
+}
+
+/// The reason an LSP rejected an LSPS2 `get_info` request without surfacing it as an
+/// [`LSPS2Event::GetInfo`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GetInfoRejectionReason {
+	/// The request carried a token although the LSP doesn't accept tokens.
+	InvalidToken,
+	/// Serving JIT channels was paused by the operator.
+	Maintenance,
+	/// The client exceeded its misbehavior allowance and its messages are refused.
+	RateLimited,
+}
+
+impl GetInfoRejectionReason {
+	/// Returns a label identifying the reason, suitable for logs and metrics.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			GetInfoRejectionReason::InvalidToken => "invalid_token",
+			GetInfoRejectionReason::Maintenance => "maintenance",
+			GetInfoRejectionReason::RateLimited => "rate_limited",
+		}
+	}
+}
+
+impl std::fmt::Display for GetInfoRejectionReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
 struct ChannelStateError(String);
 
 impl From<ChannelStateError> for LightningError {
@@ -55,17 +203,35 @@ impl From<ChannelStateError> for LightningError {
 
 struct InboundJITChannelConfig {
 	pub user_id: u128,
-	pub token: Option<String>,
+	pub correlation_id: u128,
+	pub token: Option<Token>,
 	pub payment_size_msat: Option<u64>,
+	pub label: Option<String>,
 }
 
 #[derive(PartialEq, Debug)]
 enum InboundJITChannelState {
 	VersionsRequested,
-	MenuRequested { version: u16 },
-	PendingMenuSelection { version: u16 },
-	BuyRequested { version: u16 },
-	PendingPayment { client_trusts_lsp: bool, short_channel_id: JitChannelScid },
+	MenuRequested {
+		version: u16,
+	},
+	PendingMenuSelection {
+		version: u16,
+		min_payment_size_msat: u64,
+		max_payment_size_msat: u64,
+	},
+	BuyRequested {
+		version: u16,
+	},
+	PendingPayment {
+		client_trusts_lsp: bool,
+		short_channel_id: JitChannelScid,
+		cltv_expiry_delta: u32,
+	},
+	PendingFundingConfirmation {
+		funding_txo: OutPoint,
+		confirmation_height: Option<u32>,
+	},
 }
 
 impl InboundJITChannelState {
@@ -91,10 +257,16 @@ impl InboundJITChannelState {
 		}
 	}
 
-	fn info_received(&self) -> Result<Self, ChannelStateError> {
+	fn info_received(
+		&self, min_payment_size_msat: u64, max_payment_size_msat: u64,
+	) -> Result<Self, ChannelStateError> {
 		match self {
 			InboundJITChannelState::MenuRequested { version } => {
-				Ok(InboundJITChannelState::PendingMenuSelection { version: *version })
+				Ok(InboundJITChannelState::PendingMenuSelection {
+					version: *version,
+					min_payment_size_msat,
+					max_payment_size_msat,
+				})
 			}
 			state => Err(ChannelStateError(format!(
 				"Received unexpected get_info response.  JIT Channel was in state: {:?}",
@@ -103,9 +275,55 @@ impl InboundJITChannelState {
 		}
 	}
 
-	fn opening_fee_params_selected(&self) -> Result<Self, ChannelStateError> {
+	fn version_known(&self, version: u16) -> Result<Self, ChannelStateError> {
+		match self {
+			InboundJITChannelState::VersionsRequested => {
+				Ok(InboundJITChannelState::MenuRequested { version })
+			}
+			state => Err(ChannelStateError(format!(
+				"Previously negotiated version used when JIT Channel was in state: {:?}",
+				state
+			))),
+		}
+	}
+
+	fn cached_info_received(
+		&self, version: u16, min_payment_size_msat: u64, max_payment_size_msat: u64,
+	) -> Result<Self, ChannelStateError> {
+		match self {
+			InboundJITChannelState::VersionsRequested => {
+				Ok(InboundJITChannelState::PendingMenuSelection {
+					version,
+					min_payment_size_msat,
+					max_payment_size_msat,
+				})
+			}
+			state => Err(ChannelStateError(format!(
+				"Cached get_info response used when JIT Channel was in state: {:?}",
+				state
+			))),
+		}
+	}
+
+	fn opening_fee_params_selected(
+		&self, payment_size_msat: Option<u64>,
+	) -> Result<Self, ChannelStateError> {
 		match self {
-			InboundJITChannelState::PendingMenuSelection { version } => {
+			InboundJITChannelState::PendingMenuSelection {
+				version,
+				min_payment_size_msat,
+				max_payment_size_msat,
+			} => {
+				if let Some(payment_size_msat) = payment_size_msat {
+					if payment_size_msat < *min_payment_size_msat
+						|| payment_size_msat > *max_payment_size_msat
+					{
+						return Err(ChannelStateError(format!(
+							"Payment size {} is outside of the bounds advertised by the LSP. min = {}, max = {}",
+							payment_size_msat, min_payment_size_msat, max_payment_size_msat
+						)));
+					}
+				}
 				Ok(InboundJITChannelState::BuyRequested { version: *version })
 			}
 			state => Err(ChannelStateError(format!(
@@ -115,12 +333,28 @@ impl InboundJITChannelState {
 		}
 	}
 
+	fn buy_retried(&self) -> Result<Self, ChannelStateError> {
+		match self {
+			InboundJITChannelState::BuyRequested { version } => {
+				Ok(InboundJITChannelState::MenuRequested { version: *version })
+			}
+			state => Err(ChannelStateError(format!(
+				"Buy retried when JIT Channel was in state: {:?}",
+				state
+			))),
+		}
+	}
+
 	fn invoice_params_received(
-		&self, client_trusts_lsp: bool, short_channel_id: JitChannelScid,
+		&self, client_trusts_lsp: bool, short_channel_id: JitChannelScid, cltv_expiry_delta: u32,
 	) -> Result<Self, ChannelStateError> {
 		match self {
 			InboundJITChannelState::BuyRequested { .. } => {
-				Ok(InboundJITChannelState::PendingPayment { client_trusts_lsp, short_channel_id })
+				Ok(InboundJITChannelState::PendingPayment {
+					client_trusts_lsp,
+					short_channel_id,
+					cltv_expiry_delta,
+				})
 			}
 			state => Err(ChannelStateError(format!(
 				"Invoice params received when JIT Channel was in state: {:?}",
@@ -128,25 +362,84 @@ impl InboundJITChannelState {
 			))),
 		}
 	}
+
+	fn payment_received(&self, funding_txo: OutPoint) -> Result<Self, ChannelStateError> {
+		match self {
+			InboundJITChannelState::PendingPayment { .. } => {
+				Ok(InboundJITChannelState::PendingFundingConfirmation {
+					funding_txo,
+					confirmation_height: None,
+				})
+			}
+			state => Err(ChannelStateError(format!(
+				"Payment received when JIT Channel was in state: {:?}",
+				state
+			))),
+		}
+	}
+}
+
+struct MenuExpiry {
+	valid_until: chrono::DateTime<Utc>,
+	warned: bool,
+}
+
+/// The selection a buy request was sent with, kept to retry the buy with fresh opening fee params
+/// should the selected ones have expired.
+struct BuySelection {
+	opening_fee_params: OpeningFeeParams,
+	refund_onchain_address: Option<String>,
+	announce_channel: Option<bool>,
 }
 
 struct InboundJITChannel {
 	id: u128,
 	state: InboundJITChannelState,
 	config: InboundJITChannelConfig,
+	menu_expiry: Option<MenuExpiry>,
+	selected_valid_until: Option<chrono::DateTime<Utc>>,
+	buy_selection: Option<BuySelection>,
+	buy_retried: bool,
+	// How often we scheduled to retry the pending request after it was lost to a disconnect.
+	transport_retries: u8,
+	// When to retry the pending request, if it was lost to a disconnect.
+	retry_at: Option<Instant>,
 }
 
 impl InboundJITChannel {
 	pub fn new(
-		id: u128, user_id: u128, payment_size_msat: Option<u64>, token: Option<String>,
+		id: u128, user_id: u128, correlation_id: u128, payment_size_msat: Option<u64>,
+		token: Option<Token>, label: Option<String>,
 	) -> Self {
 		Self {
 			id,
-			config: InboundJITChannelConfig { user_id, payment_size_msat, token },
+			config: InboundJITChannelConfig {
+				user_id,
+				correlation_id,
+				payment_size_msat,
+				token,
+				label,
+			},
 			state: InboundJITChannelState::VersionsRequested,
+			menu_expiry: None,
+			selected_valid_until: None,
+			buy_selection: None,
+			buy_retried: false,
+			transport_retries: 0,
+			retry_at: None,
 		}
 	}
 
+	/// Returns whether the pending request is a `get_versions` or `get_info` request, which may be
+	/// retried after it was lost to a disconnect.
+	fn awaiting_versions_or_menu(&self) -> bool {
+		matches!(
+			self.state,
+			InboundJITChannelState::VersionsRequested
+				| InboundJITChannelState::MenuRequested { .. }
+		)
+	}
+
 	pub fn versions_received(&mut self, versions: Vec<u16>) -> Result<u16, LightningError> {
 		self.state = self.state.versions_received(versions)?;
 
@@ -159,13 +452,47 @@ impl InboundJITChannel {
 		}
 	}
 
-	pub fn info_received(&mut self) -> Result<(), LightningError> {
-		self.state = self.state.info_received()?;
+	pub fn info_received(
+		&mut self, min_payment_size_msat: u64, max_payment_size_msat: u64,
+	) -> Result<(), LightningError> {
+		self.state = self.state.info_received(min_payment_size_msat, max_payment_size_msat)?;
+		Ok(())
+	}
+
+	pub fn version_known(&mut self, version: u16) -> Result<(), LightningError> {
+		self.state = self.state.version_known(version)?;
+		Ok(())
+	}
+
+	pub fn cached_info_received(
+		&mut self, version: u16, min_payment_size_msat: u64, max_payment_size_msat: u64,
+	) -> Result<(), LightningError> {
+		self.state = self.state.cached_info_received(
+			version,
+			min_payment_size_msat,
+			max_payment_size_msat,
+		)?;
 		Ok(())
 	}
 
-	pub fn opening_fee_params_selected(&mut self) -> Result<u16, LightningError> {
-		self.state = self.state.opening_fee_params_selected()?;
+	/// Tracks the earliest `valid_until` of the received opening fee params menu.
+	pub fn menu_received(&mut self, opening_fee_params_menu: &[OpeningFeeParams]) {
+		self.menu_expiry = opening_fee_params_menu
+			.iter()
+			.map(|params| params.valid_until)
+			.min()
+			.map(|valid_until| MenuExpiry { valid_until, warned: false });
+	}
+
+	pub fn awaiting_menu_selection(&self) -> bool {
+		matches!(self.state, InboundJITChannelState::PendingMenuSelection { .. })
+	}
+
+	pub fn opening_fee_params_selected(
+		&mut self, valid_until: chrono::DateTime<Utc>,
+	) -> Result<u16, LightningError> {
+		self.state = self.state.opening_fee_params_selected(self.config.payment_size_msat)?;
+		self.selected_valid_until = Some(valid_until);
 
 		match self.state {
 			InboundJITChannelState::BuyRequested { version } => Ok(version),
@@ -176,12 +503,224 @@ impl InboundJITChannel {
 		}
 	}
 
+	/// Moves back to requesting a menu to retry the buy with fresh opening fee params, returning
+	/// the version to request it with, unless the buy was retried before or the selected opening
+	/// fee params didn't expire yet.
+	pub fn retry_buy(&mut self, seconds_since_epoch: i64) -> Option<u16> {
+		if self.buy_retried {
+			return None;
+		}
+		match &self.buy_selection {
+			Some(selection)
+				if selection.opening_fee_params.valid_until.timestamp() <= seconds_since_epoch => {}
+			_ => return None,
+		}
+		self.state = self.state.buy_retried().ok()?;
+		self.buy_retried = true;
+
+		match self.state {
+			InboundJITChannelState::MenuRequested { version } => Some(version),
+			_ => None,
+		}
+	}
+
+	/// Returns the selection to retry the buy with, if the buy is being retried and the given
+	/// menu offers opening fee params on the same terms as the ones selected before.
+	pub fn buy_retry_selection(
+		&mut self, opening_fee_params_menu: &[OpeningFeeParams],
+	) -> Option<BuySelection> {
+		if !self.buy_retried {
+			return None;
+		}
+		let selection = self.buy_selection.take()?;
+		let selected = &selection.opening_fee_params;
+		let opening_fee_params = opening_fee_params_menu.iter().find(|params| {
+			params.min_fee_msat == selected.min_fee_msat
+				&& params.proportional == selected.proportional
+				&& params.min_lifetime == selected.min_lifetime
+				&& params.max_client_to_self_delay == selected.max_client_to_self_delay
+		})?;
+		Some(BuySelection { opening_fee_params: opening_fee_params.clone(), ..selection })
+	}
+
 	pub fn invoice_params_received(
 		&mut self, client_trusts_lsp: bool, jit_channel_scid: JitChannelScid,
+		cltv_expiry_delta: u32,
 	) -> Result<(), LightningError> {
-		self.state = self.state.invoice_params_received(client_trusts_lsp, jit_channel_scid)?;
+		self.state = self.state.invoice_params_received(
+			client_trusts_lsp,
+			jit_channel_scid,
+			cltv_expiry_delta,
+		)?;
 		Ok(())
 	}
+
+	/// Returns whether the client trusts the LSP to broadcast the funding transaction.
+	pub fn payment_received(&mut self, funding_txo: OutPoint) -> Result<bool, LightningError> {
+		let client_trusts_lsp = match self.state {
+			InboundJITChannelState::PendingPayment { client_trusts_lsp, .. } => client_trusts_lsp,
+			_ => false,
+		};
+		self.state = self.state.payment_received(funding_txo)?;
+		Ok(client_trusts_lsp)
+	}
+
+	pub fn pending_funding_txo(&self) -> Option<OutPoint> {
+		match self.state {
+			InboundJITChannelState::PendingFundingConfirmation { funding_txo, .. } => {
+				Some(funding_txo)
+			}
+			_ => None,
+		}
+	}
+
+	/// Records the height of the block the pending funding transaction was confirmed in.
+	pub fn funding_confirmed(&mut self, height: u32) {
+		if let InboundJITChannelState::PendingFundingConfirmation {
+			confirmation_height: confirmation_height @ None,
+			..
+		} = &mut self.state
+		{
+			*confirmation_height = Some(height);
+		}
+	}
+
+	pub fn funding_unconfirmed(&mut self) {
+		if let InboundJITChannelState::PendingFundingConfirmation { confirmation_height, .. } =
+			&mut self.state
+		{
+			*confirmation_height = None;
+		}
+	}
+
+	/// Returns the number of confirmations of the pending funding transaction given the height of
+	/// the best block.
+	pub fn funding_confirmations(&self, best_height: u32) -> u32 {
+		match self.state {
+			InboundJITChannelState::PendingFundingConfirmation {
+				confirmation_height: Some(confirmation_height),
+				..
+			} => (best_height + 1).saturating_sub(confirmation_height),
+			_ => 0,
+		}
+	}
+
+	pub fn route_hint_params(&self) -> Option<(u64, u32)> {
+		match &self.state {
+			InboundJITChannelState::PendingPayment {
+				short_channel_id, cltv_expiry_delta, ..
+			} => short_channel_id.to_scid().ok().map(|scid| (scid, *cltv_expiry_delta)),
+			_ => None,
+		}
+	}
+}
+
+/// The stage a JIT channel negotiation is in, as returned by [`LiquidityManager::jit_channel_state`].
+///
+/// Clients progress from [`JitChannelState::AwaitingGetInfo`] to
+/// [`JitChannelState::AwaitingPayment`], and to [`JitChannelState::Ready`] once the received
+/// payment may be claimed as signalled by [`LSPS2Event::ClaimPayment`]. LSPs progress from
+/// [`JitChannelState::AwaitingPayment`] to [`JitChannelState::Ready`].
+///
+/// [`LiquidityManager::jit_channel_state`]: crate::LiquidityManager::jit_channel_state
+/// [`LSPS2Event::ClaimPayment`]: crate::jit_channel::LSPS2Event::ClaimPayment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JitChannelState {
+	/// The client requested the opening fee params menu from the LSP and is awaiting it.
+	AwaitingGetInfo,
+	/// The client received the opening fee params menu and has yet to receive the invoice
+	/// parameters for its selection.
+	AwaitingBuy,
+	/// The invoice parameters were handed out and the payment is awaited.
+	AwaitingPayment,
+	/// The LSP intercepted the payment and is opening the channel.
+	OpeningChannel,
+	/// The channel is ready and the intercepted payment was forwarded.
+	Ready,
+	/// The negotiation failed and was abandoned.
+	Failed,
+}
+
+impl JitChannelState {
+	/// Returns whether a channel in state `current` may move to state `next`.
+	///
+	/// A negotiation may be (re-)started or abandoned at any point, but the payment is only awaited
+	/// after a menu entry was bought (or, for LSPs, once the buy request was accepted), and the
+	/// channel only gets ready after the payment arrived.
+	fn is_valid_transition(current: Option<Self>, next: Self) -> bool {
+		matches!(
+			(current, next),
+			(_, JitChannelState::AwaitingGetInfo)
+				| (_, JitChannelState::AwaitingBuy)
+				| (_, JitChannelState::Failed)
+				| (None, JitChannelState::AwaitingPayment)
+				| (Some(JitChannelState::AwaitingBuy), JitChannelState::AwaitingPayment)
+				| (Some(JitChannelState::AwaitingPayment), JitChannelState::OpeningChannel)
+				| (Some(JitChannelState::AwaitingPayment), JitChannelState::Ready)
+				| (Some(JitChannelState::OpeningChannel), JitChannelState::Ready)
+		)
+	}
+
+	/// Returns whether the negotiation ended in this state.
+	fn is_terminal(self) -> bool {
+		matches!(self, JitChannelState::Ready | JitChannelState::Failed)
+	}
+}
+
+/// Identifies a JIT channel whose [`JitChannelState`] we track.
+///
+/// Channels bought as client and sold as LSP are kept apart, as a node acting as both may use the
+/// same number as `user_channel_id` of one and intercept scid of the other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ChannelStateKey {
+	/// A channel bought as client, identified by its `user_channel_id`.
+	Client(u128),
+	/// A channel sold as LSP, identified by its intercept scid.
+	Lsp(u64),
+}
+
+/// The states of the JIT channels, remembering terminal states only for the most recent channels.
+#[derive(Default)]
+struct ChannelStates {
+	states: HashMap<ChannelStateKey, JitChannelState>,
+	// The channels in a terminal state, oldest first.
+	terminal: VecDeque<ChannelStateKey>,
+}
+
+impl ChannelStates {
+	fn get(&self, key: ChannelStateKey) -> Option<JitChannelState> {
+		self.states.get(&key).copied()
+	}
+
+	/// Sets the state of the given channel, returning the previous one.
+	///
+	/// Forgets the oldest terminal state if we're at capacity and the new state is terminal.
+	fn insert(&mut self, key: ChannelStateKey, state: JitChannelState) -> Option<JitChannelState> {
+		let previous_state = self.states.insert(key, state);
+		if previous_state.map_or(false, JitChannelState::is_terminal) {
+			self.terminal.retain(|terminal_key| *terminal_key != key);
+		}
+		if state.is_terminal() {
+			if self.terminal.len() >= MAX_TERMINAL_CHANNEL_STATES {
+				if let Some(oldest_key) = self.terminal.pop_front() {
+					self.states.remove(&oldest_key);
+				}
+			}
+			self.terminal.push_back(key);
+		}
+		previous_state
+	}
+}
+
+/// The parameters required to include a route hint for a JIT channel in a BOLT11 invoice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteHintBundle {
+	/// The node id of the LSP, i.e., the source node of the route hint.
+	pub counterparty_node_id: PublicKey,
+	/// The short channel id to use in the route hint.
+	pub scid: u64,
+	/// The `cltv_expiry_delta` to use in the route hint.
+	pub cltv_expiry_delta: u32,
 }
 
 #[derive(PartialEq, Debug)]
@@ -200,6 +739,10 @@ enum OutboundJITChannelState {
 	ChannelReady {
 		intercept_id: InterceptId,
 		amt_to_forward_msat: u64,
+		channel_id: ChannelId,
+		ready_at: Instant,
+		opening_fee_msat: u64,
+		opening_fee_earned: bool,
 	},
 }
 
@@ -238,15 +781,19 @@ impl OutboundJITChannelState {
 		}
 	}
 
-	pub fn channel_ready(&self) -> Result<Self, ChannelStateError> {
+	pub fn channel_ready(&self, channel_id: ChannelId) -> Result<Self, ChannelStateError> {
 		match self {
 			OutboundJITChannelState::PendingChannelOpen {
 				intercept_id,
 				amt_to_forward_msat,
-				..
+				opening_fee_msat,
 			} => Ok(OutboundJITChannelState::ChannelReady {
 				intercept_id: *intercept_id,
 				amt_to_forward_msat: *amt_to_forward_msat,
+				channel_id,
+				ready_at: utils::now(),
+				opening_fee_msat: *opening_fee_msat,
+				opening_fee_earned: false,
 			}),
 			state => Err(ChannelStateError(format!(
 				"Channel ready received when JIT Channel was in state: {:?}",
@@ -258,12 +805,31 @@ impl OutboundJITChannelState {
 
 struct OutboundJITChannel {
 	state: OutboundJITChannelState,
+	funding_txo: Option<OutPoint>,
+	announce_channel: Option<bool>,
+	channel_config: Option<ChannelConfig>,
 }
 
 impl OutboundJITChannel {
+	/// Returns the opening fee if a payment was forwarded over this channel for the first time.
+	pub fn payment_forwarded(&mut self, next_channel_id: &ChannelId) -> Option<u64> {
+		match &mut self.state {
+			OutboundJITChannelState::ChannelReady {
+				channel_id,
+				opening_fee_msat,
+				opening_fee_earned,
+				..
+			} if channel_id == next_channel_id && !*opening_fee_earned => {
+				*opening_fee_earned = true;
+				Some(*opening_fee_msat)
+			}
+			_ => None,
+		}
+	}
+
 	pub fn new(
 		scid: u64, cltv_expiry_delta: u32, payment_size_msat: Option<u64>,
-		opening_fee_params: OpeningFeeParams,
+		opening_fee_params: OpeningFeeParams, announce_channel: Option<bool>,
 	) -> Self {
 		Self {
 			state: OutboundJITChannelState::new(
@@ -272,6 +838,9 @@ impl OutboundJITChannel {
 				payment_size_msat,
 				opening_fee_params,
 			),
+			funding_txo: None,
+			announce_channel,
+			channel_config: None,
 		}
 	}
 
@@ -296,11 +865,13 @@ impl OutboundJITChannel {
 		}
 	}
 
-	pub fn channel_ready(&mut self) -> Result<(InterceptId, u64), LightningError> {
-		self.state = self.state.channel_ready()?;
+	pub fn channel_ready(
+		&mut self, channel_id: ChannelId,
+	) -> Result<(InterceptId, u64), LightningError> {
+		self.state = self.state.channel_ready(channel_id)?;
 
 		match &self.state {
-			OutboundJITChannelState::ChannelReady { intercept_id, amt_to_forward_msat } => {
+			OutboundJITChannelState::ChannelReady { intercept_id, amt_to_forward_msat, .. } => {
 				Ok((*intercept_id, *amt_to_forward_msat))
 			}
 			impossible_state => Err(LightningError {
@@ -314,12 +885,143 @@ impl OutboundJITChannel {
 	}
 }
 
+struct PromiseSecrets {
+	active: [u8; 32],
+	// Previously active secrets along with the time they were retired at, in seconds since the
+	// unix epoch.
+	retired: Vec<([u8; 32], u64)>,
+}
+
+struct LspSelectionCandidate {
+	counterparty_node_id: PublicKey,
+	jit_channel_id: u128,
+	opening_fee_params: OpeningFeeParams,
+	// The opening fee for a known payment size, or the `min_fee_msat` and `proportional` fee
+	// otherwise.
+	cost: (u64, u32),
+}
+
+struct LspSelection {
+	payment_size_msat: Option<u64>,
+	awaiting_menu: HashSet<PublicKey>,
+	channels: Vec<(PublicKey, u128)>,
+	cheapest: Option<LspSelectionCandidate>,
+	last_failure: Option<FailureReason>,
+	deadline: Instant,
+}
+
+/// The protocol versions negotiated with LSPs, keyed by their node id.
+#[derive(Default)]
+pub(crate) struct NegotiatedVersions(HashMap<PublicKey, u16>);
+
+impl Writeable for NegotiatedVersions {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_fields!(writer, {
+			(0, self.0, required),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for NegotiatedVersions {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, versions, required),
+		});
+		Ok(Self(versions.0.unwrap()))
+	}
+}
+
+/// The ids of the intercepted HTLCs we processed, oldest first.
+#[derive(Default)]
+pub(crate) struct ProcessedIntercepts(VecDeque<InterceptId>);
+
+impl ProcessedIntercepts {
+	/// Remembers the given intercept id, forgetting the oldest one if we're at capacity.
+	///
+	/// Returns whether the intercept id wasn't known yet.
+	fn insert(&mut self, intercept_id: InterceptId) -> bool {
+		if self.0.contains(&intercept_id) {
+			return false;
+		}
+		if self.0.len() >= MAX_PROCESSED_INTERCEPTS {
+			self.0.pop_front();
+		}
+		self.0.push_back(intercept_id);
+		true
+	}
+}
+
+impl Writeable for ProcessedIntercepts {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let intercept_ids: Vec<InterceptId> = self.0.iter().cloned().collect();
+		write_tlv_fields!(writer, {
+			(0, intercept_ids, required_vec),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for ProcessedIntercepts {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, intercept_ids, required_vec),
+		});
+		Ok(Self(intercept_ids.into_iter().collect()))
+	}
+}
+
+/// The channel opens we requested via [`LSPS2Event::OpenChannel`] events, and the ones deferred
+/// due to [`JITChannelsConfig::max_pending_channel_opens`].
+#[derive(Default)]
+struct ChannelOpenQueue {
+	// The `user_channel_id`s of the channels we requested to be opened that aren't ready yet.
+	in_flight: HashSet<u128>,
+	deferred: VecDeque<(u128, LSPS2Event)>,
+}
+
+impl ChannelOpenQueue {
+	/// Returns whether opening the given channel was deferred, i.e., not requested yet.
+	fn is_deferred(&self, user_channel_id: u128) -> bool {
+		self.deferred.iter().any(|(id, _)| *id == user_channel_id)
+	}
+}
+
+struct CachedGetInfoResponse {
+	version: u16,
+	response: GetInfoResponse,
+	expires_at: chrono::DateTime<Utc>,
+}
+
 #[derive(Default)]
 struct PeerState {
 	inbound_channels_by_id: HashMap<u128, InboundJITChannel>,
 	outbound_channels_by_scid: HashMap<u64, OutboundJITChannel>,
 	request_to_cid: HashMap<RequestId, u128>,
+	// The requests we sent and haven't received a response for yet.
+	sent_requests: HashMap<RequestId, LSPS2Request>,
 	pending_requests: HashMap<RequestId, LSPS2Request>,
+	// The intercept scids allocated for the pending buy requests.
+	allocated_scids: HashMap<RequestId, u64>,
+	// When the pending get_info requests duplicates may be coalesced with were received.
+	get_info_received_at: HashMap<RequestId, Instant>,
+	// The duplicate get_info requests to answer along with the pending request they duplicate.
+	coalesced_get_info_requests: HashMap<RequestId, Vec<RequestId>>,
+	// The duplicate buy requests to answer along with the pending request they duplicate.
+	coalesced_buy_requests: HashMap<RequestId, Vec<RequestId>>,
+	// The get_info requests the opening fee params handed out to the peer were generated for, by
+	// promise.
+	get_info_contexts: HashMap<String, GetInfoContext>,
+	// Whether the peer disconnected and didn't reconnect yet.
+	disconnected: bool,
+}
+
+/// The context of a `get_info` request, kept to interpret buy requests selecting opening fee
+/// params generated in response to it.
+struct GetInfoContext {
+	version: u16,
+	token: Option<Token>,
+	valid_until: chrono::DateTime<Utc>,
 }
 
 impl PeerState {
@@ -331,8 +1033,28 @@ impl PeerState {
 		self.outbound_channels_by_scid.insert(scid, channel);
 	}
 
-	pub fn insert_request(&mut self, request_id: RequestId, jit_channel_id: u128) {
-		self.request_to_cid.insert(request_id, jit_channel_id);
+	pub fn insert_request(
+		&mut self, request_id: RequestId, jit_channel_id: u128, request: LSPS2Request,
+	) {
+		self.request_to_cid.insert(request_id.clone(), jit_channel_id);
+		self.sent_requests.insert(request_id, request);
+	}
+
+	pub fn remove_request(&mut self, request_id: &RequestId) -> Option<u128> {
+		self.sent_requests.remove(request_id);
+		self.request_to_cid.remove(request_id)
+	}
+
+	pub fn remove_requests_for_channel(&mut self, jit_channel_id: u128) {
+		let sent_requests = &mut self.sent_requests;
+		self.request_to_cid.retain(|request_id, cid| {
+			if *cid == jit_channel_id {
+				sent_requests.remove(request_id);
+				false
+			} else {
+				true
+			}
+		});
 	}
 
 	pub fn remove_inbound_channel(&mut self, jit_channel_id: u128) {
@@ -342,8 +1064,19 @@ impl PeerState {
 	pub fn remove_outbound_channel(&mut self, scid: u64) {
 		self.outbound_channels_by_scid.remove(&scid);
 	}
+
+	/// Returns whether a JIT channel is being negotiated with the peer or waits to be opened.
+	pub fn has_active_negotiations(&self) -> bool {
+		!self.inbound_channels_by_id.is_empty()
+			|| !self.sent_requests.is_empty()
+			|| !self.pending_requests.is_empty()
+			|| self.outbound_channels_by_scid.values().any(|jit_channel| {
+				!matches!(jit_channel.state, OutboundJITChannelState::ChannelReady { .. })
+			})
+	}
 }
 
+#[allow(clippy::type_complexity)]
 pub struct JITChannelManager<
 	ES: Deref,
 	M: Deref,
@@ -373,15 +1106,53 @@ pub struct JITChannelManager<
 	NS::Target: NodeSigner,
 {
 	entropy_source: ES,
+	fee_estimator: F,
+	logger: L,
 	peer_manager: Mutex<Option<Arc<PeerManager<Descriptor, CM, RM, OM, L, CMH, NS>>>>,
+	logged_missing_peer_manager: AtomicBool,
 	channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>,
 	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 	pending_events: Arc<EventQueue>,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
 	peer_by_scid: RwLock<HashMap<u64, PublicKey>>,
-	promise_secret: [u8; 32],
+	promise_secrets: RwLock<PromiseSecrets>,
+	promise_secret_grace_period_secs: u64,
+	domain_separated_promises: bool,
 	min_payment_size_msat: u64,
 	max_payment_size_msat: u64,
+	min_fee_funding_fee_multiplier: Option<u64>,
+	supported_versions: Vec<u16>,
+	cache_get_info_responses: bool,
+	reject_requests_with_token: bool,
+	max_valid_until_horizon: Duration,
+	max_menu_length: usize,
+	late_htlc_grace_period: Duration,
+	get_info_dedup_window: Option<Duration>,
+	verbose_buy_events: bool,
+	retry_buy_on_expired_params: bool,
+	max_transport_retries: u8,
+	transport_retry_backoff: Duration,
+	max_pending_channel_opens: Option<usize>,
+	channel_open_queue: Mutex<ChannelOpenQueue>,
+	allow_free_channels: bool,
+	await_funding_confirmation: bool,
+	require_lsp_trusts_client: bool,
+	assume_client_trusts_lsp_when_absent: bool,
+	min_funding_confirmations: u32,
+	lsp_selection_timeout: Duration,
+	fee_params_expiry_warning_lead_time: Option<Duration>,
+	channel_sizing_strategy: ChannelSizingStrategy,
+	peer_channel_sizing_strategies: Mutex<HashMap<PublicKey, ChannelSizingStrategy>>,
+	scid_allocator: Option<Arc<dyn ScidAllocator + Send + Sync>>,
+	clock: Option<Arc<dyn Clock + Send + Sync>>,
+	lsp_selections: Mutex<HashMap<u128, LspSelection>>,
+	get_info_cache: Mutex<HashMap<(PublicKey, Option<Token>), CachedGetInfoResponse>>,
+	channel_states: Mutex<ChannelStates>,
+	channel_lsps: Mutex<HashMap<u128, PublicKey>>,
+	negotiated_versions: Mutex<HashMap<PublicKey, u16>>,
+	processed_intercepts: Mutex<ProcessedIntercepts>,
+	total_fees_earned_msat: AtomicU64,
+	get_info_rejections: Mutex<HashMap<GetInfoRejectionReason, u64>>,
 }
 
 impl<
@@ -413,22 +1184,69 @@ where
 	CMH::Target: CustomMessageHandler,
 	NS::Target: NodeSigner,
 {
+	#[allow(clippy::type_complexity)]
 	pub(crate) fn new(
-		entropy_source: ES, config: &JITChannelsConfig,
+		entropy_source: ES, fee_estimator: F, logger: L, config: &JITChannelsConfig,
 		pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 		pending_events: Arc<EventQueue>,
 		channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>,
 	) -> Self {
 		Self {
 			entropy_source,
-			promise_secret: config.promise_secret,
+			fee_estimator,
+			logger,
+			promise_secrets: RwLock::new(PromiseSecrets {
+				active: config.promise_secret,
+				retired: Vec::new(),
+			}),
+			promise_secret_grace_period_secs: config.promise_secret_grace_period_secs,
+			domain_separated_promises: config.domain_separated_promises,
 			min_payment_size_msat: config.min_payment_size_msat,
 			max_payment_size_msat: config.max_payment_size_msat,
+			min_fee_funding_fee_multiplier: config.min_fee_funding_fee_multiplier,
+			supported_versions: config
+				.supported_versions
+				.iter()
+				.filter(|version| SUPPORTED_SPEC_VERSIONS.contains(version))
+				.cloned()
+				.collect(),
+			cache_get_info_responses: config.cache_get_info_responses,
+			reject_requests_with_token: config.reject_requests_with_token,
+			max_valid_until_horizon: config.max_valid_until_horizon,
+			max_menu_length: config.max_menu_length,
+			late_htlc_grace_period: config.late_htlc_grace_period,
+			get_info_dedup_window: config.get_info_dedup_window,
+			verbose_buy_events: config.verbose_buy_events,
+			retry_buy_on_expired_params: config.retry_buy_on_expired_params,
+			max_transport_retries: config.max_transport_retries,
+			transport_retry_backoff: config.transport_retry_backoff,
+			max_pending_channel_opens: config.max_pending_channel_opens,
+			channel_open_queue: Mutex::new(ChannelOpenQueue::default()),
+			allow_free_channels: config.allow_free_channels,
+			await_funding_confirmation: config.await_funding_confirmation,
+			require_lsp_trusts_client: config.require_lsp_trusts_client,
+			assume_client_trusts_lsp_when_absent: config.assume_client_trusts_lsp_when_absent,
+			min_funding_confirmations: config.min_funding_confirmations.max(1),
+			lsp_selection_timeout: config.lsp_selection_timeout,
+			fee_params_expiry_warning_lead_time: config.fee_params_expiry_warning_lead_time,
+			channel_sizing_strategy: config.channel_sizing_strategy,
+			peer_channel_sizing_strategies: Mutex::new(HashMap::new()),
+			scid_allocator: config.scid_allocator.clone(),
+			clock: config.clock.clone(),
+			lsp_selections: Mutex::new(HashMap::new()),
+			get_info_cache: Mutex::new(HashMap::new()),
+			channel_states: Mutex::new(ChannelStates::default()),
+			channel_lsps: Mutex::new(HashMap::new()),
+			negotiated_versions: Mutex::new(HashMap::new()),
+			processed_intercepts: Mutex::new(ProcessedIntercepts::default()),
+			total_fees_earned_msat: AtomicU64::new(0),
+			get_info_rejections: Mutex::new(HashMap::new()),
 			pending_messages,
 			pending_events,
 			per_peer_state: RwLock::new(HashMap::new()),
 			peer_by_scid: RwLock::new(HashMap::new()),
 			peer_manager: Mutex::new(None),
+			logged_missing_peer_manager: AtomicBool::new(false),
 			channel_manager,
 		}
 	}
@@ -439,42 +1257,725 @@ where
 		*self.peer_manager.lock().unwrap() = Some(peer_manager);
 	}
 
-	pub fn create_invoice(
-		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
-		token: Option<String>, user_channel_id: u128,
-	) {
-		let jit_channel_id = self.generate_jit_channel_id();
-		let channel =
-			InboundJITChannel::new(jit_channel_id, user_channel_id, payment_size_msat, token);
+	pub fn peer_manager_set(&self) -> bool {
+		self.peer_manager.lock().unwrap().is_some()
+	}
 
-		let mut outer_state_lock = self.per_peer_state.write().unwrap();
-		let inner_state_lock = outer_state_lock
-			.entry(counterparty_node_id)
-			.or_insert(Mutex::new(PeerState::default()));
-		let peer_state = inner_state_lock.get_mut().unwrap();
-		peer_state.insert_inbound_channel(jit_channel_id, channel);
+	pub fn rotate_promise_secret(&self, new_secret: [u8; 32]) {
+		let retired_at = self.duration_since_epoch().as_secs();
 
-		let request_id = self.generate_request_id();
-		peer_state.insert_request(request_id.clone(), jit_channel_id);
+		let mut promise_secrets = self.promise_secrets.write().unwrap();
+		let retired_secret = std::mem::replace(&mut promise_secrets.active, new_secret);
+		promise_secrets.retired.push((retired_secret, retired_at));
+	}
 
-		{
-			let mut pending_messages = self.pending_messages.lock().unwrap();
-			pending_messages.push((
-				counterparty_node_id,
-				LSPS2Message::Request(request_id, LSPS2Request::GetVersions(GetVersionsRequest {}))
-					.into(),
-			));
+	/// Drops all retired promise secrets whose grace period has passed at `seconds_since_epoch`.
+	/// Checks the configuration only required for acting as LSP, which clients needn't set.
+	fn check_lsp_configured(&self) -> Result<(), APIError> {
+		check_promise_secret(&self.promise_secrets.read().unwrap().active)?;
+		if !self.channel_manager.get_current_default_configuration().accept_intercept_htlcs {
+			return Err(APIError::APIMisuseError {
+				err: "JIT channels require the ChannelManager to be configured with UserConfig::accept_intercept_htlcs".to_string(),
+			});
 		}
+		Ok(())
+	}
 
-		if let Some(peer_manager) = self.peer_manager.lock().unwrap().as_ref() {
-			peer_manager.process_events();
+	pub(crate) fn prune_promise_secrets(&self, seconds_since_epoch: u64) {
+		let grace_period_secs = self.promise_secret_grace_period_secs;
+		self.promise_secrets.write().unwrap().retired.retain(|(_, retired_at)| {
+			retired_at.saturating_add(grace_period_secs) >= seconds_since_epoch
+		});
+	}
+
+	fn promise_version(&self, protocol_version: u16) -> PromiseVersion {
+		if self.domain_separated_promises {
+			PromiseVersion::V2 { protocol_version }
+		} else {
+			PromiseVersion::V1
 		}
 	}
 
-	pub fn opening_fee_params_generated(
-		&self, counterparty_node_id: PublicKey, request_id: RequestId,
+	fn is_valid_opening_fee_params(
+		&self, opening_fee_params: &OpeningFeeParams, protocol_version: u16,
+	) -> bool {
+		let seconds_since_epoch = self.duration_since_epoch().as_secs();
+		self.prune_promise_secrets(seconds_since_epoch);
+
+		let promise_version = self.promise_version(protocol_version);
+		let promise_secrets = self.promise_secrets.read().unwrap();
+		let mut secrets = std::iter::once(&promise_secrets.active)
+			.chain(promise_secrets.retired.iter().map(|(secret, _)| secret));
+
+		// Verify with the indicated secret only, falling back to trying all secrets if the client
+		// didn't echo the key id or it is unknown, e.g., as the secret was pruned meanwhile.
+		let indicated_secret = opening_fee_params
+			.key_id
+			.as_ref()
+			.and_then(|key_id| secrets.clone().find(|secret| promise_key_id(secret) == *key_id));
+		match indicated_secret {
+			Some(secret) => is_valid_opening_fee_params_at(
+				opening_fee_params,
+				secret,
+				promise_version,
+				seconds_since_epoch,
+			),
+			None => secrets.any(|secret| {
+				is_valid_opening_fee_params_at(
+					opening_fee_params,
+					secret,
+					promise_version,
+					seconds_since_epoch,
+				)
+			}),
+		}
+	}
+
+	/// Returns the time elapsed since the unix epoch according to the configured [`Clock`], if
+	/// any, or the system clock otherwise.
+	fn duration_since_epoch(&self) -> Duration {
+		match &self.clock {
+			Some(clock) => clock.duration_since_epoch(),
+			None => utils::duration_since_epoch(),
+		}
+	}
+
+	pub fn create_invoice(
+		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
+		token: Option<Token>, user_channel_id: u128, label: Option<String>,
+	) -> u128 {
+		let jit_channel_id = self.generate_jit_channel_id();
+		let correlation_id = self.generate_correlation_id();
+		log_debug!(
+			self.logger,
+			"Starting JIT channel negotiation {:032x} with {} for user_channel_id {}",
+			correlation_id,
+			counterparty_node_id,
+			user_channel_id
+		);
+		let cached_response = self.cached_get_info_response(&counterparty_node_id, &token);
+		// During an LSP selection, the LSP is only known once the selection is finalized.
+		if !self.lsp_selections.lock().unwrap().contains_key(&user_channel_id) {
+			self.channel_lsps.lock().unwrap().insert(user_channel_id, counterparty_node_id);
+		}
+		let mut channel = InboundJITChannel::new(
+			jit_channel_id,
+			user_channel_id,
+			correlation_id,
+			payment_size_msat,
+			token,
+			label.clone(),
+		);
+
+		let mut outer_state_lock = self.per_peer_state.write().unwrap();
+		let inner_state_lock = outer_state_lock
+			.entry(counterparty_node_id)
+			.or_insert(Mutex::new(PeerState::default()));
+		let peer_state = inner_state_lock.get_mut().unwrap();
+
+		if let Some((version, response)) = cached_response {
+			if channel
+				.cached_info_received(
+					version,
+					response.min_payment_size_msat,
+					response.max_payment_size_msat,
+				)
+				.is_ok()
+			{
+				channel.menu_received(&response.opening_fee_params_menu);
+				peer_state.insert_inbound_channel(jit_channel_id, channel);
+				self.set_channel_state(
+					ChannelStateKey::Client(user_channel_id),
+					JitChannelState::AwaitingBuy,
+				);
+				if !self.lsp_selection_menu_received(
+					counterparty_node_id,
+					jit_channel_id,
+					user_channel_id,
+					&response,
+				) {
+					self.enqueue_event(Event::LSPS2(LSPS2Event::GetInfoResponse {
+						counterparty_node_id,
+						opening_fee_params_menu: response.opening_fee_params_menu,
+						min_payment_size_msat: response.min_payment_size_msat,
+						max_payment_size_msat: response.max_payment_size_msat,
+						jit_channel_id,
+						user_channel_id,
+						label,
+						correlation_id,
+					}));
+				}
+				return jit_channel_id;
+			}
+		}
+
+		let request = match self.negotiated_version(&counterparty_node_id) {
+			Some(version) if channel.version_known(version).is_ok() => {
+				let token = channel.config.token.clone();
+				LSPS2Request::GetInfo(GetInfoRequest { version, token })
+			}
+			_ => LSPS2Request::GetVersions(GetVersionsRequest {}),
+		};
+
+		peer_state.insert_inbound_channel(jit_channel_id, channel);
+		self.set_channel_state(
+			ChannelStateKey::Client(user_channel_id),
+			JitChannelState::AwaitingGetInfo,
+		);
+
+		let request_id = self.generate_request_id();
+		peer_state.insert_request(request_id.clone(), jit_channel_id, request.clone());
+
+		self.enqueue_message(counterparty_node_id, LSPS2Message::Request(request_id, request));
+
+		jit_channel_id
+	}
+
+	pub fn create_invoice_multi(
+		&self, lsp_candidates: Vec<PublicKey>, payment_size_msat: Option<u64>,
+		user_channel_id: u128,
+	) -> Result<(), APIError> {
+		let awaiting_menu: HashSet<PublicKey> = lsp_candidates.iter().cloned().collect();
+		if awaiting_menu.is_empty() {
+			return Err(APIError::APIMisuseError {
+				err: "At least one LSP candidate is required".to_string(),
+			});
+		}
+
+		{
+			let mut lsp_selections = self.lsp_selections.lock().unwrap();
+			if lsp_selections.contains_key(&user_channel_id) {
+				return Err(APIError::APIMisuseError {
+					err: format!(
+						"An LSP selection for user_channel_id {} is already in progress",
+						user_channel_id
+					),
+				});
+			}
+			lsp_selections.insert(
+				user_channel_id,
+				LspSelection {
+					payment_size_msat,
+					awaiting_menu: awaiting_menu.clone(),
+					channels: Vec::new(),
+					cheapest: None,
+					last_failure: None,
+					deadline: utils::now() + self.lsp_selection_timeout,
+				},
+			);
+		}
+
+		for counterparty_node_id in awaiting_menu {
+			let jit_channel_id = self.create_invoice(
+				counterparty_node_id,
+				payment_size_msat,
+				None,
+				user_channel_id,
+				None,
+			);
+			if let Some(selection) = self.lsp_selections.lock().unwrap().get_mut(&user_channel_id) {
+				selection.channels.push((counterparty_node_id, jit_channel_id));
+			}
+		}
+
+		// All menus might have been served from the cache.
+		if self.lsp_selection_complete(user_channel_id) {
+			self.finalize_lsp_selection(user_channel_id);
+		}
+
+		Ok(())
+	}
+
+	/// Finalizes all LSP selections whose timeout has passed, choosing among the menus received
+	/// so far.
+	pub fn timer_tick_occurred(&self) {
+		let seconds_since_epoch = self.duration_since_epoch().as_secs();
+		self.check_fee_params_expiry(seconds_since_epoch);
+
+		let now = utils::now();
+		let timed_out: Vec<u128> = self
+			.lsp_selections
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(_, selection)| selection.deadline <= now)
+			.map(|(user_channel_id, _)| *user_channel_id)
+			.collect();
+
+		for user_channel_id in timed_out {
+			self.finalize_lsp_selection(user_channel_id);
+		}
+
+		let counterparty_node_ids: Vec<PublicKey> =
+			self.per_peer_state.read().unwrap().keys().cloned().collect();
+		for counterparty_node_id in counterparty_node_ids {
+			self.retry_lost_requests(&counterparty_node_id);
+		}
+	}
+
+	/// Generates a [`LSPS2Event::FeeParamsExpiringSoon`] event for every received opening fee
+	/// params menu not selected from yet which expires within the configured lead time of
+	/// `seconds_since_epoch`.
+	pub(crate) fn check_fee_params_expiry(&self, seconds_since_epoch: u64) {
+		let lead_time_secs = match self.fee_params_expiry_warning_lead_time {
+			Some(lead_time) => lead_time.as_secs() as i64,
+			None => return,
+		};
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			for jit_channel in peer_state.inbound_channels_by_id.values_mut() {
+				if !jit_channel.awaiting_menu_selection() {
+					continue;
+				}
+
+				if let Some(menu_expiry) = jit_channel.menu_expiry.as_mut() {
+					let warn_at =
+						menu_expiry.valid_until.timestamp().saturating_sub(lead_time_secs);
+					if !menu_expiry.warned && warn_at <= seconds_since_epoch as i64 {
+						menu_expiry.warned = true;
+						self.enqueue_event(Event::LSPS2(LSPS2Event::FeeParamsExpiringSoon {
+							counterparty_node_id: *counterparty_node_id,
+							valid_until: menu_expiry.valid_until,
+							label: jit_channel.config.label.clone(),
+						}));
+					}
+				}
+			}
+		}
+	}
+
+	/// Records the menu received from an LSP if it is part of an ongoing LSP selection, returning
+	/// whether it was.
+	fn lsp_selection_menu_received(
+		&self, counterparty_node_id: PublicKey, jit_channel_id: u128, user_channel_id: u128,
+		response: &GetInfoResponse,
+	) -> bool {
+		let mut lsp_selections = self.lsp_selections.lock().unwrap();
+		let selection = match lsp_selections.get_mut(&user_channel_id) {
+			Some(selection) => selection,
+			None => return false,
+		};
+		if !selection.awaiting_menu.remove(&counterparty_node_id) {
+			return false;
+		}
+
+		if let Some(payment_size_msat) = selection.payment_size_msat {
+			if payment_size_msat < response.min_payment_size_msat
+				|| payment_size_msat > response.max_payment_size_msat
+			{
+				return true;
+			}
+		}
+
+		for opening_fee_params in &response.opening_fee_params_menu {
+			let cost = match selection.payment_size_msat {
+				Some(payment_size_msat) => match compute_opening_fee(
+					payment_size_msat,
+					opening_fee_params.min_fee_msat,
+					opening_fee_params.proportional,
+				) {
+					Some(opening_fee_msat) if opening_fee_msat < payment_size_msat => {
+						(opening_fee_msat, 0)
+					}
+					_ => continue,
+				},
+				None => (opening_fee_params.min_fee_msat, opening_fee_params.proportional),
+			};
+
+			if selection.cheapest.as_ref().map_or(true, |cheapest| cost < cheapest.cost) {
+				selection.cheapest = Some(LspSelectionCandidate {
+					counterparty_node_id,
+					jit_channel_id,
+					opening_fee_params: opening_fee_params.clone(),
+					cost,
+				});
+			}
+		}
+
+		true
+	}
+
+	/// Records that an LSP won't provide a menu if it is part of an ongoing LSP selection,
+	/// returning whether it was.
+	fn lsp_selection_menu_failed(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, reason: FailureReason,
+	) -> bool {
+		let mut lsp_selections = self.lsp_selections.lock().unwrap();
+		let selection = match lsp_selections.get_mut(&user_channel_id) {
+			Some(selection) => selection,
+			None => return false,
+		};
+		if !selection.awaiting_menu.remove(counterparty_node_id) {
+			return false;
+		}
+		selection.last_failure = Some(reason);
+		true
+	}
+
+	/// Marks the negotiation of the given, already removed channel as failed, either as part of an
+	/// ongoing LSP selection or by surfacing it to the user. Returns the channel's `user_channel_id`.
+	fn negotiation_failed(
+		&self, counterparty_node_id: PublicKey, jit_channel: InboundJITChannel,
+		reason: FailureReason,
+	) -> u128 {
+		let user_channel_id = jit_channel.config.user_id;
+		let correlation_id = jit_channel.config.correlation_id;
+		log_info!(
+			self.logger,
+			"JIT channel negotiation {:032x} with {} failed: {:?}",
+			correlation_id,
+			counterparty_node_id,
+			reason
+		);
+		if !self.lsp_selection_menu_failed(&counterparty_node_id, user_channel_id, reason.clone()) {
+			self.set_channel_state(
+				ChannelStateKey::Client(user_channel_id),
+				JitChannelState::Failed,
+			);
+			self.enqueue_event(Event::LSPS2(LSPS2Event::NegotiationFailed {
+				counterparty_node_id,
+				user_channel_id,
+				label: jit_channel.config.label,
+				correlation_id,
+				reason,
+			}));
+		}
+		user_channel_id
+	}
+
+	/// Fails all negotiations still awaiting a response from the given, now disconnected peer,
+	/// unless their `get_versions` or `get_info` request is to be retried once it reconnected.
+	///
+	/// JIT channels to the peer whose opening was deferred due to
+	/// [`JITChannelsConfig::max_pending_channel_opens`] are abandoned, as no
+	/// [`LSPS2Event::OpenChannel`] event was generated for them yet. Channels whose opening was
+	/// requested already are left to complete.
+	pub fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let mut failed_user_channel_ids = Vec::new();
+		let mut abandoned_intercepts = Vec::new();
+		{
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			let mut peer_state = match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => inner_state_lock.lock().unwrap(),
+				None => return,
+			};
+			peer_state.disconnected = true;
+
+			let deferred_scids: Vec<u64> = {
+				let channel_open_queue = self.channel_open_queue.lock().unwrap();
+				peer_state
+					.outbound_channels_by_scid
+					.keys()
+					.filter(|scid| channel_open_queue.is_deferred(**scid as u128))
+					.cloned()
+					.collect()
+			};
+			for scid in deferred_scids {
+				if let Some(intercept_id) = self.abandon_channel_open(&mut peer_state, scid) {
+					abandoned_intercepts.push((scid, intercept_id));
+				}
+			}
+
+			let mut jit_channel_ids: Vec<u128> =
+				peer_state.request_to_cid.values().cloned().collect();
+			jit_channel_ids.sort_unstable();
+			jit_channel_ids.dedup();
+			for jit_channel_id in jit_channel_ids {
+				if let Some(jit_channel) =
+					peer_state.inbound_channels_by_id.get_mut(&jit_channel_id)
+				{
+					if jit_channel.retry_at.is_some() || self.schedule_retry(jit_channel) {
+						continue;
+					}
+				}
+
+				peer_state.remove_requests_for_channel(jit_channel_id);
+				if let Some(jit_channel) = peer_state.inbound_channels_by_id.remove(&jit_channel_id)
+				{
+					failed_user_channel_ids.push(self.negotiation_failed(
+						*counterparty_node_id,
+						jit_channel,
+						FailureReason::Transport,
+					));
+				}
+			}
+		}
+
+		for (scid, intercept_id) in abandoned_intercepts {
+			log_info!(
+				self.logger,
+				"Abandoning JIT channel {} as {} disconnected before it was opened",
+				scid,
+				counterparty_node_id
+			);
+			self.channel_open_completed(scid as u128);
+			self.fail_abandoned_htlc(scid, intercept_id);
+		}
+
+		for user_channel_id in failed_user_channel_ids {
+			if self.lsp_selection_complete(user_channel_id) {
+				self.finalize_lsp_selection(user_channel_id);
+			}
+		}
+	}
+
+	/// Records that the given, previously disconnected peer reconnected, retrying the requests
+	/// lost to the disconnect whose backoff passed.
+	pub fn peer_connected(&self, counterparty_node_id: &PublicKey) {
+		{
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => inner_state_lock.lock().unwrap().disconnected = false,
+				None => return,
+			}
+		}
+		self.retry_lost_requests(counterparty_node_id);
+	}
+
+	/// Schedules the pending request of the given channel to be retried after the backoff,
+	/// returning whether any retries were left.
+	fn schedule_retry(&self, jit_channel: &mut InboundJITChannel) -> bool {
+		if !jit_channel.awaiting_versions_or_menu()
+			|| jit_channel.transport_retries >= self.max_transport_retries
+		{
+			return false;
+		}
+
+		// Cap the exponent so the backoff can't overflow.
+		let exponent = jit_channel.transport_retries.min(MAX_TRANSPORT_RETRY_BACKOFF_EXPONENT);
+		let backoff = self.transport_retry_backoff * 2u32.pow(exponent.into());
+		jit_channel.transport_retries += 1;
+		jit_channel.retry_at = Some(utils::now() + backoff);
+		log_debug!(
+			self.logger,
+			"Retrying request of JIT channel negotiation {:032x} in {:?}",
+			jit_channel.config.correlation_id,
+			backoff
+		);
+		true
+	}
+
+	/// Re-sends the requests lost to a disconnect of the given peer whose backoff passed if it
+	/// reconnected meanwhile. Otherwise, the attempt counts as failed and the next one is
+	/// scheduled, or the negotiation fails once no retries are left.
+	fn retry_lost_requests(&self, counterparty_node_id: &PublicKey) {
+		let now = utils::now();
+		let mut failed_user_channel_ids = Vec::new();
+		{
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			let mut peer_state = match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => inner_state_lock.lock().unwrap(),
+				None => return,
+			};
+
+			let mut due_jit_channel_ids: Vec<u128> = peer_state
+				.inbound_channels_by_id
+				.iter()
+				.filter(|(_, jit_channel)| jit_channel.retry_at.map_or(false, |at| at <= now))
+				.map(|(jit_channel_id, _)| *jit_channel_id)
+				.collect();
+			due_jit_channel_ids.sort_unstable();
+
+			for jit_channel_id in due_jit_channel_ids {
+				let disconnected = peer_state.disconnected;
+				let jit_channel = peer_state
+					.inbound_channels_by_id
+					.get_mut(&jit_channel_id)
+					.expect("channel to be present");
+				jit_channel.retry_at = None;
+				if !disconnected {
+					// Requests sent since, e.g., after a response was received meanwhile, may still
+					// be queued.
+					let queued_request_ids = self.queued_request_ids(counterparty_node_id);
+					let requests: Vec<(RequestId, LSPS2Request)> = peer_state
+						.request_to_cid
+						.iter()
+						.filter(|(request_id, cid)| {
+							**cid == jit_channel_id && !queued_request_ids.contains(*request_id)
+						})
+						.filter_map(|(request_id, _)| {
+							peer_state
+								.sent_requests
+								.get(request_id)
+								.map(|request| (request_id.clone(), request.clone()))
+						})
+						.collect();
+					for (request_id, request) in requests {
+						self.enqueue_message(
+							*counterparty_node_id,
+							LSPS2Message::Request(request_id, request),
+						);
+					}
+					continue;
+				}
+
+				if self.schedule_retry(jit_channel) {
+					continue;
+				}
+
+				peer_state.remove_requests_for_channel(jit_channel_id);
+				if let Some(jit_channel) = peer_state.inbound_channels_by_id.remove(&jit_channel_id)
+				{
+					failed_user_channel_ids.push(self.negotiation_failed(
+						*counterparty_node_id,
+						jit_channel,
+						FailureReason::Transport,
+					));
+				}
+			}
+		}
+
+		for user_channel_id in failed_user_channel_ids {
+			if self.lsp_selection_complete(user_channel_id) {
+				self.finalize_lsp_selection(user_channel_id);
+			}
+		}
+	}
+
+	fn lsp_selection_complete(&self, user_channel_id: u128) -> bool {
+		self.lsp_selections
+			.lock()
+			.unwrap()
+			.get(&user_channel_id)
+			.map_or(false, |selection| selection.awaiting_menu.is_empty())
+	}
+
+	fn finalize_lsp_selection(&self, user_channel_id: u128) {
+		let selection = match self.lsp_selections.lock().unwrap().remove(&user_channel_id) {
+			Some(selection) => selection,
+			None => return,
+		};
+		let reason = if selection.awaiting_menu.is_empty() {
+			selection.last_failure.clone()
+		} else {
+			Some(FailureReason::Timeout)
+		};
+
+		let is_cheapest = |counterparty_node_id: &PublicKey, jit_channel_id: u128| {
+			selection.cheapest.as_ref().map_or(false, |cheapest| {
+				cheapest.counterparty_node_id == *counterparty_node_id
+					&& cheapest.jit_channel_id == jit_channel_id
+			})
+		};
+		self.remove_inbound_channels(
+			selection
+				.channels
+				.iter()
+				.filter(|(counterparty_node_id, jit_channel_id)| {
+					!is_cheapest(counterparty_node_id, *jit_channel_id)
+				})
+				.cloned(),
+		);
+
+		if let Some(cheapest) = selection.cheapest {
+			match self.opening_fee_params_selected(
+				cheapest.counterparty_node_id,
+				cheapest.jit_channel_id,
+				cheapest.opening_fee_params.clone(),
+				None,
+				None,
+			) {
+				Ok(()) => {
+					self.channel_lsps
+						.lock()
+						.unwrap()
+						.insert(user_channel_id, cheapest.counterparty_node_id);
+					self.enqueue_event(Event::LSPS2(LSPS2Event::LspSelected {
+						counterparty_node_id: cheapest.counterparty_node_id,
+						opening_fee_params: cheapest.opening_fee_params,
+						user_channel_id,
+					}));
+					return;
+				}
+				Err(e) => {
+					log_error!(
+						self.logger,
+						"Failed to buy JIT channel from selected LSP {}: {:?}",
+						cheapest.counterparty_node_id,
+						e
+					);
+					self.remove_inbound_channels(std::iter::once((
+						cheapest.counterparty_node_id,
+						cheapest.jit_channel_id,
+					)));
+				}
+			}
+		}
+
+		self.set_channel_state(ChannelStateKey::Client(user_channel_id), JitChannelState::Failed);
+		self.enqueue_event(Event::LSPS2(LSPS2Event::LspSelectionFailed {
+			user_channel_id,
+			reason,
+		}));
+	}
+
+	fn remove_inbound_channels(&self, channels: impl Iterator<Item = (PublicKey, u128)>) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, jit_channel_id) in channels {
+			if let Some(inner_state_lock) = outer_state_lock.get(&counterparty_node_id) {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+				peer_state.remove_inbound_channel(jit_channel_id);
+				peer_state.remove_requests_for_channel(jit_channel_id);
+			}
+		}
+	}
+
+	pub fn opening_fee_params_generated(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId,
 		opening_fee_params_menu: Vec<RawOpeningFeeParams>,
 	) -> Result<(), APIError> {
+		self.check_lsp_configured()?;
+
+		if opening_fee_params_menu.len() > self.max_menu_length {
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"Opening fee params menu has {} entries, exceeding the maximum of {}",
+					opening_fee_params_menu.len(),
+					self.max_menu_length
+				),
+			});
+		}
+
+		let max_valid_until = self
+			.duration_since_epoch()
+			.checked_add(self.max_valid_until_horizon)
+			.map_or(i64::MAX, |max_valid_until| max_valid_until.as_secs() as i64);
+		if let Some(params) = opening_fee_params_menu
+			.iter()
+			.find(|params| params.valid_until.timestamp() > max_valid_until)
+		{
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"Opening fee params valid until {} exceed the maximum valid_until horizon",
+					params.valid_until
+				),
+			});
+		}
+		if let Some(params) = opening_fee_params_menu
+			.iter()
+			.find(|params| params.max_client_to_self_delay > MAX_CLIENT_TO_SELF_DELAY)
+		{
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"Opening fee params max_client_to_self_delay {} exceeds the maximum of {}",
+					params.max_client_to_self_delay, MAX_CLIENT_TO_SELF_DELAY
+				),
+			});
+		}
+		if !self.allow_free_channels
+			&& opening_fee_params_menu
+				.iter()
+				.any(|params| params.min_fee_msat == 0 && params.proportional == 0)
+		{
+			return Err(APIError::APIMisuseError {
+				err: "Opening fee params without any fee are only allowed if free channels are allowed"
+					.to_string(),
+			});
+		}
+
+		let promise_secret = self.promise_secrets.read().unwrap().active;
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(&counterparty_node_id) {
@@ -482,15 +1983,58 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				match peer_state.pending_requests.remove(&request_id) {
-					Some(LSPS2Request::GetInfo(_)) => {
+					Some(LSPS2Request::GetInfo(get_info_request)) => {
+						let promise_version = self.promise_version(get_info_request.version);
 						let response = LSPS2Response::GetInfo(GetInfoResponse {
 							opening_fee_params_menu: opening_fee_params_menu
 								.into_iter()
-								.map(|param| param.into_opening_fee_params(&self.promise_secret))
+								.map(|mut param| {
+									if self.min_fee_funding_fee_multiplier.is_some() {
+										param.min_fee_msat = std::cmp::max(
+											param.min_fee_msat,
+											self.suggested_min_fee_msat(),
+										);
+									}
+									param.into_opening_fee_params(&promise_secret, promise_version)
+								})
 								.collect(),
 							min_payment_size_msat: self.min_payment_size_msat,
 							max_payment_size_msat: self.max_payment_size_msat,
 						});
+						peer_state.get_info_received_at.remove(&request_id);
+
+						let now = self.duration_since_epoch().as_secs() as i64;
+						peer_state
+							.get_info_contexts
+							.retain(|_, context| context.valid_until.timestamp() >= now);
+						if let LSPS2Response::GetInfo(GetInfoResponse {
+							opening_fee_params_menu,
+							..
+						}) = &response
+						{
+							for params in opening_fee_params_menu {
+								peer_state.get_info_contexts.insert(
+									params.promise.clone(),
+									GetInfoContext {
+										version: get_info_request.version,
+										token: get_info_request.token.clone(),
+										valid_until: params.valid_until,
+									},
+								);
+							}
+						}
+
+						if let Some(coalesced_request_ids) =
+							peer_state.coalesced_get_info_requests.remove(&request_id)
+						{
+							for coalesced_request_id in coalesced_request_ids {
+								self.enqueue_response(
+									counterparty_node_id,
+									coalesced_request_id,
+									response.clone(),
+								);
+							}
+						}
 						self.enqueue_response(counterparty_node_id, request_id, response);
 						Ok(())
 					}
@@ -508,9 +2052,54 @@ where
 		}
 	}
 
+	/// Returns a `min_fee_msat` covering the configured multiple of the estimated fee of a
+	/// channel funding transaction at the current feerate.
+	pub fn suggested_min_fee_msat(&self) -> u64 {
+		let feerate_sat_per_1000_weight =
+			self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::NonAnchorChannelFee);
+		let funding_fee_sat =
+			u64::from(feerate_sat_per_1000_weight) * ESTIMATED_FUNDING_TRANSACTION_WEIGHT / 1000;
+		let multiplier = self.min_fee_funding_fee_multiplier.unwrap_or(1);
+		funding_fee_sat.saturating_mul(1000).saturating_mul(multiplier)
+	}
+
+	/// Returns the total cost the client would pay for a JIT channel opened with the given
+	/// `opening_fee_params` to receive a payment of `payment_size_msat`.
+	///
+	/// This is the opening fee as agreed upon in the spec, which the LSP deducts from the payment
+	/// before forwarding it. Saturates at [`u64::MAX`] if the computation overflows.
+	pub fn estimate_total_client_cost(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> u64 {
+		compute_opening_fee(
+			payment_size_msat,
+			opening_fee_params.min_fee_msat,
+			opening_fee_params.proportional,
+		)
+		.unwrap_or(u64::MAX)
+	}
+
+	pub fn expected_inbound_liquidity_msat(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> u64 {
+		let channel_size_msat = match self.channel_sizing_strategy {
+			ChannelSizingStrategy::Exact => payment_size_msat,
+			ChannelSizingStrategy::Additional { liquidity_msat } => {
+				payment_size_msat.saturating_add(liquidity_msat)
+			}
+		};
+		let reserve_sat = (channel_size_msat / 1000 * CHANNEL_RESERVE_PROPORTIONAL_MILLIONTHS
+			/ 1_000_000)
+			.max(MIN_CHANNEL_RESERVE_SATOSHIS);
+		channel_size_msat
+			.saturating_sub(self.estimate_total_client_cost(opening_fee_params, payment_size_msat))
+			.saturating_sub(reserve_sat * 1000)
+	}
+
 	pub fn opening_fee_params_selected(
 		&self, counterparty_node_id: PublicKey, jit_channel_id: u128,
-		opening_fee_params: OpeningFeeParams,
+		opening_fee_params: OpeningFeeParams, refund_onchain_address: Option<String>,
+		announce_channel: Option<bool>,
 	) -> Result<(), APIError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(&counterparty_node_id) {
@@ -519,36 +2108,51 @@ where
 				if let Some(jit_channel) =
 					peer_state.inbound_channels_by_id.get_mut(&jit_channel_id)
 				{
-					let version = match jit_channel.opening_fee_params_selected() {
+					if !jit_channel.awaiting_menu_selection() {
+						return Err(APIError::APIMisuseError {
+							err: format!(
+								"Channel with id {} is not awaiting an opening fee params selection",
+								jit_channel_id
+							),
+						});
+					}
+
+					let version = match jit_channel
+						.opening_fee_params_selected(opening_fee_params.valid_until)
+					{
 						Ok(version) => version,
 						Err(e) => {
+							self.set_channel_state(
+								ChannelStateKey::Client(jit_channel.config.user_id),
+								JitChannelState::Failed,
+							);
 							peer_state.remove_inbound_channel(jit_channel_id);
 							return Err(APIError::APIMisuseError { err: e.err });
 						}
 					};
 
+					if self.retry_buy_on_expired_params {
+						jit_channel.buy_selection = Some(BuySelection {
+							opening_fee_params: opening_fee_params.clone(),
+							refund_onchain_address: refund_onchain_address.clone(),
+							announce_channel,
+						});
+					}
+
 					let request_id = self.generate_request_id();
-					let payment_size_msat = jit_channel.config.payment_size_msat;
-					peer_state.insert_request(request_id.clone(), jit_channel_id);
+					let request = LSPS2Request::Buy(BuyRequest {
+						version,
+						opening_fee_params,
+						payment_size_msat: jit_channel.config.payment_size_msat,
+						refund_onchain_address,
+						announce_channel,
+					});
+					peer_state.insert_request(request_id.clone(), jit_channel_id, request.clone());
 
-					{
-						let mut pending_messages = self.pending_messages.lock().unwrap();
-						pending_messages.push((
-							counterparty_node_id,
-							LSPS2Message::Request(
-								request_id,
-								LSPS2Request::Buy(BuyRequest {
-									version,
-									opening_fee_params,
-									payment_size_msat,
-								}),
-							)
-							.into(),
-						));
-					}
-					if let Some(peer_manager) = self.peer_manager.lock().unwrap().as_ref() {
-						peer_manager.process_events();
-					}
+					self.enqueue_message(
+						counterparty_node_id,
+						LSPS2Message::Request(request_id, request),
+					);
 				} else {
 					return Err(APIError::APIMisuseError {
 						err: format!("Channel with id {} not found", jit_channel_id),
@@ -569,14 +2173,28 @@ where
 		&self, counterparty_node_id: PublicKey, request_id: RequestId, scid: u64,
 		cltv_expiry_delta: u32, client_trusts_lsp: bool,
 	) -> Result<(), APIError> {
+		self.check_lsp_configured()?;
+
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(&counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
+				if let Some(allocated_scid) = peer_state.allocated_scids.get(&request_id) {
+					if *allocated_scid != scid {
+						return Err(APIError::APIMisuseError {
+							err: format!(
+								"The scid {} differs from the scid {} allocated for buy request {:?}",
+								scid, allocated_scid, request_id
+							),
+						});
+					}
+				}
+
 				match peer_state.pending_requests.remove(&request_id) {
 					Some(LSPS2Request::Buy(buy_request)) => {
+						peer_state.allocated_scids.remove(&request_id);
 						{
 							let mut peer_by_scid = self.peer_by_scid.write().unwrap();
 							peer_by_scid.insert(scid, counterparty_node_id);
@@ -587,19 +2205,43 @@ where
 							cltv_expiry_delta,
 							buy_request.payment_size_msat,
 							buy_request.opening_fee_params,
+							buy_request.announce_channel,
 						);
 
 						peer_state.insert_outbound_channel(scid, outbound_jit_channel);
+						self.set_channel_state(
+							ChannelStateKey::Lsp(scid),
+							JitChannelState::AwaitingPayment,
+						);
 
+						let response = BuyResponse {
+							jit_channel_scid: scid.into(),
+							lsp_cltv_expiry_delta: cltv_expiry_delta,
+							client_trusts_lsp: Some(client_trusts_lsp),
+						};
+						if let Some(coalesced_request_ids) =
+							peer_state.coalesced_buy_requests.remove(&request_id)
+						{
+							for coalesced_request_id in coalesced_request_ids {
+								self.enqueue_response(
+									counterparty_node_id,
+									coalesced_request_id,
+									LSPS2Response::Buy(response.clone()),
+								);
+							}
+						}
 						self.enqueue_response(
 							counterparty_node_id,
-							request_id,
-							LSPS2Response::Buy(BuyResponse {
-								jit_channel_scid: scid.into(),
-								lsp_cltv_expiry_delta: cltv_expiry_delta,
-								client_trusts_lsp,
-							}),
+							request_id.clone(),
+							LSPS2Response::Buy(response),
 						);
+						if self.verbose_buy_events {
+							self.enqueue_event(Event::LSPS2(LSPS2Event::BuyResponseSent {
+								request_id,
+								counterparty_node_id,
+								scid,
+							}));
+						}
 
 						Ok(())
 					}
@@ -614,626 +2256,4656 @@ where
 		}
 	}
 
-	pub(crate) fn htlc_intercepted(
-		&self, scid: u64, intercept_id: InterceptId, inbound_amount_msat: u64,
-		expected_outbound_amount_msat: u64,
-	) -> Result<(), APIError> {
-		let peer_by_scid = self.peer_by_scid.read().unwrap();
-		if let Some(counterparty_node_id) = peer_by_scid.get(&scid) {
-			let outer_state_lock = self.per_peer_state.read().unwrap();
-			match outer_state_lock.get(counterparty_node_id) {
-				Some(inner_state_lock) => {
-					let mut peer_state = inner_state_lock.lock().unwrap();
-					if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get_mut(&scid) {
-						// TODO: Need to support MPP payments. If payment_amount_msat is known, needs to queue intercepted HTLCs in a map by payment_hash
-						//       LiquidityManager will need to be regularly polled so it can continually check if the payment amount has been received
-						//       and can release the payment or if the channel valid_until has expired and should be failed.
-						//       Can perform check each time HTLC is received and on interval? I guess interval only needs to check expiration as
-						//       we can only reach threshold when htlc is intercepted.
+	pub fn invoice_route_hint(&self, user_channel_id: u128) -> Option<RouteHintBundle> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let peer_state = inner_state_lock.lock().unwrap();
+			let route_hint_params = peer_state
+				.inbound_channels_by_id
+				.values()
+				.filter(|jit_channel| jit_channel.config.user_id == user_channel_id)
+				.find_map(|jit_channel| jit_channel.route_hint_params());
+			if let Some((scid, cltv_expiry_delta)) = route_hint_params {
+				return Some(RouteHintBundle {
+					counterparty_node_id: *counterparty_node_id,
+					scid,
+					cltv_expiry_delta,
+				});
+			}
+		}
+		None
+	}
 
-						match jit_channel
-							.htlc_intercepted(expected_outbound_amount_msat, intercept_id)
-						{
-							Ok((opening_fee_msat, amt_to_forward_msat)) => {
-								self.enqueue_event(Event::LSPS2(LSPS2Event::OpenChannel {
-									their_network_key: counterparty_node_id.clone(),
-									inbound_amount_msat,
-									expected_outbound_amount_msat,
-									amt_to_forward_msat,
-									opening_fee_msat,
-									user_channel_id: scid as u128,
-								}));
-							}
-							Err(e) => {
-								self.channel_manager.fail_intercepted_htlc(intercept_id)?;
-								peer_state.outbound_channels_by_scid.remove(&scid);
-								// TODO: cleanup peer_by_scid
-								return Err(APIError::APIMisuseError { err: e.err });
-							}
-						}
-					}
-				}
-				None => {
-					return Err(APIError::APIMisuseError {
-						err: format!("No counterparty found for scid: {}", scid),
-					});
-				}
+	/// Returns whether the claim has to be deferred until the funding transaction confirmed.
+	pub fn payment_received(
+		&self, user_channel_id: u128, funding_txo: OutPoint,
+	) -> Result<bool, APIError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let jit_channel_id = match peer_state
+				.inbound_channels_by_id
+				.values()
+				.find(|jit_channel| jit_channel.config.user_id == user_channel_id)
+			{
+				Some(jit_channel) => jit_channel.id,
+				None => continue,
+			};
+
+			let jit_channel = peer_state
+				.inbound_channels_by_id
+				.get_mut(&jit_channel_id)
+				.expect("channel to be present as it was just found");
+			let client_trusts_lsp = jit_channel
+				.payment_received(funding_txo)
+				.map_err(|e| APIError::APIMisuseError { err: e.err })?;
+
+			if client_trusts_lsp || !self.await_funding_confirmation {
+				let label = jit_channel.config.label.clone();
+				peer_state.remove_inbound_channel(jit_channel_id);
+				self.payment_claimable(*counterparty_node_id, user_channel_id, label);
+				return Ok(false);
 			}
+
+			return Ok(true);
 		}
 
-		Ok(())
+		Err(APIError::APIMisuseError {
+			err: format!("Could not find a channel with user_channel_id {}", user_channel_id),
+		})
 	}
 
-	// figure out which intercept id is waiting on this channel and enqueue ForwardInterceptedHTLC event
-	pub(crate) fn channel_ready(
-		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
-	) -> Result<(), APIError> {
-		if let Ok(scid) = user_channel_id.try_into() {
+	pub(crate) fn transactions_confirmed(&self, txdata: &TransactionData, height: u32) {
+		{
 			let outer_state_lock = self.per_peer_state.read().unwrap();
-			match outer_state_lock.get(counterparty_node_id) {
-				Some(inner_state_lock) => {
-					let mut peer_state = inner_state_lock.lock().unwrap();
-					if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get_mut(&scid) {
-						match jit_channel.channel_ready() {
-							Ok((intercept_id, amt_to_forward_msat)) => {
-								self.channel_manager.forward_intercepted_htlc(
-									intercept_id,
-									channel_id,
-									*counterparty_node_id,
-									amt_to_forward_msat,
-								)?;
-							}
-							Err(e) => {
-								return Err(APIError::APIMisuseError {
-									err: format!(
-										"Failed to transition to channel ready: {}",
-										e.err
-									),
-								})
-							}
-						}
-					} else {
-						return Err(APIError::APIMisuseError {
-							err: format!(
-								"Could not find a channel with user_channel_id {}",
-								user_channel_id
-							),
+			for inner_state_lock in outer_state_lock.values() {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+				for jit_channel in peer_state.inbound_channels_by_id.values_mut() {
+					let confirmed =
+						jit_channel.pending_funding_txo().map_or(false, |funding_txo| {
+							txdata.iter().any(|(_, tx)| tx.txid() == funding_txo.txid)
 						});
+					if confirmed {
+						jit_channel.funding_confirmed(height);
 					}
 				}
-				None => {
-					return Err(APIError::APIMisuseError {
-						err: format!("No counterparty state for: {}", counterparty_node_id),
-					});
-				}
 			}
 		}
 
-		Ok(())
-	}
-
-	fn generate_jit_channel_id(&self) -> u128 {
-		let bytes = self.entropy_source.get_secure_random_bytes();
-		let mut id_bytes: [u8; 16] = [0; 16];
-		id_bytes.copy_from_slice(&bytes[0..16]);
-		u128::from_be_bytes(id_bytes)
+		self.best_block_updated(height);
 	}
 
-	fn generate_request_id(&self) -> RequestId {
-		let bytes = self.entropy_source.get_secure_random_bytes();
-		RequestId(utils::hex_str(&bytes[0..16]))
+	pub(crate) fn transaction_unconfirmed(&self, txid: &Txid) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for inner_state_lock in outer_state_lock.values() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			for jit_channel in peer_state.inbound_channels_by_id.values_mut() {
+				if jit_channel.pending_funding_txo().map_or(false, |txo| txo.txid == *txid) {
+					jit_channel.funding_unconfirmed();
+				}
+			}
+		}
 	}
 
-	fn enqueue_response(
-		&self, counterparty_node_id: PublicKey, request_id: RequestId, response: LSPS2Response,
-	) {
-		{
-			let mut pending_messages = self.pending_messages.lock().unwrap();
-			pending_messages
-				.push((counterparty_node_id, LSPS2Message::Response(request_id, response).into()));
-		}
+	/// Makes payments claimable whose funding transaction reached
+	/// [`JITChannelsConfig::min_funding_confirmations`] at the given best block height.
+	///
+	/// [`JITChannelsConfig::min_funding_confirmations`]: crate::JITChannelsConfig::min_funding_confirmations
+	pub(crate) fn best_block_updated(&self, height: u32) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let buried_channels: Vec<(u128, u128, Option<String>)> = peer_state
+				.inbound_channels_by_id
+				.values()
+				.filter(|jit_channel| {
+					jit_channel.funding_confirmations(height) >= self.min_funding_confirmations
+				})
+				.map(|jit_channel| {
+					(jit_channel.id, jit_channel.config.user_id, jit_channel.config.label.clone())
+				})
+				.collect();
 
-		if let Some(peer_manager) = self.peer_manager.lock().unwrap().as_ref() {
-			peer_manager.process_events();
+			for (jit_channel_id, user_channel_id, label) in buried_channels {
+				peer_state.remove_inbound_channel(jit_channel_id);
+				self.payment_claimable(*counterparty_node_id, user_channel_id, label);
+			}
 		}
 	}
 
-	fn enqueue_event(&self, event: Event) {
-		self.pending_events.enqueue(event);
-	}
-
-	fn handle_get_versions_request(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey,
-	) -> Result<(), LightningError> {
-		self.enqueue_response(
-			*counterparty_node_id,
-			request_id,
-			LSPS2Response::GetVersions(GetVersionsResponse {
-				versions: SUPPORTED_SPEC_VERSIONS.to_vec(),
-			}),
-		);
-		Ok(())
+	fn payment_claimable(
+		&self, counterparty_node_id: PublicKey, user_channel_id: u128, label: Option<String>,
+	) {
+		self.set_channel_state(ChannelStateKey::Client(user_channel_id), JitChannelState::Ready);
+		self.enqueue_event(Event::LSPS2(LSPS2Event::ClaimPayment {
+			counterparty_node_id,
+			user_channel_id,
+			label,
+		}));
 	}
 
-	fn handle_get_versions_response(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetVersionsResponse,
-	) -> Result<(), LightningError> {
+	/// Drops the request with the given id exchanged with the given peer, i.e., a request we sent
+	/// and are awaiting a response for, or one we received and didn't respond to yet.
+	///
+	/// Any negotiation the request belonged to is left as is. Returns whether a request was
+	/// removed.
+	pub fn purge_request(&self, counterparty_node_id: &PublicKey, request_id: &RequestId) -> bool {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state = inner_state_lock.lock().unwrap();
+				let sent_request_removed = peer_state.remove_request(request_id).is_some();
+				let pending_request_removed =
+					peer_state.pending_requests.remove(request_id).is_some();
+				peer_state.allocated_scids.remove(request_id);
+				sent_request_removed || pending_request_removed
+			}
+			None => false,
+		}
+	}
 
-				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
-						err: format!(
-							"Received get_versions response for an unknown request: {:?}",
-							request_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
-
-				let jit_channel = peer_state
-					.inbound_channels_by_id
-					.get_mut(&jit_channel_id)
-					.ok_or(LightningError {
-						err: format!(
-							"Received get_versions response for an unknown channel: {:?}",
-							jit_channel_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
-
-				let token = jit_channel.config.token.clone();
-
-				let version = match jit_channel.versions_received(result.versions) {
-					Ok(version) => version,
-					Err(e) => {
-						peer_state.remove_inbound_channel(jit_channel_id);
-						return Err(e);
-					}
-				};
+	/// Enqueues all requests we sent to the given peer again, unless they are still queued, and
+	/// returns how many were enqueued.
+	///
+	/// Should be called when the peer reconnected, as requests might have been lost while it was
+	/// disconnected. Requests keep their id, so a response to either copy will be accepted.
+	///
+	/// Requests kept across a disconnect to be retried are re-sent right away rather than once
+	/// their backoff passed. Negotiations failed on disconnect, see
+	/// [`JITChannelsConfig::max_transport_retries`], have no requests left to re-send. Nothing is
+	/// re-sent while the peer is disconnected.
+	///
+	/// [`JITChannelsConfig::max_transport_retries`]: crate::JITChannelsConfig::max_transport_retries
+	pub fn resend_pending(&self, counterparty_node_id: &PublicKey) -> usize {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock = match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => inner_state_lock,
+			None => return 0,
+		};
+		let mut peer_state = inner_state_lock.lock().unwrap();
+		if peer_state.disconnected {
+			return 0;
+		}
 
-				let request_id = self.generate_request_id();
-				peer_state.insert_request(request_id.clone(), jit_channel_id);
+		let queued_request_ids = self.queued_request_ids(counterparty_node_id);
+		let requests: Vec<(RequestId, LSPS2Request)> = peer_state
+			.sent_requests
+			.iter()
+			.filter(|(request_id, _)| !queued_request_ids.contains(*request_id))
+			.map(|(request_id, request)| (request_id.clone(), request.clone()))
+			.collect();
 
+		for (request_id, request) in requests.iter() {
+			// The request is re-sent now, so its scheduled retry is done.
+			if let Some(jit_channel_id) = peer_state.request_to_cid.get(request_id).copied() {
+				if let Some(jit_channel) =
+					peer_state.inbound_channels_by_id.get_mut(&jit_channel_id)
 				{
-					let mut pending_messages = self.pending_messages.lock().unwrap();
-					pending_messages.push((
-						*counterparty_node_id,
-						LSPS2Message::Request(
-							request_id,
-							LSPS2Request::GetInfo(GetInfoRequest { version, token }),
-						)
-						.into(),
-					));
+					jit_channel.retry_at = None;
 				}
+			}
+			self.enqueue_message(
+				*counterparty_node_id,
+				LSPS2Message::Request(request_id.clone(), request.clone()),
+			);
+		}
+		requests.len()
+	}
 
-				if let Some(peer_manager) = self.peer_manager.lock().unwrap().as_ref() {
-					peer_manager.process_events();
-				}
+	/// Returns the ids of the requests to the given peer that are still queued to be sent.
+	fn queued_request_ids(&self, counterparty_node_id: &PublicKey) -> HashSet<RequestId> {
+		self.pending_messages
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(node_id, _)| node_id == counterparty_node_id)
+			.filter_map(|(_, message)| message.get_request_id_and_method())
+			.map(|(request_id, _)| request_id)
+			.collect()
+	}
+
+	/// Drops all state of ongoing negotiations with the given peer.
+	///
+	/// Channels for which an HTLC was already intercepted are retained.
+	pub fn clear_negotiation_state(&self, counterparty_node_id: &PublicKey) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			peer_state.pending_requests.clear();
+			peer_state.allocated_scids.clear();
+			peer_state.request_to_cid.clear();
+			peer_state.sent_requests.clear();
+			for (_, jit_channel) in peer_state.inbound_channels_by_id.drain() {
+				self.set_channel_state(
+					ChannelStateKey::Client(jit_channel.config.user_id),
+					JitChannelState::Failed,
+				);
 			}
-			None => {
-				return Err(LightningError {
-					err: format!(
-						"Received get_versions response from unknown peer: {:?}",
-						counterparty_node_id
-					),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
+
+			let negotiated_scids: Vec<u64> = peer_state
+				.outbound_channels_by_scid
+				.iter()
+				.filter(|(_, jit_channel)| {
+					matches!(
+						jit_channel.state,
+						OutboundJITChannelState::InvoiceParametersGenerated { .. }
+					)
 				})
+				.map(|(scid, _)| *scid)
+				.collect();
+
+			let mut peer_by_scid = self.peer_by_scid.write().unwrap();
+			for scid in negotiated_scids {
+				self.set_channel_state(ChannelStateKey::Lsp(scid), JitChannelState::Failed);
+				peer_state.remove_outbound_channel(scid);
+				peer_by_scid.remove(&scid);
 			}
 		}
-
-		Ok(())
 	}
 
-	fn handle_get_info_request(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: GetInfoRequest,
-	) -> Result<(), LightningError> {
-		let mut outer_state_lock = self.per_peer_state.write().unwrap();
-		let inner_state_lock: &mut Mutex<PeerState> = outer_state_lock
-			.entry(*counterparty_node_id)
-			.or_insert(Mutex::new(PeerState::default()));
-		let peer_state = inner_state_lock.get_mut().unwrap();
-		peer_state
-			.pending_requests
-			.insert(request_id.clone(), LSPS2Request::GetInfo(params.clone()));
+	pub fn channel_state(&self, user_channel_id: u128) -> Option<JitChannelState> {
+		self.channel_states.lock().unwrap().get(ChannelStateKey::Client(user_channel_id))
+	}
 
-		self.enqueue_event(Event::LSPS2(LSPS2Event::GetInfo {
-			request_id,
-			counterparty_node_id: *counterparty_node_id,
-			version: params.version,
-			token: params.token,
-		}));
-		Ok(())
+	pub fn lsp_channel_state(&self, scid: u64) -> Option<JitChannelState> {
+		self.channel_states.lock().unwrap().get(ChannelStateKey::Lsp(scid))
 	}
 
-	fn handle_get_info_response(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetInfoResponse,
-	) -> Result<(), LightningError> {
+	pub fn recommended_invoice_expiry_secs(&self, user_channel_id: u128) -> Option<u32> {
+		let seconds_since_epoch = self.duration_since_epoch().as_secs();
+
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(counterparty_node_id) {
-			Some(inner_state_lock) => {
-				let mut peer_state = inner_state_lock.lock().unwrap();
+		let valid_until = outer_state_lock.values().find_map(|inner_state_lock| {
+			inner_state_lock
+				.lock()
+				.unwrap()
+				.inbound_channels_by_id
+				.values()
+				.filter(|jit_channel| jit_channel.config.user_id == user_channel_id)
+				.find_map(|jit_channel| jit_channel.selected_valid_until)
+		})?;
 
-				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
-						err: format!(
-							"Received get_info response for an unknown request: {:?}",
-							request_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+		let expiry_secs =
+			(valid_until.timestamp().max(0) as u64).saturating_sub(seconds_since_epoch);
+		Some(expiry_secs.min(u32::MAX as u64).max(MIN_INVOICE_EXPIRY_SECS as u64) as u32)
+	}
 
-				let jit_channel = peer_state
-					.inbound_channels_by_id
-					.get_mut(&jit_channel_id)
-					.ok_or(LightningError {
-						err: format!(
-							"Received get_info response for an unknown channel: {:?}",
-							jit_channel_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+	pub fn lsp_for_channel(&self, user_channel_id: u128) -> Option<PublicKey> {
+		self.channel_lsps.lock().unwrap().get(&user_channel_id).copied()
+	}
 
-				if let Err(e) = jit_channel.info_received() {
-					peer_state.remove_inbound_channel(jit_channel_id);
-					return Err(e);
-				}
+	fn set_channel_state(&self, key: ChannelStateKey, state: JitChannelState) {
+		let previous_state = self.channel_states.lock().unwrap().insert(key, state);
+		debug_assert!(
+			JitChannelState::is_valid_transition(previous_state, state),
+			"illegal JIT channel state transition from {:?} to {:?}",
+			previous_state,
+			state
+		);
 
-				self.enqueue_event(Event::LSPS2(LSPS2Event::GetInfoResponse {
-					counterparty_node_id: *counterparty_node_id,
-					opening_fee_params_menu: result.opening_fee_params_menu,
-					min_payment_size_msat: result.min_payment_size_msat,
-					max_payment_size_msat: result.max_payment_size_msat,
-					jit_channel_id: jit_channel.id,
-					user_channel_id: jit_channel.config.user_id,
-				}));
-			}
-			None => {
-				return Err(LightningError {
-					err: format!(
-						"Received get_info response from unknown peer: {:?}",
-						counterparty_node_id
-					),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				})
+		// The LSP of a channel we bought is only tracked while it's being negotiated.
+		if let ChannelStateKey::Client(user_channel_id) = key {
+			if state.is_terminal() {
+				self.channel_lsps.lock().unwrap().remove(&user_channel_id);
 			}
 		}
+	}
 
-		Ok(())
+	#[cfg(test)]
+	pub(crate) fn transition_channel_state(&self, user_channel_id: u128, state: JitChannelState) {
+		self.set_channel_state(ChannelStateKey::Client(user_channel_id), state);
 	}
 
-	fn handle_get_info_error(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, _error: ResponseError,
-	) -> Result<(), LightningError> {
+	pub(crate) fn peer_snapshots(&self) -> Vec<PeerSnapshot> {
+		let request_snapshots = |requests: &HashMap<RequestId, LSPS2Request>| {
+			let mut snapshots: Vec<RequestSnapshot> = requests
+				.iter()
+				.map(|(request_id, request)| RequestSnapshot {
+					request_id: request_id.as_str().to_string(),
+					method: request.method().to_string(),
+				})
+				.collect();
+			snapshots.sort_by(|a, b| a.request_id.cmp(&b.request_id));
+			snapshots
+		};
+
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(counterparty_node_id) {
-			Some(inner_state_lock) => {
-				let mut peer_state = inner_state_lock.lock().unwrap();
+		let mut counterparty_node_ids: Vec<PublicKey> = outer_state_lock
+			.keys()
+			.chain(self.negotiated_versions.lock().unwrap().keys())
+			.cloned()
+			.collect();
+		counterparty_node_ids.sort_unstable();
+		counterparty_node_ids.dedup();
 
-				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
-						err: format!(
-							"Received get_info error for an unknown request: {:?}",
-							request_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+		let mut snapshots = Vec::new();
+		for counterparty_node_id in counterparty_node_ids {
+			let mut snapshot = PeerSnapshot {
+				counterparty_node_id: counterparty_node_id.to_string(),
+				negotiated_version: self.negotiated_version(&counterparty_node_id),
+				sent_requests: Vec::new(),
+				received_requests: Vec::new(),
+				inbound_channels: Vec::new(),
+				outbound_channels: Vec::new(),
+			};
 
-				peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
-					LightningError {
-						err: format!(
-							"Received get_info error for an unknown channel: {:?}",
-							jit_channel_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					},
-				)?;
-				Ok(())
-			}
-			None => {
-				return Err(LightningError { err: format!("Received error response for a get_info request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
+			if let Some(inner_state_lock) = outer_state_lock.get(&counterparty_node_id) {
+				let peer_state = inner_state_lock.lock().unwrap();
+				let channel_states = self.channel_states.lock().unwrap();
+				let state_of = |key| channel_states.get(key).map(|state| format!("{:?}", state));
+
+				snapshot.sent_requests = request_snapshots(&peer_state.sent_requests);
+				snapshot.received_requests = request_snapshots(&peer_state.pending_requests);
+				for jit_channel in peer_state.inbound_channels_by_id.values() {
+					let short_channel_id = match &jit_channel.state {
+						InboundJITChannelState::PendingPayment { short_channel_id, .. } => {
+							short_channel_id.to_scid().ok()
+						}
+						_ => None,
+					};
+					snapshot.inbound_channels.push(JitChannelSnapshot {
+						user_channel_id: jit_channel.config.user_id,
+						short_channel_id,
+						state: state_of(ChannelStateKey::Client(jit_channel.config.user_id)),
+					});
+				}
+				for scid in peer_state.outbound_channels_by_scid.keys() {
+					snapshot.outbound_channels.push(JitChannelSnapshot {
+						user_channel_id: *scid as u128,
+						short_channel_id: Some(*scid),
+						state: state_of(ChannelStateKey::Lsp(*scid)),
+					});
+				}
+				snapshot.inbound_channels.sort_by_key(|channel| channel.user_channel_id);
+				snapshot.outbound_channels.sort_by_key(|channel| channel.user_channel_id);
 			}
-		}
-	}
 
-	fn handle_buy_request(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: BuyRequest,
-	) -> Result<(), LightningError> {
-		if !SUPPORTED_SPEC_VERSIONS.contains(&params.version) {
-			self.enqueue_response(
-				*counterparty_node_id,
-				request_id,
-				LSPS2Response::BuyError(ResponseError {
-					code: LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
-					message: format!("version {} is not supported", params.version),
-					data: Some(format!("Supported versions are {:?}", SUPPORTED_SPEC_VERSIONS)),
-				}),
-			);
-			return Err(LightningError {
-				err: format!("client requested unsupported version {}", params.version),
-				action: ErrorAction::IgnoreAndLog(Level::Info),
-			});
+			snapshots.push(snapshot);
 		}
+		snapshots
+	}
 
-		if let Some(payment_size_msat) = params.payment_size_msat {
-			if payment_size_msat < self.min_payment_size_msat {
-				self.enqueue_response(
-					*counterparty_node_id,
-					request_id,
-					LSPS2Response::BuyError(ResponseError {
-						code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
-						message: "payment size is below our minimum supported payment size"
-							.to_string(),
-						data: None,
-					}),
-				);
-				return Err(LightningError {
-					err: "payment size is below our minimum supported payment size".to_string(),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				});
-			}
+	/// Returns the peers we are negotiating a JIT channel with or which wait for one to be opened,
+	/// ordered by node id.
+	pub fn active_peers(&self) -> Vec<PublicKey> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let mut active_peers: Vec<PublicKey> = outer_state_lock
+			.iter()
+			.filter(|(_, inner_state_lock)| {
+				inner_state_lock.lock().unwrap().has_active_negotiations()
+			})
+			.map(|(counterparty_node_id, _)| *counterparty_node_id)
+			.collect();
+		active_peers.sort_unstable();
+		active_peers
+	}
 
-			if payment_size_msat > self.max_payment_size_msat {
-				self.enqueue_response(
-					*counterparty_node_id,
-					request_id,
-					LSPS2Response::BuyError(ResponseError {
-						code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
-						message: "payment size is above our maximum supported payment size"
-							.to_string(),
-						data: None,
-					}),
-				);
-				return Err(LightningError {
-					err: "payment size is above our maximum supported payment size".to_string(),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				});
-			}
+	/// Returns the number of peers we are negotiating a JIT channel with or which wait for one to
+	/// be opened.
+	pub fn active_peer_count(&self) -> usize {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		outer_state_lock
+			.values()
+			.filter(|inner_state_lock| inner_state_lock.lock().unwrap().has_active_negotiations())
+			.count()
+	}
 
-			match compute_opening_fee(
-				payment_size_msat,
-				params.opening_fee_params.min_fee_msat,
-				params.opening_fee_params.proportional,
-			) {
-				Some(opening_fee) => {
-					if opening_fee >= payment_size_msat {
-						self.enqueue_response(
-							*counterparty_node_id,
-							request_id,
-							LSPS2Response::BuyError(ResponseError {
-								code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
-								message: "payment size is too small to cover the opening fee"
-									.to_string(),
-								data: None,
-							}),
-						);
-						return Err(LightningError {
-							err: "payment size is too small to cover the opening fee".to_string(),
-							action: ErrorAction::IgnoreAndLog(Level::Info),
-						});
+	/// Fails back all HTLCs intercepted for JIT channels that weren't opened yet, abandoning the
+	/// channels.
+	pub fn shutdown(&self) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let pending_intercepts: Vec<(u64, InterceptId)> = peer_state
+				.outbound_channels_by_scid
+				.iter()
+				.filter_map(|(scid, jit_channel)| match jit_channel.state {
+					OutboundJITChannelState::PendingChannelOpen { intercept_id, .. } => {
+						Some((*scid, intercept_id))
 					}
-				}
-				None => {
-					self.enqueue_response(
-						*counterparty_node_id,
-						request_id,
-						LSPS2Response::BuyError(ResponseError {
-							code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
-							message: "overflow error when calculating opening_fee".to_string(),
-							data: None,
-						}),
+					_ => None,
+				})
+				.collect();
+
+			for (scid, intercept_id) in pending_intercepts {
+				peer_state.remove_outbound_channel(scid);
+				self.set_channel_state(ChannelStateKey::Lsp(scid), JitChannelState::Failed);
+				if let Err(e) = self.channel_manager.fail_intercepted_htlc(intercept_id) {
+					log_error!(
+						self.logger,
+						"Failed to fail back HTLC intercepted for scid {} on shutdown: {:?}",
+						scid,
+						e
 					);
-					return Err(LightningError {
-						err: "overflow error when calculating opening_fee".to_string(),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					});
 				}
+				self.enqueue_event(Event::LSPS2(LSPS2Event::InterceptedHtlcFailedOnShutdown {
+					counterparty_node_id: *counterparty_node_id,
+					user_channel_id: scid as u128,
+					intercept_id,
+				}));
 			}
 		}
 
-		// TODO: if payment_size_msat is specified, make sure our node has sufficient incoming liquidity from public network to receive it.
-
-		if !is_valid_opening_fee_params(&params.opening_fee_params, &self.promise_secret) {
-			self.enqueue_response(
-				*counterparty_node_id,
-				request_id,
-				LSPS2Response::BuyError(ResponseError {
-					code: LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
-					message: "valid_until is already past OR the promise did not match the provided parameters".to_string(),
-					data: None,
-				}),
-			);
-			return Err(LightningError {
-				err: "invalid opening fee parameters were supplied by client".to_string(),
-				action: ErrorAction::IgnoreAndLog(Level::Info),
-			});
-		}
-
-		let mut outer_state_lock = self.per_peer_state.write().unwrap();
-		let inner_state_lock = outer_state_lock
-			.entry(*counterparty_node_id)
-			.or_insert(Mutex::new(PeerState::default()));
-		let peer_state = inner_state_lock.get_mut().unwrap();
-		peer_state.pending_requests.insert(request_id.clone(), LSPS2Request::Buy(params.clone()));
-
-		self.enqueue_event(Event::LSPS2(LSPS2Event::BuyRequest {
-			request_id,
-			version: params.version,
-			counterparty_node_id: *counterparty_node_id,
-			opening_fee_params: params.opening_fee_params,
-			payment_size_msat: params.payment_size_msat,
-		}));
-
-		Ok(())
+		// All channels we requested to be opened, or deferred, were abandoned above.
+		*self.channel_open_queue.lock().unwrap() = ChannelOpenQueue::default();
 	}
 
-	fn handle_buy_response(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: BuyResponse,
-	) -> Result<(), LightningError> {
+	pub fn outstanding_scids(&self) -> Vec<(u64, PublicKey, u128)> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(counterparty_node_id) {
-			Some(inner_state_lock) => {
-				let mut peer_state = inner_state_lock.lock().unwrap();
-
-				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
-						err: format!(
-							"Received buy response for an unknown request: {:?}",
-							request_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
-
-				let jit_channel = peer_state
-					.inbound_channels_by_id
-					.get_mut(&jit_channel_id)
-					.ok_or(LightningError {
-						err: format!(
-							"Received buy response for an unknown channel: {:?}",
-							jit_channel_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+		let mut outstanding_scids = Vec::new();
 
-				if let Err(e) = jit_channel.invoice_params_received(
-					result.client_trusts_lsp,
-					result.jit_channel_scid.clone(),
-				) {
-					peer_state.remove_inbound_channel(jit_channel_id);
-					return Err(e);
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let peer_state = inner_state_lock.lock().unwrap();
+			for (scid, jit_channel) in peer_state.outbound_channels_by_scid.iter() {
+				if let OutboundJITChannelState::InvoiceParametersGenerated { .. } =
+					jit_channel.state
+				{
+					outstanding_scids.push((*scid, *counterparty_node_id, *scid as u128));
 				}
+			}
+		}
 
-				if let Ok(scid) = result.jit_channel_scid.to_scid() {
-					self.enqueue_event(Event::LSPS2(LSPS2Event::InvoiceGenerationReady {
-						counterparty_node_id: *counterparty_node_id,
-						scid,
-						cltv_expiry_delta: result.lsp_cltv_expiry_delta,
-						payment_size_msat: jit_channel.config.payment_size_msat,
-						client_trusts_lsp: result.client_trusts_lsp,
-						user_channel_id: jit_channel.config.user_id,
-					}));
-				} else {
-					return Err(LightningError {
-						err: format!(
-							"Received buy response with an invalid scid {:?}",
-							result.jit_channel_scid
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					});
+		outstanding_scids.sort_unstable_by_key(|(scid, _, _)| *scid);
+		outstanding_scids
+	}
+
+	pub(crate) fn payment_forwarded(&self, next_channel_id: &ChannelId) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for inner_state_lock in outer_state_lock.values() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			for jit_channel in peer_state.outbound_channels_by_scid.values_mut() {
+				if let Some(opening_fee_msat) = jit_channel.payment_forwarded(next_channel_id) {
+					self.total_fees_earned_msat.fetch_add(opening_fee_msat, Ordering::AcqRel);
+					return;
 				}
 			}
-			None => {
-				return Err(LightningError {
-					err: format!(
-						"Received buy response from unknown peer: {:?}",
-						counterparty_node_id
-					),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				});
-			}
 		}
-		Ok(())
 	}
 
-	fn handle_buy_error(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, _error: ResponseError,
-	) -> Result<(), LightningError> {
-		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(counterparty_node_id) {
-			Some(inner_state_lock) => {
-				let mut peer_state = inner_state_lock.lock().unwrap();
+	pub fn total_fees_earned_msat(&self) -> u64 {
+		self.total_fees_earned_msat.load(Ordering::Acquire)
+	}
 
-				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
-						err: format!("Received buy error for an unknown request: {:?}", request_id),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+	pub(crate) fn get_info_rejected(
+		&self, counterparty_node_id: &PublicKey, reason: GetInfoRejectionReason,
+	) {
+		log_info!(
+			self.logger,
+			"Rejected get_info request from {}, reason: {}",
+			counterparty_node_id,
+			reason
+		);
+		*self.get_info_rejections.lock().unwrap().entry(reason).or_insert(0) += 1;
+	}
 
-				let _jit_channel = peer_state
-					.inbound_channels_by_id
-					.remove(&jit_channel_id)
-					.ok_or(LightningError {
-						err: format!(
-							"Received buy error for an unknown channel: {:?}",
-							jit_channel_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
-				Ok(())
+	pub fn get_info_rejection_count(&self, reason: GetInfoRejectionReason) -> u64 {
+		self.get_info_rejections.lock().unwrap().get(&reason).copied().unwrap_or(0)
+	}
+
+	pub(crate) fn set_peer_channel_sizing_strategy(
+		&self, counterparty_node_id: PublicKey, strategy: Option<ChannelSizingStrategy>,
+	) {
+		let mut peer_channel_sizing_strategies =
+			self.peer_channel_sizing_strategies.lock().unwrap();
+		match strategy {
+			Some(strategy) => {
+				peer_channel_sizing_strategies.insert(counterparty_node_id, strategy);
 			}
 			None => {
-				return Err(LightningError { err: format!("Received error response for a buy request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
+				peer_channel_sizing_strategies.remove(&counterparty_node_id);
 			}
 		}
 	}
-}
 
-impl<
-		ES: Deref,
-		M: Deref,
-		T: Deref,
-		F: Deref,
-		R: Deref,
-		SP: Deref,
-		Descriptor: SocketDescriptor,
-		L: Deref,
-		RM: Deref,
-		CM: Deref,
-		OM: Deref,
-		CMH: Deref,
-		NS: Deref,
-	> ProtocolMessageHandler
-	for JITChannelManager<ES, M, T, F, R, SP, Descriptor, L, RM, CM, OM, CMH, NS>
-where
-	ES::Target: EntropySource,
-	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
-	T::Target: BroadcasterInterface,
-	F::Target: FeeEstimator,
-	R::Target: Router,
-	SP::Target: SignerProvider,
-	L::Target: Logger,
-	RM::Target: RoutingMessageHandler,
-	CM::Target: ChannelMessageHandler,
-	OM::Target: OnionMessageHandler,
-	CMH::Target: CustomMessageHandler,
-	NS::Target: NodeSigner,
-{
-	type ProtocolMessage = LSPS2Message;
-	const PROTOCOL_NUMBER: Option<u16> = Some(2);
+	/// Returns the capacity of a JIT channel to the given client fitting the given payment
+	/// according to the applicable [`ChannelSizingStrategy`], rounded up to full satoshis.
+	fn channel_capacity_sat(&self, counterparty_node_id: &PublicKey, payment_msat: u64) -> u64 {
+		let strategy = self
+			.peer_channel_sizing_strategies
+			.lock()
+			.unwrap()
+			.get(counterparty_node_id)
+			.copied()
+			.unwrap_or(self.channel_sizing_strategy);
+		let channel_size_msat = match strategy {
+			ChannelSizingStrategy::Exact => payment_msat,
+			ChannelSizingStrategy::Additional { liquidity_msat } => {
+				payment_msat.saturating_add(liquidity_msat)
+			}
+		};
+		(channel_size_msat / 1000) + if channel_size_msat % 1000 > 0 { 1 } else { 0 }
+	}
 
-	fn handle_message(
-		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
-	) -> Result<(), LightningError> {
-		match message {
-			LSPS2Message::Request(request_id, request) => match request {
-				LSPS2Request::GetVersions(_) => {
-					self.handle_get_versions_request(request_id, counterparty_node_id)
+	pub(crate) fn set_channel_config(
+		&self, scid: u64, channel_config: ChannelConfig,
+	) -> Result<(), APIError> {
+		let peer_by_scid = self.peer_by_scid.read().unwrap();
+		let counterparty_node_id = peer_by_scid.get(&scid).ok_or(APIError::APIMisuseError {
+			err: format!("No JIT channel with scid {} is awaiting a payment", scid),
+		})?;
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or(APIError::APIMisuseError {
+				err: format!("No counterparty found for scid: {}", scid),
+			})?;
+		let mut peer_state = inner_state_lock.lock().unwrap();
+		match peer_state.outbound_channels_by_scid.get_mut(&scid) {
+			Some(jit_channel) => match jit_channel.state {
+				OutboundJITChannelState::InvoiceParametersGenerated { .. } => {
+					jit_channel.channel_config = Some(channel_config);
+					Ok(())
 				}
-				LSPS2Request::GetInfo(params) => {
-					self.handle_get_info_request(request_id, counterparty_node_id, params)
+				_ => Err(APIError::APIMisuseError {
+					err: format!("JIT channel with scid {} is already being opened", scid),
+				}),
+			},
+			None => Err(APIError::APIMisuseError {
+				err: format!("No JIT channel with scid {} is awaiting a payment", scid),
+			}),
+		}
+	}
+
+	pub(crate) fn htlc_intercepted(
+		&self, scid: u64, intercept_id: InterceptId, inbound_amount_msat: u64,
+		expected_outbound_amount_msat: u64,
+	) -> Result<(), APIError> {
+		let peer_by_scid = self.peer_by_scid.read().unwrap();
+		if let Some(counterparty_node_id) = peer_by_scid.get(&scid) {
+			// Events may be replayed, e.g., after a restart, in which case we already acted on it.
+			if !self.processed_intercepts.lock().unwrap().insert(intercept_id) {
+				log_debug!(
+					self.logger,
+					"Ignoring already processed HTLC intercepted for scid {}",
+					scid
+				);
+				return Ok(());
+			}
+
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => {
+					let mut peer_state = inner_state_lock.lock().unwrap();
+					if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get_mut(&scid) {
+						if let OutboundJITChannelState::ChannelReady {
+							channel_id, ready_at, ..
+						} = jit_channel.state
+						{
+							// A late part of a payment we already opened the channel for.
+							if utils::now().duration_since(ready_at) < self.late_htlc_grace_period {
+								log_info!(
+									self.logger,
+									"Forwarding late HTLC for scid {} over JIT channel {}",
+									scid,
+									channel_id
+								);
+								return self.channel_manager.forward_intercepted_htlc(
+									intercept_id,
+									&channel_id,
+									*counterparty_node_id,
+									expected_outbound_amount_msat,
+								);
+							}
+
+							self.channel_manager.fail_intercepted_htlc(intercept_id)?;
+							return Err(APIError::APIMisuseError {
+								err: format!(
+									"Intercepted HTLC for scid {} after the grace period of its JIT channel",
+									scid
+								),
+							});
+						}
+
+						// TODO: Need to support MPP payments. If payment_amount_msat is known, needs to queue intercepted HTLCs in a map by payment_hash
+						//       LiquidityManager will need to be regularly polled so it can continually check if the payment amount has been received
+						//       and can release the payment or if the channel valid_until has expired and should be failed.
+						//       Can perform check each time HTLC is received and on interval? I guess interval only needs to check expiration as
+						//       we can only reach threshold when htlc is intercepted.
+
+						match jit_channel
+							.htlc_intercepted(expected_outbound_amount_msat, intercept_id)
+						{
+							Ok((_, amt_to_forward_msat))
+								if inbound_amount_msat < amt_to_forward_msat =>
+							{
+								self.set_channel_state(
+									ChannelStateKey::Lsp(scid),
+									JitChannelState::Failed,
+								);
+								self.enqueue_event(Event::LSPS2(
+									LSPS2Event::InsufficientInboundAmount {
+										counterparty_node_id: *counterparty_node_id,
+										user_channel_id: scid as u128,
+										intercept_id,
+										inbound_amount_msat,
+										amt_to_forward_msat,
+									},
+								));
+								peer_state.remove_outbound_channel(scid);
+								self.channel_manager.fail_intercepted_htlc(intercept_id)?;
+								return Err(APIError::APIMisuseError {
+									err: format!(
+										"Intercepted HTLC amount {} is less than the amount to forward {}",
+										inbound_amount_msat, amt_to_forward_msat
+									),
+								});
+							}
+							Ok((opening_fee_msat, amt_to_forward_msat)) => {
+								self.set_channel_state(
+									ChannelStateKey::Lsp(scid),
+									JitChannelState::OpeningChannel,
+								);
+								self.request_channel_open(
+									scid as u128,
+									LSPS2Event::OpenChannel {
+										their_network_key: *counterparty_node_id,
+										inbound_amount_msat,
+										expected_outbound_amount_msat,
+										amt_to_forward_msat,
+										opening_fee_msat,
+										channel_capacity_sat: self.channel_capacity_sat(
+											counterparty_node_id,
+											expected_outbound_amount_msat,
+										),
+										user_channel_id: scid as u128,
+										announce_channel: jit_channel.announce_channel,
+										channel_config: jit_channel.channel_config,
+									},
+								);
+							}
+							Err(e) => {
+								self.channel_manager.fail_intercepted_htlc(intercept_id)?;
+								// A further HTLC for a channel being opened already is rejected
+								// on its own, leaving the channel to the HTLC it is opened for.
+								if let OutboundJITChannelState::InvoiceParametersGenerated {
+									..
+								} = jit_channel.state
+								{
+									self.set_channel_state(
+										ChannelStateKey::Lsp(scid),
+										JitChannelState::Failed,
+									);
+									peer_state.remove_outbound_channel(scid);
+								}
+								// TODO: cleanup peer_by_scid
+								return Err(APIError::APIMisuseError { err: e.err });
+							}
+						}
+					}
 				}
-				LSPS2Request::Buy(params) => {
-					self.handle_buy_request(request_id, counterparty_node_id, params)
+				None => {
+					return Err(APIError::APIMisuseError {
+						err: format!("No counterparty found for scid: {}", scid),
+					});
 				}
-			},
-			LSPS2Message::Response(request_id, response) => match response {
-				LSPS2Response::GetVersions(result) => {
-					self.handle_get_versions_response(request_id, counterparty_node_id, result)
+			}
+		}
+
+		Ok(())
+	}
+
+	// figure out which intercept id is waiting on this channel and enqueue ForwardInterceptedHTLC event
+	pub(crate) fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<ChannelReadyOutcome, APIError> {
+		// The user_channel_id of a JIT channel is its scid, so anything else wasn't opened by us.
+		let scid: Option<u64> = user_channel_id.try_into().ok();
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let mut peer_state = outer_state_lock
+			.get(counterparty_node_id)
+			.map(|inner_state_lock| inner_state_lock.lock().unwrap());
+		let jit_channel = match (scid, peer_state.as_mut()) {
+			(Some(scid), Some(peer_state)) => peer_state.outbound_channels_by_scid.get_mut(&scid),
+			_ => None,
+		};
+		let jit_channel = match jit_channel {
+			Some(jit_channel) => jit_channel,
+			None => {
+				log_trace!(
+					self.logger,
+					"Ignoring channel {} with user_channel_id {} not being a JIT channel",
+					channel_id,
+					user_channel_id
+				);
+				return Ok(ChannelReadyOutcome::Ignored);
+			}
+		};
+
+		match jit_channel.channel_ready(*channel_id) {
+			Ok((intercept_id, amt_to_forward_msat)) => {
+				// The channel was found by its scid, so the user_channel_id fits.
+				self.set_channel_state(
+					ChannelStateKey::Lsp(user_channel_id as u64),
+					JitChannelState::Ready,
+				);
+				self.channel_open_completed(user_channel_id);
+				self.channel_manager.forward_intercepted_htlc(
+					intercept_id,
+					channel_id,
+					*counterparty_node_id,
+					amt_to_forward_msat,
+				)?;
+				Ok(ChannelReadyOutcome::Forwarded)
+			}
+			Err(e) => Err(APIError::APIMisuseError {
+				err: format!("Failed to transition to channel ready: {}", e.err),
+			}),
+		}
+	}
+
+	pub(crate) fn funding_created(
+		&self, user_channel_id: u128, funding_txo: OutPoint,
+	) -> Result<(), APIError> {
+		let scid: u64 = user_channel_id.try_into().map_err(|_| APIError::APIMisuseError {
+			err: format!("Could not find a channel with user_channel_id {}", user_channel_id),
+		})?;
+
+		let peer_by_scid = self.peer_by_scid.read().unwrap();
+		let counterparty_node_id = peer_by_scid.get(&scid).ok_or(APIError::APIMisuseError {
+			err: format!("No counterparty found for scid: {}", scid),
+		})?;
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+				match peer_state.outbound_channels_by_scid.get_mut(&scid) {
+					Some(jit_channel) => {
+						jit_channel.funding_txo = Some(funding_txo);
+						Ok(())
+					}
+					None => Err(APIError::APIMisuseError {
+						err: format!(
+							"Could not find a channel with user_channel_id {}",
+							user_channel_id
+						),
+					}),
 				}
-				LSPS2Response::GetInfo(result) => {
-					self.handle_get_info_response(request_id, counterparty_node_id, result)
+			}
+			None => Err(APIError::APIMisuseError {
+				err: format!("No counterparty state for: {}", counterparty_node_id),
+			}),
+		}
+	}
+
+	pub(crate) fn get_relevant_txids(&self) -> Vec<(Txid, Option<BlockHash>)> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let mut relevant_txids = Vec::new();
+		for inner_state_lock in outer_state_lock.values() {
+			let peer_state = inner_state_lock.lock().unwrap();
+			for jit_channel in peer_state.outbound_channels_by_scid.values() {
+				if let Some(funding_txo) = jit_channel.funding_txo {
+					relevant_txids.push((funding_txo.txid, None));
 				}
-				LSPS2Response::GetInfoError(error) => {
-					self.handle_get_info_error(request_id, counterparty_node_id, error)
+			}
+			for jit_channel in peer_state.inbound_channels_by_id.values() {
+				if let Some(funding_txo) = jit_channel.pending_funding_txo() {
+					relevant_txids.push((funding_txo.txid, None));
 				}
-				LSPS2Response::Buy(result) => {
-					self.handle_buy_response(request_id, counterparty_node_id, result)
+			}
+		}
+		relevant_txids
+	}
+
+	/// Returns the version previously negotiated with the given LSP, if we still support it.
+	fn negotiated_version(&self, counterparty_node_id: &PublicKey) -> Option<u16> {
+		self.negotiated_versions
+			.lock()
+			.unwrap()
+			.get(counterparty_node_id)
+			.cloned()
+			.filter(|version| SUPPORTED_SPEC_VERSIONS.contains(version))
+	}
+
+	pub(crate) fn negotiated_versions(&self) -> NegotiatedVersions {
+		NegotiatedVersions(self.negotiated_versions.lock().unwrap().clone())
+	}
+
+	/// Restores previously negotiated versions, keeping any negotiated since.
+	pub(crate) fn restore_negotiated_versions(&self, restored: NegotiatedVersions) {
+		let mut negotiated_versions = self.negotiated_versions.lock().unwrap();
+		for (counterparty_node_id, version) in restored.0 {
+			negotiated_versions.entry(counterparty_node_id).or_insert(version);
+		}
+	}
+
+	pub(crate) fn processed_intercepts(&self) -> ProcessedIntercepts {
+		ProcessedIntercepts(self.processed_intercepts.lock().unwrap().0.clone())
+	}
+
+	/// Restores previously processed intercepts, keeping any processed since.
+	pub(crate) fn restore_processed_intercepts(&self, restored: ProcessedIntercepts) {
+		let mut processed_intercepts = self.processed_intercepts.lock().unwrap();
+		let processed_since = std::mem::replace(&mut *processed_intercepts, restored);
+		for intercept_id in processed_since.0 {
+			processed_intercepts.insert(intercept_id);
+		}
+	}
+
+	fn cached_get_info_response(
+		&self, counterparty_node_id: &PublicKey, token: &Option<Token>,
+	) -> Option<(u16, GetInfoResponse)> {
+		if !self.cache_get_info_responses {
+			return None;
+		}
+
+		let seconds_since_epoch = self.duration_since_epoch().as_secs() as i64;
+
+		let mut get_info_cache = self.get_info_cache.lock().unwrap();
+		let key = (*counterparty_node_id, token.clone());
+		match get_info_cache.get(&key) {
+			Some(cached) if cached.expires_at.timestamp() > seconds_since_epoch => {
+				Some((cached.version, cached.response.clone()))
+			}
+			Some(_) => {
+				get_info_cache.remove(&key);
+				None
+			}
+			None => None,
+		}
+	}
+
+	fn cache_get_info_response(
+		&self, counterparty_node_id: PublicKey, token: Option<Token>, version: u16,
+		response: &GetInfoResponse,
+	) {
+		if !self.cache_get_info_responses {
+			return;
+		}
+
+		let earliest_valid_until =
+			response.opening_fee_params_menu.iter().map(|params| params.valid_until).min();
+
+		if let Some(expires_at) = earliest_valid_until {
+			self.get_info_cache.lock().unwrap().insert(
+				(counterparty_node_id, token),
+				CachedGetInfoResponse { version, response: response.clone(), expires_at },
+			);
+		}
+	}
+
+	fn generate_jit_channel_id(&self) -> u128 {
+		let bytes = self.entropy_source.get_secure_random_bytes();
+		let mut id_bytes: [u8; 16] = [0; 16];
+		id_bytes.copy_from_slice(&bytes[0..16]);
+		u128::from_be_bytes(id_bytes)
+	}
+
+	fn generate_correlation_id(&self) -> u128 {
+		let bytes = self.entropy_source.get_secure_random_bytes();
+		let mut id_bytes: [u8; 16] = [0; 16];
+		id_bytes.copy_from_slice(&bytes[16..32]);
+		u128::from_be_bytes(id_bytes)
+	}
+
+	fn generate_request_id(&self) -> RequestId {
+		utils::generate_request_id(&self.entropy_source)
+	}
+
+	fn enqueue_response(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, response: LSPS2Response,
+	) {
+		self.enqueue_message(counterparty_node_id, LSPS2Message::Response(request_id, response));
+	}
+
+	fn enqueue_message(&self, counterparty_node_id: PublicKey, message: LSPS2Message) {
+		{
+			let mut pending_messages = self.pending_messages.lock().unwrap();
+			pending_messages.push((counterparty_node_id, message.into()));
+		}
+
+		if let Some(peer_manager) = self.peer_manager.lock().unwrap().as_ref() {
+			peer_manager.process_events();
+		} else if !self.logged_missing_peer_manager.swap(true, Ordering::AcqRel) {
+			log_debug!(
+				self.logger,
+				"Enqueued an LSPS2 message while no PeerManager is set. Messages will only be sent when the PeerManager is polled; call LiquidityManager::set_peer_manager to send them immediately."
+			);
+		}
+	}
+
+	fn enqueue_event(&self, event: Event) {
+		self.pending_events.enqueue(event);
+	}
+
+	/// Enqueues the given [`LSPS2Event::OpenChannel`] event, or defers it if the maximum number of
+	/// pending channel opens is reached.
+	fn request_channel_open(&self, user_channel_id: u128, event: LSPS2Event) {
+		let mut channel_open_queue = self.channel_open_queue.lock().unwrap();
+		if let Some(max_pending_channel_opens) = self.max_pending_channel_opens {
+			if channel_open_queue.in_flight.len() >= max_pending_channel_opens {
+				log_debug!(
+					self.logger,
+					"Deferring opening channel {} as {} channel opens are pending",
+					user_channel_id,
+					channel_open_queue.in_flight.len()
+				);
+				channel_open_queue.deferred.push_back((user_channel_id, event));
+				return;
+			}
+		}
+		channel_open_queue.in_flight.insert(user_channel_id);
+		self.enqueue_event(Event::LSPS2(event));
+	}
+
+	/// Releases the pending channel open of the given channel, which either got ready or was
+	/// abandoned, enqueueing the deferred ones that fit within the limit again.
+	fn channel_open_completed(&self, user_channel_id: u128) {
+		let mut events = Vec::new();
+		{
+			let mut channel_open_queue = self.channel_open_queue.lock().unwrap();
+			channel_open_queue.in_flight.remove(&user_channel_id);
+			// Deferred opens of channels abandoned meanwhile are dropped rather than requested.
+			channel_open_queue.deferred.retain(|(id, _)| {
+				*id != user_channel_id
+					&& self.lsp_channel_state(*id as u64) == Some(JitChannelState::OpeningChannel)
+			});
+			while self
+				.max_pending_channel_opens
+				.map_or(true, |max| channel_open_queue.in_flight.len() < max)
+			{
+				match channel_open_queue.deferred.pop_front() {
+					Some((id, event)) => {
+						channel_open_queue.in_flight.insert(id);
+						events.push(event);
+					}
+					None => break,
 				}
-				LSPS2Response::BuyError(error) => {
-					self.handle_buy_error(request_id, counterparty_node_id, error)
+			}
+		}
+		for event in events {
+			self.enqueue_event(Event::LSPS2(event));
+		}
+	}
+
+	/// Abandons the JIT channel with the given scid if its opening was requested, returning the id
+	/// of the HTLC intercepted for it.
+	///
+	/// The pending channel open has to be released via [`Self::channel_open_completed`] once the
+	/// peer's state is unlocked.
+	fn abandon_channel_open(&self, peer_state: &mut PeerState, scid: u64) -> Option<InterceptId> {
+		let intercept_id = match peer_state.outbound_channels_by_scid.get(&scid) {
+			Some(OutboundJITChannel {
+				state: OutboundJITChannelState::PendingChannelOpen { intercept_id, .. },
+				..
+			}) => *intercept_id,
+			_ => return None,
+		};
+		peer_state.remove_outbound_channel(scid);
+		self.set_channel_state(ChannelStateKey::Lsp(scid), JitChannelState::Failed);
+		Some(intercept_id)
+	}
+
+	/// Fails back the HTLC intercepted for an abandoned JIT channel.
+	fn fail_abandoned_htlc(&self, scid: u64, intercept_id: InterceptId) {
+		if let Err(e) = self.channel_manager.fail_intercepted_htlc(intercept_id) {
+			log_error!(
+				self.logger,
+				"Failed to fail back HTLC intercepted for abandoned JIT channel {}: {:?}",
+				scid,
+				e
+			);
+		}
+	}
+
+	/// Abandons the JIT channel whose opening was requested via an [`LSPS2Event::OpenChannel`]
+	/// event, failing back the intercepted HTLC.
+	pub fn channel_open_failed(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+	) -> Result<(), APIError> {
+		let intercept_id = {
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			let mut peer_state = match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => inner_state_lock.lock().unwrap(),
+				None => {
+					return Err(APIError::APIMisuseError {
+						err: format!("No counterparty state for: {}", counterparty_node_id),
+					})
 				}
-			},
+			};
+			user_channel_id
+				.try_into()
+				.ok()
+				.and_then(|scid| self.abandon_channel_open(&mut peer_state, scid))
+				.ok_or_else(|| APIError::APIMisuseError {
+					err: format!(
+						"No channel with user_channel_id {} is being opened",
+						user_channel_id
+					),
+				})?
+		};
+
+		log_info!(self.logger, "Abandoning JIT channel {} as it failed to open", user_channel_id);
+		self.channel_open_completed(user_channel_id);
+		self.fail_abandoned_htlc(user_channel_id as u64, intercept_id);
+		Ok(())
+	}
+
+	/// Abandons the JIT channel an intercepted HTLC was failed for, e.g., as it expired before the
+	/// channel got ready.
+	pub fn htlc_handling_failed(&self, failed_next_destination: HTLCDestination) {
+		let scid = match failed_next_destination {
+			HTLCDestination::UnknownNextHop { requested_forward_scid }
+			| HTLCDestination::InvalidForward { requested_forward_scid } => requested_forward_scid,
+			_ => return,
+		};
+		let counterparty_node_id = match self.peer_by_scid.read().unwrap().get(&scid) {
+			Some(counterparty_node_id) => *counterparty_node_id,
+			None => return,
+		};
+
+		let abandoned = {
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			match outer_state_lock.get(&counterparty_node_id) {
+				Some(inner_state_lock) => {
+					let mut peer_state = inner_state_lock.lock().unwrap();
+					self.abandon_channel_open(&mut peer_state, scid).is_some()
+				}
+				None => false,
+			}
+		};
+		if abandoned {
+			log_info!(
+				self.logger,
+				"Abandoning JIT channel {} as its intercepted HTLC was failed",
+				scid
+			);
+			self.channel_open_completed(scid as u128);
+		}
+	}
+
+	fn handle_get_versions_request(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		self.enqueue_response(
+			*counterparty_node_id,
+			request_id,
+			LSPS2Response::GetVersions(GetVersionsResponse {
+				versions: self.supported_versions.clone(),
+			}),
+		);
+		Ok(())
+	}
+
+	fn handle_get_versions_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetVersionsResponse,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+
+				let jit_channel_id =
+					peer_state.remove_request(&request_id).ok_or(LightningError {
+						err: format!(
+							"Received get_versions response for an unknown request: {:?}",
+							request_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let jit_channel = peer_state
+					.inbound_channels_by_id
+					.get_mut(&jit_channel_id)
+					.ok_or(LightningError {
+						err: format!(
+							"Received get_versions response for an unknown channel: {:?}",
+							jit_channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let token = jit_channel.config.token.clone();
+
+				let version = match jit_channel.versions_received(result.versions) {
+					Ok(version) => version,
+					Err(e) => {
+						self.set_channel_state(
+							ChannelStateKey::Client(jit_channel.config.user_id),
+							JitChannelState::Failed,
+						);
+						peer_state.remove_inbound_channel(jit_channel_id);
+						return Err(e);
+					}
+				};
+
+				self.negotiated_versions.lock().unwrap().insert(*counterparty_node_id, version);
+
+				let request_id = self.generate_request_id();
+				let request = LSPS2Request::GetInfo(GetInfoRequest { version, token });
+				peer_state.insert_request(request_id.clone(), jit_channel_id, request.clone());
+
+				self.enqueue_message(
+					*counterparty_node_id,
+					LSPS2Message::Request(request_id, request),
+				);
+			}
+			None => {
+				return Err(LightningError {
+					err: format!(
+						"Received get_versions response from unknown peer: {:?}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				})
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handle_get_info_request(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: GetInfoRequest,
+	) -> Result<(), LightningError> {
+		if !self.supported_versions.contains(&params.version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::GetInfoError(ResponseError {
+					code: LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE,
+					message: format!("version {} is not supported", params.version),
+					data: Some(format!("Supported versions are {:?}", self.supported_versions)),
+				}),
+			);
+			return Err(LightningError {
+				err: format!("client requested unsupported version {}", params.version),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if self.reject_requests_with_token && params.token.is_some() {
+			self.get_info_rejected(counterparty_node_id, GetInfoRejectionReason::InvalidToken);
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::GetInfoError(ResponseError {
+					code: LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+					message: "tokens are not accepted".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: "client sent a token although tokens are rejected".to_string(),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let mut outer_state_lock = self.per_peer_state.write().unwrap();
+		let inner_state_lock: &mut Mutex<PeerState> = outer_state_lock
+			.entry(*counterparty_node_id)
+			.or_insert(Mutex::new(PeerState::default()));
+		let peer_state = inner_state_lock.get_mut().unwrap();
+
+		if let Some(get_info_dedup_window) = self.get_info_dedup_window {
+			let now = utils::now();
+			let get_info_received_at = &peer_state.get_info_received_at;
+			let duplicated_request_id = peer_state.pending_requests.iter().find_map(
+				|(pending_request_id, request)| match (
+					request,
+					get_info_received_at.get(pending_request_id),
+				) {
+					(LSPS2Request::GetInfo(pending_params), Some(received_at))
+						if *pending_params == params
+							&& now.duration_since(*received_at) <= get_info_dedup_window =>
+					{
+						Some(pending_request_id.clone())
+					}
+					_ => None,
+				},
+			);
+			if let Some(duplicated_request_id) = duplicated_request_id {
+				log_debug!(
+					self.logger,
+					"Coalescing get_info request {:?} from {} with identical request {:?}",
+					request_id,
+					counterparty_node_id,
+					duplicated_request_id
+				);
+				peer_state
+					.coalesced_get_info_requests
+					.entry(duplicated_request_id)
+					.or_default()
+					.push(request_id);
+				return Ok(());
+			}
+			peer_state.get_info_received_at.insert(request_id.clone(), now);
+		}
+
+		peer_state
+			.pending_requests
+			.insert(request_id.clone(), LSPS2Request::GetInfo(params.clone()));
+
+		self.enqueue_event(Event::LSPS2(LSPS2Event::GetInfo {
+			request_id,
+			counterparty_node_id: *counterparty_node_id,
+			version: params.version,
+			token: params.token,
+		}));
+		Ok(())
+	}
+
+	fn handle_get_info_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetInfoResponse,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let (user_channel_id, jit_channel_id, buy_retry_selection) =
+			match outer_state_lock.get(counterparty_node_id) {
+				Some(inner_state_lock) => {
+					let mut peer_state = inner_state_lock.lock().unwrap();
+
+					let jit_channel_id =
+						peer_state.remove_request(&request_id).ok_or(LightningError {
+							err: format!(
+								"Received get_info response for an unknown request: {:?}",
+								request_id
+							),
+							action: ErrorAction::IgnoreAndLog(Level::Info),
+						})?;
+
+					let jit_channel = peer_state
+						.inbound_channels_by_id
+						.get_mut(&jit_channel_id)
+						.ok_or(LightningError {
+							err: format!(
+								"Received get_info response for an unknown channel: {:?}",
+								jit_channel_id
+							),
+							action: ErrorAction::IgnoreAndLog(Level::Info),
+						})?;
+
+					if let Err(e) = jit_channel
+						.info_received(result.min_payment_size_msat, result.max_payment_size_msat)
+					{
+						self.set_channel_state(
+							ChannelStateKey::Client(jit_channel.config.user_id),
+							JitChannelState::Failed,
+						);
+						peer_state.remove_inbound_channel(jit_channel_id);
+						return Err(e);
+					}
+
+					if let InboundJITChannelState::PendingMenuSelection { version, .. } =
+						jit_channel.state
+					{
+						self.cache_get_info_response(
+							*counterparty_node_id,
+							jit_channel.config.token.clone(),
+							version,
+							&result,
+						);
+					}
+
+					jit_channel.menu_received(&result.opening_fee_params_menu);
+					let buy_retry_selection =
+						jit_channel.buy_retry_selection(&result.opening_fee_params_menu);
+					let user_channel_id = jit_channel.config.user_id;
+					let label = jit_channel.config.label.clone();
+					let correlation_id = jit_channel.config.correlation_id;
+					log_debug!(
+						self.logger,
+						"Received opening fee params menu for JIT channel negotiation {:032x}",
+						correlation_id
+					);
+					self.set_channel_state(
+						ChannelStateKey::Client(user_channel_id),
+						JitChannelState::AwaitingBuy,
+					);
+					if buy_retry_selection.is_none()
+						&& !self.lsp_selection_menu_received(
+							*counterparty_node_id,
+							jit_channel_id,
+							user_channel_id,
+							&result,
+						) {
+						self.enqueue_event(Event::LSPS2(LSPS2Event::GetInfoResponse {
+							counterparty_node_id: *counterparty_node_id,
+							opening_fee_params_menu: result.opening_fee_params_menu,
+							min_payment_size_msat: result.min_payment_size_msat,
+							max_payment_size_msat: result.max_payment_size_msat,
+							jit_channel_id,
+							user_channel_id,
+							label,
+							correlation_id,
+						}));
+					}
+					(user_channel_id, jit_channel_id, buy_retry_selection)
+				}
+				None => {
+					return Err(LightningError {
+						err: format!(
+							"Received get_info response from unknown peer: {:?}",
+							counterparty_node_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})
+				}
+			};
+		drop(outer_state_lock);
+
+		if let Some(selection) = buy_retry_selection {
+			if let Err(e) = self.opening_fee_params_selected(
+				*counterparty_node_id,
+				jit_channel_id,
+				selection.opening_fee_params,
+				selection.refund_onchain_address,
+				selection.announce_channel,
+			) {
+				log_error!(
+					self.logger,
+					"Failed to retry buy with fresh opening fee params: {:?}",
+					e
+				);
+			}
+		}
+
+		if self.lsp_selection_complete(user_channel_id) {
+			self.finalize_lsp_selection(user_channel_id);
+		}
+
+		Ok(())
+	}
+
+	fn handle_get_info_error(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let user_channel_id = match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+
+				let jit_channel_id =
+					peer_state.remove_request(&request_id).ok_or(LightningError {
+						err: format!(
+							"Received get_info error for an unknown request: {:?}",
+							request_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let jit_channel = peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
+					LightningError {
+						err: format!(
+							"Received get_info error for an unknown channel: {:?}",
+							jit_channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					},
+				)?;
+				self.negotiation_failed(
+					*counterparty_node_id,
+					jit_channel,
+					FailureReason::Protocol(error),
+				)
+			}
+			None => {
+				return Err(LightningError { err: format!("Received error response for a get_info request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)})
+			}
+		};
+		drop(outer_state_lock);
+
+		if self.lsp_selection_complete(user_channel_id) {
+			self.finalize_lsp_selection(user_channel_id);
+		}
+
+		Ok(())
+	}
+
+	fn handle_buy_request(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: BuyRequest,
+	) -> Result<(), LightningError> {
+		if self.verbose_buy_events {
+			self.enqueue_event(Event::LSPS2(LSPS2Event::BuyRequestReceived {
+				request_id: request_id.clone(),
+				counterparty_node_id: *counterparty_node_id,
+			}));
+		}
+
+		if !self.supported_versions.contains(&params.version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+					message: format!("version {} is not supported", params.version),
+					data: Some(format!("Supported versions are {:?}", self.supported_versions)),
+				}),
+			);
+			return Err(LightningError {
+				err: format!("client requested unsupported version {}", params.version),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if let Some(payment_size_msat) = params.payment_size_msat {
+			if payment_size_msat == 0 {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::BuyError(ResponseError {
+						code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+						message: "payment size must be greater than zero".to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: "client requested a zero payment size".to_string(),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+
+			if payment_size_msat < self.min_payment_size_msat {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::BuyError(ResponseError {
+						code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+						message: "payment size is below our minimum supported payment size"
+							.to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: "payment size is below our minimum supported payment size".to_string(),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+
+			if payment_size_msat > self.max_payment_size_msat {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::BuyError(ResponseError {
+						code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+						message: "payment size is above our maximum supported payment size"
+							.to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: "payment size is above our maximum supported payment size".to_string(),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+
+			match compute_opening_fee(
+				payment_size_msat,
+				params.opening_fee_params.min_fee_msat,
+				params.opening_fee_params.proportional,
+			) {
+				Some(opening_fee) => {
+					if opening_fee >= payment_size_msat {
+						self.enqueue_response(
+							*counterparty_node_id,
+							request_id,
+							LSPS2Response::BuyError(ResponseError {
+								code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+								message: "payment size is too small to cover the opening fee"
+									.to_string(),
+								data: None,
+							}),
+						);
+						return Err(LightningError {
+							err: "payment size is too small to cover the opening fee".to_string(),
+							action: ErrorAction::IgnoreAndLog(Level::Info),
+						});
+					}
+				}
+				None => {
+					self.enqueue_response(
+						*counterparty_node_id,
+						request_id,
+						LSPS2Response::BuyError(ResponseError {
+							code: LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+							message: "overflow error when calculating opening_fee".to_string(),
+							data: None,
+						}),
+					);
+					return Err(LightningError {
+						err: "overflow error when calculating opening_fee".to_string(),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+			}
+		}
+
+		// TODO: if payment_size_msat is specified, make sure our node has sufficient incoming liquidity from public network to receive it.
+
+		if params.opening_fee_params.max_client_to_self_delay > MAX_CLIENT_TO_SELF_DELAY {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+					message: "max_client_to_self_delay exceeds the maximum we can honor"
+						.to_string(),
+					data: Some(format!("Maximum is {}", MAX_CLIENT_TO_SELF_DELAY)),
+				}),
+			);
+			return Err(LightningError {
+				err: format!(
+					"client supplied max_client_to_self_delay {} above the maximum of {}",
+					params.opening_fee_params.max_client_to_self_delay, MAX_CLIENT_TO_SELF_DELAY
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if !self.is_valid_opening_fee_params(&params.opening_fee_params, params.version) {
+			self.enqueue_response(
+				*counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+					message: "valid_until is already past OR the promise did not match the provided parameters".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: "invalid opening fee parameters were supplied by client".to_string(),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let mut outer_state_lock = self.per_peer_state.write().unwrap();
+		let inner_state_lock = outer_state_lock
+			.entry(*counterparty_node_id)
+			.or_insert(Mutex::new(PeerState::default()));
+		let peer_state = inner_state_lock.get_mut().unwrap();
+
+		// Identical buy requests racing each other, e.g., concurrent retries of a client, are
+		// served by a single JIT channel.
+		let duplicated_request_id =
+			peer_state.pending_requests.iter().find_map(|(pending_request_id, request)| {
+				match request {
+					LSPS2Request::Buy(pending_params) if *pending_params == params => {
+						Some(pending_request_id.clone())
+					}
+					_ => None,
+				}
+			});
+		if let Some(duplicated_request_id) = duplicated_request_id {
+			log_debug!(
+				self.logger,
+				"Coalescing buy request {:?} from {} with identical request {:?}",
+				request_id,
+				counterparty_node_id,
+				duplicated_request_id
+			);
+			peer_state
+				.coalesced_buy_requests
+				.entry(duplicated_request_id)
+				.or_default()
+				.push(request_id);
+			return Ok(());
+		}
+
+		let scid_allocation = match &self.scid_allocator {
+			Some(scid_allocator) => scid_allocator.allocate_scid(),
+			None => self.channel_manager.allocate_scid(),
+		};
+		let scid = match scid_allocation {
+			Some(scid) => scid,
+			None => {
+				self.enqueue_response(
+					*counterparty_node_id,
+					request_id,
+					LSPS2Response::BuyError(ResponseError {
+						code: LSPS2_BUY_REQUEST_TEMPORARY_FAILURE_ERROR_CODE,
+						message: "no channel can be offered at this time, try again later"
+							.to_string(),
+						data: None,
+					}),
+				);
+				self.enqueue_event(Event::LSPS2(LSPS2Event::ScidExhausted {
+					counterparty_node_id: *counterparty_node_id,
+				}));
+				return Err(LightningError {
+					err: "no scid is available for the client's buy request".to_string(),
+					action: ErrorAction::IgnoreAndLog(Level::Warn),
+				});
+			}
+		};
+
+		peer_state.pending_requests.insert(request_id.clone(), LSPS2Request::Buy(params.clone()));
+		peer_state.allocated_scids.insert(request_id.clone(), scid);
+
+		let token = match peer_state.get_info_contexts.get(&params.opening_fee_params.promise) {
+			Some(context) => {
+				if context.version != params.version {
+					log_debug!(
+						self.logger,
+						"Buy request {:?} from {} uses version {} while the opening fee params were requested with version {}",
+						request_id,
+						counterparty_node_id,
+						params.version,
+						context.version
+					);
+				}
+				context.token.clone()
+			}
+			None => None,
+		};
+
+		if self.verbose_buy_events {
+			self.enqueue_event(Event::LSPS2(LSPS2Event::BuyRequestValidated {
+				request_id: request_id.clone(),
+				counterparty_node_id: *counterparty_node_id,
+			}));
+		}
+		self.enqueue_event(Event::LSPS2(LSPS2Event::BuyRequest {
+			request_id,
+			version: params.version,
+			counterparty_node_id: *counterparty_node_id,
+			opening_fee_params: params.opening_fee_params,
+			payment_size_msat: params.payment_size_msat,
+			refund_onchain_address: params.refund_onchain_address,
+			announce_channel: params.announce_channel,
+			scid,
+			token,
+		}));
+
+		Ok(())
+	}
+
+	fn handle_buy_response(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: BuyResponse,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+
+				let jit_channel_id =
+					peer_state.remove_request(&request_id).ok_or(LightningError {
+						err: format!(
+							"Received buy response for an unknown request: {:?}",
+							request_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let jit_channel = peer_state
+					.inbound_channels_by_id
+					.get_mut(&jit_channel_id)
+					.ok_or(LightningError {
+						err: format!(
+							"Received buy response for an unknown channel: {:?}",
+							jit_channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let client_trusts_lsp =
+					result.client_trusts_lsp.unwrap_or(self.assume_client_trusts_lsp_when_absent);
+				if let Err(e) = jit_channel.invoice_params_received(
+					client_trusts_lsp,
+					result.jit_channel_scid.clone(),
+					result.lsp_cltv_expiry_delta,
+				) {
+					self.set_channel_state(
+						ChannelStateKey::Client(jit_channel.config.user_id),
+						JitChannelState::Failed,
+					);
+					peer_state.remove_inbound_channel(jit_channel_id);
+					return Err(e);
+				}
+
+				if let Ok(scid) = result.jit_channel_scid.to_scid() {
+					log_debug!(
+						self.logger,
+						"Received invoice parameters for JIT channel negotiation {:032x}",
+						jit_channel.config.correlation_id
+					);
+					self.set_channel_state(
+						ChannelStateKey::Client(jit_channel.config.user_id),
+						JitChannelState::AwaitingPayment,
+					);
+					if self.require_lsp_trusts_client && client_trusts_lsp {
+						self.enqueue_event(Event::LSPS2(LSPS2Event::TrustModelMismatch {
+							counterparty_node_id: *counterparty_node_id,
+							scid,
+							cltv_expiry_delta: result.lsp_cltv_expiry_delta,
+							payment_size_msat: jit_channel.config.payment_size_msat,
+							user_channel_id: jit_channel.config.user_id,
+							label: jit_channel.config.label.clone(),
+							correlation_id: jit_channel.config.correlation_id,
+						}));
+					} else {
+						self.enqueue_event(Event::LSPS2(LSPS2Event::InvoiceGenerationReady {
+							counterparty_node_id: *counterparty_node_id,
+							scid,
+							cltv_expiry_delta: result.lsp_cltv_expiry_delta,
+							payment_size_msat: jit_channel.config.payment_size_msat,
+							client_trusts_lsp,
+							user_channel_id: jit_channel.config.user_id,
+							label: jit_channel.config.label.clone(),
+							correlation_id: jit_channel.config.correlation_id,
+						}));
+					}
+				} else {
+					self.set_channel_state(
+						ChannelStateKey::Client(jit_channel.config.user_id),
+						JitChannelState::Failed,
+					);
+					return Err(LightningError {
+						err: format!(
+							"Received buy response with an invalid scid {:?}",
+							result.jit_channel_scid
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+			}
+			None => {
+				return Err(LightningError {
+					err: format!(
+						"Received buy response from unknown peer: {:?}",
+						counterparty_node_id
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				});
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_buy_error(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+
+				let jit_channel_id =
+					peer_state.remove_request(&request_id).ok_or(LightningError {
+						err: format!("Received buy error for an unknown request: {:?}", request_id),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				if self.retry_buy_on_expired_params
+					&& error.code == LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE
+				{
+					if let Some(jit_channel) =
+						peer_state.inbound_channels_by_id.get_mut(&jit_channel_id)
+					{
+						let seconds_since_epoch = self.duration_since_epoch().as_secs() as i64;
+						if let Some(version) = jit_channel.retry_buy(seconds_since_epoch) {
+							log_info!(
+								self.logger,
+								"Opening fee params of JIT channel negotiation {:032x} were rejected by {}, retrying with fresh ones",
+								jit_channel.config.correlation_id,
+								counterparty_node_id
+							);
+							let token = jit_channel.config.token.clone();
+							self.set_channel_state(
+								ChannelStateKey::Client(jit_channel.config.user_id),
+								JitChannelState::AwaitingGetInfo,
+							);
+
+							let request_id = self.generate_request_id();
+							let request = LSPS2Request::GetInfo(GetInfoRequest { version, token });
+							peer_state.insert_request(
+								request_id.clone(),
+								jit_channel_id,
+								request.clone(),
+							);
+							self.enqueue_message(
+								*counterparty_node_id,
+								LSPS2Message::Request(request_id, request),
+							);
+							return Ok(());
+						}
+					}
+				}
+
+				let jit_channel = peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
+					LightningError {
+						err: format!(
+							"Received buy error for an unknown channel: {:?}",
+							jit_channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					},
+				)?;
+				self.negotiation_failed(
+					*counterparty_node_id,
+					jit_channel,
+					FailureReason::Protocol(error),
+				);
+				Ok(())
+			}
+			None => Err(LightningError {
+				err: format!(
+					"Received error response for a buy request from an unknown counterparty ({:?})",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
+		}
+	}
+}
+
+impl<
+		ES: Deref,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		Descriptor: SocketDescriptor,
+		L: Deref,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+	> ProtocolMessageHandler
+	for JITChannelManager<ES, M, T, F, R, SP, Descriptor, L, RM, CM, OM, CMH, NS>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+{
+	type ProtocolMessage = LSPS2Message;
+	const PROTOCOL_NUMBER: Option<u16> = Some(2);
+
+	fn handle_message(
+		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		match message {
+			LSPS2Message::Request(request_id, request) => match request {
+				LSPS2Request::GetVersions(_) => {
+					self.handle_get_versions_request(request_id, counterparty_node_id)
+				}
+				LSPS2Request::GetInfo(params) => {
+					self.handle_get_info_request(request_id, counterparty_node_id, params)
+				}
+				LSPS2Request::Buy(params) => {
+					self.handle_buy_request(request_id, counterparty_node_id, params)
+				}
+			},
+			LSPS2Message::Response(request_id, response) => match response {
+				LSPS2Response::GetVersions(result) => {
+					self.handle_get_versions_response(request_id, counterparty_node_id, result)
+				}
+				LSPS2Response::GetInfo(result) => {
+					self.handle_get_info_response(request_id, counterparty_node_id, result)
+				}
+				LSPS2Response::GetInfoError(error) => {
+					self.handle_get_info_error(request_id, counterparty_node_id, error)
+				}
+				LSPS2Response::Buy(result) => {
+					self.handle_buy_response(request_id, counterparty_node_id, result)
+				}
+				LSPS2Response::BuyError(error) => {
+					self.handle_buy_error(request_id, counterparty_node_id, error)
+				}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{
+		buy_jit_channel, create_node, default_jit_channels_config, get_events, pass_messages,
+		pass_messages_to_any, raw_opening_fee_params, request_opening_fee_params_menu,
+		try_create_node, Node,
+	};
+	use crate::transport::message_handler::LiquidityProviderConfig;
+	use crate::transport::msgs::RawLSPSMessage;
+
+	use lightning::ln::features::InitFeatures;
+	use lightning::util::config::UserConfig;
+
+	use std::sync::atomic::Ordering;
+	use std::time::SystemTime;
+
+	#[test]
+	fn create_invoice_reuses_cached_get_info_response() {
+		let mut client_config = default_jit_channels_config();
+		client_config.cache_get_info_responses = true;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let first_menu = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { opening_fee_params_menu, .. } => opening_fee_params_menu,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 2, None)
+			.unwrap();
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfoResponse {
+				opening_fee_params_menu,
+				user_channel_id,
+				counterparty_node_id,
+				..
+			})) => {
+				assert_eq!(opening_fee_params_menu, first_menu);
+				assert_eq!(user_channel_id, 2);
+				assert_eq!(counterparty_node_id, lsp.node_id);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn opening_fee_params_selected_rejects_payment_size_outside_advertised_bounds() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			Some(500),
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse {
+				jit_channel_id,
+				mut opening_fee_params_menu,
+				min_payment_size_msat,
+				..
+			} => {
+				assert!(500 < min_payment_size_msat);
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		let result = client.liquidity_manager.opening_fee_params_selected(
+			lsp.node_id,
+			jit_channel_id,
+			opening_fee_params,
+			None,
+			None,
+		);
+		assert!(matches!(result, Err(APIError::APIMisuseError { .. })));
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+	}
+
+	#[test]
+	fn opening_fee_params_selected_rejects_unknown_channel_id() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		let result = client.liquidity_manager.opening_fee_params_selected(
+			lsp.node_id,
+			jit_channel_id.wrapping_add(1),
+			opening_fee_params.clone(),
+			None,
+			None,
+		);
+		assert!(matches!(result, Err(APIError::APIMisuseError { .. })));
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params.clone(),
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		// Selecting again is rejected, as the channel is no longer awaiting a selection.
+		let result = client.liquidity_manager.opening_fee_params_selected(
+			lsp.node_id,
+			jit_channel_id,
+			opening_fee_params,
+			None,
+			None,
+		);
+		assert!(matches!(result, Err(APIError::APIMisuseError { .. })));
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+	}
+
+	#[test]
+	fn opening_fee_params_selected_sends_buy_within_advertised_bounds() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			Some(50_000),
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { payment_size_msat, .. })) => {
+				assert_eq!(payment_size_msat, Some(50_000));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn buy_request_event_includes_refund_onchain_address() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				Some("bcrt1qxyz".to_string()),
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { refund_onchain_address, .. })) => {
+				assert_eq!(refund_onchain_address, Some("bcrt1qxyz".to_string()));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn invoice_route_hint_matches_buy_response() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert_eq!(client.liquidity_manager.invoice_route_hint(7), None);
+
+		match buy_jit_channel(&client, &lsp, None, 7, 42) {
+			LSPS2Event::InvoiceGenerationReady {
+				counterparty_node_id,
+				scid,
+				cltv_expiry_delta,
+				user_channel_id,
+				..
+			} => {
+				assert_eq!(user_channel_id, 7);
+				assert_eq!(
+					client.liquidity_manager.invoice_route_hint(user_channel_id),
+					Some(RouteHintBundle { counterparty_node_id, scid, cltv_expiry_delta })
+				);
+				assert_eq!(counterparty_node_id, lsp.node_id);
+				assert_eq!(scid, 42);
+				assert_eq!(cltv_expiry_delta, 144);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert_eq!(client.liquidity_manager.invoice_route_hint(8), None);
+	}
+
+	#[test]
+	fn announce_channel_preference_reaches_open_channel_event() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		lsp.scid_allocator.queue_scid(scid);
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				Some(true),
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, announce_channel, .. })) => {
+				assert_eq!(announce_channel, Some(true));
+				request_id
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, scid, 144, false)
+			.unwrap();
+
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::OpenChannel { announce_channel, .. })) => {
+				assert_eq!(announce_channel, Some(true));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	fn handle_get_versions_request(node: &Node) -> String {
+		let counterparty_node_id = create_node(1, None).node_id;
+		let request = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","id":"abc","method":"lsps2.get_versions","params":{}}"#
+				.to_string(),
+		};
+		node.liquidity_manager.handle_custom_message(request, &counterparty_node_id).unwrap();
+		assert!(get_events(node).is_empty());
+
+		let mut msgs = node.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let (node_id, msg) = msgs.pop().unwrap();
+		assert_eq!(node_id, counterparty_node_id);
+		msg.payload
+	}
+
+	#[test]
+	fn get_versions_request_is_answered_with_configured_versions() {
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert_eq!(
+			handle_get_versions_request(&lsp),
+			r#"{"jsonrpc":"2.0","id":"abc","result":{"versions":[1]}}"#
+		);
+
+		let mut config = default_jit_channels_config();
+		config.supported_versions = vec![];
+		let lsp = create_node(2, Some(config));
+		assert_eq!(
+			handle_get_versions_request(&lsp),
+			r#"{"jsonrpc":"2.0","id":"abc","result":{"versions":[]}}"#
+		);
+	}
+
+	fn handle_request_with_unsupported_version(lsp: &Node, request: LSPS2Request) -> i32 {
+		let client = create_node(1, None);
+		let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+			RequestId::new("abc".to_string()).unwrap(),
+			request,
+		));
+		let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+		match lsp.liquidity_manager.handle_custom_message(msg, &client.node_id) {
+			Err(e) => assert!(e.err.contains("unsupported version 2")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+		assert!(get_events(lsp).is_empty());
+
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let response: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(response["id"], "abc");
+		response["error"]["code"].as_i64().unwrap() as i32
+	}
+
+	#[test]
+	fn get_info_request_with_unsupported_version_is_rejected() {
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let request = LSPS2Request::GetInfo(GetInfoRequest { version: 2, token: None });
+		assert_eq!(
+			handle_request_with_unsupported_version(&lsp, request),
+			LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE
+		);
+	}
+
+	#[test]
+	fn buy_request_with_unsupported_version_is_rejected() {
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let request = LSPS2Request::Buy(BuyRequest {
+			version: 2,
+			opening_fee_params: raw_opening_fee_params(100, 21)
+				.into_opening_fee_params(&[42; 32], PromiseVersion::V1),
+			payment_size_msat: None,
+			refund_onchain_address: None,
+			announce_channel: None,
+		});
+		assert_eq!(
+			handle_request_with_unsupported_version(&lsp, request),
+			LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE
+		);
+	}
+
+	#[test]
+	fn suggested_min_fee_msat_scales_with_fee_estimate() {
+		let mut config = default_jit_channels_config();
+		config.min_fee_funding_fee_multiplier = Some(2);
+		let lsp = create_node(2, Some(config));
+
+		lsp.fee_estimator.sat_per_kw.store(1000, Ordering::Release);
+		let suggested_min_fee_msat = lsp.liquidity_manager.suggested_min_fee_msat().unwrap();
+		assert_eq!(suggested_min_fee_msat, 2 * ESTIMATED_FUNDING_TRANSACTION_WEIGHT * 1000);
+
+		lsp.fee_estimator.sat_per_kw.store(5000, Ordering::Release);
+		assert_eq!(
+			lsp.liquidity_manager.suggested_min_fee_msat().unwrap(),
+			5 * suggested_min_fee_msat
+		);
+	}
+
+	#[test]
+	fn opening_fee_params_generated_raises_min_fee_to_suggested() {
+		let mut config = default_jit_channels_config();
+		config.min_fee_funding_fee_multiplier = Some(2);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		lsp.fee_estimator.sat_per_kw.store(1000, Ordering::Release);
+
+		match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21), raw_opening_fee_params(10_000_000, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { opening_fee_params_menu, .. } => {
+				assert_eq!(
+					opening_fee_params_menu[0].min_fee_msat,
+					2 * ESTIMATED_FUNDING_TRANSACTION_WEIGHT * 1000
+				);
+				assert_eq!(opening_fee_params_menu[1].min_fee_msat, 10_000_000);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn enqueueing_without_peer_manager_logs_once() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert!(!client.liquidity_manager.peer_manager_set());
+
+		let missing_peer_manager_logs = |node: &Node| {
+			node.logger
+				.lines
+				.lock()
+				.unwrap()
+				.iter()
+				.filter(|(level, line)| *level == Level::Debug && line.contains("no PeerManager"))
+				.count()
+		};
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(missing_peer_manager_logs(&client), 1);
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 2, None)
+			.unwrap();
+		assert_eq!(missing_peer_manager_logs(&client), 1);
+	}
+
+	#[test]
+	fn create_invoice_requests_get_info_without_cache() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		);
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 2, None)
+			.unwrap();
+		assert_eq!(client.liquidity_manager.get_and_clear_pending_msg().len(), 1);
+		assert!(get_events(&client).is_empty());
+	}
+
+	fn receive_get_info_request(client: &Node, lsp: &Node) -> RequestId {
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(client, lsp), 1);
+		assert_eq!(pass_messages(lsp, client), 1);
+		assert_eq!(pass_messages(client, lsp), 1);
+		match get_events(lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn estimate_total_client_cost_matches_hand_computed_fees() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let cost = |min_fee_msat, proportional, payment_size_msat| {
+			let params = raw_opening_fee_params(min_fee_msat, proportional)
+				.into_opening_fee_params(&[0; 32], PromiseVersion::V1);
+			client.liquidity_manager.estimate_total_client_cost(&params, payment_size_msat).unwrap()
+		};
+
+		// 21 ppm of 1_000_000 msat are 21 msat, below the minimum fee.
+		assert_eq!(cost(100, 21, 1_000_000), 100);
+		// 21 ppm of 10_000_000_000 msat are 210_000 msat.
+		assert_eq!(cost(100, 21, 10_000_000_000), 210_000);
+		// 1_000 ppm of 1_000_001 msat are 1_000.001 msat, rounded up.
+		assert_eq!(cost(100, 1_000, 1_000_001), 1_001);
+		assert_eq!(cost(100, 21, u64::MAX), u64::MAX);
+	}
+
+	#[test]
+	fn expected_inbound_liquidity_matches_hand_computed_values() {
+		let mut additional_config = default_jit_channels_config();
+		additional_config.channel_sizing_strategy =
+			ChannelSizingStrategy::Additional { liquidity_msat: 1_000_000_000 };
+		let exact = create_node(1, Some(default_jit_channels_config()));
+		let additional = create_node(2, Some(additional_config));
+		let params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[0; 32], PromiseVersion::V1);
+		let liquidity = |node: &Node, payment_size_msat| {
+			node.liquidity_manager
+				.expected_inbound_liquidity_msat(&params, payment_size_msat)
+				.unwrap()
+		};
+
+		// 10_000_000_000 msat less a fee of 210_000 msat and a reserve of 1% of the channel.
+		assert_eq!(liquidity(&exact, 10_000_000_000), 10_000_000_000 - 210_000 - 100_000_000);
+		// 50_000_000 msat less a fee of 1_050 msat and the minimum reserve of 1_000 sat.
+		assert_eq!(liquidity(&exact, 50_000_000), 50_000_000 - 1_050 - 1_000_000);
+		// The channel can't even cover the reserve.
+		assert_eq!(liquidity(&exact, 500_000), 0);
+		// 11_000_000_000 msat less a fee of 210_000 msat and a reserve of 1% of the channel.
+		assert_eq!(liquidity(&additional, 10_000_000_000), 11_000_000_000 - 210_000 - 110_000_000);
+	}
+
+	fn opening_fee_params_valid_for(valid_for: Duration) -> RawOpeningFeeParams {
+		let mut params = raw_opening_fee_params(100, 21);
+		params.valid_until = (SystemTime::now() + valid_for).into();
+		params
+	}
+
+	#[test]
+	fn recommended_invoice_expiry_matches_time_to_valid_until() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let select = |user_channel_id, valid_for| {
+			let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+				&client,
+				&lsp,
+				None,
+				user_channel_id,
+				vec![opening_fee_params_valid_for(valid_for)],
+			) {
+				LSPS2Event::GetInfoResponse { jit_channel_id, opening_fee_params_menu, .. } => {
+					(jit_channel_id, opening_fee_params_menu[0].clone())
+				}
+				event => panic!("Unexpected event: {:?}", event),
+			};
+			assert_eq!(
+				client.liquidity_manager.recommended_invoice_expiry_secs(user_channel_id),
+				None
+			);
+			client
+				.liquidity_manager
+				.opening_fee_params_selected(
+					lsp.node_id,
+					jit_channel_id,
+					opening_fee_params,
+					None,
+					None,
+				)
+				.unwrap();
+			// Drop the buy request, the recommendation is available right away.
+			client.liquidity_manager.get_and_clear_pending_msg();
+			client.liquidity_manager.recommended_invoice_expiry_secs(user_channel_id).unwrap()
+		};
+
+		// Sub-second precision of `valid_until` is dropped, so allow for some slack.
+		let expiry_secs = select(1, Duration::from_secs(3600));
+		assert!((3598..=3600).contains(&expiry_secs), "{}", expiry_secs);
+
+		assert_eq!(select(2, Duration::from_secs(10)), MIN_INVOICE_EXPIRY_SECS);
+	}
+
+	#[test]
+	fn opening_fee_params_generated_rejects_entries_beyond_horizon() {
+		let mut config = default_jit_channels_config();
+		config.max_valid_until_horizon = Duration::from_secs(24 * 60 * 60);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let request_id = receive_get_info_request(&client, &lsp);
+
+		let menu = vec![
+			opening_fee_params_valid_for(Duration::from_secs(60 * 60)),
+			opening_fee_params_valid_for(Duration::from_secs(2 * 24 * 60 * 60)),
+		];
+		assert!(lsp
+			.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, menu)
+			.is_err());
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+	}
+
+	#[test]
+	fn opening_fee_params_generated_accepts_entries_within_horizon() {
+		let mut config = default_jit_channels_config();
+		config.max_valid_until_horizon = Duration::from_secs(24 * 60 * 60);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let request_id = receive_get_info_request(&client, &lsp);
+
+		let menu = vec![opening_fee_params_valid_for(Duration::from_secs(60 * 60))];
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, menu)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn horizon_is_measured_from_injected_clock() {
+		let clock = Arc::new(TestClock {
+			duration_since_epoch: Mutex::new(
+				utils::duration_since_epoch() + Duration::from_secs(2 * 24 * 60 * 60),
+			),
+		});
+		let mut config = default_jit_channels_config();
+		config.max_valid_until_horizon = Duration::from_secs(24 * 60 * 60);
+		config.clock = Some(Arc::clone(&clock) as Arc<dyn Clock + Send + Sync>);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let request_id = receive_get_info_request(&client, &lsp);
+
+		// Beyond the horizon as seen by the system clock, but not by the injected one.
+		let menu = vec![opening_fee_params_valid_for(Duration::from_secs(2 * 24 * 60 * 60))];
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, menu)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn opening_fee_params_generated_enforces_max_menu_length() {
+		let mut config = default_jit_channels_config();
+		config.max_menu_length = 2;
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let request_id = receive_get_info_request(&client, &lsp);
+
+		let menu = vec![raw_opening_fee_params(100, 21); 3];
+		assert!(lsp
+			.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id.clone(), menu)
+			.is_err());
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		let menu = vec![raw_opening_fee_params(100, 21); 2];
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, menu)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn trust_model_mismatch_is_surfaced_to_client() {
+		let mut client_config = default_jit_channels_config();
+		client_config.require_lsp_trusts_client = true;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		lsp.scid_allocator.queue_scid(42);
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, 42, 144, true)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		match get_events(&client).as_slice() {
+			[Event::LSPS2(LSPS2Event::TrustModelMismatch {
+				counterparty_node_id,
+				scid,
+				user_channel_id,
+				..
+			})] => {
+				assert_eq!(*counterparty_node_id, lsp.node_id);
+				assert_eq!(*scid, 42);
+				assert_eq!(*user_channel_id, 1);
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+	}
+
+	#[test]
+	fn opening_fee_params_generated_validates_max_client_to_self_delay() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let request_id = receive_get_info_request(&client, &lsp);
+
+		let mut params = raw_opening_fee_params(100, 21);
+		params.max_client_to_self_delay = MAX_CLIENT_TO_SELF_DELAY + 1;
+		assert!(lsp
+			.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id.clone(), vec![params.clone()])
+			.is_err());
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		params.max_client_to_self_delay = MAX_CLIENT_TO_SELF_DELAY;
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, vec![params])
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn zero_payment_size_is_rejected_by_client() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		match client.liquidity_manager.jit_channel_create_invoice(
+			lsp.node_id,
+			Some(0),
+			None,
+			1,
+			None,
+		) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("greater than zero")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), None);
+	}
+
+	#[test]
+	fn identical_get_info_requests_are_coalesced_within_dedup_window() {
+		let mut config = default_jit_channels_config();
+		config.get_info_dedup_window = Some(Duration::from_secs(10));
+		let client = create_node(1, None);
+		let lsp = create_node(2, Some(config));
+		let get_info = |id: &str| {
+			RawLSPSMessage {
+			payload: format!(
+				"{{\"jsonrpc\":\"2.0\",\"id\":\"{}\",\"method\":\"lsps2.get_info\",\"params\":{{\"version\":1,\"token\":\"coupon\"}}}}",
+				id
+			),
+		}
+		};
+
+		lsp.liquidity_manager.handle_custom_message(get_info("first"), &client.node_id).unwrap();
+		lsp.liquidity_manager.handle_custom_message(get_info("retry"), &client.node_id).unwrap();
+
+		let mut events = get_events(&lsp);
+		assert_eq!(events.len(), 1);
+		let request_id = match events.pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		assert_eq!(request_id.as_str(), "first");
+
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(100, 21)],
+			)
+			.unwrap();
+
+		let responses: Vec<serde_json::Value> = lsp
+			.liquidity_manager
+			.get_and_clear_pending_msg()
+			.into_iter()
+			.map(|(_, msg)| serde_json::from_str(&msg.payload).unwrap())
+			.collect();
+		assert_eq!(responses.len(), 2);
+		let mut ids: Vec<&str> =
+			responses.iter().map(|response| response["id"].as_str().unwrap()).collect();
+		ids.sort_unstable();
+		assert_eq!(ids, vec!["first", "retry"]);
+		assert_eq!(responses[0]["result"], responses[1]["result"]);
+
+		// Once answered, a further identical request is surfaced again.
+		lsp.liquidity_manager.handle_custom_message(get_info("later"), &client.node_id).unwrap();
+		assert_eq!(get_events(&lsp).len(), 1);
+	}
+
+	#[test]
+	fn tokened_get_info_is_rejected_by_no_token_lsp() {
+		let mut config = default_jit_channels_config();
+		config.reject_requests_with_token = true;
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, Some("coupon".to_string()), 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		let (_, request) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		match lsp.liquidity_manager.handle_custom_message(request, &client.node_id) {
+			Err(e) => assert!(e.err.contains("token")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+		assert!(get_events(&lsp).is_empty());
+
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let error: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(
+			error["error"]["code"],
+			LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE
+		);
+		client.liquidity_manager.handle_custom_message(response, &lsp.node_id).unwrap();
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+
+		// Requests without a token are served as usual.
+		match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			2,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { user_channel_id, .. } => assert_eq!(user_channel_id, 2),
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn promise_is_verified_with_secret_indicated_by_key_id() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let opening_fee_params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		assert_eq!(opening_fee_params.key_id, Some(promise_key_id(&[42; 32])));
+		lsp.liquidity_manager.rotate_promise_secret([43; 32]).unwrap();
+
+		let buy = |request_id: &str, opening_fee_params: OpeningFeeParams| {
+			let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId::new(request_id.to_string()).unwrap(),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params,
+					payment_size_msat: None,
+					refund_onchain_address: None,
+					announce_channel: None,
+				}),
+			));
+			let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+			let result = lsp.liquidity_manager.handle_custom_message(msg, &client.node_id);
+			lsp.liquidity_manager.get_and_clear_pending_msg();
+			get_events(&lsp);
+			result.is_ok()
+		};
+
+		// The retired secret indicated by the key id is used.
+		assert!(buy("a", opening_fee_params.clone()));
+
+		// Without a key id, all known secrets are tried.
+		let mut without_key_id = opening_fee_params.clone();
+		without_key_id.key_id = None;
+		assert!(buy("b", without_key_id));
+
+		// Only the indicated secret is tried if it is known.
+		let mut wrong_key_id = opening_fee_params;
+		wrong_key_id.key_id = Some(promise_key_id(&[43; 32]));
+		assert!(!buy("c", wrong_key_id));
+	}
+
+	struct LimitedScidAllocator {
+		available_scids: Mutex<Vec<u64>>,
+	}
+
+	impl ScidAllocator for LimitedScidAllocator {
+		fn allocate_scid(&self) -> Option<u64> {
+			self.available_scids.lock().unwrap().pop()
+		}
+	}
+
+	#[test]
+	fn buy_response_carries_allocated_scid() {
+		let scid = utils::scid_from_human_readable_string("29451x4815x1").unwrap();
+		let mut config = default_jit_channels_config();
+		config.scid_allocator =
+			Some(Arc::new(LimitedScidAllocator { available_scids: Mutex::new(vec![scid]) }));
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let (request_id, allocated_scid) = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, scid, .. })) => {
+				(request_id, scid)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		assert_eq!(allocated_scid, scid);
+
+		// Another scid than the allocated one is refused, leaving the request pending.
+		match lsp.liquidity_manager.invoice_parameters_generated(
+			client.node_id,
+			request_id.clone(),
+			allocated_scid + 1,
+			144,
+			false,
+		) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("allocated")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, allocated_scid, 144, false)
+			.unwrap();
+
+		let msgs = lsp.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let response: serde_json::Value = serde_json::from_str(&msgs[0].1.payload).unwrap();
+		assert_eq!(response["result"]["jit_channel_scid"], "29451x4815x1");
+
+		client.liquidity_manager.handle_custom_message(msgs[0].1.clone(), &lsp.node_id).unwrap();
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::InvoiceGenerationReady {
+				scid: invoice_scid, ..
+			})) => {
+				assert_eq!(invoice_scid, scid);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn buy_request_is_rejected_temporarily_once_scids_are_exhausted() {
+		let mut config = default_jit_channels_config();
+		config.scid_allocator =
+			Some(Arc::new(LimitedScidAllocator { available_scids: Mutex::new(vec![42]) }));
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		let opening_fee_params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		let buy = |request_id: &str, payment_size_msat: Option<u64>| {
+			let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId::new(request_id.to_string()).unwrap(),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params.clone(),
+					payment_size_msat,
+					refund_onchain_address: None,
+					announce_channel: None,
+				}),
+			));
+			let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+			lsp.liquidity_manager.handle_custom_message(msg, &client.node_id)
+		};
+
+		buy("a", None).unwrap();
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { scid, .. })) => assert_eq!(scid, 42),
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		assert!(buy("b", Some(1_000_000)).is_err());
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::ScidExhausted { counterparty_node_id })) => {
+				assert_eq!(counterparty_node_id, client.node_id);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let response: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(response["id"], "b");
+		assert_eq!(response["error"]["code"], LSPS2_BUY_REQUEST_TEMPORARY_FAILURE_ERROR_CODE);
+	}
+
+	#[test]
+	fn intercept_scids_are_allocated_by_channel_manager_by_default() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		// Unlike `create_node`, this doesn't configure a test allocator.
+		let provider_config = LiquidityProviderConfig {
+			jit_channels: Some(default_jit_channels_config()),
+			..Default::default()
+		};
+		let user_config = UserConfig { accept_intercept_htlcs: true, ..Default::default() };
+		let lsp = try_create_node(2, Some(provider_config), Box::new(HashMap::new()), user_config)
+			.unwrap();
+
+		let opening_fee_params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		let mut scids = Vec::new();
+		for (request_id, payment_size_msat) in [("a", 1_000_000), ("b", 2_000_000)].iter() {
+			let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId::new(request_id.to_string()).unwrap(),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params.clone(),
+					payment_size_msat: Some(*payment_size_msat),
+					refund_onchain_address: None,
+					announce_channel: None,
+				}),
+			));
+			let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+			lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+			match get_events(&lsp).pop() {
+				Some(Event::LSPS2(LSPS2Event::BuyRequest { scid, .. })) => scids.push(scid),
+				event => panic!("Unexpected event: {:?}", event),
+			}
+		}
+		assert_ne!(scids[0], 0);
+		assert_ne!(scids[0], scids[1]);
+	}
+
+	#[test]
+	fn zero_payment_size_is_rejected_by_lsp() {
+		let mut config = default_jit_channels_config();
+		config.min_payment_size_msat = 0;
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		// The client API refuses zero payment sizes, so send the buy request directly.
+		let opening_fee_params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+			RequestId::new("abc".to_string()).unwrap(),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params,
+				payment_size_msat: Some(0),
+				refund_onchain_address: None,
+				announce_channel: None,
+			}),
+		));
+		let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+		match lsp.liquidity_manager.handle_custom_message(msg, &client.node_id) {
+			Err(e) => assert!(e.err.contains("zero payment size")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+		assert!(get_events(&lsp).is_empty());
+
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let response: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(response["error"]["code"], LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE);
+		assert_eq!(response["error"]["message"], "payment size must be greater than zero");
+	}
+
+	fn buy_with_max_client_to_self_delay(max_client_to_self_delay: u32) -> (Node, Node) {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let jit_channel_id = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, .. } => jit_channel_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		// Params over the limit can't be generated, so sign them with the LSP's secret directly.
+		let mut params = raw_opening_fee_params(100, 21);
+		params.max_client_to_self_delay = max_client_to_self_delay;
+		let opening_fee_params = params.into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		(client, lsp)
+	}
+
+	#[test]
+	fn buy_request_with_excessive_max_client_to_self_delay_is_rejected() {
+		let (client, lsp) = buy_with_max_client_to_self_delay(MAX_CLIENT_TO_SELF_DELAY + 1);
+		let (_, msg) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).is_err());
+		assert!(get_events(&lsp).is_empty());
+
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+	}
+
+	struct TestClock {
+		duration_since_epoch: Mutex<Duration>,
+	}
+
+	impl Clock for TestClock {
+		fn duration_since_epoch(&self) -> Duration {
+			*self.duration_since_epoch.lock().unwrap()
+		}
+	}
+
+	#[test]
+	fn buy_request_is_rejected_once_injected_clock_passed_valid_until() {
+		let clock =
+			Arc::new(TestClock { duration_since_epoch: Mutex::new(utils::duration_since_epoch()) });
+		let mut lsp_config = default_jit_channels_config();
+		lsp_config.clock = Some(Arc::clone(&clock) as Arc<dyn Clock + Send + Sync>);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(lsp_config));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		// Only the LSP's clock moves just past `valid_until`, the system clock stays put.
+		let valid_until = opening_fee_params.valid_until.timestamp() as u64;
+		*clock.duration_since_epoch.lock().unwrap() = Duration::from_secs(valid_until + 1);
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		let (_, msg) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).is_err());
+		assert!(get_events(&lsp).is_empty());
+
+		let (_, msg) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(msg.payload.contains("valid_until is already past"));
+		client.liquidity_manager.handle_custom_message(msg, &lsp.node_id).unwrap();
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+	}
+
+	#[test]
+	fn client_retries_buy_with_fresh_params_once_selected_ones_expired() {
+		let clock =
+			Arc::new(TestClock { duration_since_epoch: Mutex::new(utils::duration_since_epoch()) });
+		let mut client_config = default_jit_channels_config();
+		client_config.retry_buy_on_expired_params = true;
+		client_config.clock = Some(Arc::clone(&clock) as Arc<dyn Clock + Send + Sync>);
+		let mut lsp_config = default_jit_channels_config();
+		lsp_config.clock = Some(Arc::clone(&clock) as Arc<dyn Clock + Send + Sync>);
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(lsp_config));
+		lsp.scid_allocator.queue_scid(42);
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		let valid_until = opening_fee_params.valid_until;
+		*clock.duration_since_epoch.lock().unwrap() =
+			Duration::from_secs(valid_until.timestamp() as u64 + 1);
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		let (_, msg) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).is_err());
+
+		// Rather than failing, the client requests a fresh menu.
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert!(get_events(&client).is_empty());
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(1),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		let mut fresh_params = raw_opening_fee_params(100, 21);
+		fresh_params.valid_until = valid_until + chrono::Duration::days(1);
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, vec![fresh_params])
+			.unwrap();
+
+		// The buy is retried with the fresh params without involving the user.
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert!(get_events(&client).is_empty());
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest {
+				request_id, opening_fee_params, ..
+			})) => {
+				assert_eq!(opening_fee_params.valid_until, valid_until + chrono::Duration::days(1));
+				request_id
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, 42, 144, false)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::InvoiceGenerationReady { user_channel_id, .. })) => {
+				assert_eq!(user_channel_id, 1);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn client_does_not_retry_buy_rejected_for_params_that_did_not_expire() {
+		let mut client_config = default_jit_channels_config();
+		client_config.retry_buy_on_expired_params = true;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, mut opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		// Breaks the promise, so the LSP rejects the params although they are still valid.
+		opening_fee_params.min_fee_msat += 1;
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		let (_, msg) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).is_err());
+
+		// The error is surfaced rather than retrying with a fresh menu.
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+	}
+
+	#[test]
+	fn buy_request_with_max_client_to_self_delay_within_limit_is_accepted() {
+		let (client, lsp) = buy_with_max_client_to_self_delay(MAX_CLIENT_TO_SELF_DELAY);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { opening_fee_params, .. })) => {
+				assert_eq!(opening_fee_params.max_client_to_self_delay, MAX_CLIENT_TO_SELF_DELAY);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn channel_states_of_client_and_lsp_are_kept_apart() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let node = create_node(2, Some(default_jit_channels_config()));
+		let lsp = create_node(3, Some(default_jit_channels_config()));
+
+		// The node sells a channel with scid 42 and buys one with user_channel_id 42.
+		buy_jit_channel(&client, &node, None, 1, 42);
+		request_opening_fee_params_menu(
+			&node,
+			&lsp,
+			None,
+			42,
+			vec![raw_opening_fee_params(100, 21)],
+		);
+
+		assert_eq!(
+			node.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::AwaitingPayment)
+		);
+		assert_eq!(
+			node.liquidity_manager.jit_channel_state(42),
+			Some(JitChannelState::AwaitingBuy)
+		);
+	}
+
+	#[test]
+	fn only_the_most_recent_terminal_channel_states_are_remembered() {
+		let mut channel_states = ChannelStates::default();
+		channel_states.insert(ChannelStateKey::Client(0), JitChannelState::AwaitingGetInfo);
+		for scid in 0..MAX_TERMINAL_CHANNEL_STATES as u64 {
+			channel_states.insert(ChannelStateKey::Lsp(scid), JitChannelState::Failed);
+		}
+		// A channel leaving its terminal state isn't forgotten.
+		channel_states.insert(ChannelStateKey::Lsp(0), JitChannelState::AwaitingGetInfo);
+		channel_states.insert(ChannelStateKey::Client(1), JitChannelState::Ready);
+		channel_states.insert(ChannelStateKey::Client(2), JitChannelState::Ready);
+
+		assert_eq!(channel_states.states.len(), MAX_TERMINAL_CHANNEL_STATES + 2);
+		assert_eq!(
+			channel_states.get(ChannelStateKey::Client(0)),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
+		assert_eq!(
+			channel_states.get(ChannelStateKey::Lsp(0)),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
+		assert_eq!(channel_states.get(ChannelStateKey::Lsp(1)), None);
+		assert_eq!(channel_states.get(ChannelStateKey::Lsp(2)), Some(JitChannelState::Failed));
+		assert_eq!(channel_states.get(ChannelStateKey::Client(2)), Some(JitChannelState::Ready));
+	}
+
+	#[test]
+	fn channel_state_progresses_through_successful_flow() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let user_channel_id = 7;
+		let scid = 42;
+		lsp.scid_allocator.queue_scid(scid);
+		assert_eq!(client.liquidity_manager.jit_channel_state(user_channel_id), None);
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, user_channel_id, None)
+			.unwrap();
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(user_channel_id),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
+
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(100, 21)],
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(user_channel_id),
+			Some(JitChannelState::AwaitingBuy)
+		);
+
+		let (jit_channel_id, opening_fee_params) = match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfoResponse {
+				jit_channel_id,
+				mut opening_fee_params_menu,
+				..
+			})) => (jit_channel_id, opening_fee_params_menu.remove(0)),
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, scid, 144, false)
+			.unwrap();
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::AwaitingPayment)
+		);
+
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(user_channel_id),
+			Some(JitChannelState::AwaitingPayment)
+		);
+
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::OpeningChannel)
+		);
+
+		// Forwarding the intercepted HTLC fails as no actual channel exists in the test, but the
+		// channel is considered ready regardless.
+		let _ =
+			lsp.liquidity_manager.channel_ready(scid as u128, &ChannelId([0; 32]), &client.node_id);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::Ready)
+		);
+	}
+
+	#[test]
+	fn channel_ready_for_unknown_channel_is_ignored() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+
+		// A channel the counterparty opened to us, or one we opened normally, isn't tracked by its
+		// user_channel_id.
+		let user_channel_id = 12345;
+		assert_eq!(
+			lsp.liquidity_manager.channel_ready(
+				user_channel_id,
+				&ChannelId([7; 32]),
+				&client.node_id
+			),
+			Ok(ChannelReadyOutcome::Ignored)
+		);
+		assert_eq!(lsp.liquidity_manager.jit_channel_state_by_scid(user_channel_id as u64), None);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::AwaitingPayment)
+		);
+		assert!(get_events(&lsp).is_empty());
+		assert!(lsp
+			.logger
+			.lines
+			.lock()
+			.unwrap()
+			.iter()
+			.any(|(level, line)| *level == Level::Trace && line.contains("user_channel_id 12345")));
+
+		// The same goes for peers we don't hold any state for.
+		assert_eq!(
+			lsp.liquidity_manager.channel_ready(42, &ChannelId([7; 32]), &lsp.node_id),
+			Ok(ChannelReadyOutcome::Ignored)
+		);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::AwaitingPayment)
+		);
+	}
+
+	#[test]
+	fn channel_config_is_carried_to_open_channel_event() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		let channel_config = ChannelConfig {
+			forwarding_fee_base_msat: 0,
+			forwarding_fee_proportional_millionths: 2_500,
+			..Default::default()
+		};
+		assert!(lsp
+			.liquidity_manager
+			.set_jit_channel_config(scid as u128 + 1, channel_config)
+			.is_err());
+		lsp.liquidity_manager.set_jit_channel_config(scid as u128, channel_config).unwrap();
+
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::OpenChannel { channel_config: config, .. })) => {
+				assert_eq!(config, Some(channel_config));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		// The config can't be changed once the channel is being opened.
+		assert!(lsp
+			.liquidity_manager
+			.set_jit_channel_config(scid as u128, channel_config)
+			.is_err());
+	}
+
+	#[test]
+	fn open_channel_event_is_held_back_while_events_are_paused() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		lsp.liquidity_manager.pause_events();
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert!(get_events(&lsp).is_empty());
+
+		lsp.liquidity_manager.resume_events();
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::OpenChannel { user_channel_id, .. })) => {
+				assert_eq!(user_channel_id, scid as u128);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn total_fees_earned_sums_opening_fees_of_completed_channels() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let mut expected_total_msat = 0;
+		for (scid, amount_msat, opening_fee_msat) in
+			[(42u64, 1_000_000u64, 100u64), (43, 10_000_000, 210)].iter()
+		{
+			buy_jit_channel(&client, &lsp, None, *scid as u128, *scid);
+			lsp.liquidity_manager
+				.htlc_intercepted(*scid, InterceptId([*scid as u8; 32]), *amount_msat, *amount_msat)
+				.unwrap();
+			match get_events(&lsp).pop() {
+				Some(Event::LSPS2(LSPS2Event::OpenChannel { opening_fee_msat: fee, .. })) => {
+					assert_eq!(fee, *opening_fee_msat);
+				}
+				event => panic!("Unexpected event: {:?}", event),
+			}
+
+			// The fee is only earned once the payment was forwarded over the channel.
+			let channel_id = ChannelId([*scid as u8; 32]);
+			let _ =
+				lsp.liquidity_manager.channel_ready(*scid as u128, &channel_id, &client.node_id);
+			assert_eq!(lsp.liquidity_manager.total_fees_earned_msat(), expected_total_msat);
+
+			lsp.liquidity_manager.payment_forwarded(Some(channel_id));
+			expected_total_msat += opening_fee_msat;
+			assert_eq!(lsp.liquidity_manager.total_fees_earned_msat(), expected_total_msat);
+		}
+		assert_eq!(lsp.liquidity_manager.total_fees_earned_msat(), 100 + 210);
+
+		// Later forwards over the same channels or over unrelated channels don't count.
+		lsp.liquidity_manager.payment_forwarded(Some(ChannelId([42; 32])));
+		lsp.liquidity_manager.payment_forwarded(Some(ChannelId([7; 32])));
+		lsp.liquidity_manager.payment_forwarded(None);
+		assert_eq!(lsp.liquidity_manager.total_fees_earned_msat(), expected_total_msat);
+	}
+
+	#[test]
+	fn underfunded_htlc_is_failed_instead_of_opening_channel() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		// With an opening fee of 100 msat, we'd have to forward 999_900 msat.
+		assert!(lsp
+			.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 999_899, 1_000_000)
+			.is_err());
+		match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::InsufficientInboundAmount {
+				counterparty_node_id,
+				user_channel_id,
+				inbound_amount_msat,
+				amt_to_forward_msat,
+				..
+			})] => {
+				assert_eq!(*counterparty_node_id, client.node_id);
+				assert_eq!(*user_channel_id, scid as u128);
+				assert_eq!(*inbound_amount_msat, 999_899);
+				assert_eq!(*amt_to_forward_msat, 999_900);
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::Failed)
+		);
+		assert!(lsp.liquidity_manager.outstanding_scids().is_empty());
+	}
+
+	#[test]
+	fn late_htlc_is_forwarded_over_ready_channel() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert_eq!(get_events(&lsp).len(), 1);
+		let channel_id = ChannelId([1; 32]);
+		let _ = lsp.liquidity_manager.channel_ready(scid as u128, &channel_id, &client.node_id);
+
+		// Forwarding fails as no actual channel exists in the test, but it is attempted over the
+		// ready channel rather than opening another one.
+		let result =
+			lsp.liquidity_manager.htlc_intercepted(scid, InterceptId([1; 32]), 500_000, 500_000);
+		assert!(matches!(result, Err(APIError::ChannelUnavailable { .. })));
+		assert!(get_events(&lsp).is_empty());
+		assert!(lsp.logger.lines.lock().unwrap().iter().any(|(_, line)| line
+			== &format!("Forwarding late HTLC for scid {} over JIT channel {}", scid, channel_id)));
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::Ready)
+		);
+	}
+
+	#[test]
+	fn late_htlc_after_grace_period_is_failed() {
+		let mut config = default_jit_channels_config();
+		config.late_htlc_grace_period = Duration::from_secs(0);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		lsp.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([0; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert_eq!(get_events(&lsp).len(), 1);
+		let _ =
+			lsp.liquidity_manager.channel_ready(scid as u128, &ChannelId([1; 32]), &client.node_id);
+
+		assert!(lsp
+			.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([1; 32]), 500_000, 500_000)
+			.is_err());
+		assert!(get_events(&lsp).is_empty());
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::Ready)
+		);
+	}
+
+	fn respond_with_menu(lsp: &Node, client: &Node, min_fee_msat: u64) {
+		let request_id = match get_events(lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(min_fee_msat, 21)],
+			)
+			.unwrap();
+		assert_eq!(pass_messages(lsp, client), 1);
+	}
+
+	#[test]
+	fn lsp_for_channel_returns_lsp_of_negotiation() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let first_lsp = create_node(2, Some(default_jit_channels_config()));
+		let second_lsp = create_node(3, Some(default_jit_channels_config()));
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(1), None);
+
+		buy_jit_channel(&client, &first_lsp, None, 1, 42);
+		request_opening_fee_params_menu(
+			&client,
+			&second_lsp,
+			None,
+			2,
+			vec![raw_opening_fee_params(100, 21)],
+		);
+
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(1), Some(first_lsp.node_id));
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(2), Some(second_lsp.node_id));
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(3), None);
+
+		// The LSP is forgotten once the negotiation ended, here as the LSP disconnected before
+		// responding to the `get_info` request.
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(second_lsp.node_id, None, None, 3, None)
+			.unwrap();
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(3), Some(second_lsp.node_id));
+		client.liquidity_manager.peer_disconnected(&second_lsp.node_id);
+		assert_eq!(client.liquidity_manager.jit_channel_state(3), Some(JitChannelState::Failed));
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(3), None);
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(2), Some(second_lsp.node_id));
+	}
+
+	#[test]
+	fn negotiated_version_survives_persistence() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		);
+
+		let mut encoded = Vec::new();
+		client.liquidity_manager.write_negotiated_versions(&mut encoded).unwrap();
+
+		let restored_client = create_node(1, Some(default_jit_channels_config()));
+		restored_client.liquidity_manager.restore_negotiated_versions(&mut &encoded[..]).unwrap();
+		restored_client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 2, None)
+			.unwrap();
+
+		// The get_info request is sent right away, skipping get_versions.
+		let msgs = restored_client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		assert!(msgs[0].1.payload.contains(r#""method":"lsps2.get_info""#));
+		assert!(msgs[0].1.payload.contains(r#""version":1"#));
+	}
+
+	#[test]
+	fn replayed_intercept_does_not_open_another_channel() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		let intercept_id = InterceptId([42; 32]);
+		lsp.liquidity_manager.htlc_intercepted(scid, intercept_id, 1_000_000, 1_000_000).unwrap();
+		match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::OpenChannel { .. })] => {}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+
+		// Replaying the event neither opens another channel nor fails the pending one.
+		lsp.liquidity_manager.htlc_intercepted(scid, intercept_id, 1_000_000, 1_000_000).unwrap();
+		assert!(get_events(&lsp).is_empty());
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::OpeningChannel)
+		);
+
+		let mut encoded = Vec::new();
+		lsp.liquidity_manager.write_processed_intercepts(&mut encoded).unwrap();
+
+		let restored_lsp = create_node(2, Some(default_jit_channels_config()));
+		restored_lsp.liquidity_manager.restore_processed_intercepts(&mut &encoded[..]).unwrap();
+		buy_jit_channel(&client, &restored_lsp, None, 2, scid);
+
+		restored_lsp
+			.liquidity_manager
+			.htlc_intercepted(scid, intercept_id, 1_000_000, 1_000_000)
+			.unwrap();
+		assert!(get_events(&restored_lsp).is_empty());
+		assert_eq!(
+			restored_lsp.liquidity_manager.jit_channel_state_by_scid(scid),
+			Some(JitChannelState::AwaitingPayment)
+		);
+
+		// HTLCs we didn't process yet are handled as usual.
+		restored_lsp
+			.liquidity_manager
+			.htlc_intercepted(scid, InterceptId([43; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		match get_events(&restored_lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::OpenChannel { .. })] => {}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+	}
+
+	#[test]
+	fn create_invoice_multi_selects_cheapest_lsp() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let expensive_lsp = create_node(2, Some(default_jit_channels_config()));
+		let cheap_lsp = create_node(3, Some(default_jit_channels_config()));
+		let lsps = [&expensive_lsp, &cheap_lsp];
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice_multi(
+				vec![expensive_lsp.node_id, cheap_lsp.node_id],
+				Some(1_000_000),
+				7,
+			)
+			.unwrap();
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(7), None);
+
+		// get_versions requests and responses.
+		assert_eq!(pass_messages_to_any(&client, &lsps), 2);
+		assert_eq!(pass_messages(&expensive_lsp, &client), 1);
+		assert_eq!(pass_messages(&cheap_lsp, &client), 1);
+
+		// get_info requests and responses.
+		assert_eq!(pass_messages_to_any(&client, &lsps), 2);
+		respond_with_menu(&expensive_lsp, &client, 2_000);
+		assert!(get_events(&client).is_empty());
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		respond_with_menu(&cheap_lsp, &client, 1_000);
+
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::LspSelected {
+				counterparty_node_id,
+				opening_fee_params,
+				user_channel_id,
+			})) => {
+				assert_eq!(counterparty_node_id, cheap_lsp.node_id);
+				assert_eq!(opening_fee_params.min_fee_msat, 1_000);
+				assert_eq!(user_channel_id, 7);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert_eq!(client.liquidity_manager.lsp_for_jit_channel(7), Some(cheap_lsp.node_id));
+
+		// Only the selected LSP receives a buy request.
+		assert_eq!(pass_messages(&client, &cheap_lsp), 1);
+		match get_events(&cheap_lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { payment_size_msat, .. })) => {
+				assert_eq!(payment_size_msat, Some(1_000_000));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn create_invoice_multi_selects_among_received_menus_on_timeout() {
+		let config = default_jit_channels_config();
+		let lsp_selection_timeout = config.lsp_selection_timeout;
+		let client = create_node(1, Some(config));
+		let responsive_lsp = create_node(2, Some(default_jit_channels_config()));
+		let silent_lsp = create_node(3, Some(default_jit_channels_config()));
+		let lsps = [&responsive_lsp, &silent_lsp];
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice_multi(
+				vec![responsive_lsp.node_id, silent_lsp.node_id],
+				None,
+				7,
+			)
+			.unwrap();
+		assert_eq!(pass_messages_to_any(&client, &lsps), 2);
+		assert_eq!(pass_messages(&responsive_lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &responsive_lsp), 1);
+		respond_with_menu(&responsive_lsp, &client, 1_000);
+		assert!(get_events(&client).is_empty());
+
+		// The silent LSP is still waited for until the selection times out.
+		client.liquidity_manager.timer_tick_occurred();
+		assert!(get_events(&client).is_empty());
+
+		client.advance_time_and_blocks(6, lsp_selection_timeout);
+		client.liquidity_manager.timer_tick_occurred();
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::LspSelected { counterparty_node_id, .. })) => {
+				assert_eq!(counterparty_node_id, responsive_lsp.node_id);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+	fn expect_negotiation_failed(
+		client: &Node, lsp: &Node, user_channel_id: u128,
+	) -> FailureReason {
+		match get_events(client).pop() {
+			Some(Event::LSPS2(event @ LSPS2Event::NegotiationFailed { .. })) => {
+				let decoded: LSPS2Event = Readable::read(&mut &event.encode()[..]).unwrap();
+				assert_eq!(decoded, event);
+				match event {
+					LSPS2Event::NegotiationFailed {
+						counterparty_node_id,
+						user_channel_id: failed_user_channel_id,
+						reason,
+						..
+					} => {
+						assert_eq!(counterparty_node_id, lsp.node_id);
+						assert_eq!(failed_user_channel_id, user_channel_id);
+						reason
+					}
+					_ => unreachable!(),
+				}
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn error_response_is_classified_as_protocol_failure() {
+		let mut config = default_jit_channels_config();
+		config.reject_requests_with_token = true;
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, Some("coupon".to_string()), 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		let (_, request) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(request, &client.node_id).is_err());
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		match expect_negotiation_failed(&client, &lsp, 1) {
+			FailureReason::Protocol(error) => {
+				assert_eq!(
+					error.code,
+					LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE
+				);
+			}
+			reason => panic!("Unexpected reason: {:?}", reason),
+		}
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+	}
+
+	#[test]
+	fn disconnect_is_classified_as_transport_failure() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+		assert_eq!(expect_negotiation_failed(&client, &lsp, 1), FailureReason::Transport);
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+		assert_eq!(client.liquidity_manager.resend_pending_requests(&lsp.node_id), 0);
+	}
+
+	#[test]
+	fn lost_get_versions_request_is_retried_once_lsp_reconnected() {
+		let mut config = default_jit_channels_config();
+		config.max_transport_retries = 2;
+		let backoff = config.transport_retry_backoff;
+		let client = create_node(1, Some(config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+
+		// The get_versions request is lost as the LSP disconnects.
+		assert_eq!(client.liquidity_manager.get_and_clear_pending_msg().len(), 1);
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+		assert!(get_events(&client).is_empty());
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(1),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
+
+		// It is only retried once the backoff passed.
+		client.liquidity_manager.peer_connected(&lsp.node_id, &InitFeatures::empty());
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		client.advance_time_and_blocks(0, backoff);
+		client.liquidity_manager.timer_tick_occurred();
+
+		// The negotiation proceeds as usual.
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { counterparty_node_id, .. })) => {
+				assert_eq!(counterparty_node_id, client.node_id);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn lost_get_versions_request_fails_once_retries_are_exhausted() {
+		let mut config = default_jit_channels_config();
+		config.max_transport_retries = 2;
+		let backoff = config.transport_retry_backoff;
+		let client = create_node(1, Some(config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(client.liquidity_manager.get_and_clear_pending_msg().len(), 1);
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+
+		// The LSP stays disconnected during both retries, with the backoff doubling in between.
+		client.advance_time_and_blocks(0, backoff);
+		client.liquidity_manager.timer_tick_occurred();
+		client.advance_time_and_blocks(0, backoff);
+		client.liquidity_manager.timer_tick_occurred();
+		assert!(get_events(&client).is_empty());
+		client.advance_time_and_blocks(0, backoff);
+		client.liquidity_manager.timer_tick_occurred();
+
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert_eq!(expect_negotiation_failed(&client, &lsp, 1), FailureReason::Transport);
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Failed));
+	}
+
+	#[test]
+	fn unanswered_lsp_selection_is_classified_as_timeout_failure() {
+		let config = default_jit_channels_config();
+		let lsp_selection_timeout = config.lsp_selection_timeout;
+		let client = create_node(1, Some(config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice_multi(vec![lsp.node_id], None, 7)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		client.advance_time_and_blocks(0, lsp_selection_timeout);
+		client.liquidity_manager.timer_tick_occurred();
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::LspSelectionFailed { user_channel_id, reason })) => {
+				assert_eq!(user_channel_id, 7);
+				assert_eq!(reason, Some(FailureReason::Timeout));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn resend_pending_requeues_requests_lost_to_a_disconnect() {
+		let mut config = default_jit_channels_config();
+		config.max_transport_retries = 1;
+		let backoff = config.transport_retry_backoff;
+		let client = create_node(1, Some(config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+
+		// Still queued requests are not duplicated.
+		assert_eq!(client.liquidity_manager.resend_pending_requests(&lsp.node_id), 0);
+
+		// The request is handed out but lost as the peer disconnected.
+		let lost_msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(lost_msgs.len(), 1);
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+		assert_eq!(client.liquidity_manager.resend_pending_requests(&lsp.node_id), 0);
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		client.liquidity_manager.peer_connected(&lsp.node_id, &InitFeatures::empty());
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert_eq!(client.liquidity_manager.resend_pending_requests(&lsp.node_id), 1);
+		let resent_msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(resent_msgs, lost_msgs);
+
+		// The re-sent request isn't sent once more when its scheduled retry would be due.
+		client.advance_time_and_blocks(0, backoff);
+		client.liquidity_manager.timer_tick_occurred();
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		// Once responded to, the request is no longer re-sent.
+		for (_, msg) in resent_msgs {
+			lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+		}
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(client.liquidity_manager.get_and_clear_pending_msg().len(), 1);
+		assert_eq!(client.liquidity_manager.resend_pending_requests(&lsp.node_id), 1);
+		match client.liquidity_manager.get_and_clear_pending_msg().as_slice() {
+			[(node_id, msg)] => {
+				assert_eq!(*node_id, lsp.node_id);
+				assert!(msg.payload.contains("lsps2.get_info"));
+			}
+			msgs => panic!("Unexpected messages: {:?}", msgs),
+		}
+	}
+
+	#[test]
+	fn debug_snapshot_reflects_negotiations_and_redacts_secrets() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, Some("coupon".to_string()), 2, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let client_snapshot = client.liquidity_manager.debug_snapshot();
+		assert_eq!(client_snapshot.peers.len(), 1);
+		let peer = &client_snapshot.peers[0];
+		assert_eq!(peer.counterparty_node_id, lsp.node_id.to_string());
+		assert_eq!(peer.negotiated_version, Some(1));
+		assert_eq!(peer.sent_requests.len(), 1);
+		assert_eq!(peer.sent_requests[0].method, "lsps2.get_info");
+		let inbound_channels: Vec<(u128, Option<u64>)> = peer
+			.inbound_channels
+			.iter()
+			.map(|channel| (channel.user_channel_id, channel.short_channel_id))
+			.collect();
+		assert_eq!(inbound_channels, vec![(1, Some(42)), (2, None)]);
+
+		let lsp_snapshot = lsp.liquidity_manager.debug_snapshot();
+		assert_eq!(lsp_snapshot.peers.len(), 1);
+		let peer = &lsp_snapshot.peers[0];
+		assert_eq!(peer.counterparty_node_id, client.node_id.to_string());
+		assert_eq!(peer.received_requests.len(), 1);
+		assert_eq!(peer.received_requests[0].method, "lsps2.get_info");
+		assert_eq!(peer.outbound_channels.len(), 1);
+		assert_eq!(peer.outbound_channels[0].short_channel_id, Some(42));
+
+		let promise_secret: String =
+			[42u8; 32].iter().map(|byte| format!("{:02x}", byte)).collect();
+		for snapshot in [client_snapshot, lsp_snapshot].iter() {
+			let json = serde_json::to_string(snapshot).unwrap();
+			assert!(!json.contains("coupon"));
+			assert!(!json.contains(&promise_secret));
+		}
+	}
+
+	fn client_trusts_lsp_with_flag_absent(assume_client_trusts_lsp_when_absent: bool) -> bool {
+		let mut client_config = default_jit_channels_config();
+		client_config.assume_client_trusts_lsp_when_absent = assume_client_trusts_lsp_when_absent;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		let buy_response = RawLSPSMessage {
+			payload: format!(
+				r#"{{"jsonrpc":"2.0","id":"{}","result":{{"jit_channel_scid":"0x0x42","lsp_cltv_expiry_delta":144}}}}"#,
+				request_id.as_str()
+			),
+		};
+		client.liquidity_manager.handle_custom_message(buy_response, &lsp.node_id).unwrap();
+
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::InvoiceGenerationReady {
+				client_trusts_lsp, ..
+			})) => client_trusts_lsp,
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn absent_client_trusts_lsp_flag_resolves_to_configured_default() {
+		assert!(!client_trusts_lsp_with_flag_absent(false));
+		assert!(client_trusts_lsp_with_flag_absent(true));
+	}
+
+	#[test]
+	fn correlation_id_spans_whole_negotiation() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		lsp.scid_allocator.queue_scid(42);
+
+		let (jit_channel_id, opening_fee_params, get_info_correlation_id) =
+			match request_opening_fee_params_menu(
+				&client,
+				&lsp,
+				None,
+				1,
+				vec![raw_opening_fee_params(100, 21)],
+			) {
+				LSPS2Event::GetInfoResponse {
+					jit_channel_id,
+					mut opening_fee_params_menu,
+					correlation_id,
+					..
+				} => (jit_channel_id, opening_fee_params_menu.remove(0), correlation_id),
+				event => panic!("Unexpected event: {:?}", event),
+			};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, 42, 144, false)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::InvoiceGenerationReady { correlation_id, .. })) => {
+				assert_eq!(correlation_id, get_info_correlation_id)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		let correlation_id = format!("{:032x}", get_info_correlation_id);
+		assert!(
+			client
+				.logger
+				.lines
+				.lock()
+				.unwrap()
+				.iter()
+				.filter(|(_, line)| line.contains(&correlation_id))
+				.count() >= 3
+		);
+
+		// Another negotiation is correlated by a different id.
+		match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			2,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { correlation_id, .. } => {
+				assert_ne!(correlation_id, get_info_correlation_id)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn verbose_buy_events_are_generated_in_order() {
+		let mut lsp_config = default_jit_channels_config();
+		lsp_config.verbose_buy_events = true;
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(lsp_config));
+		lsp.scid_allocator.queue_scid(42);
+
+		let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let request_id = match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::BuyRequestReceived { request_id: received_id, .. }), Event::LSPS2(LSPS2Event::BuyRequestValidated { request_id: validated_id, .. }), Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })] =>
+			{
+				assert_eq!(received_id, request_id);
+				assert_eq!(validated_id, request_id);
+				request_id.clone()
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		};
+
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id.clone(), 42, 144, false)
+			.unwrap();
+		match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::BuyResponseSent {
+				request_id: sent_id,
+				counterparty_node_id,
+				scid,
+			})] => {
+				assert_eq!(*sent_id, request_id);
+				assert_eq!(*counterparty_node_id, client.node_id);
+				assert_eq!(*scid, 42);
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn identical_buy_requests_are_served_by_a_single_jit_channel() {
+		let scid_allocator =
+			Arc::new(LimitedScidAllocator { available_scids: Mutex::new(vec![43, 42]) });
+		let mut config = default_jit_channels_config();
+		config.scid_allocator =
+			Some(Arc::clone(&scid_allocator) as Arc<dyn ScidAllocator + Send + Sync>);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+
+		let opening_fee_params =
+			raw_opening_fee_params(100, 21).into_opening_fee_params(&[42; 32], PromiseVersion::V1);
+		let buy = |request_id: &str| {
+			let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+				RequestId::new(request_id.to_string()).unwrap(),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: opening_fee_params.clone(),
+					payment_size_msat: None,
+					refund_onchain_address: None,
+					announce_channel: None,
+				}),
+			));
+			let msg = RawLSPSMessage { payload: serde_json::to_string(&request).unwrap() };
+			lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+		};
+		let responses = || -> Vec<(String, String)> {
+			lsp.liquidity_manager
+				.get_and_clear_pending_msg()
+				.into_iter()
+				.map(|(_, msg)| {
+					let response: serde_json::Value = serde_json::from_str(&msg.payload).unwrap();
+					(
+						response["id"].as_str().unwrap().to_string(),
+						response["result"]["jit_channel_scid"].as_str().unwrap().to_string(),
+					)
+				})
+				.collect()
+		};
+
+		buy("a");
+		buy("b");
+		let request_id = match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::BuyRequest { request_id, scid, .. })] => {
+				assert_eq!(*scid, 42);
+				request_id.clone()
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		};
+		assert!(responses().is_empty());
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, 42, 144, false)
+			.unwrap();
+		let mut answered = responses();
+		answered.sort();
+		assert_eq!(
+			answered,
+			vec![("a".to_string(), "0x0x42".to_string()), ("b".to_string(), "0x0x42".to_string())]
+		);
+
+		assert_eq!(*scid_allocator.available_scids.lock().unwrap(), vec![43]);
+		let snapshot = lsp.liquidity_manager.debug_snapshot();
+		assert_eq!(snapshot.peers[0].outbound_channels.len(), 1);
+	}
+
+	#[test]
+	fn shutdown_fails_back_htlcs_of_unopened_jit_channels() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+		buy_jit_channel(&client, &lsp, None, 2, 43);
+
+		lsp.liquidity_manager
+			.htlc_intercepted(42, InterceptId([42; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::OpenChannel { user_channel_id, .. })) => {
+				assert_eq!(user_channel_id, 42)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		lsp.liquidity_manager.shutdown();
+		match get_events(&lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::InterceptedHtlcFailedOnShutdown {
+				counterparty_node_id,
+				user_channel_id,
+				intercept_id,
+			})] => {
+				assert_eq!(*counterparty_node_id, client.node_id);
+				assert_eq!(*user_channel_id, 42);
+				assert_eq!(*intercept_id, InterceptId([42; 32]));
+			}
+			events => panic!("Unexpected events: {:?}", events),
+		}
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::Failed)
+		);
+
+		// JIT channels without an intercepted HTLC are left alone.
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(43),
+			Some(JitChannelState::AwaitingPayment)
+		);
+		assert_eq!(lsp.liquidity_manager.outstanding_scids(), vec![(43, client.node_id, 43)]);
+	}
+
+	#[test]
+	fn peers_with_in_flight_negotiations_are_listed_as_active() {
+		let client_a = create_node(1, Some(default_jit_channels_config()));
+		let client_b = create_node(2, Some(default_jit_channels_config()));
+		let lsp = create_node(3, Some(default_jit_channels_config()));
+		assert_eq!(lsp.liquidity_manager.active_peer_count(), 0);
+
+		buy_jit_channel(&client_a, &lsp, None, 1, 42);
+		client_b
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client_b, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client_b), 1);
+		assert_eq!(pass_messages(&client_b, &lsp), 1);
+
+		let mut expected_peers = vec![client_a.node_id, client_b.node_id];
+		expected_peers.sort_unstable();
+		assert_eq!(lsp.liquidity_manager.active_peer_count(), 2);
+		assert_eq!(lsp.liquidity_manager.active_peers(), expected_peers);
+		assert_eq!(client_b.liquidity_manager.active_peers(), vec![lsp.node_id]);
+
+		// Once its JIT channel is open, a peer no longer counts as active.
+		lsp.liquidity_manager
+			.htlc_intercepted(42, InterceptId([42; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		let channel_id = ChannelId([42; 32]);
+		let _ = lsp.liquidity_manager.channel_ready(42, &channel_id, &client_a.node_id);
+		assert_eq!(lsp.liquidity_manager.active_peers(), vec![client_b.node_id]);
+		assert_eq!(lsp.liquidity_manager.active_peer_count(), 1);
+	}
+
+	#[test]
+	fn channel_opens_beyond_limit_are_deferred() {
+		let mut config = default_jit_channels_config();
+		config.max_pending_channel_opens = Some(1);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		let scids = [42, 43, 44, 45, 46, 47];
+		for (user_channel_id, scid) in scids.iter().enumerate() {
+			buy_jit_channel(&client, &lsp, None, user_channel_id as u128, *scid);
+		}
+
+		let intercept_htlc = |scid: u64| {
+			lsp.liquidity_manager
+				.htlc_intercepted(scid, InterceptId([scid as u8; 32]), 1_000_000, 1_000_000)
+				.unwrap();
+		};
+		for scid in &scids[..4] {
+			intercept_htlc(*scid);
+		}
+
+		let opened_channel = |lsp: &Node| match get_events(lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::OpenChannel { user_channel_id, .. })] => *user_channel_id,
+			events => panic!("Unexpected events: {:?}", events),
+		};
+		assert_eq!(opened_channel(&lsp), 42);
+		assert!(get_events(&lsp).is_empty());
+
+		// A failed open releases the next deferred one.
+		lsp.liquidity_manager.channel_open_failed(&client.node_id, 42).unwrap();
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::Failed)
+		);
+		assert!(lsp.liquidity_manager.channel_open_failed(&client.node_id, 42).is_err());
+		assert_eq!(opened_channel(&lsp), 43);
+
+		// A deferred open whose HTLC expired meanwhile is dropped.
+		lsp.liquidity_manager
+			.htlc_handling_failed(HTLCDestination::InvalidForward { requested_forward_scid: 44 });
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(44),
+			Some(JitChannelState::Failed)
+		);
+		assert!(get_events(&lsp).is_empty());
+
+		// Forwarding the intercepted HTLC fails as no actual channel exists in the test.
+		let _ = lsp.liquidity_manager.channel_ready(43, &ChannelId([43; 32]), &client.node_id);
+		assert_eq!(opened_channel(&lsp), 45);
+
+		// The client disconnecting abandons the deferred channel open, but not the requested one.
+		intercept_htlc(46);
+		assert!(get_events(&lsp).is_empty());
+		lsp.liquidity_manager.peer_disconnected(&client.node_id);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(45),
+			Some(JitChannelState::OpeningChannel)
+		);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(46),
+			Some(JitChannelState::Failed)
+		);
+		lsp.liquidity_manager.peer_connected(&client.node_id, &InitFeatures::empty());
+
+		let _ = lsp.liquidity_manager.channel_ready(45, &ChannelId([45; 32]), &client.node_id);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(45),
+			Some(JitChannelState::Ready)
+		);
+		assert!(get_events(&lsp).is_empty());
+
+		intercept_htlc(47);
+		assert_eq!(opened_channel(&lsp), 47);
+		assert!(get_events(&lsp).is_empty());
+	}
+
+	#[test]
+	fn second_htlc_for_channel_being_opened_is_rejected_alone() {
+		let mut config = default_jit_channels_config();
+		config.max_pending_channel_opens = Some(1);
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(config));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+		buy_jit_channel(&client, &lsp, None, 2, 43);
+
+		let opened_channel = |lsp: &Node| match get_events(lsp).as_slice() {
+			[Event::LSPS2(LSPS2Event::OpenChannel { user_channel_id, .. })] => *user_channel_id,
+			events => panic!("Unexpected events: {:?}", events),
+		};
+		lsp.liquidity_manager
+			.htlc_intercepted(42, InterceptId([1; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert_eq!(opened_channel(&lsp), 42);
+
+		// Failing back the second HTLC errors as no actual HTLC exists in the test.
+		assert!(lsp
+			.liquidity_manager
+			.htlc_intercepted(42, InterceptId([2; 32]), 1_000_000, 1_000_000)
+			.is_err());
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::OpeningChannel)
+		);
+
+		lsp.liquidity_manager
+			.htlc_intercepted(43, InterceptId([3; 32]), 1_000_000, 1_000_000)
+			.unwrap();
+		assert!(get_events(&lsp).is_empty());
+
+		// The channel is still found for the first HTLC, releasing the next channel open.
+		let _ = lsp.liquidity_manager.channel_ready(42, &ChannelId([42; 32]), &client.node_id);
+		assert_eq!(
+			lsp.liquidity_manager.jit_channel_state_by_scid(42),
+			Some(JitChannelState::Ready)
+		);
+		assert_eq!(opened_channel(&lsp), 43);
+	}
+
+	#[test]
+	fn open_channel_event_carries_capacity_and_fee() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+		buy_jit_channel(&client, &lsp, None, 2, 43);
+
+		let open_channel = |scid: u64, expected_outbound_amount_msat| {
+			lsp.liquidity_manager
+				.htlc_intercepted(
+					scid,
+					InterceptId([scid as u8; 32]),
+					expected_outbound_amount_msat,
+					expected_outbound_amount_msat,
+				)
+				.unwrap();
+			match get_events(&lsp).pop() {
+				Some(Event::LSPS2(LSPS2Event::OpenChannel {
+					opening_fee_msat,
+					amt_to_forward_msat,
+					channel_capacity_sat,
+					..
+				})) => (opening_fee_msat, amt_to_forward_msat, channel_capacity_sat),
+				event => panic!("Unexpected event: {:?}", event),
+			}
+		};
+
+		// With the exact sizing strategy, the channel fits the payment, rounded up to full sats.
+		assert_eq!(open_channel(42, 1_000_500), (100, 1_000_400, 1_001));
+
+		lsp.liquidity_manager
+			.set_peer_channel_sizing_strategy(
+				client.node_id,
+				Some(ChannelSizingStrategy::Additional { liquidity_msat: 1_000_000_000 }),
+			)
+			.unwrap();
+		// 21 ppm of 10_000_000_000 msat are 210_000 msat.
+		assert_eq!(open_channel(43, 10_000_000_000), (210_000, 9_999_790_000, 11_000_000));
+	}
+
+	fn generate_menu_with_free_channel(lsp_config: JITChannelsConfig) -> Result<(), APIError> {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(lsp_config));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		lsp.liquidity_manager.opening_fee_params_generated(
+			client.node_id,
+			request_id,
+			vec![raw_opening_fee_params(100, 21), raw_opening_fee_params(0, 0)],
+		)
+	}
+
+	#[test]
+	fn free_channels_are_rejected_by_default() {
+		match generate_menu_with_free_channel(default_jit_channels_config()) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("without any fee")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+	}
+
+	#[test]
+	fn free_channels_are_offered_if_allowed() {
+		let mut lsp_config = default_jit_channels_config();
+		lsp_config.allow_free_channels = true;
+		generate_menu_with_free_channel(lsp_config).unwrap();
+	}
+
+	#[test]
+	fn buy_request_carries_token_of_originating_get_info_request() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let token = Token::new("gold-tier".to_string());
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, Some("gold-tier".to_string()), 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo {
+				request_id,
+				token: Some(get_info_token),
+				..
+			})) => {
+				assert_eq!(get_info_token, token);
+				request_id
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(100, 21)],
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		let (jit_channel_id, opening_fee_params) = match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfoResponse {
+				jit_channel_id,
+				mut opening_fee_params_menu,
+				..
+			})) => (jit_channel_id, opening_fee_params_menu.remove(0)),
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { token: buy_token, .. })) => {
+				assert_eq!(buy_token, Some(token));
+			}
+			event => panic!("Unexpected event: {:?}", event),
 		}
 	}
 }
@@ -0,0 +1,72 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Human-readable descriptions of the LSPS2 error codes.
+
+use crate::jit_channel::msgs::{
+	LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+	LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+	LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+};
+
+/// Returns a human-readable description of the given LSPS2 error code, e.g., to be displayed to a
+/// user when a request was rejected by the LSP.
+///
+/// Unknown codes map to a generic description.
+pub fn lsps2_error_message(code: i32) -> &'static str {
+	match code {
+		LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE => {
+			"The LSP does not support the requested protocol version"
+		}
+		LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE => {
+			"The opening fee parameters expired or were not issued by the LSP"
+		}
+		LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE => {
+			"The payment size is too small for the LSP to open a channel"
+		}
+		LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE => {
+			"The payment size is too large for the LSP to open a channel"
+		}
+		_ => "The LSP rejected the request for an unknown reason",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::collections::HashSet;
+
+	#[test]
+	fn known_error_codes_map_to_distinct_messages() {
+		let codes = [
+			LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+			LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+			LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+			LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+		];
+		let generic_message = lsps2_error_message(0);
+
+		let messages: HashSet<&str> = codes.iter().map(|code| lsps2_error_message(*code)).collect();
+		assert_eq!(messages.len(), codes.len());
+		for message in messages {
+			assert!(!message.is_empty());
+			assert_ne!(message, generic_message);
+		}
+	}
+
+	#[test]
+	fn unknown_error_codes_map_to_generic_message() {
+		let generic_message = lsps2_error_message(0);
+		assert!(!generic_message.is_empty());
+		assert_eq!(lsps2_error_message(-32700), generic_message);
+		assert_eq!(lsps2_error_message(i32::MAX), generic_message);
+	}
+}
@@ -8,9 +8,52 @@
 // licenses.
 
 use bitcoin::secp256k1::PublicKey;
+use chrono::Utc;
+use lightning::io;
+use lightning::ln::channelmanager::InterceptId;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::config::ChannelConfig;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
 
-use super::msgs::OpeningFeeParams;
-use crate::transport::msgs::RequestId;
+use super::msgs::{read_datetime, OpeningFeeParams, Token};
+use crate::transport::msgs::{RequestId, ResponseError};
+
+/// Why negotiating a JIT channel with an LSP failed, allowing to decide whether to retry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FailureReason {
+	/// The LSP disconnected before responding. Retrying once it reconnected may succeed.
+	Transport,
+	/// The LSP rejected our request with the given error.
+	Protocol(ResponseError),
+	/// The LSP didn't respond in time.
+	Timeout,
+}
+
+impl Writeable for FailureReason {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match self {
+			FailureReason::Transport => 0u8.write(writer),
+			FailureReason::Protocol(error) => {
+				2u8.write(writer)?;
+				error.write(writer)
+			}
+			FailureReason::Timeout => 4u8.write(writer),
+		}
+	}
+}
+
+impl Readable for FailureReason {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let id: u8 = Readable::read(reader)?;
+		match id {
+			0 => Ok(FailureReason::Transport),
+			2 => Ok(FailureReason::Protocol(Readable::read(reader)?)),
+			4 => Ok(FailureReason::Timeout),
+			_ => Err(DecodeError::UnknownRequiredFeature),
+		}
+	}
+}
 
 /// An event which you should probably take some action in response to.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -31,7 +74,7 @@ pub enum LSPS2Event {
 		/// The protocol version they would like to use.
 		version: u16,
 		/// An optional token that can be used as an API key, coupon code, etc.
-		token: Option<String>,
+		token: Option<Token>,
 	},
 	/// Information from the LSP about their current fee rates and channel parameters.
 	///
@@ -59,6 +102,16 @@ pub enum LSPS2Event {
 		///
 		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
 		user_channel_id: u128,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
+		/// An identifier for the negotiation started via
+		/// [`LiquidityManager::jit_channel_create_invoice`], which stays the same across all of its
+		/// events and log lines, unlike the ids of the individual requests.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		correlation_id: u128,
 	},
 	/// A client has selected a opening fee parameter to use and would like to
 	/// purchase a channel with an optional initial payment size.
@@ -83,6 +136,23 @@ pub enum LSPS2Event {
 		opening_fee_params: OpeningFeeParams,
 		/// The size of the initial payment they would like to receive.
 		payment_size_msat: Option<u64>,
+		/// An on-chain address the client would like to be refunded to if the channel can't be
+		/// opened.
+		refund_onchain_address: Option<String>,
+		/// Whether they would like the channel to be announced, if they have a preference.
+		announce_channel: Option<bool>,
+		/// The intercept scid allocated for this request, see [`JITChannelsConfig::scid_allocator`].
+		///
+		/// Must be passed to [`LiquidityManager::invoice_parameters_generated`].
+		///
+		/// [`JITChannelsConfig::scid_allocator`]: crate::JITChannelsConfig::scid_allocator
+		/// [`LiquidityManager::invoice_parameters_generated`]: crate::LiquidityManager::invoice_parameters_generated
+		scid: u64,
+		/// The token the client supplied with the `get_info` request the selected opening fee
+		/// params were generated for, if any.
+		///
+		/// Allows to honor the terms the token entitles the client to, e.g., a pricing tier.
+		token: Option<Token>,
 	},
 	/// Use the provided fields to generate an invoice and give to payer.
 	///
@@ -103,6 +173,40 @@ pub enum LSPS2Event {
 		///
 		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
 		user_channel_id: u128,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
+		/// The `correlation_id` of the negotiation, as given in the [`Self::GetInfoResponse`] event.
+		correlation_id: u128,
+	},
+	/// The LSP expects you to trust it although [`JITChannelsConfig::require_lsp_trusts_client`]
+	/// is set.
+	///
+	/// The LSP may claim the payment before broadcasting the funding transaction. To proceed
+	/// regardless, generate an invoice with the provided fields as for
+	/// [`Self::InvoiceGenerationReady`]. To abort, simply don't.
+	///
+	/// [`JITChannelsConfig::require_lsp_trusts_client`]: crate::JITChannelsConfig::require_lsp_trusts_client
+	TrustModelMismatch {
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The short channel id to use in the route hint.
+		scid: u64,
+		/// The `cltv_expiry_delta` to use in the route hint.
+		cltv_expiry_delta: u32,
+		/// The initial payment size you specified.
+		payment_size_msat: Option<u64>,
+		/// The `user_channel_id` value passed in to [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
+		/// The `correlation_id` of the negotiation, as given in the [`Self::GetInfoResponse`] event.
+		correlation_id: u128,
 	},
 	/// You should open a channel using [`ChannelManager::create_channel`].
 	///
@@ -118,7 +222,747 @@ pub enum LSPS2Event {
 		amt_to_forward_msat: u64,
 		/// The fee earned for opening the channel.
 		opening_fee_msat: u64,
+		/// The capacity to open the channel with, as determined by the
+		/// [`JITChannelsConfig::channel_sizing_strategy`] or the strategy set for the client via
+		/// [`LiquidityManager::set_peer_channel_sizing_strategy`].
+		///
+		/// [`JITChannelsConfig::channel_sizing_strategy`]: crate::JITChannelsConfig::channel_sizing_strategy
+		/// [`LiquidityManager::set_peer_channel_sizing_strategy`]: crate::LiquidityManager::set_peer_channel_sizing_strategy
+		channel_capacity_sat: u64,
 		/// An internal id used to track channel open.
 		user_channel_id: u128,
+		/// Whether the client would like the channel to be announced, if they have a preference.
+		///
+		/// Should be reflected in [`ChannelHandshakeConfig::announced_channel`] when opening the
+		/// channel.
+		///
+		/// [`ChannelHandshakeConfig::announced_channel`]: lightning::util::config::ChannelHandshakeConfig::announced_channel
+		announce_channel: Option<bool>,
+		/// The channel config given to [`LiquidityManager::set_jit_channel_config`], if any.
+		///
+		/// Should be used as [`UserConfig::channel_config`] when opening the channel.
+		///
+		/// [`LiquidityManager::set_jit_channel_config`]: crate::LiquidityManager::set_jit_channel_config
+		/// [`UserConfig::channel_config`]: lightning::util::config::UserConfig::channel_config
+		channel_config: Option<ChannelConfig>,
+	},
+	/// An LSP was selected among the candidates given to
+	/// [`LiquidityManager::jit_channel_create_invoice_multi`] and the cheapest entry of its opening
+	/// fee params menu was bought.
+	///
+	/// An [`LSPS2Event::InvoiceGenerationReady`] event will follow once the LSP responded.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice_multi`]: crate::LiquidityManager::jit_channel_create_invoice_multi
+	LspSelected {
+		/// The node id of the selected LSP.
+		counterparty_node_id: PublicKey,
+		/// The opening fee params bought from the selected LSP.
+		opening_fee_params: OpeningFeeParams,
+		/// The `user_channel_id` value passed in to [`LiquidityManager::jit_channel_create_invoice_multi`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice_multi`]: crate::LiquidityManager::jit_channel_create_invoice_multi
+		user_channel_id: u128,
+	},
+	/// None of the candidates given to [`LiquidityManager::jit_channel_create_invoice_multi`]
+	/// offered acceptable opening fee params in time.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice_multi`]: crate::LiquidityManager::jit_channel_create_invoice_multi
+	LspSelectionFailed {
+		/// The `user_channel_id` value passed in to [`LiquidityManager::jit_channel_create_invoice_multi`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice_multi`]: crate::LiquidityManager::jit_channel_create_invoice_multi
+		user_channel_id: u128,
+		/// [`FailureReason::Timeout`] if some candidates didn't respond in time, otherwise why the
+		/// last failing candidate didn't provide a menu, if any did.
+		///
+		/// Is [`Option::None`] if all candidates provided a menu, but none was acceptable.
+		reason: Option<FailureReason>,
+	},
+	/// The earliest expiring entry of an opening fee params menu received from an LSP will expire
+	/// within [`JITChannelsConfig::fee_params_expiry_warning_lead_time`].
+	///
+	/// If no selection was made yet, you may want to request a fresh menu via
+	/// [`LiquidityManager::jit_channel_create_invoice`].
+	///
+	/// [`JITChannelsConfig::fee_params_expiry_warning_lead_time`]: crate::JITChannelsConfig::fee_params_expiry_warning_lead_time
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	FeeParamsExpiringSoon {
+		/// The node id of the LSP that provided the menu.
+		counterparty_node_id: PublicKey,
+		/// The time the earliest expiring entry of the menu is valid until.
+		valid_until: chrono::DateTime<Utc>,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
+	},
+	/// The payment received over a JIT channel may now be claimed.
+	///
+	/// Generated in response to [`LiquidityManager::jit_channel_payment_received`], either
+	/// immediately or, if [`JITChannelsConfig::await_funding_confirmation`] is set and the client
+	/// doesn't trust the LSP, once the channel funding transaction confirmed.
+	///
+	/// [`LiquidityManager::jit_channel_payment_received`]: crate::LiquidityManager::jit_channel_payment_received
+	/// [`JITChannelsConfig::await_funding_confirmation`]: crate::JITChannelsConfig::await_funding_confirmation
+	ClaimPayment {
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` value passed in to [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
 	},
+	/// The [`JITChannelsConfig::scid_allocator`] ran out of scids, so a client's buy request was
+	/// rejected with a temporary failure.
+	///
+	/// Only generated for custom allocators, as the default one never runs out of scids.
+	///
+	/// You should provision more scids to be able to serve further clients.
+	///
+	/// [`JITChannelsConfig::scid_allocator`]: crate::JITChannelsConfig::scid_allocator
+	ScidExhausted {
+		/// The node id of the client whose buy request was rejected.
+		counterparty_node_id: PublicKey,
+	},
+	/// An intercepted HTLC was failed as its amount doesn't cover the amount we'd have to forward
+	/// to the client after deducting the opening fee. The JIT channel was abandoned.
+	InsufficientInboundAmount {
+		/// The node id of the client the JIT channel was negotiated with.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` that would have been given in the [`Self::OpenChannel`] event.
+		user_channel_id: u128,
+		/// The id of the failed HTLC.
+		intercept_id: InterceptId,
+		/// The intercepted HTLC amount in msats.
+		inbound_amount_msat: u64,
+		/// The amount we'd have to forward after fees.
+		amt_to_forward_msat: u64,
+	},
+	/// Negotiating a JIT channel with an LSP started via
+	/// [`LiquidityManager::jit_channel_create_invoice`] failed.
+	///
+	/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+	NegotiationFailed {
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` value passed in to [`LiquidityManager::jit_channel_create_invoice`].
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		user_channel_id: u128,
+		/// The `label` value passed in to [`LiquidityManager::jit_channel_create_invoice`], if any.
+		///
+		/// [`LiquidityManager::jit_channel_create_invoice`]: crate::LiquidityManager::jit_channel_create_invoice
+		label: Option<String>,
+		/// The `correlation_id` of the negotiation, as given in the [`Self::GetInfoResponse`] event.
+		correlation_id: u128,
+		/// Why the negotiation failed.
+		reason: FailureReason,
+	},
+	/// A buy request was received from a client and is about to be validated.
+	///
+	/// Only generated if [`JITChannelsConfig::verbose_buy_events`] is set.
+	///
+	/// [`JITChannelsConfig::verbose_buy_events`]: crate::JITChannelsConfig::verbose_buy_events
+	BuyRequestReceived {
+		/// The id of the buy request.
+		request_id: RequestId,
+		/// The node id of the client making the request.
+		counterparty_node_id: PublicKey,
+	},
+	/// A buy request passed validation. An [`LSPS2Event::BuyRequest`] event for it follows.
+	///
+	/// Only generated if [`JITChannelsConfig::verbose_buy_events`] is set.
+	///
+	/// [`JITChannelsConfig::verbose_buy_events`]: crate::JITChannelsConfig::verbose_buy_events
+	BuyRequestValidated {
+		/// The id of the buy request.
+		request_id: RequestId,
+		/// The node id of the client making the request.
+		counterparty_node_id: PublicKey,
+	},
+	/// The response to a buy request, carrying the scid to use in the invoice, was queued to be
+	/// sent to the client.
+	///
+	/// Only generated if [`JITChannelsConfig::verbose_buy_events`] is set.
+	///
+	/// [`JITChannelsConfig::verbose_buy_events`]: crate::JITChannelsConfig::verbose_buy_events
+	BuyResponseSent {
+		/// The id of the buy request.
+		request_id: RequestId,
+		/// The node id of the client the response is sent to.
+		counterparty_node_id: PublicKey,
+		/// The scid handed out to the client.
+		scid: u64,
+	},
+	/// An HTLC intercepted for a JIT channel that wasn't opened yet was failed back as
+	/// [`LiquidityManager::shutdown`] was called.
+	///
+	/// [`LiquidityManager::shutdown`]: crate::LiquidityManager::shutdown
+	InterceptedHtlcFailedOnShutdown {
+		/// The node id of the client the JIT channel was negotiated with.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` that was given in the [`Self::OpenChannel`] event.
+		user_channel_id: u128,
+		/// The id of the failed HTLC.
+		intercept_id: InterceptId,
+	},
+}
+
+impl Writeable for LSPS2Event {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match self {
+			LSPS2Event::GetInfo { request_id, counterparty_node_id, version, token } => {
+				0u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, request_id, required),
+					(2, counterparty_node_id, required),
+					(4, version, required),
+					(6, token, option),
+				});
+			}
+			LSPS2Event::GetInfoResponse {
+				jit_channel_id,
+				counterparty_node_id,
+				opening_fee_params_menu,
+				min_payment_size_msat,
+				max_payment_size_msat,
+				user_channel_id,
+				label,
+				correlation_id,
+			} => {
+				2u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, jit_channel_id, required),
+					(2, counterparty_node_id, required),
+					(4, *opening_fee_params_menu, required_vec),
+					(6, min_payment_size_msat, required),
+					(8, max_payment_size_msat, required),
+					(10, user_channel_id, required),
+					(12, label, option),
+					(14, correlation_id, required),
+				});
+			}
+			LSPS2Event::BuyRequest {
+				request_id,
+				counterparty_node_id,
+				version,
+				opening_fee_params,
+				payment_size_msat,
+				refund_onchain_address,
+				announce_channel,
+				scid,
+				token,
+			} => {
+				4u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, request_id, required),
+					(2, counterparty_node_id, required),
+					(4, version, required),
+					(6, opening_fee_params, required),
+					(8, payment_size_msat, option),
+					(10, refund_onchain_address, option),
+					(12, announce_channel, option),
+					(14, scid, required),
+					(16, token, option),
+				});
+			}
+			LSPS2Event::InvoiceGenerationReady {
+				counterparty_node_id,
+				scid,
+				cltv_expiry_delta,
+				payment_size_msat,
+				client_trusts_lsp,
+				user_channel_id,
+				label,
+				correlation_id,
+			} => {
+				6u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, scid, required),
+					(4, cltv_expiry_delta, required),
+					(6, payment_size_msat, option),
+					(8, client_trusts_lsp, required),
+					(10, user_channel_id, required),
+					(12, label, option),
+					(14, correlation_id, required),
+				});
+			}
+			LSPS2Event::OpenChannel {
+				their_network_key,
+				inbound_amount_msat,
+				expected_outbound_amount_msat,
+				amt_to_forward_msat,
+				opening_fee_msat,
+				channel_capacity_sat,
+				user_channel_id,
+				announce_channel,
+				channel_config,
+			} => {
+				8u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, their_network_key, required),
+					(2, inbound_amount_msat, required),
+					(4, expected_outbound_amount_msat, required),
+					(6, amt_to_forward_msat, required),
+					(8, opening_fee_msat, required),
+					(10, user_channel_id, required),
+					(12, announce_channel, option),
+					(14, channel_config, option),
+					(16, channel_capacity_sat, required),
+				});
+			}
+			LSPS2Event::LspSelected {
+				counterparty_node_id,
+				opening_fee_params,
+				user_channel_id,
+			} => {
+				10u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, opening_fee_params, required),
+					(4, user_channel_id, required),
+				});
+			}
+			LSPS2Event::LspSelectionFailed { user_channel_id, reason } => {
+				12u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, user_channel_id, required),
+					(2, reason, option),
+				});
+			}
+			LSPS2Event::FeeParamsExpiringSoon { counterparty_node_id, valid_until, label } => {
+				14u8.write(writer)?;
+				let valid_until = valid_until.to_rfc3339();
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, valid_until, required),
+					(4, label, option),
+				});
+			}
+			LSPS2Event::ClaimPayment { counterparty_node_id, user_channel_id, label } => {
+				16u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, user_channel_id, required),
+					(4, label, option),
+				});
+			}
+			LSPS2Event::ScidExhausted { counterparty_node_id } => {
+				18u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+				});
+			}
+			LSPS2Event::TrustModelMismatch {
+				counterparty_node_id,
+				scid,
+				cltv_expiry_delta,
+				payment_size_msat,
+				user_channel_id,
+				label,
+				correlation_id,
+			} => {
+				20u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, scid, required),
+					(4, cltv_expiry_delta, required),
+					(6, payment_size_msat, option),
+					(8, user_channel_id, required),
+					(10, label, option),
+					(12, correlation_id, required),
+				});
+			}
+			LSPS2Event::InsufficientInboundAmount {
+				counterparty_node_id,
+				user_channel_id,
+				intercept_id,
+				inbound_amount_msat,
+				amt_to_forward_msat,
+			} => {
+				22u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, user_channel_id, required),
+					(4, intercept_id, required),
+					(6, inbound_amount_msat, required),
+					(8, amt_to_forward_msat, required),
+				});
+			}
+			LSPS2Event::NegotiationFailed {
+				counterparty_node_id,
+				user_channel_id,
+				label,
+				correlation_id,
+				reason,
+			} => {
+				24u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, user_channel_id, required),
+					(4, label, option),
+					(6, reason, required),
+					(8, correlation_id, required),
+				});
+			}
+			LSPS2Event::BuyRequestReceived { request_id, counterparty_node_id } => {
+				26u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, request_id, required),
+					(2, counterparty_node_id, required),
+				});
+			}
+			LSPS2Event::BuyRequestValidated { request_id, counterparty_node_id } => {
+				28u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, request_id, required),
+					(2, counterparty_node_id, required),
+				});
+			}
+			LSPS2Event::BuyResponseSent { request_id, counterparty_node_id, scid } => {
+				30u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, request_id, required),
+					(2, counterparty_node_id, required),
+					(4, scid, required),
+				});
+			}
+			LSPS2Event::InterceptedHtlcFailedOnShutdown {
+				counterparty_node_id,
+				user_channel_id,
+				intercept_id,
+			} => {
+				32u8.write(writer)?;
+				write_tlv_fields!(writer, {
+					(0, counterparty_node_id, required),
+					(2, user_channel_id, required),
+					(4, intercept_id, required),
+				});
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Readable for LSPS2Event {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let id: u8 = Readable::read(reader)?;
+		// Each variant is read in a closure as the TLV reading macros may `return` early.
+		match id {
+			0 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, request_id, required),
+						(2, counterparty_node_id, required),
+						(4, version, required),
+						(6, token, option),
+					});
+					Ok(LSPS2Event::GetInfo {
+						request_id: request_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						version: version.0.unwrap(),
+						token,
+					})
+				};
+				f()
+			}
+			2 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, jit_channel_id, required),
+						(2, counterparty_node_id, required),
+						(4, opening_fee_params_menu, required_vec),
+						(6, min_payment_size_msat, required),
+						(8, max_payment_size_msat, required),
+						(10, user_channel_id, required),
+						(12, label, option),
+						(14, correlation_id, required),
+					});
+					Ok(LSPS2Event::GetInfoResponse {
+						jit_channel_id: jit_channel_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						opening_fee_params_menu,
+						min_payment_size_msat: min_payment_size_msat.0.unwrap(),
+						max_payment_size_msat: max_payment_size_msat.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						label,
+						correlation_id: correlation_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			4 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, request_id, required),
+						(2, counterparty_node_id, required),
+						(4, version, required),
+						(6, opening_fee_params, required),
+						(8, payment_size_msat, option),
+						(10, refund_onchain_address, option),
+						(12, announce_channel, option),
+						(14, scid, required),
+						(16, token, option),
+					});
+					Ok(LSPS2Event::BuyRequest {
+						request_id: request_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						version: version.0.unwrap(),
+						opening_fee_params: opening_fee_params.0.unwrap(),
+						payment_size_msat,
+						refund_onchain_address,
+						announce_channel,
+						scid: scid.0.unwrap(),
+						token,
+					})
+				};
+				f()
+			}
+			6 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, scid, required),
+						(4, cltv_expiry_delta, required),
+						(6, payment_size_msat, option),
+						(8, client_trusts_lsp, required),
+						(10, user_channel_id, required),
+						(12, label, option),
+						(14, correlation_id, required),
+					});
+					Ok(LSPS2Event::InvoiceGenerationReady {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						scid: scid.0.unwrap(),
+						cltv_expiry_delta: cltv_expiry_delta.0.unwrap(),
+						payment_size_msat,
+						client_trusts_lsp: client_trusts_lsp.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						label,
+						correlation_id: correlation_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			8 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, their_network_key, required),
+						(2, inbound_amount_msat, required),
+						(4, expected_outbound_amount_msat, required),
+						(6, amt_to_forward_msat, required),
+						(8, opening_fee_msat, required),
+						(10, user_channel_id, required),
+						(12, announce_channel, option),
+						(14, channel_config, option),
+						(16, channel_capacity_sat, required),
+					});
+					Ok(LSPS2Event::OpenChannel {
+						their_network_key: their_network_key.0.unwrap(),
+						inbound_amount_msat: inbound_amount_msat.0.unwrap(),
+						expected_outbound_amount_msat: expected_outbound_amount_msat.0.unwrap(),
+						amt_to_forward_msat: amt_to_forward_msat.0.unwrap(),
+						opening_fee_msat: opening_fee_msat.0.unwrap(),
+						channel_capacity_sat: channel_capacity_sat.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						announce_channel,
+						channel_config,
+					})
+				};
+				f()
+			}
+			10 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, opening_fee_params, required),
+						(4, user_channel_id, required),
+					});
+					Ok(LSPS2Event::LspSelected {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						opening_fee_params: opening_fee_params.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			12 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, user_channel_id, required),
+						(2, reason, option),
+					});
+					Ok(LSPS2Event::LspSelectionFailed {
+						user_channel_id: user_channel_id.0.unwrap(),
+						reason,
+					})
+				};
+				f()
+			}
+			14 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, valid_until, required),
+						(4, label, option),
+					});
+					let valid_until: String = valid_until.0.unwrap();
+					Ok(LSPS2Event::FeeParamsExpiringSoon {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						valid_until: read_datetime(&valid_until)?,
+						label,
+					})
+				};
+				f()
+			}
+			16 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, user_channel_id, required),
+						(4, label, option),
+					});
+					Ok(LSPS2Event::ClaimPayment {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						label,
+					})
+				};
+				f()
+			}
+			18 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+					});
+					Ok(LSPS2Event::ScidExhausted {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			20 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, scid, required),
+						(4, cltv_expiry_delta, required),
+						(6, payment_size_msat, option),
+						(8, user_channel_id, required),
+						(10, label, option),
+						(12, correlation_id, required),
+					});
+					Ok(LSPS2Event::TrustModelMismatch {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						scid: scid.0.unwrap(),
+						cltv_expiry_delta: cltv_expiry_delta.0.unwrap(),
+						payment_size_msat,
+						user_channel_id: user_channel_id.0.unwrap(),
+						label,
+						correlation_id: correlation_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			22 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, user_channel_id, required),
+						(4, intercept_id, required),
+						(6, inbound_amount_msat, required),
+						(8, amt_to_forward_msat, required),
+					});
+					Ok(LSPS2Event::InsufficientInboundAmount {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						intercept_id: intercept_id.0.unwrap(),
+						inbound_amount_msat: inbound_amount_msat.0.unwrap(),
+						amt_to_forward_msat: amt_to_forward_msat.0.unwrap(),
+					})
+				};
+				f()
+			}
+			24 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, user_channel_id, required),
+						(4, label, option),
+						(6, reason, required),
+						(8, correlation_id, required),
+					});
+					Ok(LSPS2Event::NegotiationFailed {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						label,
+						correlation_id: correlation_id.0.unwrap(),
+						reason: reason.0.unwrap(),
+					})
+				};
+				f()
+			}
+			26 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, request_id, required),
+						(2, counterparty_node_id, required),
+					});
+					Ok(LSPS2Event::BuyRequestReceived {
+						request_id: request_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			28 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, request_id, required),
+						(2, counterparty_node_id, required),
+					});
+					Ok(LSPS2Event::BuyRequestValidated {
+						request_id: request_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			30 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, request_id, required),
+						(2, counterparty_node_id, required),
+						(4, scid, required),
+					});
+					Ok(LSPS2Event::BuyResponseSent {
+						request_id: request_id.0.unwrap(),
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						scid: scid.0.unwrap(),
+					})
+				};
+				f()
+			}
+			32 => {
+				let f = || {
+					_init_and_read_len_prefixed_tlv_fields!(reader, {
+						(0, counterparty_node_id, required),
+						(2, user_channel_id, required),
+						(4, intercept_id, required),
+					});
+					Ok(LSPS2Event::InterceptedHtlcFailedOnShutdown {
+						counterparty_node_id: counterparty_node_id.0.unwrap(),
+						user_channel_id: user_channel_id.0.unwrap(),
+						intercept_id: intercept_id.0.unwrap(),
+					})
+				};
+				f()
+			}
+			_ => Err(DecodeError::UnknownRequiredFeature),
+		}
+	}
 }
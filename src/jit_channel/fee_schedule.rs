@@ -0,0 +1,115 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+use chrono::Utc;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::msgs::RawOpeningFeeParams;
+use crate::utils;
+
+/// A single entry of a [`FeeSchedule`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeeTier {
+	/// The minimum fee required for the channel open.
+	pub min_fee_msat: u64,
+	/// A fee proportional to the size of the initial payment.
+	pub proportional: u32,
+	/// The number of blocks after confirmation that the LSP promises it will keep the channel
+	/// alive without closing.
+	pub min_lifetime: u32,
+	/// The maximum number of blocks that the client is allowed to set its `to_self_delay`
+	/// parameter.
+	pub max_client_to_self_delay: u32,
+}
+
+/// A declarative fee schedule an LSP can build the opening fee params menu from.
+///
+/// Use [`FeeSchedule::build_menu`] to produce the menu passed to
+/// [`LiquidityManager::opening_fee_params_generated`] in response to an
+/// [`LSPS2Event::GetInfo`] event.
+///
+/// [`LiquidityManager::opening_fee_params_generated`]: crate::LiquidityManager::opening_fee_params_generated
+/// [`LSPS2Event::GetInfo`]: crate::jit_channel::LSPS2Event::GetInfo
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeeSchedule {
+	/// The tiers offered, each resulting in one entry of the menu.
+	pub tiers: Vec<FeeTier>,
+}
+
+impl FeeSchedule {
+	/// Creates a fee schedule offering the given tiers.
+	pub fn new(tiers: Vec<FeeTier>) -> Self {
+		Self { tiers }
+	}
+
+	/// Builds the opening fee params menu, with all entries valid for the given duration from now.
+	///
+	/// The entries are ordered by increasing `min_fee_msat`, then `proportional`, as required by
+	/// the specification.
+	pub fn build_menu(&self, valid_for: Duration) -> Vec<RawOpeningFeeParams> {
+		let valid_until: chrono::DateTime<Utc> =
+			(UNIX_EPOCH + utils::duration_since_epoch() + valid_for).into();
+		let mut menu: Vec<RawOpeningFeeParams> = self
+			.tiers
+			.iter()
+			.map(|tier| RawOpeningFeeParams {
+				min_fee_msat: tier.min_fee_msat,
+				proportional: tier.proportional,
+				valid_until,
+				min_lifetime: tier.min_lifetime,
+				max_client_to_self_delay: tier.max_client_to_self_delay,
+			})
+			.collect();
+		menu.sort_by_key(|params| (params.min_fee_msat, params.proportional));
+		menu
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn two_tier_schedule_builds_ordered_menu() {
+		let schedule = FeeSchedule::new(vec![
+			FeeTier {
+				min_fee_msat: 5_000,
+				proportional: 2_000,
+				min_lifetime: 4_320,
+				max_client_to_self_delay: 2_016,
+			},
+			FeeTier {
+				min_fee_msat: 1_000,
+				proportional: 1_000,
+				min_lifetime: 1_008,
+				max_client_to_self_delay: 144,
+			},
+		]);
+
+		let before = utils::duration_since_epoch();
+		let menu = schedule.build_menu(Duration::from_secs(3600));
+		let after = utils::duration_since_epoch();
+
+		assert_eq!(menu.len(), 2);
+		assert_eq!(menu[0].min_fee_msat, 1_000);
+		assert_eq!(menu[0].proportional, 1_000);
+		assert_eq!(menu[0].min_lifetime, 1_008);
+		assert_eq!(menu[0].max_client_to_self_delay, 144);
+		assert_eq!(menu[1].min_fee_msat, 5_000);
+		assert_eq!(menu[1].proportional, 2_000);
+		assert_eq!(menu[1].min_lifetime, 4_320);
+		assert_eq!(menu[1].max_client_to_self_delay, 2_016);
+
+		assert_eq!(menu[0].valid_until, menu[1].valid_until);
+		let valid_until = menu[0].valid_until.timestamp() as u64;
+		assert!(valid_until >= before.as_secs() + 3600);
+		assert!(valid_until <= after.as_secs() + 3600);
+	}
+}
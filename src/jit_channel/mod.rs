@@ -10,10 +10,19 @@
 //! Implementation of LSPS2: JIT Channel Negotiation specification.
 
 pub(crate) mod channel_manager;
+pub(crate) mod errors;
 pub(crate) mod event;
+pub(crate) mod fee_schedule;
 /// Message, request, and other primitive types used to implement LSPS2.
 pub mod msgs;
 pub(crate) mod utils;
 
-pub use event::LSPS2Event;
-pub use msgs::{BuyResponse, GetInfoResponse, OpeningFeeParams, RawOpeningFeeParams};
+pub use channel_manager::{
+	ChannelReadyOutcome, ChannelSizingStrategy, Clock, GetInfoRejectionReason, JitChannelState,
+	RouteHintBundle, ScidAllocator,
+};
+pub use errors::lsps2_error_message;
+pub use event::{FailureReason, LSPS2Event};
+pub use fee_schedule::{FeeSchedule, FeeTier};
+pub use msgs::{BuyResponse, GetInfoResponse, OpeningFeeParams, RawOpeningFeeParams, Token};
+pub use utils::usable_menu_entries;
@@ -1,22 +1,39 @@
 use std::convert::TryFrom;
+use std::fmt;
 
-use bitcoin::hashes::hmac::{Hmac, HmacEngine};
-use bitcoin::hashes::sha256::Hash as Sha256;
-use bitcoin::hashes::{Hash, HashEngine};
-use chrono::Utc;
+use chrono::{Timelike, Utc};
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
 use serde::{Deserialize, Serialize};
 
+use crate::jit_channel::utils::{
+	canonical_valid_until, compute_promise, promise_key_id, PromiseVersion,
+};
 use crate::transport::msgs::{LSPSMessage, RequestId, ResponseError};
 use crate::utils;
 
+/// Identifies the revision of the LSPS2 specification whose wire format this crate implements.
+///
+/// The revision covers protocol version 1 with amounts in msat encoded as JSON numbers. The
+/// messages exchanged under it are checked against the fixtures in `src/jit_channel/fixtures`.
+pub const LSPS2_SPEC_REVISION: &str = "v1-2023-draft";
+
 pub(crate) const LSPS2_GET_VERSIONS_METHOD_NAME: &str = "lsps2.get_versions";
 pub(crate) const LSPS2_GET_INFO_METHOD_NAME: &str = "lsps2.get_info";
 pub(crate) const LSPS2_BUY_METHOD_NAME: &str = "lsps2.buy";
 
+pub(crate) const LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
+pub(crate) const LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE: i32 = 2;
+
 pub(crate) const LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
 pub(crate) const LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE: i32 = 2;
 pub(crate) const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE: i32 = 3;
 pub(crate) const LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE: i32 = 4;
+/// Not defined by LSPS2, taken from the JSON-RPC range reserved for implementation-defined server
+/// errors. Signals the request may be retried later.
+pub(crate) const LSPS2_BUY_REQUEST_TEMPORARY_FAILURE_ERROR_CODE: i32 = -32000;
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 /// A request made to an LSP to learn what versions of the protocol they support.
@@ -29,15 +46,60 @@ pub struct GetVersionsResponse {
 	pub versions: Vec<u16>,
 }
 
+/// A token provided to the LSP, e.g., used as an API key or coupon code.
+///
+/// As tokens might be secret, their contents are redacted when formatted via [`fmt::Debug`] or
+/// [`fmt::Display`]. Use [`Token::as_str`] to access them.
+#[derive(Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Token(String);
+
+impl Token {
+	/// Creates a new [`Token`].
+	pub fn new(token: String) -> Self {
+		Self(token)
+	}
+
+	/// Returns the token as a string slice.
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+impl Writeable for Token {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for Token {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self(Readable::read(reader)?))
+	}
+}
+
+impl fmt::Debug for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Token(***)")
+	}
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "***")
+	}
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 /// A request made to an LSP to learn their current channel fees and parameters.
 pub struct GetInfoRequest {
 	/// What version of the protocol we want to use.
 	pub version: u16,
 	/// An optional token to provide to the LSP.
-	pub token: Option<String>,
+	pub token: Option<Token>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 /// Fees and parameters for a JIT Channel without the promise.
 ///
 /// The promise will be calculated automatically for the LSP and this type converted
@@ -56,22 +118,37 @@ pub struct RawOpeningFeeParams {
 }
 
 impl RawOpeningFeeParams {
-	pub(crate) fn into_opening_fee_params(self, promise_secret: &[u8; 32]) -> OpeningFeeParams {
-		let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
-		hmac.input(&self.min_fee_msat.to_be_bytes());
-		hmac.input(&self.proportional.to_be_bytes());
-		hmac.input(self.valid_until.to_rfc3339().as_bytes());
-		hmac.input(&self.min_lifetime.to_be_bytes());
-		hmac.input(&self.max_client_to_self_delay.to_be_bytes());
-		let promise_bytes = Hmac::from_engine(hmac).into_inner();
-		let promise = utils::hex_str(&promise_bytes[..]);
+	/// Computes the promise for these parameters without consuming them, e.g., to log or preview
+	/// the promise while retaining the raw parameters.
+	///
+	/// The promise is calculated as given out if [`JITChannelsConfig::domain_separated_promises`]
+	/// is unset.
+	///
+	/// [`JITChannelsConfig::domain_separated_promises`]: crate::JITChannelsConfig::domain_separated_promises
+	pub fn compute_promise(&self, promise_secret: &[u8; 32]) -> String {
+		self.compute_versioned_promise(promise_secret, PromiseVersion::V1)
+	}
+
+	pub(crate) fn compute_versioned_promise(
+		&self, promise_secret: &[u8; 32], promise_version: PromiseVersion,
+	) -> String {
+		compute_promise(self, promise_secret, promise_version)
+	}
+
+	pub(crate) fn into_opening_fee_params(
+		self, promise_secret: &[u8; 32], promise_version: PromiseVersion,
+	) -> OpeningFeeParams {
+		let promise = self.compute_versioned_promise(promise_secret, promise_version);
+		let key_id = Some(promise_key_id(promise_secret));
 		OpeningFeeParams {
 			min_fee_msat: self.min_fee_msat,
 			proportional: self.proportional,
-			valid_until: self.valid_until.clone(),
+			// Drop any sub-second precision not covered by the promise.
+			valid_until: self.valid_until.with_nanosecond(0).expect("zero is a valid nanosecond"),
 			min_lifetime: self.min_lifetime,
 			max_client_to_self_delay: self.max_client_to_self_delay,
 			promise,
+			key_id,
 		}
 	}
 }
@@ -88,6 +165,7 @@ pub struct OpeningFeeParams {
 	/// A fee proportional to the size of the initial payment.
 	pub proportional: u32,
 	/// An [`ISO8601`](https://www.iso.org/iso-8601-date-and-time-format.html) formatted date for which these params are valid.
+	#[serde(serialize_with = "serialize_valid_until")]
 	pub valid_until: chrono::DateTime<Utc>,
 	/// The number of blocks after confirmation that the LSP promises it will keep the channel alive without closing.
 	pub min_lifetime: u32,
@@ -95,6 +173,96 @@ pub struct OpeningFeeParams {
 	pub max_client_to_self_delay: u32,
 	/// The HMAC used to verify the authenticity of these parameters.
 	pub promise: String,
+	/// Identifies the secret the promise was calculated with, allowing the LSP to verify it without
+	/// trying every secret it knows.
+	///
+	/// Not part of the LSPS2 specification. Other implementations may not set or echo it, in which
+	/// case all known secrets are tried.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub key_id: Option<String>,
+}
+
+fn serialize_valid_until<S>(
+	valid_until: &chrono::DateTime<Utc>, serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+	S: serde::Serializer,
+{
+	serializer.serialize_str(&canonical_valid_until(valid_until))
+}
+
+impl Writeable for OpeningFeeParams {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let valid_until = self.valid_until.to_rfc3339();
+		write_tlv_fields!(writer, {
+			(0, self.min_fee_msat, required),
+			(2, self.proportional, required),
+			(4, valid_until, required),
+			(6, self.min_lifetime, required),
+			(8, self.max_client_to_self_delay, required),
+			(10, self.promise, required),
+			(12, self.key_id, option),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for OpeningFeeParams {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, min_fee_msat, required),
+			(2, proportional, required),
+			(4, valid_until, required),
+			(6, min_lifetime, required),
+			(8, max_client_to_self_delay, required),
+			(10, promise, required),
+			(12, key_id, option),
+		});
+		let valid_until: String = valid_until.0.unwrap();
+		Ok(Self {
+			min_fee_msat: min_fee_msat.0.unwrap(),
+			proportional: proportional.0.unwrap(),
+			valid_until: read_datetime(&valid_until)?,
+			min_lifetime: min_lifetime.0.unwrap(),
+			max_client_to_self_delay: max_client_to_self_delay.0.unwrap(),
+			promise: promise.0.unwrap(),
+			key_id,
+		})
+	}
+}
+
+/// Parses a timestamp persisted as RFC3339 string.
+pub(crate) fn read_datetime(datetime: &str) -> Result<chrono::DateTime<Utc>, DecodeError> {
+	chrono::DateTime::parse_from_rfc3339(datetime)
+		.map(Into::into)
+		.map_err(|_| DecodeError::InvalidValue)
+}
+
+impl OpeningFeeParams {
+	/// Strips the promise, returning the [`RawOpeningFeeParams`] it was calculated over.
+	///
+	/// Note that this doesn't verify the promise.
+	pub fn into_raw(self) -> RawOpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat: self.min_fee_msat,
+			proportional: self.proportional,
+			valid_until: self.valid_until,
+			min_lifetime: self.min_lifetime,
+			max_client_to_self_delay: self.max_client_to_self_delay,
+		}
+	}
+
+	/// Returns the promise these parameters carry along with the one recomputed with the given
+	/// secret, e.g., to compare them side by side when auditing a dispute.
+	///
+	/// The promise is recomputed as given out if [`JITChannelsConfig::domain_separated_promises`]
+	/// is unset.
+	///
+	/// [`JITChannelsConfig::domain_separated_promises`]: crate::JITChannelsConfig::domain_separated_promises
+	pub fn audit_promise(&self, promise_secret: &[u8; 32]) -> (String, String) {
+		let recomputed = self.clone().into_raw().compute_promise(promise_secret);
+		(self.promise.clone(), recomputed)
+	}
 }
 
 /// A response to a [`GetInfoRequest`]
@@ -118,6 +286,13 @@ pub struct BuyRequest {
 	/// The size of the initial payment you expect to receive.
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub payment_size_msat: Option<u64>,
+	/// An on-chain address the LSP may refund the intercepted payment to if the JIT channel
+	/// can't be opened.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub refund_onchain_address: Option<String>,
+	/// Whether you would like the JIT channel to be announced to the network.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub announce_channel: Option<bool>,
 }
 
 /// A newtype that holds a `short_channel_id` in human readable format of BBBxTTTx000.
@@ -134,8 +309,20 @@ impl From<u64> for JitChannelScid {
 	}
 }
 
+impl TryFrom<&str> for JitChannelScid {
+	type Error = ();
+
+	/// Parses a `short_channel_id` given in the human readable format of BBBxTTTx000, failing
+	/// if it is malformed or any of its components is out of range.
+	fn try_from(human_readable_scid: &str) -> Result<Self, Self::Error> {
+		utils::scid_from_human_readable_string(human_readable_scid)?;
+		Ok(Self(human_readable_scid.to_string()))
+	}
+}
+
 impl JitChannelScid {
 	/// Try to convert a [`JitChannelScid`] into a u64 used by LDK.
+	#[allow(clippy::result_unit_err)]
 	pub fn to_scid(&self) -> Result<u64, ()> {
 		utils::scid_from_human_readable_string(&self.0)
 	}
@@ -151,8 +338,13 @@ pub struct BuyResponse {
 	/// The locktime expiry delta the lsp requires.
 	pub lsp_cltv_expiry_delta: u32,
 	/// A flag that indicates who is trusting who.
-	#[serde(default)]
-	pub client_trusts_lsp: bool,
+	///
+	/// Some LSPs omit the flag, in which case it is `None` and the client resolves it according to
+	/// [`JITChannelsConfig::assume_client_trusts_lsp_when_absent`].
+	///
+	/// [`JITChannelsConfig::assume_client_trusts_lsp_when_absent`]: crate::JITChannelsConfig::assume_client_trusts_lsp_when_absent
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub client_trusts_lsp: Option<bool>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -175,6 +367,26 @@ impl LSPS2Request {
 			LSPS2Request::Buy(_) => LSPS2_BUY_METHOD_NAME,
 		}
 	}
+
+	/// Returns the LSPS2-defined error codes an LSP may respond to the underlying request with.
+	///
+	/// Generic JSON-RPC errors, e.g., for malformed requests, may be returned for any request and
+	/// are not included.
+	pub fn possible_error_codes(&self) -> &'static [i32] {
+		match self {
+			LSPS2Request::GetVersions(_) => &[],
+			LSPS2Request::GetInfo(_) => &[
+				LSPS2_GET_INFO_REQUEST_INVALID_VERSION_ERROR_CODE,
+				LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
+			],
+			LSPS2Request::Buy(_) => &[
+				LSPS2_BUY_REQUEST_INVALID_VERSION_ERROR_CODE,
+				LSPS2_BUY_REQUEST_INVALID_OPENING_FEE_PARAMS_ERROR_CODE,
+				LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_SMALL_ERROR_CODE,
+				LSPS2_BUY_REQUEST_PAYMENT_SIZE_TOO_LARGE_ERROR_CODE,
+			],
+		}
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -192,6 +404,19 @@ pub enum LSPS2Response {
 	BuyError(ResponseError),
 }
 
+impl LSPS2Response {
+	/// Returns the method of the request this is a response to.
+	pub fn method(&self) -> &str {
+		match self {
+			LSPS2Response::GetVersions(_) => LSPS2_GET_VERSIONS_METHOD_NAME,
+			LSPS2Response::GetInfo(_) | LSPS2Response::GetInfoError(_) => {
+				LSPS2_GET_INFO_METHOD_NAME
+			}
+			LSPS2Response::Buy(_) | LSPS2Response::BuyError(_) => LSPS2_BUY_METHOD_NAME,
+		}
+	}
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 /// An enum that captures all valid JSON-RPC messages in the LSPS2 protocol.
 pub enum LSPS2Message {
@@ -224,6 +449,117 @@ mod tests {
 	use super::*;
 	use crate::jit_channel::utils::is_valid_opening_fee_params;
 
+	use std::collections::HashMap;
+
+	macro_rules! spec_fixture {
+		($name: expr) => {
+			include_str!(concat!("fixtures/v1-2023-draft/", $name, ".json"))
+		};
+	}
+
+	/// Asserts the given message serializes to the given fixture and is parsed from it.
+	fn assert_matches_fixture(message: LSPSMessage, fixture: &str, method: Option<&str>) {
+		let serialized: serde_json::Value = serde_json::to_value(&message).unwrap();
+		let expected: serde_json::Value = serde_json::from_str(fixture).unwrap();
+		assert_eq!(serialized, expected);
+
+		let mut request_id_to_method = HashMap::new();
+		if let (Some(method), Some(id)) = (method, expected["id"].as_str()) {
+			request_id_to_method
+				.insert(RequestId::new(id.to_string()).unwrap(), method.to_string());
+		}
+		assert_eq!(
+			LSPSMessage::from_str_with_id_map(fixture, &mut request_id_to_method).unwrap(),
+			message
+		);
+	}
+
+	fn fixture_opening_fee_params() -> OpeningFeeParams {
+		OpeningFeeParams {
+			min_fee_msat: 546000,
+			proportional: 1200,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 1008,
+			max_client_to_self_delay: 2016,
+			promise: "abcdefghijklmnopqrstuvwxyz".to_string(),
+			key_id: None,
+		}
+	}
+
+	#[test]
+	fn messages_match_spec_revision_fixtures() {
+		assert_eq!(LSPS2_SPEC_REVISION, "v1-2023-draft");
+		let id = |id: &str| RequestId::new(id.to_string()).unwrap();
+
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				id("request:1"),
+				LSPS2Request::GetVersions(GetVersionsRequest {}),
+			)),
+			spec_fixture!("get_versions_request"),
+			None,
+		);
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				id("request:1"),
+				LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+			)),
+			spec_fixture!("get_versions_response"),
+			Some(LSPS2_GET_VERSIONS_METHOD_NAME),
+		);
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				id("request:2"),
+				LSPS2Request::GetInfo(GetInfoRequest {
+					version: 1,
+					token: Some(Token::new("SECRETDROPRATE".to_string())),
+				}),
+			)),
+			spec_fixture!("get_info_request"),
+			None,
+		);
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				id("request:2"),
+				LSPS2Response::GetInfo(GetInfoResponse {
+					opening_fee_params_menu: vec![fixture_opening_fee_params()],
+					min_payment_size_msat: 1000,
+					max_payment_size_msat: 1_000_000,
+				}),
+			)),
+			spec_fixture!("get_info_response"),
+			Some(LSPS2_GET_INFO_METHOD_NAME),
+		);
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Request(
+				id("request:3"),
+				LSPS2Request::Buy(BuyRequest {
+					version: 1,
+					opening_fee_params: fixture_opening_fee_params(),
+					payment_size_msat: Some(42000),
+					refund_onchain_address: None,
+					announce_channel: None,
+				}),
+			)),
+			spec_fixture!("buy_request"),
+			None,
+		);
+		assert_matches_fixture(
+			LSPSMessage::LSPS2(LSPS2Message::Response(
+				id("request:3"),
+				LSPS2Response::Buy(BuyResponse {
+					jit_channel_scid: JitChannelScid::try_from("29451x4815x1").unwrap(),
+					lsp_cltv_expiry_delta: 144,
+					client_trusts_lsp: Some(false),
+				}),
+			)),
+			spec_fixture!("buy_response"),
+			Some(LSPS2_BUY_METHOD_NAME),
+		);
+	}
+
 	#[test]
 	fn into_opening_fee_params_produces_valid_promise() {
 		let min_fee_msat = 100;
@@ -236,14 +572,14 @@ mod tests {
 		let raw = RawOpeningFeeParams {
 			min_fee_msat,
 			proportional,
-			valid_until: valid_until.clone().into(),
+			valid_until,
 			min_lifetime,
 			max_client_to_self_delay,
 		};
 
 		let promise_secret = [1u8; 32];
 
-		let opening_fee_params = raw.into_opening_fee_params(&promise_secret);
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1);
 
 		assert_eq!(opening_fee_params.min_fee_msat, min_fee_msat);
 		assert_eq!(opening_fee_params.proportional, proportional);
@@ -251,7 +587,113 @@ mod tests {
 		assert_eq!(opening_fee_params.min_lifetime, min_lifetime);
 		assert_eq!(opening_fee_params.max_client_to_self_delay, max_client_to_self_delay);
 
-		assert!(is_valid_opening_fee_params(&opening_fee_params, &promise_secret));
+		assert!(is_valid_opening_fee_params(
+			&opening_fee_params,
+			&promise_secret,
+			PromiseVersion::V1
+		));
+	}
+
+	#[test]
+	fn compute_promise_matches_into_opening_fee_params() {
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		};
+		let promise_secret = [1u8; 32];
+
+		let promise = raw.compute_promise(&promise_secret);
+		let opening_fee_params =
+			raw.clone().into_opening_fee_params(&promise_secret, PromiseVersion::V1);
+		assert_eq!(promise, opening_fee_params.promise);
+
+		let version = PromiseVersion::V2 { protocol_version: 1 };
+		let versioned_promise = raw.compute_versioned_promise(&promise_secret, version);
+		assert_eq!(
+			versioned_promise,
+			raw.into_opening_fee_params(&promise_secret, version).promise
+		);
+		assert_ne!(versioned_promise, promise);
+	}
+
+	#[test]
+	fn audit_promise_detects_tampered_params() {
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		};
+		let promise_secret = [1u8; 32];
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1);
+
+		let (stored, recomputed) = opening_fee_params.audit_promise(&promise_secret);
+		assert_eq!(stored, opening_fee_params.promise);
+		assert_eq!(stored, recomputed);
+
+		let mut tampered = opening_fee_params.clone();
+		tampered.min_fee_msat = 1;
+		let (stored, recomputed) = tampered.audit_promise(&promise_secret);
+		assert_eq!(stored, opening_fee_params.promise);
+		assert_ne!(stored, recomputed);
+
+		// A wrong secret doesn't reproduce the promise either.
+		let (stored, recomputed) = opening_fee_params.audit_promise(&[2u8; 32]);
+		assert_ne!(stored, recomputed);
+	}
+
+	#[test]
+	fn into_raw_reverses_into_opening_fee_params() {
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		};
+
+		let promise_secret = [1u8; 32];
+		assert_eq!(
+			raw.clone().into_opening_fee_params(&promise_secret, PromiseVersion::V1).into_raw(),
+			raw
+		);
+	}
+
+	#[test]
+	fn domain_separated_promises_differ_from_legacy_promises() {
+		let raw = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		};
+		let promise_secret = [1u8; 32];
+		let v2 = PromiseVersion::V2 { protocol_version: 1 };
+
+		let v1_params = raw.clone().into_opening_fee_params(&promise_secret, PromiseVersion::V1);
+		let v2_params = raw.clone().into_opening_fee_params(&promise_secret, v2);
+		assert_ne!(v1_params.promise, v2_params.promise);
+		assert_eq!(v1_params.clone().into_raw(), v2_params.clone().into_raw());
+
+		assert!(is_valid_opening_fee_params(&v2_params, &promise_secret, v2));
+		assert!(!is_valid_opening_fee_params(&v2_params, &promise_secret, PromiseVersion::V1));
+		assert!(!is_valid_opening_fee_params(&v1_params, &promise_secret, v2));
+
+		let other_version = PromiseVersion::V2 { protocol_version: 2 };
+		assert!(!is_valid_opening_fee_params(&v2_params, &promise_secret, other_version));
 	}
 
 	#[test]
@@ -272,9 +714,14 @@ mod tests {
 
 		let promise_secret = [1u8; 32];
 
-		let mut opening_fee_params = raw.into_opening_fee_params(&promise_secret);
+		let mut opening_fee_params =
+			raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1);
 		opening_fee_params.min_fee_msat = min_fee_msat + 1;
-		assert!(!is_valid_opening_fee_params(&opening_fee_params, &promise_secret));
+		assert!(!is_valid_opening_fee_params(
+			&opening_fee_params,
+			&promise_secret,
+			PromiseVersion::V1
+		));
 	}
 
 	#[test]
@@ -296,8 +743,12 @@ mod tests {
 		let promise_secret = [1u8; 32];
 		let other_secret = [2u8; 32];
 
-		let opening_fee_params = raw.into_opening_fee_params(&promise_secret);
-		assert!(!is_valid_opening_fee_params(&opening_fee_params, &other_secret));
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1);
+		assert!(!is_valid_opening_fee_params(
+			&opening_fee_params,
+			&other_secret,
+			PromiseVersion::V1
+		));
 	}
 
 	#[test]
@@ -318,7 +769,143 @@ mod tests {
 
 		let promise_secret = [1u8; 32];
 
-		let opening_fee_params = raw.into_opening_fee_params(&promise_secret);
-		assert!(!is_valid_opening_fee_params(&opening_fee_params, &promise_secret));
+		let opening_fee_params = raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1);
+		assert!(!is_valid_opening_fee_params(
+			&opening_fee_params,
+			&promise_secret,
+			PromiseVersion::V1
+		));
+	}
+
+	fn test_opening_fee_params() -> OpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		}
+		.into_opening_fee_params(&[1u8; 32], PromiseVersion::V1)
+	}
+
+	#[test]
+	fn buy_request_round_trips_refund_onchain_address() {
+		let request = BuyRequest {
+			version: 1,
+			opening_fee_params: test_opening_fee_params(),
+			payment_size_msat: None,
+			refund_onchain_address: Some("bcrt1qxyz".to_string()),
+			announce_channel: None,
+		};
+
+		let json = serde_json::to_value(&request).unwrap();
+		assert_eq!(json["refund_onchain_address"], "bcrt1qxyz");
+		assert_eq!(serde_json::from_value::<BuyRequest>(json).unwrap(), request);
+	}
+
+	#[test]
+	fn buy_request_without_refund_onchain_address() {
+		let request = BuyRequest {
+			version: 1,
+			opening_fee_params: test_opening_fee_params(),
+			payment_size_msat: Some(42),
+			refund_onchain_address: None,
+			announce_channel: None,
+		};
+
+		let json = serde_json::to_value(&request).unwrap();
+		assert!(json.get("refund_onchain_address").is_none());
+		assert_eq!(serde_json::from_value::<BuyRequest>(json).unwrap(), request);
+	}
+
+	#[test]
+	fn buy_request_round_trips_announce_channel() {
+		let mut request = BuyRequest {
+			version: 1,
+			opening_fee_params: test_opening_fee_params(),
+			payment_size_msat: None,
+			refund_onchain_address: None,
+			announce_channel: Some(true),
+		};
+
+		let json = serde_json::to_value(&request).unwrap();
+		assert_eq!(json["announce_channel"], true);
+		assert_eq!(serde_json::from_value::<BuyRequest>(json).unwrap(), request);
+
+		request.announce_channel = None;
+		let json = serde_json::to_value(&request).unwrap();
+		assert!(json.get("announce_channel").is_none());
+		assert_eq!(serde_json::from_value::<BuyRequest>(json).unwrap(), request);
+	}
+
+	#[test]
+	fn token_is_redacted_but_serialized() {
+		let secret = "s3cr3t-api-key".to_string();
+		let token = Token::new(secret.clone());
+		assert_eq!(format!("{:?}", token), "Token(***)");
+		assert!(!format!("{}", token).contains(&secret));
+
+		let request = GetInfoRequest { version: 1, token: Some(token) };
+		assert!(!format!("{:?}", request).contains(&secret));
+
+		let json = serde_json::to_string(&request).unwrap();
+		assert_eq!(json, r#"{"version":1,"token":"s3cr3t-api-key"}"#);
+		assert_eq!(serde_json::from_str::<GetInfoRequest>(&json).unwrap(), request);
+	}
+
+	#[test]
+	fn equal_valid_until_representations_produce_identical_promises() {
+		let promise_secret = [1u8; 32];
+		let promise_for = |valid_until: &str| {
+			let mut raw = test_opening_fee_params().into_raw();
+			raw.valid_until = chrono::DateTime::parse_from_rfc3339(valid_until).unwrap().into();
+			raw.into_opening_fee_params(&promise_secret, PromiseVersion::V1)
+		};
+
+		let canonical = promise_for("2035-05-20T08:30:45Z");
+		assert_eq!(promise_for("2035-05-20T08:30:45.000Z"), canonical);
+		assert_eq!(promise_for("2035-05-20T08:30:45.000000+00:00"), canonical);
+		assert_eq!(promise_for("2035-05-20T10:30:45+02:00"), canonical);
+
+		// Sub-second precision isn't part of the promise and is dropped.
+		let truncated = promise_for("2035-05-20T08:30:45.123456789Z");
+		assert_eq!(truncated, canonical);
+		assert!(is_valid_opening_fee_params(&truncated, &promise_secret, PromiseVersion::V1));
+
+		let json = serde_json::to_value(&canonical).unwrap();
+		assert_eq!(json["valid_until"], "2035-05-20T08:30:45Z");
+		let deserialized: OpeningFeeParams = serde_json::from_value(json).unwrap();
+		assert!(is_valid_opening_fee_params(&deserialized, &promise_secret, PromiseVersion::V1));
+	}
+
+	#[test]
+	fn jit_channel_scid_try_from_str() {
+		let scid = JitChannelScid::try_from("820000x42x1").unwrap();
+		assert_eq!(scid, JitChannelScid::from(scid.to_scid().unwrap()));
+		assert_eq!(scid.to_scid().unwrap(), (820000 << 40) | (42 << 16) | 1);
+
+		assert!(JitChannelScid::try_from("").is_err());
+		assert!(JitChannelScid::try_from("820000x42").is_err());
+		assert!(JitChannelScid::try_from("820000x42x1x0").is_err());
+		assert!(JitChannelScid::try_from("820000xAx1").is_err());
+		assert!(JitChannelScid::try_from("820000x42x65536").is_err());
+		assert!(JitChannelScid::try_from("16777216x0x0").is_err());
+	}
+
+	#[test]
+	fn buy_request_lists_the_defined_error_codes() {
+		let request = LSPS2Request::Buy(BuyRequest {
+			version: 1,
+			opening_fee_params: test_opening_fee_params(),
+			payment_size_msat: None,
+			refund_onchain_address: None,
+			announce_channel: None,
+		});
+		assert_eq!(request.possible_error_codes(), &[1, 2, 3, 4]);
+
+		let request = LSPS2Request::GetVersions(GetVersionsRequest {});
+		assert!(request.possible_error_codes().is_empty());
 	}
 }
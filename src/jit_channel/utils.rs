@@ -2,20 +2,77 @@ use bitcoin::hashes::hmac::{Hmac, HmacEngine};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::{Hash, HashEngine};
 
+use chrono::{SecondsFormat, Utc};
+
 use std::convert::TryInto;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::jit_channel::msgs::OpeningFeeParams;
+use crate::jit_channel::msgs::{OpeningFeeParams, RawOpeningFeeParams};
 use crate::utils;
 
-/// Determines if the given parameters are valid given the secret used to generate the promise.
+const PROMISE_DOMAIN_SEPARATION_TAG: &[u8] = b"LSPS2 opening_fee_params promise";
+
+/// The scheme used to calculate the promise of [`OpeningFeeParams`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PromiseVersion {
+	/// The promise is an HMAC over the fee parameters only.
+	V1,
+	/// The promise is an HMAC over a domain separation tag, the LSPS2 protocol version the
+	/// parameters were negotiated for, and the fee parameters.
+	V2 { protocol_version: u16 },
+}
+
+/// Returns the canonical string representation of a `valid_until` timestamp, i.e., RFC3339 in UTC
+/// with second precision and a `Z` suffix.
+///
+/// Used both on the wire and as HMAC input so that equal timestamps always produce equal promises.
+pub(crate) fn canonical_valid_until(valid_until: &chrono::DateTime<Utc>) -> String {
+	valid_until.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Returns the identifier of the given promise secret, allowing to look it up without revealing it.
+pub(crate) fn promise_key_id(promise_secret: &[u8; 32]) -> String {
+	let key_hash = Sha256::hash(promise_secret).into_inner();
+	utils::hex_str(&key_hash[..8])
+}
+
+/// Calculates the promise for the given parameters.
+pub(crate) fn compute_promise(
+	raw: &RawOpeningFeeParams, promise_secret: &[u8; 32], promise_version: PromiseVersion,
+) -> String {
+	let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
+	if let PromiseVersion::V2 { protocol_version } = promise_version {
+		hmac.input(PROMISE_DOMAIN_SEPARATION_TAG);
+		hmac.input(&protocol_version.to_be_bytes());
+	}
+	hmac.input(&raw.min_fee_msat.to_be_bytes());
+	hmac.input(&raw.proportional.to_be_bytes());
+	hmac.input(canonical_valid_until(&raw.valid_until).as_bytes());
+	hmac.input(&raw.min_lifetime.to_be_bytes());
+	hmac.input(&raw.max_client_to_self_delay.to_be_bytes());
+	let promise_bytes = Hmac::from_engine(hmac).into_inner();
+	utils::hex_str(&promise_bytes[..])
+}
+
+/// Determines if the given parameters are currently valid given the secret used to generate the
+/// promise.
+#[cfg(test)]
 pub fn is_valid_opening_fee_params(
-	fee_params: &OpeningFeeParams, promise_secret: &[u8; 32],
+	fee_params: &OpeningFeeParams, promise_secret: &[u8; 32], promise_version: PromiseVersion,
+) -> bool {
+	is_valid_opening_fee_params_at(
+		fee_params,
+		promise_secret,
+		promise_version,
+		utils::duration_since_epoch().as_secs(),
+	)
+}
+
+/// Determines if the given parameters are valid at the given time, in seconds since the unix
+/// epoch, given the secret used to generate the promise.
+pub(crate) fn is_valid_opening_fee_params_at(
+	fee_params: &OpeningFeeParams, promise_secret: &[u8; 32], promise_version: PromiseVersion,
+	seconds_since_epoch: u64,
 ) -> bool {
-	let seconds_since_epoch = SystemTime::now()
-		.duration_since(UNIX_EPOCH)
-		.expect("system clock to be ahead of the unix epoch")
-		.as_secs();
 	let valid_until_seconds_since_epoch = fee_params
 		.valid_until
 		.timestamp()
@@ -25,17 +82,22 @@ pub fn is_valid_opening_fee_params(
 		return false;
 	}
 
-	let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
-	hmac.input(&fee_params.min_fee_msat.to_be_bytes());
-	hmac.input(&fee_params.proportional.to_be_bytes());
-	hmac.input(fee_params.valid_until.to_rfc3339().as_bytes());
-	hmac.input(&fee_params.min_lifetime.to_be_bytes());
-	hmac.input(&fee_params.max_client_to_self_delay.to_be_bytes());
-	let promise_bytes = Hmac::from_engine(hmac).into_inner();
-	let promise = utils::hex_str(&promise_bytes[..]);
+	let promise = compute_promise(&fee_params.clone().into_raw(), promise_secret, promise_version);
 	promise == fee_params.promise
 }
 
+/// Returns the entries of the given menu that didn't expire yet at the given time.
+///
+/// Meant for clients to filter the menu of a [`GetInfoResponse`] before selecting an entry. As
+/// only the LSP knows the secret the promises were calculated with, this doesn't verify them.
+///
+/// [`GetInfoResponse`]: crate::jit_channel::GetInfoResponse
+pub fn usable_menu_entries(
+	menu: &[OpeningFeeParams], now: chrono::DateTime<Utc>,
+) -> Vec<&OpeningFeeParams> {
+	menu.iter().filter(|fee_params| fee_params.valid_until >= now).collect()
+}
+
 /// Computes the opening fee given a payment size and the fee parameters.
 ///
 /// Returns [`Option::None`] when the computation overflows.
@@ -50,3 +112,37 @@ pub fn compute_opening_fee(
 	let t4 = std::cmp::max(t3, opening_fee_min_fee_msat);
 	Some(t4)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn menu_entry(valid_until: chrono::DateTime<Utc>) -> OpeningFeeParams {
+		OpeningFeeParams {
+			min_fee_msat: 546_000,
+			proportional: 1_000,
+			valid_until,
+			min_lifetime: 1_008,
+			max_client_to_self_delay: 2_016,
+			promise: "ceb6f6d1b4e7a34a8f2b2b8e3f9ac1d6".to_string(),
+			key_id: None,
+		}
+	}
+
+	#[test]
+	fn usable_menu_entries_skips_expired_entries() {
+		let now: chrono::DateTime<Utc> =
+			chrono::DateTime::parse_from_rfc3339("2023-05-20T08:30:45Z").unwrap().into();
+		let menu = vec![
+			menu_entry(now - chrono::Duration::days(1)),
+			menu_entry(now + chrono::Duration::hours(1)),
+			menu_entry(now - chrono::Duration::seconds(1)),
+			menu_entry(now),
+			menu_entry(now + chrono::Duration::days(1)),
+		];
+
+		let usable = usable_menu_entries(&menu, now);
+		assert_eq!(usable, vec![&menu[1], &menu[3], &menu[4]]);
+		assert!(usable_menu_entries(&menu, now + chrono::Duration::days(2)).is_empty());
+	}
+}
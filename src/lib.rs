@@ -18,13 +18,26 @@
 #![allow(clippy::drop_non_drop)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+#[macro_use]
+mod macros;
+
 mod channel_request;
 pub mod events;
 pub mod jit_channel;
+mod metrics;
+mod snapshot;
+#[cfg(test)]
+mod test_utils;
 mod transport;
 mod utils;
 
+pub use metrics::AtomicMetrics;
+pub use snapshot::{JitChannelSnapshot, ManagerSnapshot, PeerSnapshot, RequestSnapshot};
 pub use transport::message_handler::{
-	JITChannelsConfig, LiquidityManager, LiquidityProviderConfig,
+	JITChannelsConfig, LiquidityManager, LiquidityProviderConfig, UnknownProtocolPolicy,
+};
+pub use transport::msgs::{
+	RawLSPSMessage, RequestId, RequestMethodStore, ResponseError, DEFAULT_MAX_PAYLOAD_LENGTH,
+	LSPS_MESSAGE_TYPE_ID, MAX_REQUEST_ID_LENGTH,
 };
-pub use transport::msgs::{RawLSPSMessage, LSPS_MESSAGE_TYPE_ID};
+pub use transport::roles::{LspClient, LspServer};
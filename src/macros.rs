@@ -0,0 +1,49 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Logging macros mirroring the ones exported by `lightning`.
+//!
+//! We can't reuse LDK's macros directly, as they check for its `max_level_*` features which
+//! are not defined for this crate.
+
+macro_rules! log_given_level {
+	($logger: expr, $lvl: expr, $($arg: tt)+) => {
+		$logger.log(&lightning::util::logger::Record::new(
+			$lvl,
+			format_args!($($arg)+),
+			module_path!(),
+			file!(),
+			line!(),
+		))
+	};
+}
+
+macro_rules! log_error {
+	($logger: expr, $($arg: tt)+) => {
+		log_given_level!($logger, lightning::util::logger::Level::Error, $($arg)+)
+	};
+}
+
+macro_rules! log_info {
+	($logger: expr, $($arg: tt)+) => {
+		log_given_level!($logger, lightning::util::logger::Level::Info, $($arg)+)
+	};
+}
+
+macro_rules! log_debug {
+	($logger: expr, $($arg: tt)+) => {
+		log_given_level!($logger, lightning::util::logger::Level::Debug, $($arg)+)
+	};
+}
+
+macro_rules! log_trace {
+	($logger: expr, $($arg: tt)+) => {
+		log_given_level!($logger, lightning::util::logger::Level::Trace, $($arg)+)
+	};
+}
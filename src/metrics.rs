@@ -0,0 +1,120 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Metrics about the messages a [`LiquidityManager`] exchanges, e.g., to be scraped by Prometheus.
+//!
+//! [`LiquidityManager`]: crate::LiquidityManager
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Counts the messages a [`LiquidityManager`] exchanges with its peers.
+///
+/// Opt in by passing it to [`LiquidityManager::set_metrics`], then serve the output of
+/// [`Self::render_prometheus`] to be scraped.
+///
+/// [`LiquidityManager`]: crate::LiquidityManager
+/// [`LiquidityManager::set_metrics`]: crate::LiquidityManager::set_metrics
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+	messages_received: Mutex<BTreeMap<String, u64>>,
+	messages_sent: Mutex<BTreeMap<String, u64>>,
+	error_responses_received: Mutex<BTreeMap<i32, u64>>,
+	error_responses_sent: Mutex<BTreeMap<i32, u64>>,
+	active_negotiation_peers: AtomicU64,
+}
+
+impl AtomicMetrics {
+	/// Creates a new [`AtomicMetrics`] with all counters at zero.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Records a message received with the given method and, for error responses, error code.
+	pub(crate) fn message_received(&self, method: Option<&str>, error_code: Option<i32>) {
+		record(&self.messages_received, &self.error_responses_received, method, error_code);
+	}
+
+	/// Records a message sent with the given method and, for error responses, error code.
+	pub(crate) fn message_sent(&self, method: Option<&str>, error_code: Option<i32>) {
+		record(&self.messages_sent, &self.error_responses_sent, method, error_code);
+	}
+
+	pub(crate) fn set_active_negotiation_peers(&self, count: usize) {
+		self.active_negotiation_peers.store(count as u64, Ordering::Release);
+	}
+
+	/// Renders the metrics in the Prometheus text exposition format.
+	pub fn render_prometheus(&self) -> String {
+		let mut output = String::new();
+		render_counter(
+			&mut output,
+			"lsps_messages_received_total",
+			"The number of LSPS messages received, by method.",
+			"method",
+			&self.messages_received.lock().unwrap(),
+		);
+		render_counter(
+			&mut output,
+			"lsps_messages_sent_total",
+			"The number of LSPS messages sent, by method.",
+			"method",
+			&self.messages_sent.lock().unwrap(),
+		);
+		render_counter(
+			&mut output,
+			"lsps_error_responses_received_total",
+			"The number of LSPS error responses received, by error code.",
+			"code",
+			&self.error_responses_received.lock().unwrap(),
+		);
+		render_counter(
+			&mut output,
+			"lsps_error_responses_sent_total",
+			"The number of LSPS error responses sent, by error code.",
+			"code",
+			&self.error_responses_sent.lock().unwrap(),
+		);
+		let name = "lsps_active_negotiation_peers";
+		writeln!(
+			output,
+			"# HELP {} The number of peers with in-flight JIT channel negotiations.",
+			name
+		)
+		.unwrap();
+		writeln!(output, "# TYPE {} gauge", name).unwrap();
+		writeln!(output, "{} {}", name, self.active_negotiation_peers.load(Ordering::Acquire))
+			.unwrap();
+		output
+	}
+}
+
+fn record(
+	messages: &Mutex<BTreeMap<String, u64>>, error_responses: &Mutex<BTreeMap<i32, u64>>,
+	method: Option<&str>, error_code: Option<i32>,
+) {
+	if let Some(method) = method {
+		*messages.lock().unwrap().entry(method.to_string()).or_default() += 1;
+	}
+	if let Some(error_code) = error_code {
+		*error_responses.lock().unwrap().entry(error_code).or_default() += 1;
+	}
+}
+
+fn render_counter<K: Display>(
+	output: &mut String, name: &str, help: &str, label: &str, values: &BTreeMap<K, u64>,
+) {
+	writeln!(output, "# HELP {} {}", name, help).unwrap();
+	writeln!(output, "# TYPE {} counter", name).unwrap();
+	for (value, count) in values {
+		writeln!(output, "{}{{{}=\"{}\"}} {}", name, label, value, count).unwrap();
+	}
+}
@@ -0,0 +1,71 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Snapshots of the state of a [`LiquidityManager`] for debugging purposes.
+//!
+//! [`LiquidityManager`]: crate::LiquidityManager
+
+use serde::Serialize;
+
+/// A snapshot of the state of a [`LiquidityManager`], e.g., to attach to a bug report.
+///
+/// Obtained via [`LiquidityManager::debug_snapshot`]. Sensitive data, such as tokens and promise
+/// secrets, is never included.
+///
+/// [`LiquidityManager`]: crate::LiquidityManager
+/// [`LiquidityManager::debug_snapshot`]: crate::LiquidityManager::debug_snapshot
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ManagerSnapshot {
+	/// The hash of the best block we know of.
+	pub best_block_hash: String,
+	/// The height of the best block we know of.
+	pub best_block_height: u32,
+	/// The peers we hold JIT channel state for, ordered by node id.
+	pub peers: Vec<PeerSnapshot>,
+}
+
+/// The JIT channel state held for a single peer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerSnapshot {
+	/// The node id of the peer.
+	pub counterparty_node_id: String,
+	/// The LSPS2 protocol version negotiated with the peer, if any.
+	pub negotiated_version: Option<u16>,
+	/// The requests we sent to the peer that weren't responded to yet.
+	pub sent_requests: Vec<RequestSnapshot>,
+	/// The requests we received from the peer that weren't responded to yet.
+	pub received_requests: Vec<RequestSnapshot>,
+	/// The JIT channels we are buying from the peer.
+	pub inbound_channels: Vec<JitChannelSnapshot>,
+	/// The JIT channels we are selling to the peer.
+	pub outbound_channels: Vec<JitChannelSnapshot>,
+}
+
+/// A request awaiting a response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RequestSnapshot {
+	/// The id of the request.
+	pub request_id: String,
+	/// The JSON-RPC method of the request.
+	pub method: String,
+}
+
+/// A JIT channel being negotiated or opened.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JitChannelSnapshot {
+	/// The `user_channel_id` the JIT channel is tracked by.
+	pub user_channel_id: u128,
+	/// The intercept scid handed out for the JIT channel, if any.
+	pub short_channel_id: Option<u64>,
+	/// The state of the JIT channel, as returned by
+	/// [`LiquidityManager::jit_channel_state`], if known.
+	///
+	/// [`LiquidityManager::jit_channel_state`]: crate::LiquidityManager::jit_channel_state
+	pub state: Option<String>,
+}
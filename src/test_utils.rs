@@ -0,0 +1,461 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities shared by the unit tests.
+
+use crate::events::Event;
+use crate::jit_channel::msgs::RawOpeningFeeParams;
+use crate::jit_channel::{LSPS2Event, ScidAllocator};
+use crate::transport::message_handler::{
+	JITChannelsConfig, LiquidityManager, LiquidityProviderConfig,
+};
+use crate::transport::msgs::RequestMethodStore;
+use crate::utils;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, MonitorEvent};
+use lightning::chain::transaction::OutPoint;
+use lightning::chain::{
+	self, BestBlock, ChannelMonitorUpdateStatus, Filter, Listen, WatchedOutput,
+};
+use lightning::ln::channelmanager::{ChainParameters, ChannelManager};
+use lightning::ln::msgs::LightningError;
+use lightning::ln::peer_handler::{
+	CustomMessageHandler, ErroringMessageHandler, IgnoringMessageHandler, SocketDescriptor,
+};
+use lightning::routing::router::{InFlightHtlcs, Route, RouteParameters, Router};
+use lightning::sign::{InMemorySigner, KeysManager};
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
+use lightning::util::logger::{Logger, Record};
+
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Block, BlockHeader, Network, Script, Transaction, TxMerkleNode, Txid};
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) struct TestFeeEstimator {
+	pub sat_per_kw: AtomicU32,
+}
+
+impl TestFeeEstimator {
+	pub fn new(sat_per_kw: u32) -> Self {
+		Self { sat_per_kw: AtomicU32::new(sat_per_kw) }
+	}
+}
+
+impl FeeEstimator for TestFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u32 {
+		self.sat_per_kw.load(Ordering::Acquire)
+	}
+}
+
+pub(crate) struct TestBroadcaster {}
+
+impl BroadcasterInterface for TestBroadcaster {
+	fn broadcast_transactions(&self, _txs: &[&Transaction]) {}
+}
+
+pub(crate) struct TestRouter {}
+
+impl Router for TestRouter {
+	fn find_route(
+		&self, _payer: &PublicKey, _route_params: &RouteParameters,
+		_first_hops: Option<&[&lightning::ln::channelmanager::ChannelDetails]>,
+		_inflight_htlcs: InFlightHtlcs,
+	) -> Result<Route, LightningError> {
+		Err(LightningError {
+			err: "Routing is not supported in tests".to_string(),
+			action: lightning::ln::msgs::ErrorAction::IgnoreError,
+		})
+	}
+}
+
+#[derive(Default)]
+pub(crate) struct TestLogger {
+	pub lines: Mutex<Vec<(lightning::util::logger::Level, String)>>,
+}
+
+impl Logger for TestLogger {
+	fn log(&self, record: &Record) {
+		self.lines.lock().unwrap().push((record.level, format!("{}", record.args)));
+	}
+}
+
+pub(crate) struct TestChainMonitor {}
+
+impl chain::Watch<InMemorySigner> for TestChainMonitor {
+	fn watch_channel(
+		&self, _funding_txo: OutPoint, _monitor: ChannelMonitor<InMemorySigner>,
+	) -> Result<ChannelMonitorUpdateStatus, ()> {
+		Ok(ChannelMonitorUpdateStatus::Completed)
+	}
+
+	fn update_channel(
+		&self, _funding_txo: OutPoint, _update: &ChannelMonitorUpdate,
+	) -> ChannelMonitorUpdateStatus {
+		ChannelMonitorUpdateStatus::Completed
+	}
+
+	fn release_pending_monitor_events(
+		&self,
+	) -> Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)> {
+		Vec::new()
+	}
+}
+
+#[derive(Default)]
+pub(crate) struct TestFilter {
+	pub registered_txs: Mutex<Vec<(Txid, Script)>>,
+	pub registered_outputs: Mutex<Vec<WatchedOutput>>,
+}
+
+impl Filter for TestFilter {
+	fn register_tx(&self, txid: &Txid, script_pubkey: &Script) {
+		self.registered_txs.lock().unwrap().push((*txid, script_pubkey.clone()));
+	}
+
+	fn register_output(&self, output: WatchedOutput) {
+		self.registered_outputs.lock().unwrap().push(output);
+	}
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct TestDescriptor {}
+
+impl SocketDescriptor for TestDescriptor {
+	fn send_data(&mut self, data: &[u8], _resume_read: bool) -> usize {
+		data.len()
+	}
+
+	fn disconnect_socket(&mut self) {}
+}
+
+pub(crate) type TestLiquidityManager = LiquidityManager<
+	Arc<KeysManager>,
+	Arc<TestChainMonitor>,
+	Arc<TestBroadcaster>,
+	Arc<TestFeeEstimator>,
+	Arc<TestRouter>,
+	Arc<KeysManager>,
+	Arc<TestLogger>,
+	TestDescriptor,
+	Arc<IgnoringMessageHandler>,
+	Arc<ErroringMessageHandler>,
+	Arc<IgnoringMessageHandler>,
+	Arc<IgnoringMessageHandler>,
+	Arc<KeysManager>,
+	Arc<TestFilter>,
+>;
+
+/// Hands out the scids queued via [`Self::queue_scid`], or fresh ones otherwise.
+pub(crate) struct TestScidAllocator {
+	queued_scids: Mutex<VecDeque<u64>>,
+	next_fresh_scid: AtomicU64,
+}
+
+impl Default for TestScidAllocator {
+	fn default() -> Self {
+		// Fresh scids are far off the small ones tests queue.
+		Self { queued_scids: Mutex::new(VecDeque::new()), next_fresh_scid: AtomicU64::new(1 << 40) }
+	}
+}
+
+impl TestScidAllocator {
+	/// Makes the given scid the one allocated for the next buy request.
+	pub fn queue_scid(&self, scid: u64) {
+		self.queued_scids.lock().unwrap().push_back(scid);
+	}
+}
+
+impl ScidAllocator for TestScidAllocator {
+	fn allocate_scid(&self) -> Option<u64> {
+		let queued_scid = self.queued_scids.lock().unwrap().pop_front();
+		Some(queued_scid.unwrap_or_else(|| self.next_fresh_scid.fetch_add(1, Ordering::AcqRel)))
+	}
+}
+
+pub(crate) struct Node {
+	pub node_id: PublicKey,
+	pub liquidity_manager: TestLiquidityManager,
+	pub fee_estimator: Arc<TestFeeEstimator>,
+	pub logger: Arc<TestLogger>,
+	pub filter: Arc<TestFilter>,
+	/// Allocates the intercept scids of the node as LSP, unless another allocator was configured.
+	pub scid_allocator: Arc<TestScidAllocator>,
+}
+
+impl Node {
+	/// Connects `blocks` empty blocks on top of the node's best block and moves the clock forward
+	/// by `duration`, so that both block- and time-based expiries can be exercised in one step.
+	pub fn advance_time_and_blocks(&self, blocks: u32, duration: Duration) {
+		for _ in 0..blocks {
+			let best_block = self.liquidity_manager.best_block();
+			let header = BlockHeader {
+				version: 0x2000_0000,
+				prev_blockhash: best_block.block_hash(),
+				merkle_root: TxMerkleNode::all_zeros(),
+				time: 42,
+				bits: 0,
+				nonce: 0,
+			};
+			let block = Block { header, txdata: Vec::new() };
+			self.liquidity_manager.block_connected(&block, best_block.height() + 1);
+		}
+		utils::advance_time(duration);
+	}
+}
+
+pub(crate) fn default_jit_channels_config() -> JITChannelsConfig {
+	JITChannelsConfig {
+		promise_secret: [42u8; 32],
+		min_payment_size_msat: 1_000,
+		max_payment_size_msat: 100_000_000,
+		max_valid_until_horizon: Duration::from_secs(50 * 365 * 24 * 60 * 60),
+		min_funding_confirmations: 1,
+		..Default::default()
+	}
+}
+
+pub(crate) fn create_node(seed: u8, jit_channels_config: Option<JITChannelsConfig>) -> Node {
+	let provider_config = jit_channels_config
+		.map(|config| LiquidityProviderConfig { jit_channels: Some(config), ..Default::default() });
+	create_node_with_provider_config(seed, provider_config)
+}
+
+pub(crate) fn create_node_with_provider_config(
+	seed: u8, provider_config: Option<LiquidityProviderConfig>,
+) -> Node {
+	create_node_with_request_method_store(seed, provider_config, Box::new(HashMap::new()))
+}
+
+/// Creates a node whose intercept scids are allocated by [`Node::scid_allocator`], unless another
+/// allocator was configured, so tests may choose the scids handed out.
+pub(crate) fn create_node_with_request_method_store(
+	seed: u8, mut provider_config: Option<LiquidityProviderConfig>,
+	request_method_store: Box<dyn RequestMethodStore + Send>,
+) -> Node {
+	let scid_allocator = Arc::new(TestScidAllocator::default());
+	if let Some(jit_channels_config) =
+		provider_config.as_mut().and_then(|config| config.jit_channels.as_mut())
+	{
+		if jit_channels_config.scid_allocator.is_none() {
+			jit_channels_config.scid_allocator =
+				Some(Arc::clone(&scid_allocator) as Arc<dyn ScidAllocator + Send + Sync>);
+		}
+	}
+	let user_config = UserConfig { accept_intercept_htlcs: true, ..Default::default() };
+	let node = try_create_node(seed, provider_config, request_method_store, user_config).unwrap();
+	Node { scid_allocator, ..node }
+}
+
+pub(crate) fn try_create_node(
+	seed: u8, provider_config: Option<LiquidityProviderConfig>,
+	request_method_store: Box<dyn RequestMethodStore + Send>, user_config: UserConfig,
+) -> Result<Node, APIError> {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	let keys_manager = Arc::new(KeysManager::new(&[seed; 32], now.as_secs(), now.subsec_nanos()));
+	let fee_estimator = Arc::new(TestFeeEstimator::new(253));
+	let logger = Arc::new(TestLogger::default());
+	let filter = Arc::new(TestFilter::default());
+
+	let network = Network::Regtest;
+	let chain_params = || ChainParameters { network, best_block: BestBlock::from_network(network) };
+
+	let channel_manager = Arc::new(ChannelManager::new(
+		Arc::clone(&fee_estimator),
+		Arc::new(TestChainMonitor {}),
+		Arc::new(TestBroadcaster {}),
+		Arc::new(TestRouter {}),
+		Arc::clone(&logger),
+		Arc::clone(&keys_manager),
+		Arc::clone(&keys_manager),
+		Arc::clone(&keys_manager),
+		user_config,
+		chain_params(),
+		now.as_secs() as u32,
+	));
+
+	let liquidity_manager = LiquidityManager::new_with_request_method_store(
+		Arc::clone(&keys_manager),
+		provider_config,
+		Arc::clone(&channel_manager),
+		Arc::clone(&fee_estimator),
+		Arc::clone(&logger),
+		Some(Arc::clone(&filter)),
+		chain_params(),
+		request_method_store,
+	)?;
+
+	Ok(Node {
+		node_id: channel_manager.get_our_node_id(),
+		liquidity_manager,
+		fee_estimator,
+		logger,
+		filter,
+		scid_allocator: Arc::new(TestScidAllocator::default()),
+	})
+}
+
+/// Delivers all messages `from` has queued for `to`, returning how many were delivered.
+pub(crate) fn pass_messages(from: &Node, to: &Node) -> usize {
+	let msgs = from.liquidity_manager.get_and_clear_pending_msg();
+	let mut delivered = 0;
+	for (node_id, msg) in msgs {
+		assert_eq!(node_id, to.node_id);
+		to.liquidity_manager.handle_custom_message(msg, &from.node_id).unwrap();
+		delivered += 1;
+	}
+	delivered
+}
+
+/// Delivers all messages `from` has queued to their respective recipient among `to`, returning how
+/// many were delivered.
+pub(crate) fn pass_messages_to_any(from: &Node, to: &[&Node]) -> usize {
+	let msgs = from.liquidity_manager.get_and_clear_pending_msg();
+	let mut delivered = 0;
+	for (node_id, msg) in msgs {
+		let recipient = to.iter().find(|node| node.node_id == node_id).expect("unknown recipient");
+		recipient.liquidity_manager.handle_custom_message(msg, &from.node_id).unwrap();
+		delivered += 1;
+	}
+	delivered
+}
+
+pub(crate) fn get_events(node: &Node) -> Vec<Event> {
+	node.liquidity_manager.get_and_clear_pending_events()
+}
+
+pub(crate) fn raw_opening_fee_params(min_fee_msat: u64, proportional: u32) -> RawOpeningFeeParams {
+	RawOpeningFeeParams {
+		min_fee_msat,
+		proportional,
+		valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z").unwrap().into(),
+		min_lifetime: 144,
+		max_client_to_self_delay: 128,
+	}
+}
+
+/// Drives a JIT channel negotiation from `client` with `lsp` until the client received the
+/// `GetInfoResponse`, with the LSP offering the given menu. Returns the client's event.
+pub(crate) fn request_opening_fee_params_menu(
+	client: &Node, lsp: &Node, payment_size_msat: Option<u64>, user_channel_id: u128,
+	menu: Vec<RawOpeningFeeParams>,
+) -> LSPS2Event {
+	client
+		.liquidity_manager
+		.jit_channel_create_invoice(lsp.node_id, payment_size_msat, None, user_channel_id, None)
+		.unwrap();
+
+	// get_versions request and response, unless a version was negotiated with the LSP before, and
+	// get_info request.
+	assert_eq!(pass_messages(client, lsp), 1);
+	let mut events = get_events(lsp);
+	if events.is_empty() {
+		assert_eq!(pass_messages(lsp, client), 1);
+		assert_eq!(pass_messages(client, lsp), 1);
+		events = get_events(lsp);
+	}
+	let request_id = match events.pop() {
+		Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+		event => panic!("Unexpected event: {:?}", event),
+	};
+	lsp.liquidity_manager.opening_fee_params_generated(client.node_id, request_id, menu).unwrap();
+	assert_eq!(pass_messages(lsp, client), 1);
+
+	match get_events(client).pop() {
+		Some(Event::LSPS2(event @ LSPS2Event::GetInfoResponse { .. })) => event,
+		event => panic!("Unexpected event: {:?}", event),
+	}
+}
+
+/// Drives a JIT channel negotiation from `client` with `lsp` until the client received the
+/// invoice parameters, with the LSP handing out the given `scid`. Returns the client's event.
+pub(crate) fn buy_jit_channel(
+	client: &Node, lsp: &Node, payment_size_msat: Option<u64>, user_channel_id: u128, scid: u64,
+) -> LSPS2Event {
+	let (jit_channel_id, opening_fee_params) = match request_opening_fee_params_menu(
+		client,
+		lsp,
+		payment_size_msat,
+		user_channel_id,
+		vec![raw_opening_fee_params(100, 21)],
+	) {
+		LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+			(jit_channel_id, opening_fee_params_menu.remove(0))
+		}
+		event => panic!("Unexpected event: {:?}", event),
+	};
+
+	client
+		.liquidity_manager
+		.opening_fee_params_selected(lsp.node_id, jit_channel_id, opening_fee_params, None, None)
+		.unwrap();
+	lsp.scid_allocator.queue_scid(scid);
+	assert_eq!(pass_messages(client, lsp), 1);
+
+	let request_id = match get_events(lsp).pop() {
+		Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+		event => panic!("Unexpected event: {:?}", event),
+	};
+	lsp.liquidity_manager
+		.invoice_parameters_generated(client.node_id, request_id, scid, 144, false)
+		.unwrap();
+	assert_eq!(pass_messages(lsp, client), 1);
+
+	match get_events(client).pop() {
+		Some(Event::LSPS2(event @ LSPS2Event::InvoiceGenerationReady { .. })) => event,
+		event => panic!("Unexpected event: {:?}", event),
+	}
+}
+
+pub(crate) fn to_vec(hex: &str) -> Option<Vec<u8>> {
+	let mut out = Vec::with_capacity(hex.len() / 2);
+
+	let mut b = 0;
+	for (idx, c) in hex.as_bytes().iter().enumerate() {
+		b <<= 4;
+		match *c {
+			b'A'..=b'F' => b |= c - b'A' + 10,
+			b'a'..=b'f' => b |= c - b'a' + 10,
+			b'0'..=b'9' => b |= c - b'0',
+			_ => return None,
+		}
+		if (idx & 1) == 1 {
+			out.push(b);
+			b = 0;
+		}
+	}
+
+	Some(out)
+}
+
+pub(crate) fn to_compressed_pubkey(hex: &str) -> Option<PublicKey> {
+	if hex.len() != 33 * 2 {
+		return None;
+	}
+	let data = to_vec(&hex[0..33 * 2])?;
+	PublicKey::from_slice(&data).ok()
+}
+
+pub(crate) fn parse_pubkey(pubkey_str: &str) -> Result<PublicKey, std::io::Error> {
+	let pubkey = to_compressed_pubkey(pubkey_str);
+	if pubkey.is_none() {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::Other,
+			"ERROR: unable to parse given pubkey for node",
+		));
+	}
+
+	Ok(pubkey.unwrap())
+}
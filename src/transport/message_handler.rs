@@ -1,37 +1,87 @@
 use crate::events::{Event, EventQueue};
-use crate::jit_channel::channel_manager::JITChannelManager;
-use crate::jit_channel::msgs::{OpeningFeeParams, RawOpeningFeeParams};
-use crate::transport::msgs::RequestId;
-use crate::transport::msgs::{LSPSMessage, RawLSPSMessage, LSPS_MESSAGE_TYPE_ID};
+use crate::jit_channel::channel_manager::{
+	ChannelReadyOutcome, ChannelSizingStrategy, Clock, GetInfoRejectionReason, JITChannelManager,
+	JitChannelState, NegotiatedVersions, ProcessedIntercepts, RouteHintBundle, ScidAllocator,
+};
+use crate::jit_channel::msgs::{
+	LSPS2Message, LSPS2Request, OpeningFeeParams, RawOpeningFeeParams, Token,
+	LSPS2_GET_INFO_METHOD_NAME, LSPS2_SPEC_REVISION,
+};
+use crate::metrics::AtomicMetrics;
+use crate::snapshot::ManagerSnapshot;
+use crate::transport::msgs::{
+	request_protocol_number, response_request_id, RequestId, RequestMethodStore,
+};
+use crate::transport::msgs::{
+	LSPSMessage, RawLSPSMessage, DEFAULT_MAX_PAYLOAD_LENGTH, LSPS_MESSAGE_TYPE_ID,
+};
 use crate::transport::protocol::LSPS0MessageHandler;
+use crate::transport::roles::{LspClient, LspServer};
 
 use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::transaction::OutPoint;
 use lightning::chain::{self, BestBlock, Confirm, Filter, Listen};
+use lightning::events::HTLCDestination;
+use lightning::io;
 use lightning::ln::channelmanager::{ChainParameters, ChannelManager, InterceptId};
 use lightning::ln::features::{InitFeatures, NodeFeatures};
 use lightning::ln::msgs::{
-	ChannelMessageHandler, ErrorAction, LightningError, OnionMessageHandler, RoutingMessageHandler,
+	ChannelMessageHandler, DecodeError, ErrorAction, LightningError, OnionMessageHandler,
+	RoutingMessageHandler,
 };
 use lightning::ln::peer_handler::{CustomMessageHandler, PeerManager, SocketDescriptor};
 use lightning::ln::wire::CustomMessageReader;
 use lightning::ln::ChannelId;
 use lightning::routing::router::Router;
 use lightning::sign::{EntropySource, NodeSigner, SignerProvider};
+use lightning::util::config::ChannelConfig;
 use lightning::util::errors::APIError;
 use lightning::util::logger::{Level, Logger};
-use lightning::util::ser::Readable;
+use lightning::util::ser::{Readable, WithoutLength, Writeable, Writer};
 
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::secp256k1::PublicKey;
-use bitcoin::BlockHash;
+use bitcoin::{BlockHash, Network, Script, Txid};
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 const LSPS_FEATURE_BIT: usize = 729;
 
+/// Returns whether the given features signal support for LSPS, i.e., have either the required or
+/// the optional variant of [`LSPS_FEATURE_BIT`] set.
+fn supports_lsps(features: &InitFeatures) -> bool {
+	// Features are encoded big-endian, so reverse to index them by bit number.
+	let mut flags = WithoutLength(features).encode();
+	flags.reverse();
+	let required_bit = LSPS_FEATURE_BIT - 1;
+	[required_bit, LSPS_FEATURE_BIT]
+		.iter()
+		.any(|bit| flags.get(bit / 8).map_or(false, |byte| byte & (1 << (bit % 8)) != 0))
+}
+
+/// Returns whether the given raw message is an LSPS2 `get_info` request, without fully parsing it.
+fn is_get_info_request(payload: &str) -> bool {
+	serde_json::from_str::<serde_json::Value>(payload)
+		.map_or(false, |message| message["method"] == LSPS2_GET_INFO_METHOD_NAME)
+}
+
+/// Records the method and request id of the given message, if any, on the given span.
+#[cfg(feature = "tracing")]
+fn message_span(span: tracing::Span, msg: &LSPSMessage) -> tracing::Span {
+	if let Some(method) = msg.method() {
+		span.record("method", method);
+	}
+	if let Some(request_id) = msg.request_id() {
+		span.record("request_id", request_id.as_str());
+	}
+	span
+}
+
 /// A trait used to implement a specific LSPS protocol.
 ///
 /// The messages the protocol uses need to be able to be mapped
@@ -45,6 +95,32 @@ pub(crate) trait ProtocolMessageHandler {
 	) -> Result<(), LightningError>;
 }
 
+fn handler_protocol_number<H: ProtocolMessageHandler>(_handler: &H) -> Option<u16> {
+	H::PROTOCOL_NUMBER
+}
+
+/// Rejects an all-zero promise secret, which is what an operator forgetting to set one ends up
+/// with.
+pub(crate) fn check_promise_secret(promise_secret: &[u8; 32]) -> Result<(), APIError> {
+	if promise_secret.iter().all(|byte| *byte == 0) {
+		return Err(APIError::APIMisuseError {
+			err: "The promise_secret must not be all zeros".to_string(),
+		});
+	}
+	Ok(())
+}
+
+/// How to treat requests for LSPS protocols the [`LiquidityManager`] doesn't implement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownProtocolPolicy {
+	/// Silently drop the request.
+	Ignore,
+	/// Log and drop the request.
+	Log,
+	/// Respond with a JSON-RPC `Method not found` error, as mandated by LSPS0.
+	RespondWithError,
+}
+
 /// A configuration for [`LiquidityManager`].
 ///
 /// Allows end-user to configure options when using the [`LiquidityManager`]
@@ -53,18 +129,267 @@ pub struct LiquidityProviderConfig {
 	/// Optional configuration for JIT channels
 	/// should you want to support them.
 	pub jit_channels: Option<JITChannelsConfig>,
+	/// Whether to treat responses that don't match an outstanding request as an error.
+	///
+	/// If set, such responses are logged and surfaced as an [`Event::MismatchedResponse`]
+	/// rather than being answered with a JSON-RPC parse error.
+	pub strict_request_matching: bool,
+	/// The number of malformed messages, e.g., requests with invalid params, after which a peer
+	/// is considered misbehaving.
+	///
+	/// Error responses and responses to requests we no longer track, e.g., as we gave up on them,
+	/// aren't counted.
+	///
+	/// Once reached, any negotiation state with the peer is dropped, an
+	/// [`Event::PeerMisbehaved`] is generated, and all further messages from the peer are
+	/// refused. If [`Option::None`], misbehavior is not tracked.
+	pub max_peer_misbehaviors: Option<u32>,
+	/// How to treat requests for LSPS protocols we don't implement, including LSPS2 requests if
+	/// [`Self::jit_channels`] isn't configured.
+	///
+	/// Without a [`LiquidityProviderConfig`], [`UnknownProtocolPolicy::RespondWithError`] is used.
+	pub unknown_protocol_policy: UnknownProtocolPolicy,
+}
+
+impl Default for LiquidityProviderConfig {
+	fn default() -> Self {
+		Self {
+			jit_channels: None,
+			strict_request_matching: false,
+			max_peer_misbehaviors: None,
+			unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+		}
+	}
 }
 
 /// Configuration options for JIT channels.
 pub struct JITChannelsConfig {
 	/// Used to calculate the promise for channel parameters supplied to clients.
 	///
-	/// Note: If this changes then old promises given out will be considered invalid.
+	/// Note: If this changes then old promises given out will be considered invalid. Use
+	/// [`LiquidityManager::rotate_promise_secret`] to replace the secret while continuing to
+	/// accept outstanding promises for a grace period.
+	///
+	/// Only used by LSPs, which always need to set it to a secret random value. It defaults to all
+	/// zeros, which is rejected once the node acts as LSP, e.g., by
+	/// [`LiquidityManager::opening_fee_params_generated`].
 	pub promise_secret: [u8; 32],
+	/// The number of seconds promises calculated with a secret replaced via
+	/// [`LiquidityManager::rotate_promise_secret`] are still accepted for.
+	pub promise_secret_grace_period_secs: u64,
+	/// Whether to domain-separate promises by including a fixed tag and the LSPS2 protocol
+	/// version in their calculation, preventing their replay across protocol versions.
+	///
+	/// Note: If this changes then old promises given out will be considered invalid.
+	pub domain_separated_promises: bool,
 	/// The minimum payment size you are willing to accept.
 	pub min_payment_size_msat: u64,
 	/// The maximum payment size you are willing to accept.
 	pub max_payment_size_msat: u64,
+	/// If set, the `min_fee_msat` of every entry in the opening fee params menu is raised to at
+	/// least this multiple of the estimated fee of a channel funding transaction at the current
+	/// feerate.
+	pub min_fee_funding_fee_multiplier: Option<u64>,
+	/// Whether an LSP may offer free JIT channels, i.e., opening fee params with both a
+	/// `min_fee_msat` and a `proportional` fee of zero.
+	///
+	/// If unset, [`LiquidityManager::opening_fee_params_generated`] rejects menus with such
+	/// entries, as they are usually a misconfiguration.
+	pub allow_free_channels: bool,
+	/// The LSPS2 protocol versions advertised in response to `lsps2.get_versions` requests.
+	///
+	/// Buy requests for versions not listed here will be rejected. Versions not implemented by
+	/// this crate are ignored.
+	pub supported_versions: Vec<u16>,
+	/// Whether to cache the `get_info` responses received from an LSP.
+	///
+	/// If set, subsequent invoice creations with the same LSP and token will reuse the cached
+	/// opening fee parameters until the earliest `valid_until` of the menu has passed, rather than
+	/// requesting them anew.
+	pub cache_get_info_responses: bool,
+	/// Whether to reject all `get_info` requests carrying a token.
+	///
+	/// Useful for open-access LSPs that don't hand out tokens and want to avoid any ambiguity
+	/// about what a token would grant.
+	pub reject_requests_with_token: bool,
+	/// The maximum time into the future the `valid_until` of an opening fee params menu entry
+	/// may lie.
+	///
+	/// Menus containing entries valid for longer are rejected by
+	/// [`LiquidityManager::opening_fee_params_generated`], guarding against committing to stale
+	/// fees.
+	pub max_valid_until_horizon: Duration,
+	/// The maximum number of entries an opening fee params menu may contain.
+	///
+	/// Longer menus are rejected by [`LiquidityManager::opening_fee_params_generated`], guarding
+	/// against accidentally sending huge responses.
+	pub max_menu_length: usize,
+	/// Whether a client not trusting the LSP defers claiming payments received over a JIT channel
+	/// until the channel funding transaction confirmed.
+	///
+	/// If set, [`LSPS2Event::ClaimPayment`] will only be generated once the funding transaction
+	/// was reported as confirmed via the [`Confirm`] or [`Listen`] interfaces.
+	///
+	/// [`LSPS2Event::ClaimPayment`]: crate::jit_channel::LSPS2Event::ClaimPayment
+	pub await_funding_confirmation: bool,
+	/// Whether a client requires the LSP-trusts-client model, i.e., expects the LSP to broadcast
+	/// the funding transaction before the payment is claimed.
+	///
+	/// If set and a buy response indicates the client is expected to trust the LSP,
+	/// [`LSPS2Event::TrustModelMismatch`] is generated instead of
+	/// [`LSPS2Event::InvoiceGenerationReady`].
+	///
+	/// [`LSPS2Event::TrustModelMismatch`]: crate::jit_channel::LSPS2Event::TrustModelMismatch
+	/// [`LSPS2Event::InvoiceGenerationReady`]: crate::jit_channel::LSPS2Event::InvoiceGenerationReady
+	pub require_lsp_trusts_client: bool,
+	/// Whether a client assumes the client-trusts-LSP model if a buy response omits the
+	/// `client_trusts_lsp` flag.
+	///
+	/// Some older LSPs omit the flag although they expect to be trusted.
+	pub assume_client_trusts_lsp_when_absent: bool,
+	/// The number of confirmations the funding transaction needs before it is considered buried
+	/// if [`Self::await_funding_confirmation`] is set. Typically 3 or 6.
+	///
+	/// A value of 0 is treated as 1.
+	pub min_funding_confirmations: u32,
+	/// How long [`LiquidityManager::jit_channel_create_invoice_multi`] waits for the opening fee
+	/// params menus of the LSP candidates before choosing among the ones received.
+	///
+	/// Note that the timeout is only enforced when [`LiquidityManager::timer_tick_occurred`] is
+	/// called.
+	pub lsp_selection_timeout: Duration,
+	/// If set, an [`LSPS2Event::FeeParamsExpiringSoon`] event is generated once a received
+	/// opening fee params menu is about to expire within this lead time without a selection
+	/// having been made.
+	///
+	/// Note that expiry is only checked when [`LiquidityManager::timer_tick_occurred`] is called.
+	///
+	/// [`LSPS2Event::FeeParamsExpiringSoon`]: crate::jit_channel::LSPS2Event::FeeParamsExpiringSoon
+	pub fee_params_expiry_warning_lead_time: Option<Duration>,
+	/// The strategy the LSP uses to size JIT channels.
+	///
+	/// An LSP uses it to compute the capacity given in the [`LSPS2Event::OpenChannel`] event,
+	/// unless a strategy was set for the client via
+	/// [`LiquidityManager::set_peer_channel_sizing_strategy`]. A client uses it to compute
+	/// [`LiquidityManager::expected_inbound_liquidity_msat`], so it should set it to match its
+	/// LSP's strategy.
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub channel_sizing_strategy: ChannelSizingStrategy,
+	/// Allocates the intercept scid handed out via [`LSPS2Event::BuyRequest::scid`] for every
+	/// valid buy request.
+	///
+	/// If [`Option::None`], fresh intercept scids are obtained from the [`ChannelManager`], which
+	/// never runs out of them. If a custom allocator has no scid available, the client is asked to
+	/// retry later and an [`LSPS2Event::ScidExhausted`] event is generated.
+	///
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	/// [`LSPS2Event::BuyRequest::scid`]: crate::jit_channel::LSPS2Event::BuyRequest::scid
+	/// [`LSPS2Event::ScidExhausted`]: crate::jit_channel::LSPS2Event::ScidExhausted
+	pub scid_allocator: Option<Arc<dyn ScidAllocator + Send + Sync>>,
+	/// If set, consulted instead of the system clock whenever the current time matters, e.g., when
+	/// checking whether the opening fee params supplied with buy requests expired.
+	pub clock: Option<Arc<dyn Clock + Send + Sync>>,
+	/// For how long after a JIT channel became ready further intercepted HTLCs for its scid, e.g.,
+	/// late parts of a multi-part payment, are forwarded over it.
+	///
+	/// HTLCs intercepted after this period are failed.
+	pub late_htlc_grace_period: Duration,
+	/// If set, a `get_info` request identical to one received from the same peer within this
+	/// window, which wasn't answered yet, is coalesced with it, e.g., to absorb client retries.
+	///
+	/// No further [`LSPS2Event::GetInfo`] event is generated for a coalesced request. Instead it's
+	/// answered with the menu passed to [`LiquidityManager::opening_fee_params_generated`] for the
+	/// first request.
+	///
+	/// [`LSPS2Event::GetInfo`]: crate::jit_channel::LSPS2Event::GetInfo
+	pub get_info_dedup_window: Option<Duration>,
+	/// Whether an LSP generates fine-grained events for the steps of handling a buy request, i.e.,
+	/// [`LSPS2Event::BuyRequestReceived`], [`LSPS2Event::BuyRequestValidated`] and
+	/// [`LSPS2Event::BuyResponseSent`], e.g., for observability.
+	///
+	/// [`LSPS2Event::BuyRequestReceived`]: crate::jit_channel::LSPS2Event::BuyRequestReceived
+	/// [`LSPS2Event::BuyRequestValidated`]: crate::jit_channel::LSPS2Event::BuyRequestValidated
+	/// [`LSPS2Event::BuyResponseSent`]: crate::jit_channel::LSPS2Event::BuyResponseSent
+	pub verbose_buy_events: bool,
+	/// Whether a client whose buy request was rejected as the selected opening fee params expired
+	/// meanwhile requests a fresh menu and retries the buy once.
+	///
+	/// A rejection is only attributed to expiry if the `valid_until` of the selected opening fee
+	/// params passed according to the client's clock, see [`JITChannelsConfig::clock`]. Other
+	/// rejections of the opening fee params fail the negotiation as usual.
+	///
+	/// The buy is only retried automatically if the fresh menu offers opening fee params on the
+	/// same terms as the ones selected before. Otherwise, an [`LSPS2Event::GetInfoResponse`] is
+	/// generated for the fresh menu as usual.
+	///
+	/// [`LSPS2Event::GetInfoResponse`]: crate::jit_channel::LSPS2Event::GetInfoResponse
+	pub retry_buy_on_expired_params: bool,
+	/// How often a client retries a `get_versions` or `get_info` request lost as the LSP
+	/// disconnected before responding.
+	///
+	/// Requests are only retried once the LSP reconnected, see [`LiquidityManager::peer_connected`].
+	/// Every retry whose backoff passes while the LSP is still disconnected counts as failed. Once
+	/// no retries are left, the negotiation fails with [`FailureReason::Transport`]. If zero, the
+	/// negotiation fails as soon as the LSP disconnects.
+	///
+	/// [`FailureReason::Transport`]: crate::jit_channel::FailureReason::Transport
+	pub max_transport_retries: u8,
+	/// The backoff before the first retry of a request lost to a disconnect, doubled for every
+	/// further retry. See [`Self::max_transport_retries`].
+	pub transport_retry_backoff: Duration,
+	/// The maximum number of [`LSPS2Event::OpenChannel`] events awaiting a call to
+	/// [`LiquidityManager::channel_ready`] at any time, or [`Option::None`] for no limit.
+	///
+	/// Further events are deferred, in the order the HTLCs were intercepted in, until an earlier
+	/// channel became ready or its opening was abandoned, i.e., it was reported to
+	/// [`LiquidityManager::channel_open_failed`], its intercepted HTLC was reported to
+	/// [`LiquidityManager::htlc_handling_failed`]. Deferred events whose intercepted HTLC failed
+	/// meanwhile are dropped, as are the ones for a client that disconnected, see
+	/// [`LiquidityManager::peer_disconnected`].
+	///
+	/// Must not be zero, as no channel could be opened at all, see [`LiquidityManager::new`].
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub max_pending_channel_opens: Option<usize>,
+}
+
+impl Default for JITChannelsConfig {
+	/// Returns a config without any payment size limits and with all optional behavior disabled.
+	///
+	/// Note that LSPs still need to set [`JITChannelsConfig::promise_secret`].
+	fn default() -> Self {
+		Self {
+			promise_secret: [0; 32],
+			promise_secret_grace_period_secs: 3600,
+			domain_separated_promises: false,
+			min_payment_size_msat: 0,
+			max_payment_size_msat: u64::MAX,
+			min_fee_funding_fee_multiplier: None,
+			allow_free_channels: false,
+			supported_versions: vec![1],
+			cache_get_info_responses: false,
+			reject_requests_with_token: false,
+			max_valid_until_horizon: Duration::from_secs(7 * 24 * 60 * 60),
+			max_menu_length: 16,
+			await_funding_confirmation: false,
+			require_lsp_trusts_client: false,
+			assume_client_trusts_lsp_when_absent: false,
+			min_funding_confirmations: 6,
+			lsp_selection_timeout: Duration::from_secs(60),
+			fee_params_expiry_warning_lead_time: None,
+			channel_sizing_strategy: ChannelSizingStrategy::Exact,
+			scid_allocator: None,
+			clock: None,
+			late_htlc_grace_period: Duration::from_secs(60),
+			get_info_dedup_window: None,
+			verbose_buy_events: false,
+			retry_buy_on_expired_params: false,
+			max_transport_retries: 0,
+			transport_retry_backoff: Duration::from_secs(1),
+			max_pending_channel_opens: None,
+		}
+	}
 }
 
 /// The main interface into LSP functionality.
@@ -79,13 +404,23 @@ pub struct JITChannelsConfig {
 /// Users need to continually poll [`LiquidityManager::get_and_clear_pending_events`] in order to surface
 /// [`Event`]'s that likely need to be handled.
 ///
+/// Any messages resulting from a call into the [`LiquidityManager`], e.g., the response generated
+/// by [`LiquidityManager::opening_fee_params_generated`] when handling an event, are queued before
+/// the call returns. They are therefore always included in the next call to
+/// [`CustomMessageHandler::get_and_clear_pending_msg`], regardless of whether events were drained
+/// in between.
+///
 /// Users must forward the [`Event::HTLCIntercepted`] event parameters to [`LiquidityManager::htlc_intercepted`]
 /// and the [`Event::ChannelReady`] event parameters to [`LiquidityManager::channel_ready`].
+/// To account for the opening fees earned, the [`Event::PaymentForwarded`] event parameters should
+/// be forwarded to [`LiquidityManager::payment_forwarded`].
 ///
+/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
 /// [`PeerManager`]: lightning::ln::peer_handler::PeerManager
 /// [`MessageHandler`]: lightning::ln::peer_handler::MessageHandler
 /// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
 /// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+#[allow(clippy::type_complexity)]
 pub struct LiquidityManager<
 	ES: Deref + Clone,
 	M: Deref,
@@ -118,13 +453,19 @@ pub struct LiquidityManager<
 {
 	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 	pending_events: Arc<EventQueue>,
-	request_id_to_method_map: Mutex<HashMap<String, String>>,
+	logger: L,
+	request_method_store: Mutex<Box<dyn RequestMethodStore + Send>>,
+	peer_lsp_support: RwLock<HashMap<PublicKey, bool>>,
+	misbehavior_counts: Mutex<HashMap<PublicKey, u32>>,
+	jit_channels_paused: AtomicBool,
+	max_payload_length: AtomicU16,
+	metrics: Mutex<Option<Arc<AtomicMetrics>>>,
 	lsps0_message_handler: LSPS0MessageHandler<ES>,
 	lsps2_message_handler:
 		Option<JITChannelManager<ES, M, T, F, R, SP, Descriptor, L, RM, CM, OM, CMH, NS>>,
 	provider_config: Option<LiquidityProviderConfig>,
-	channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>,
 	chain_source: Option<C>,
+	network: Network,
 	genesis_hash: BlockHash,
 	best_block: RwLock<BestBlock>,
 }
@@ -136,7 +477,7 @@ impl<
 		F: Deref,
 		R: Deref,
 		SP: Deref,
-		L: Deref,
+		L: Deref + Clone,
 		Descriptor: SocketDescriptor,
 		RM: Deref,
 		CM: Deref,
@@ -163,22 +504,65 @@ where
 	/// Constructor for the [`LiquidityManager`].
 	///
 	/// Sets up the required protocol message handlers based on the given [`LiquidityProviderConfig`].
+	///
+	/// Fails if [`JITChannelsConfig::max_pending_channel_opens`] is zero.
+	///
+	/// Note that the configuration only required for acting as LSP, i.e., setting
+	/// [`JITChannelsConfig::promise_secret`] and [`UserConfig::accept_intercept_htlcs`], is
+	/// checked by [`Self::opening_fee_params_generated`] and
+	/// [`Self::invoice_parameters_generated`] instead, as clients needn't set either.
+	///
+	/// [`UserConfig::accept_intercept_htlcs`]: lightning::util::config::UserConfig::accept_intercept_htlcs
+	#[allow(clippy::type_complexity)]
 	pub fn new(
 		entropy_source: ES, provider_config: Option<LiquidityProviderConfig>,
-		channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>, chain_source: Option<C>,
-		chain_params: ChainParameters,
-	) -> Self
-where {
+		channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>, fee_estimator: F,
+		logger: L, chain_source: Option<C>, chain_params: ChainParameters,
+	) -> Result<Self, APIError> {
+		Self::new_with_request_method_store(
+			entropy_source,
+			provider_config,
+			channel_manager,
+			fee_estimator,
+			logger,
+			chain_source,
+			chain_params,
+			Box::new(HashMap::new()),
+		)
+	}
+
+	/// Constructor for the [`LiquidityManager`] using the given [`RequestMethodStore`] to keep
+	/// track of the methods of the requests we sent, rather than holding them in memory.
+	///
+	/// Fails under the same conditions as [`Self::new`].
+	///
+	/// [`RequestMethodStore`]: crate::RequestMethodStore
+	#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+	pub fn new_with_request_method_store(
+		entropy_source: ES, provider_config: Option<LiquidityProviderConfig>,
+		channel_manager: Arc<ChannelManager<M, T, ES, NS, SP, F, R, L>>, fee_estimator: F,
+		logger: L, chain_source: Option<C>, chain_params: ChainParameters,
+		request_method_store: Box<dyn RequestMethodStore + Send>,
+	) -> Result<Self, APIError> {
+		if let Some(jit_channels_config) =
+			provider_config.as_ref().and_then(|config| config.jit_channels.as_ref())
+		{
+			if jit_channels_config.max_pending_channel_opens == Some(0) {
+				return Err(APIError::APIMisuseError {
+					err: "max_pending_channel_opens must not be zero".to_string(),
+				});
+			}
+		}
+
 		let pending_messages = Arc::new(Mutex::new(vec![]));
 		let pending_events = Arc::new(EventQueue::default());
 
-		let lsps0_message_handler =
-			LSPS0MessageHandler::new(entropy_source.clone(), vec![], Arc::clone(&pending_messages));
-
 		let lsps2_message_handler = provider_config.as_ref().and_then(|config| {
 			config.jit_channels.as_ref().map(|jit_channels_config| {
 				JITChannelManager::new(
 					entropy_source.clone(),
+					fee_estimator,
+					logger.clone(),
 					jit_channels_config,
 					Arc::clone(&pending_messages),
 					Arc::clone(&pending_events),
@@ -187,18 +571,32 @@ where {
 			})
 		});
 
-		Self {
+		// LSPS0 itself is implied and therefore not advertised.
+		let protocols = lsps2_message_handler.iter().filter_map(handler_protocol_number).collect();
+		let lsps0_message_handler = LSPS0MessageHandler::new(
+			entropy_source.clone(),
+			protocols,
+			Arc::clone(&pending_messages),
+		);
+
+		Ok(Self {
 			pending_messages,
 			pending_events,
-			request_id_to_method_map: Mutex::new(HashMap::new()),
+			logger,
+			request_method_store: Mutex::new(request_method_store),
+			peer_lsp_support: RwLock::new(HashMap::new()),
+			misbehavior_counts: Mutex::new(HashMap::new()),
+			jit_channels_paused: AtomicBool::new(false),
+			max_payload_length: AtomicU16::new(DEFAULT_MAX_PAYLOAD_LENGTH),
+			metrics: Mutex::new(None),
 			lsps0_message_handler,
 			lsps2_message_handler,
 			provider_config,
-			channel_manager,
 			chain_source,
+			network: chain_params.network,
 			genesis_hash: genesis_block(chain_params.network).header.block_hash(),
 			best_block: RwLock::new(chain_params.best_block),
-		}
+		})
 	}
 
 	/// Blocks until next event is ready and returns it.
@@ -208,6 +606,21 @@ where {
 		self.pending_events.wait_next_event()
 	}
 
+	/// Blocks until next event is ready and returns it along with its sequence number.
+	///
+	/// Sequence numbers are assigned in strictly increasing order when events are generated.
+	pub fn wait_next_event_with_sequence_number(&self) -> (u64, Event) {
+		self.pending_events.wait_next_event_with_sequence_number()
+	}
+
+	/// Blocks until next event is ready or `timeout` elapsed, whichever comes first.
+	///
+	/// Returns [`Option::None`] if no event became available in time. This allows to integrate
+	/// event handling into select loops without polling [`Self::get_and_clear_pending_events`].
+	pub fn wait_next_event_timeout(&self, timeout: Duration) -> Option<Event> {
+		self.pending_events.wait_next_event_timeout(timeout)
+	}
+
 	/// Returns and clears all events without blocking.
 	///
 	/// Typically you would spawn a thread or task that calls this in a loop.
@@ -215,6 +628,120 @@ where {
 		self.pending_events.get_and_clear_pending_events()
 	}
 
+	/// Returns and clears all events without blocking, along with their sequence numbers.
+	///
+	/// Events are returned in the order they were generated, i.e., with strictly increasing
+	/// sequence numbers.
+	pub fn get_and_clear_pending_events_with_sequence_numbers(&self) -> Vec<(u64, Event)> {
+		self.pending_events.get_and_clear_pending_events_with_sequence_numbers()
+	}
+
+	/// Stops handing out newly generated events, e.g., to apply backpressure upstream under load.
+	///
+	/// Events generated while paused aren't lost but held back until [`Self::resume_events`] is
+	/// called. Events generated before pausing are still handed out.
+	pub fn pause_events(&self) {
+		self.pending_events.pause();
+	}
+
+	/// Resumes handing out events after [`Self::pause_events`], starting with all events that
+	/// were held back in the meantime, in the order they were generated.
+	pub fn resume_events(&self) {
+		self.pending_events.resume();
+	}
+
+	/// Returns the sequence number of the last event that was handed out, if any.
+	///
+	/// This allows callers to resume consuming events and detect any gaps.
+	pub fn last_consumed_event_sequence_number(&self) -> Option<u64> {
+		self.pending_events.last_consumed_sequence_number()
+	}
+
+	/// Returns a subscription receiving a copy of every event generated from now on.
+	///
+	/// Allows multiple consumers, e.g., a dashboard and a payment processor, to each see every
+	/// event. Events are still queued for [`Self::get_and_clear_pending_events`] and friends as
+	/// usual, so one of the consumers should keep draining them.
+	#[cfg(feature = "event-subscriptions")]
+	pub fn subscribe_events(&self) -> crate::events::EventSubscriber {
+		self.pending_events.subscribe()
+	}
+
+	/// Writes all events that were not handed out yet, so they can be replayed via
+	/// [`Self::restore_pending_events`] after a restart.
+	pub fn write_pending_events<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.pending_events.write(writer)
+	}
+
+	/// Restores events previously persisted via [`Self::write_pending_events`].
+	///
+	/// The restored events are handed out in their original order, and before any events
+	/// generated since this [`LiquidityManager`] was created.
+	///
+	/// Note that events may have been handled after they were persisted, in which case they will
+	/// be surfaced again. Event handling should therefore be idempotent, e.g., by deduplicating
+	/// on the `request_id` or `user_channel_id` carried by the event.
+	pub fn restore_pending_events<RD: io::Read>(&self, reader: &mut RD) -> Result<(), DecodeError> {
+		let restored: EventQueue = Readable::read(reader)?;
+		self.pending_events.restore(restored);
+		Ok(())
+	}
+
+	/// Writes the LSPS2 protocol versions negotiated with LSPs, so they can be restored via
+	/// [`Self::restore_negotiated_versions`] after a restart.
+	pub fn write_negotiated_versions<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match &self.lsps2_message_handler {
+			Some(lsps2_message_handler) => {
+				lsps2_message_handler.negotiated_versions().write(writer)
+			}
+			None => NegotiatedVersions::default().write(writer),
+		}
+	}
+
+	/// Restores the LSPS2 protocol versions previously persisted via
+	/// [`Self::write_negotiated_versions`].
+	///
+	/// JIT channels bought from an LSP with a restored version we still support skip the
+	/// `lsps2.get_versions` request. Versions negotiated since this [`LiquidityManager`] was
+	/// created take precedence.
+	pub fn restore_negotiated_versions<RD: io::Read>(
+		&self, reader: &mut RD,
+	) -> Result<(), DecodeError> {
+		let restored: NegotiatedVersions = Readable::read(reader)?;
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.restore_negotiated_versions(restored);
+		}
+		Ok(())
+	}
+
+	/// Writes the ids of the most recently processed intercepted HTLCs, so they can be restored
+	/// via [`Self::restore_processed_intercepts`] after a restart.
+	pub fn write_processed_intercepts<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match &self.lsps2_message_handler {
+			Some(lsps2_message_handler) => {
+				lsps2_message_handler.processed_intercepts().write(writer)
+			}
+			None => ProcessedIntercepts::default().write(writer),
+		}
+	}
+
+	/// Restores the ids of intercepted HTLCs previously persisted via
+	/// [`Self::write_processed_intercepts`].
+	///
+	/// Replays of [`Event::HTLCIntercepted`] events for restored ids are ignored by
+	/// [`Self::htlc_intercepted`], so we never open a second channel for the same HTLC.
+	///
+	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
+	pub fn restore_processed_intercepts<RD: io::Read>(
+		&self, reader: &mut RD,
+	) -> Result<(), DecodeError> {
+		let restored: ProcessedIntercepts = Readable::read(reader)?;
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.restore_processed_intercepts(restored);
+		}
+		Ok(())
+	}
+
 	/// Set a [`PeerManager`] reference for the message handlers.
 	///
 	/// This allows the message handlers to wake the [`PeerManager`] by calling
@@ -232,30 +759,17 @@ where {
 		}
 	}
 
-	/// Initiate the creation of an invoice that when paid will open a channel
-	/// with enough inbound liquidity to be able to receive the payment.
-	///
-	/// `counterparty_node_id` is the node_id of the LSP you would like to use.
+	/// Used by LSP to replace the secret used to calculate the promise of opening fee parameters.
 	///
-	/// If `payment_size_msat` is [`Option::Some`] then the invoice will be for a fixed amount
-	/// and MPP can be used to pay it.
-	///
-	/// If `payment_size_msat` is [`Option::None`] then the invoice can be for an arbitrary amount
-	/// but MPP can no longer be used to pay it.
+	/// Promises calculated with the previous secret will still be accepted for
+	/// [`JITChannelsConfig::promise_secret_grace_period_secs`] after the rotation, allowing
+	/// clients to buy channels with opening fee parameters they received before.
 	///
-	/// `token` is an optional String that will be provided to the LSP.
-	/// It can be used by the LSP as an API key, coupon code, or some other way to identify a user.
-	pub fn jit_channel_create_invoice(
-		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
-		token: Option<String>, user_channel_id: u128,
-	) -> Result<(), APIError> {
+	/// Fails if the new secret is all zeros.
+	pub fn rotate_promise_secret(&self, new_secret: [u8; 32]) -> Result<(), APIError> {
 		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.create_invoice(
-				counterparty_node_id,
-				payment_size_msat,
-				token,
-				user_channel_id,
-			);
+			check_promise_secret(&new_secret)?;
+			lsps2_message_handler.rotate_promise_secret(new_secret);
 			Ok(())
 		} else {
 			Err(APIError::APIMisuseError {
@@ -265,401 +779,2634 @@ where {
 		}
 	}
 
-	/// Used by LSP to provide fee parameters to a client requesting a JIT Channel.
-	///
-	/// Should be called in response to receiving a [`LSPS2Event::GetInfo`] event.
+	/// Forward the features a peer sent in its `init` message into this function whenever it
+	/// connects.
 	///
-	/// [`LSPS2Event::GetInfo`]: crate::jit_channel::LSPS2Event::GetInfo
-	pub fn opening_fee_params_generated(
-		&self, counterparty_node_id: PublicKey, request_id: RequestId,
-		opening_fee_params_menu: Vec<RawOpeningFeeParams>,
-	) -> Result<(), APIError> {
+	/// Records whether the peer advertised support for LSPS, which can then be queried via
+	/// [`Self::peer_supports_lsp`]. Requests to the peer lost to a previous disconnect are retried
+	/// if configured via [`JITChannelsConfig::max_transport_retries`].
+	pub fn peer_connected(
+		&self, counterparty_node_id: &PublicKey, their_init_features: &InitFeatures,
+	) {
+		self.peer_lsp_support
+			.write()
+			.unwrap()
+			.insert(*counterparty_node_id, supports_lsps(their_init_features));
+
 		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.opening_fee_params_generated(
-				counterparty_node_id,
-				request_id,
-				opening_fee_params_menu,
-			)
-		} else {
-			Err(APIError::APIMisuseError {
-				err: "JIT Channels were not configured when LSPManager was instantiated"
-					.to_string(),
-			})
+			lsps2_message_handler.peer_connected(counterparty_node_id);
 		}
 	}
 
-	/// Used by client to confirm which channel parameters to use for the JIT Channel buy request.
-	/// The client agrees to paying an opening fee equal to
-	/// `max(min_fee_msat, proportional*(payment_size_msat/1_000_000))`.
+	/// Used by client to re-send all requests to the given LSP which weren't responded to yet,
+	/// e.g., as they might have been lost while the peer was disconnected.
 	///
-	/// Should be called in response to receiving a [`LSPS2Event::GetInfoResponse`] event.
+	/// Should be called when the peer reconnected. Returns how many requests were re-sent.
 	///
-	/// [`LSPS2Event::GetInfoResponse`]: crate::jit_channel::LSPS2Event::GetInfoResponse
-	pub fn opening_fee_params_selected(
-		&self, counterparty_node_id: PublicKey, channel_id: u128,
-		opening_fee_params: OpeningFeeParams,
-	) -> Result<(), APIError> {
-		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.opening_fee_params_selected(
-				counterparty_node_id,
-				channel_id,
-				opening_fee_params,
-			)
-		} else {
-			Err(APIError::APIMisuseError {
-				err: "JIT Channels were not configured when LSPManager was instantiated"
-					.to_string(),
-			})
-		}
+	/// Only requests of negotiations that weren't failed as the peer disconnected are re-sent,
+	/// see [`JITChannelsConfig::max_transport_retries`]. Their retries are then no longer due.
+	pub fn resend_pending_requests(&self, counterparty_node_id: &PublicKey) -> usize {
+		self.lsps2_message_handler.as_ref().map_or(0, |lsps2_message_handler| {
+			lsps2_message_handler.resend_pending(counterparty_node_id)
+		})
 	}
 
-	/// Used by LSP to provide client with the scid and cltv_expiry_delta to use in their invoice.
+	/// Used by client to abandon all JIT channel negotiations still awaiting a response from the
+	/// given LSP once it disconnected.
 	///
-	/// Should be called in response to receiving a [`LSPS2Event::BuyRequest`] event.
+	/// Each abandoned negotiation is surfaced with [`FailureReason::Transport`], either via an
+	/// [`LSPS2Event::NegotiationFailed`] event or as part of an LSP selection, unless its
+	/// `get_versions` or `get_info` request is retried as configured via
+	/// [`JITChannelsConfig::max_transport_retries`]. If you'd rather
+	/// resume the negotiations once the peer reconnected, call [`Self::resend_pending_requests`]
+	/// instead.
 	///
-	/// [`LSPS2Event::BuyRequest`]: crate::jit_channel::LSPS2Event::BuyRequest
-	pub fn invoice_parameters_generated(
-		&self, counterparty_node_id: PublicKey, request_id: RequestId, scid: u64,
-		cltv_expiry_delta: u32, client_trusts_lsp: bool,
-	) -> Result<(), APIError> {
+	/// Used by LSP to abandon the JIT channels to the given client whose opening was deferred due
+	/// to [`JITChannelsConfig::max_pending_channel_opens`], failing back their intercepted HTLCs.
+	/// Channels an [`LSPS2Event::OpenChannel`] event was generated for already are left to
+	/// complete.
+	///
+	/// [`FailureReason::Transport`]: crate::jit_channel::FailureReason::Transport
+	/// [`LSPS2Event::NegotiationFailed`]: crate::jit_channel::LSPS2Event::NegotiationFailed
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		self.lsps0_message_handler.peer_disconnected(counterparty_node_id);
+
 		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.invoice_parameters_generated(
-				counterparty_node_id,
-				request_id,
-				scid,
-				cltv_expiry_delta,
-				client_trusts_lsp,
-			)
-		} else {
-			Err(APIError::APIMisuseError {
-				err: "JIT Channels were not configured when LSPManager was instantiated"
-					.to_string(),
-			})
+			lsps2_message_handler.peer_disconnected(counterparty_node_id);
 		}
 	}
 
-	/// Forward [`Event::HTLCIntercepted`] event parameters into this function.
-	///
-	/// Will fail the intercepted HTLC if the scid matches a payment we are expecting
-	/// but the payment amount is incorrect or the expiry has passed.
+	/// Returns the peers with in-flight JIT channel negotiations, ordered by node id.
 	///
-	/// Will generate a [`LSPS2Event::OpenChannel`] event if the scid matches a payment we are expected
-	/// and the payment amount is correct and the offer has not expired.
+	/// A peer counts as active from the first request of a negotiation until the JIT channel was
+	/// opened, no matter whether we act as client or LSP towards it.
+	pub fn active_peers(&self) -> Vec<PublicKey> {
+		self.lsps2_message_handler
+			.as_ref()
+			.map_or(Vec::new(), |lsps2_message_handler| lsps2_message_handler.active_peers())
+	}
+
+	/// Returns the number of peers with in-flight JIT channel negotiations, see
+	/// [`Self::active_peers`].
+	pub fn active_peer_count(&self) -> usize {
+		self.lsps2_message_handler
+			.as_ref()
+			.map_or(0, |lsps2_message_handler| lsps2_message_handler.active_peer_count())
+	}
+
+	/// Used by LSP to fail back all HTLCs intercepted for JIT channels that weren't opened yet,
+	/// rather than leaving them to time out.
 	///
-	/// Will do nothing if the scid does not match any of the ones we gave out.
+	/// Should be called before shutting down the node. Generates an
+	/// [`LSPS2Event::InterceptedHtlcFailedOnShutdown`] event for each failed HTLC.
 	///
-	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
-	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
-	pub fn htlc_intercepted(
-		&self, scid: u64, intercept_id: InterceptId, inbound_amount_msat: u64,
-		expected_outbound_amount_msat: u64,
-	) -> Result<(), APIError> {
+	/// [`LSPS2Event::InterceptedHtlcFailedOnShutdown`]: crate::jit_channel::LSPS2Event::InterceptedHtlcFailedOnShutdown
+	pub fn shutdown(&self) {
 		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.htlc_intercepted(
-				scid,
-				intercept_id,
-				inbound_amount_msat,
-				expected_outbound_amount_msat,
-			)?;
+			lsps2_message_handler.shutdown();
 		}
+	}
 
-		Ok(())
+	/// Drops the request with the given id exchanged with the given peer without touching any
+	/// other state, e.g., to unblock a stuck negotiation.
+	///
+	/// Any response to a request we sent will be ignored afterwards, as the request will also be
+	/// removed from the [`RequestMethodStore`]. Returns whether a request was removed.
+	pub fn purge_request(&self, counterparty_node_id: &PublicKey, request_id: &RequestId) -> bool {
+		let request_purged =
+			self.lsps2_message_handler.as_ref().map_or(false, |lsps2_message_handler| {
+				lsps2_message_handler.purge_request(counterparty_node_id, request_id)
+			});
+		let method_removed =
+			self.request_method_store.lock().unwrap().remove_method(request_id).is_some();
+		request_purged || method_removed
 	}
 
-	/// Forward [`Event::ChannelReady`] event parameters into this function.
+	/// Pauses or resumes serving JIT channels at runtime.
 	///
-	/// Will forward the intercepted HTLC if it matches a channel
-	/// we need to forward a payment over otherwise it will be ignored.
+	/// While paused, incoming LSPS2 requests are answered with an error signalling the protocol is
+	/// temporarily unavailable. Responses to our own requests are still processed.
+	pub fn set_jit_channels_paused(&self, paused: bool) {
+		self.jit_channels_paused.store(paused, Ordering::Release);
+	}
+
+	/// Sets the maximum length in bytes of the payload of incoming messages.
 	///
-	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
-	pub fn channel_ready(
-		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
-	) -> Result<(), APIError> {
+	/// Reading a message with a longer payload fails with [`DecodeError::InvalidValue`] before the
+	/// payload is read, protecting against peers exhausting our memory with oversized messages.
+	/// Defaults to [`DEFAULT_MAX_PAYLOAD_LENGTH`].
+	///
+	/// [`DecodeError::InvalidValue`]: lightning::ln::msgs::DecodeError::InvalidValue
+	pub fn set_max_payload_length(&self, max_payload_length: u16) {
+		self.max_payload_length.store(max_payload_length, Ordering::Release);
+	}
+
+	/// Sets the [`AtomicMetrics`] to record the messages exchanged with peers in.
+	///
+	/// The number of peers with in-flight JIT channel negotiations is updated whenever the pending
+	/// messages are retrieved via [`CustomMessageHandler::get_and_clear_pending_msg`].
+	pub fn set_metrics(&self, metrics: Arc<AtomicMetrics>) {
+		metrics.set_active_negotiation_peers(self.active_peer_count());
+		*self.metrics.lock().unwrap() = Some(metrics);
+	}
+
+	/// Returns a snapshot of the manager's state for debugging, e.g., to attach to a bug report.
+	///
+	/// Covers the best block, the protocol versions negotiated with peers, the requests awaiting a
+	/// response and the JIT channels being negotiated or opened. Sensitive data, such as tokens
+	/// and promise secrets, is never included.
+	pub fn debug_snapshot(&self) -> ManagerSnapshot {
+		let best_block = *self.best_block.read().unwrap();
+		ManagerSnapshot {
+			best_block_hash: best_block.block_hash().to_string(),
+			best_block_height: best_block.height(),
+			peers: self
+				.lsps2_message_handler
+				.as_ref()
+				.map_or(Vec::new(), |lsps2_message_handler| lsps2_message_handler.peer_snapshots()),
+		}
+	}
+
+	/// Returns the network we operate on, as given in the [`ChainParameters`] at construction.
+	pub fn network(&self) -> Network {
+		self.network
+	}
+
+	/// Returns the hash of the genesis block of the network we operate on, e.g., to check it
+	/// matches the one of the other components of the node.
+	pub fn genesis_hash(&self) -> BlockHash {
+		self.genesis_hash
+	}
+
+	/// Returns the revision of the LSPS2 specification whose wire format is implemented, see
+	/// [`LSPS2_SPEC_REVISION`].
+	///
+	/// [`LSPS2_SPEC_REVISION`]: crate::jit_channel::msgs::LSPS2_SPEC_REVISION
+	pub fn lsps2_spec_revision(&self) -> &'static str {
+		LSPS2_SPEC_REVISION
+	}
+
+	/// Returns how many LSPS2 `get_info` requests were rejected for the given reason since this
+	/// [`LiquidityManager`] was created.
+	///
+	/// Each rejection is also logged along with its reason.
+	pub fn get_info_rejection_count(&self, reason: GetInfoRejectionReason) -> u64 {
+		self.lsps2_message_handler.as_ref().map_or(0, |lsps2_message_handler| {
+			lsps2_message_handler.get_info_rejection_count(reason)
+		})
+	}
+
+	/// Returns the number of malformed or contradictory messages recorded for the given peer.
+	///
+	/// Always zero if [`LiquidityProviderConfig::max_peer_misbehaviors`] isn't set. Once the score
+	/// reaches that threshold, further messages from the peer are refused.
+	pub fn peer_abuse_score(&self, counterparty_node_id: &PublicKey) -> u32 {
+		self.misbehavior_counts.lock().unwrap().get(counterparty_node_id).copied().unwrap_or(0)
+	}
+
+	/// Resets the abuse score of the given peer to zero, e.g., to manually unblock it after it
+	/// reached [`LiquidityProviderConfig::max_peer_misbehaviors`].
+	pub fn reset_peer_abuse(&self, counterparty_node_id: &PublicKey) {
+		self.misbehavior_counts.lock().unwrap().remove(counterparty_node_id);
+	}
+
+	/// Returns whether the given peer advertised support for LSPS when it last connected.
+	///
+	/// Returns [`Option::None`] if [`Self::peer_connected`] was never called for the peer.
+	pub fn peer_supports_lsp(&self, counterparty_node_id: &PublicKey) -> Option<bool> {
+		self.peer_lsp_support.read().unwrap().get(counterparty_node_id).copied()
+	}
+
+	/// Asks the given peer which LSPS protocols it supports via an LSPS0 `list_protocols` request.
+	///
+	/// Once the peer responded, the supported protocols can be queried via
+	/// [`Self::peer_protocols`].
+	pub fn list_protocols(&self, counterparty_node_id: PublicKey) {
+		self.lsps0_message_handler.list_protocols(counterparty_node_id);
+	}
+
+	/// Returns the LSPS protocols the given peer reported in its last `list_protocols` response.
+	///
+	/// Returns [`Option::None`] if the peer didn't respond since it last connected.
+	pub fn peer_protocols(&self, counterparty_node_id: &PublicKey) -> Option<Vec<u16>> {
+		self.lsps0_message_handler.peer_protocols(counterparty_node_id)
+	}
+
+	/// Returns whether a [`PeerManager`] reference was set via [`Self::set_peer_manager`].
+	///
+	/// [`PeerManager`]: lightning::ln::peer_handler::PeerManager
+	pub fn peer_manager_set(&self) -> bool {
+		self.lsps2_message_handler
+			.as_ref()
+			.map_or(false, |lsps2_message_handler| lsps2_message_handler.peer_manager_set())
+	}
+
+	/// Returns the number of messages queued for each peer, i.e., the number of messages that
+	/// will be handed to each peer on the next call to
+	/// [`CustomMessageHandler::get_and_clear_pending_msg`].
+	///
+	/// Note that queued messages are never batched into a single [`RawLSPSMessage`], as LSPS0
+	/// does not allow JSON-RPC batch requests or responses, so each queued message will be sent
+	/// as a separate Lightning message.
+	pub fn pending_message_counts(&self) -> HashMap<PublicKey, usize> {
+		let mut pending_message_counts = HashMap::new();
+		for (counterparty_node_id, _) in self.pending_messages.lock().unwrap().iter() {
+			*pending_message_counts.entry(*counterparty_node_id).or_insert(0) += 1;
+		}
+		pending_message_counts
+	}
+
+	/// Returns a view exposing only the methods used by a client buying JIT channels from an LSP.
+	#[allow(clippy::type_complexity)]
+	pub fn as_client(
+		&self,
+	) -> LspClient<'_, ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C> {
+		LspClient { liquidity_manager: self }
+	}
+
+	/// Returns a view exposing only the methods used by an LSP selling JIT channels to clients.
+	#[allow(clippy::type_complexity)]
+	pub fn as_server(
+		&self,
+	) -> LspServer<'_, ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C> {
+		LspServer { liquidity_manager: self }
+	}
+
+	/// Initiate the creation of an invoice that when paid will open a channel
+	/// with enough inbound liquidity to be able to receive the payment.
+	///
+	/// `counterparty_node_id` is the node_id of the LSP you would like to use.
+	///
+	/// If `payment_size_msat` is [`Option::Some`] then the invoice will be for a fixed amount
+	/// and MPP can be used to pay it. The amount must not be zero.
+	///
+	/// If `payment_size_msat` is [`Option::None`] then the invoice can be for an arbitrary amount
+	/// but MPP can no longer be used to pay it.
+	///
+	/// `token` is an optional String that will be provided to the LSP.
+	/// It can be used by the LSP as an API key, coupon code, or some other way to identify a user.
+	///
+	/// `label` is an optional String that is never sent to the LSP, but echoed back in all events
+	/// related to this JIT channel, e.g., to correlate them with a support ticket.
+	pub fn jit_channel_create_invoice(
+		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
+		token: Option<String>, user_channel_id: u128, label: Option<String>,
+	) -> Result<(), APIError> {
+		if payment_size_msat == Some(0) {
+			return Err(APIError::APIMisuseError {
+				err: "payment_size_msat must be greater than zero if given".to_string(),
+			});
+		}
+
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.create_invoice(
+				counterparty_node_id,
+				payment_size_msat,
+				token.map(Token::new),
+				user_channel_id,
+				label,
+			);
+			Ok(())
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Initiate the creation of an invoice like [`LiquidityManager::jit_channel_create_invoice`],
+	/// but with the cheapest of the given LSPs.
+	///
+	/// Requests the opening fee params menus from all `lsp_candidates` and, once all of them
+	/// responded or [`JITChannelsConfig::lsp_selection_timeout`] passed, buys the cheapest entry
+	/// among them. If `payment_size_msat` is [`Option::Some`], entries are compared by the opening
+	/// fee for that payment size, otherwise by their `min_fee_msat` and then `proportional` fee.
+	///
+	/// Will generate an [`LSPS2Event::LspSelected`] event naming the chosen LSP, followed by an
+	/// [`LSPS2Event::InvoiceGenerationReady`] event as usual. If no LSP offered acceptable
+	/// parameters, an [`LSPS2Event::LspSelectionFailed`] event is generated instead.
+	///
+	/// [`LSPS2Event::LspSelected`]: crate::jit_channel::LSPS2Event::LspSelected
+	/// [`LSPS2Event::InvoiceGenerationReady`]: crate::jit_channel::LSPS2Event::InvoiceGenerationReady
+	/// [`LSPS2Event::LspSelectionFailed`]: crate::jit_channel::LSPS2Event::LspSelectionFailed
+	pub fn jit_channel_create_invoice_multi(
+		&self, lsp_candidates: Vec<PublicKey>, payment_size_msat: Option<u64>,
+		user_channel_id: u128,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.create_invoice_multi(
+				lsp_candidates,
+				payment_size_msat,
+				user_channel_id,
+			)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Should be called roughly once per minute to enforce timeouts, e.g., of the LSP selection
+	/// started by [`LiquidityManager::jit_channel_create_invoice_multi`], to warn about expiring
+	/// opening fee params, and to retry requests lost to a disconnect.
+	pub fn timer_tick_occurred(&self) {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.timer_tick_occurred();
+		}
+	}
+
+	#[cfg(test)]
+	pub(crate) fn best_block(&self) -> BestBlock {
+		*self.best_block.read().unwrap()
+	}
+
+	/// Used by LSP to provide fee parameters to a client requesting a JIT Channel.
+	///
+	/// Should be called in response to receiving a [`LSPS2Event::GetInfo`] event.
+	///
+	/// Fails if [`JITChannelsConfig::promise_secret`] is all zeros, as is the case if it was left
+	/// unset, since the promises calculated with it would be trivial to forge, or if the
+	/// [`ChannelManager`] isn't configured with [`UserConfig::accept_intercept_htlcs`], as JIT
+	/// channels can't be opened without it.
+	///
+	/// [`LSPS2Event::GetInfo`]: crate::jit_channel::LSPS2Event::GetInfo
+	/// [`UserConfig::accept_intercept_htlcs`]: lightning::util::config::UserConfig::accept_intercept_htlcs
+	pub fn opening_fee_params_generated(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId,
+		opening_fee_params_menu: Vec<RawOpeningFeeParams>,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.opening_fee_params_generated(
+				counterparty_node_id,
+				request_id,
+				opening_fee_params_menu,
+			)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Returns a `min_fee_msat` an LSP may use in its opening fee params menu that covers the
+	/// configured multiple of the estimated fee of a channel funding transaction at the current
+	/// feerate.
+	///
+	/// Returns [`Option::None`] if JIT Channels were not configured.
+	///
+	/// See [`JITChannelsConfig::min_fee_funding_fee_multiplier`].
+	pub fn suggested_min_fee_msat(&self) -> Option<u64> {
+		self.lsps2_message_handler
+			.as_ref()
+			.map(|lsps2_message_handler| lsps2_message_handler.suggested_min_fee_msat())
+	}
+
+	/// Returns the total cost in msat the client would pay for a JIT channel opened with the given
+	/// `opening_fee_params` to receive a payment of `payment_size_msat`.
+	///
+	/// Allows to display the cost to the user before calling
+	/// [`LiquidityManager::opening_fee_params_selected`]. Returns [`Option::None`] if JIT Channels
+	/// were not configured.
+	pub fn estimate_total_client_cost(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> Option<u64> {
+		self.lsps2_message_handler.as_ref().map(|lsps2_message_handler| {
+			lsps2_message_handler.estimate_total_client_cost(opening_fee_params, payment_size_msat)
+		})
+	}
+
+	/// Returns the liquidity in msat the JIT channel opened with the given `opening_fee_params` to
+	/// receive a payment of `payment_size_msat` will provide, i.e., the channel size as per
+	/// [`JITChannelsConfig::channel_sizing_strategy`] less the opening fee and the channel reserve.
+	///
+	/// Allows to decide whether the channel is worth its cost before calling
+	/// [`LiquidityManager::opening_fee_params_selected`]. Returns [`Option::None`] if JIT Channels
+	/// were not configured.
+	pub fn expected_inbound_liquidity_msat(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> Option<u64> {
+		self.lsps2_message_handler.as_ref().map(|lsps2_message_handler| {
+			lsps2_message_handler
+				.expected_inbound_liquidity_msat(opening_fee_params, payment_size_msat)
+		})
+	}
+
+	/// Used by client to confirm which channel parameters to use for the JIT Channel buy request.
+	/// The client agrees to paying an opening fee equal to
+	/// `max(min_fee_msat, proportional*(payment_size_msat/1_000_000))`.
+	///
+	/// Should be called in response to receiving a [`LSPS2Event::GetInfoResponse`] event.
+	///
+	/// Will return an error and abandon the JIT channel if the `payment_size_msat` given to
+	/// [`LiquidityManager::jit_channel_create_invoice`] is outside of the bounds advertised by the
+	/// LSP in its get_info response.
+	///
+	/// Will return an [`APIError::APIMisuseError`] if `channel_id` doesn't refer to a JIT channel
+	/// for which we received a get_info response that is still awaiting a selection.
+	///
+	/// `refund_onchain_address` is an optional on-chain address the LSP may refund the
+	/// intercepted payment to if the JIT channel can't be opened.
+	///
+	/// `announce_channel` optionally expresses whether the JIT channel should be announced to the
+	/// network. It is surfaced to the LSP in the [`LSPS2Event::OpenChannel`] event.
+	///
+	/// [`LSPS2Event::GetInfoResponse`]: crate::jit_channel::LSPS2Event::GetInfoResponse
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn opening_fee_params_selected(
+		&self, counterparty_node_id: PublicKey, channel_id: u128,
+		opening_fee_params: OpeningFeeParams, refund_onchain_address: Option<String>,
+		announce_channel: Option<bool>,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.opening_fee_params_selected(
+				counterparty_node_id,
+				channel_id,
+				opening_fee_params,
+				refund_onchain_address,
+				announce_channel,
+			)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Used by client to retrieve the parameters required to include a route hint for the JIT
+	/// channel with the given `user_channel_id` in their invoice.
+	///
+	/// Returns [`Option::None`] if we didn't receive the invoice parameters from the LSP yet,
+	/// i.e., before the [`LSPS2Event::InvoiceGenerationReady`] event was generated.
+	///
+	/// [`LSPS2Event::InvoiceGenerationReady`]: crate::jit_channel::LSPS2Event::InvoiceGenerationReady
+	pub fn invoice_route_hint(&self, user_channel_id: u128) -> Option<RouteHintBundle> {
+		self.lsps2_message_handler.as_ref().and_then(|lsps2_message_handler| {
+			lsps2_message_handler.invoice_route_hint(user_channel_id)
+		})
+	}
+
+	/// Used by client to get the stage the JIT channel negotiation with the given
+	/// `user_channel_id` is in.
+	///
+	/// Returns [`Option::None`] if no JIT channel with the given `user_channel_id` is known. Once
+	/// a negotiation ended, i.e., reached [`JitChannelState::Ready`] or
+	/// [`JitChannelState::Failed`], its state is only remembered for a limited number of further
+	/// negotiations.
+	pub fn jit_channel_state(&self, user_channel_id: u128) -> Option<JitChannelState> {
+		self.lsps2_message_handler
+			.as_ref()
+			.and_then(|lsps2_message_handler| lsps2_message_handler.channel_state(user_channel_id))
+	}
+
+	/// Used by LSP to get the stage the JIT channel with the given intercept scid is in.
+	///
+	/// The scid is also the `user_channel_id` given in the [`LSPS2Event::OpenChannel`] event.
+	/// Returns [`Option::None`] under the same conditions as [`Self::jit_channel_state`].
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn jit_channel_state_by_scid(&self, scid: u64) -> Option<JitChannelState> {
+		self.lsps2_message_handler
+			.as_ref()
+			.and_then(|lsps2_message_handler| lsps2_message_handler.lsp_channel_state(scid))
+	}
+
+	/// Returns the expiry in seconds the invoice for the JIT channel with the given
+	/// `user_channel_id` should have, i.e., the time left until the selected opening fee params
+	/// expire, but at least a minute.
+	///
+	/// An invoice outlasting the opening fee params might be paid when the LSP no longer opens the
+	/// channel on the agreed terms.
+	///
+	/// Returns [`Option::None`] if no opening fee params were selected for the JIT channel yet, or
+	/// if the payment was already received.
+	pub fn recommended_invoice_expiry_secs(&self, user_channel_id: u128) -> Option<u32> {
+		self.lsps2_message_handler.as_ref().and_then(|lsps2_message_handler| {
+			lsps2_message_handler.recommended_invoice_expiry_secs(user_channel_id)
+		})
+	}
+
+	/// Returns the node id of the LSP the JIT channel with the given `user_channel_id` is negotiated
+	/// with.
+	///
+	/// For negotiations started via [`Self::jit_channel_create_invoice_multi`], the LSP is only
+	/// known once it was selected, i.e., when the [`LSPS2Event::LspSelected`] event was generated.
+	///
+	/// Returns [`Option::None`] if no JIT channel with the given `user_channel_id` is being
+	/// negotiated, including once the negotiation reached [`JitChannelState::Ready`] or
+	/// [`JitChannelState::Failed`].
+	///
+	/// [`LSPS2Event::LspSelected`]: crate::jit_channel::LSPS2Event::LspSelected
+	pub fn lsp_for_jit_channel(&self, user_channel_id: u128) -> Option<PublicKey> {
+		self.lsps2_message_handler.as_ref().and_then(|lsps2_message_handler| {
+			lsps2_message_handler.lsp_for_channel(user_channel_id)
+		})
+	}
+
+	/// Used by LSP to provide client with the scid and cltv_expiry_delta to use in their invoice.
+	///
+	/// Should be called in response to receiving a [`LSPS2Event::BuyRequest`] event, passing the
+	/// `scid` allocated for the request as given in the event.
+	///
+	/// Fails if the `scid` differs from the allocated one, and under the same conditions as
+	/// [`Self::opening_fee_params_generated`].
+	///
+	/// [`LSPS2Event::BuyRequest`]: crate::jit_channel::LSPS2Event::BuyRequest
+	pub fn invoice_parameters_generated(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, scid: u64,
+		cltv_expiry_delta: u32, client_trusts_lsp: bool,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.invoice_parameters_generated(
+				counterparty_node_id,
+				request_id,
+				scid,
+				cltv_expiry_delta,
+				client_trusts_lsp,
+			)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Used by LSP to list all scids it handed out to clients that are still awaiting an
+	/// intercepted payment.
+	///
+	/// Returns `(scid, counterparty_node_id, user_channel_id)` tuples ordered by scid, where
+	/// `user_channel_id` is the one that will be given in the [`LSPS2Event::OpenChannel`] event.
+	/// Returns an empty list if JIT Channels were not configured.
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn outstanding_scids(&self) -> Vec<(u64, PublicKey, u128)> {
+		self.lsps2_message_handler
+			.as_ref()
+			.map_or(Vec::new(), |lsps2_message_handler| lsps2_message_handler.outstanding_scids())
+	}
+
+	/// Used by LSP to set the [`ChannelConfig`] it will open the JIT channel with the given
+	/// `user_channel_id` with, e.g., to charge custom forwarding fees.
+	///
+	/// The config is carried through to the [`LSPS2Event::OpenChannel`] event. Must be called
+	/// after [`Self::invoice_parameters_generated`] and before the payment is intercepted.
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn set_jit_channel_config(
+		&self, user_channel_id: u128, channel_config: ChannelConfig,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			let scid = u64::try_from(user_channel_id).map_err(|_| APIError::APIMisuseError {
+				err: format!("Invalid user_channel_id {} for a JIT channel", user_channel_id),
+			})?;
+			lsps2_message_handler.set_channel_config(scid, channel_config)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Used by LSP to size the JIT channels opened to the given client with the given strategy
+	/// rather than [`JITChannelsConfig::channel_sizing_strategy`], e.g., to provide additional
+	/// liquidity to clients with a high payment volume.
+	///
+	/// Passing [`Option::None`] reverts to the configured strategy. The resulting capacity is
+	/// given in the [`LSPS2Event::OpenChannel`] event.
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn set_peer_channel_sizing_strategy(
+		&self, counterparty_node_id: PublicKey, strategy: Option<ChannelSizingStrategy>,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.set_peer_channel_sizing_strategy(counterparty_node_id, strategy);
+			Ok(())
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Used by LSP to register the funding outpoint of a channel it opened in response to a
+	/// [`LSPS2Event::OpenChannel`] event.
+	///
+	/// `user_channel_id` is the one given in the [`LSPS2Event::OpenChannel`] event and
+	/// `funding_script_pubkey` the `output_script` of the corresponding
+	/// [`Event::FundingGenerationReady`]. The funding transaction will be registered with the
+	/// chain source given on construction, if any, and will be returned by
+	/// [`Confirm::get_relevant_txids`] going forward.
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	/// [`Event::FundingGenerationReady`]: lightning::events::Event::FundingGenerationReady
+	pub fn jit_channel_funding_created(
+		&self, user_channel_id: u128, funding_txid: Txid, funding_output_index: u16,
+		funding_script_pubkey: &Script,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			let funding_txo = OutPoint { txid: funding_txid, index: funding_output_index };
+			lsps2_message_handler.funding_created(user_channel_id, funding_txo)?;
+
+			if let Some(chain_source) = &self.chain_source {
+				chain_source.register_tx(&funding_txid, funding_script_pubkey);
+			}
+
+			Ok(())
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Used by client to signal a payment was received over the JIT channel with the given
+	/// `user_channel_id`, funded by the given outpoint paying to `funding_script_pubkey`, e.g., as
+	/// returned by [`ChannelMonitor::get_funding_txo`].
+	///
+	/// Should be called upon [`Event::PaymentClaimable`] for an invoice generated in response to
+	/// [`LSPS2Event::InvoiceGenerationReady`]. Will generate an [`LSPS2Event::ClaimPayment`]
+	/// event once the payment may be claimed. If
+	/// [`JITChannelsConfig::await_funding_confirmation`] is set and the client doesn't trust the
+	/// LSP, this is deferred until the funding transaction confirmed, which will be registered
+	/// with the [`Filter`] and returned by [`Confirm::get_relevant_txids`] in the meantime.
+	///
+	/// [`Event::PaymentClaimable`]: lightning::events::Event::PaymentClaimable
+	/// [`LSPS2Event::InvoiceGenerationReady`]: crate::jit_channel::LSPS2Event::InvoiceGenerationReady
+	/// [`LSPS2Event::ClaimPayment`]: crate::jit_channel::LSPS2Event::ClaimPayment
+	/// [`ChannelMonitor::get_funding_txo`]: lightning::chain::channelmonitor::ChannelMonitor::get_funding_txo
+	pub fn jit_channel_payment_received(
+		&self, user_channel_id: u128, funding_txid: Txid, funding_output_index: u16,
+		funding_script_pubkey: &Script,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			let funding_txo = OutPoint { txid: funding_txid, index: funding_output_index };
+			if lsps2_message_handler.payment_received(user_channel_id, funding_txo)? {
+				if let Some(chain_source) = &self.chain_source {
+					chain_source.register_tx(&funding_txid, funding_script_pubkey);
+				}
+			}
+
+			Ok(())
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Forward [`Event::HTLCIntercepted`] event parameters into this function.
+	///
+	/// Will fail the intercepted HTLC if the scid matches a payment we are expecting
+	/// but the payment amount is incorrect or the expiry has passed. If `inbound_amount_msat`
+	/// doesn't cover the amount we'd forward after deducting the opening fee, an
+	/// [`LSPS2Event::InsufficientInboundAmount`] event is generated.
+	///
+	/// Will generate a [`LSPS2Event::OpenChannel`] event if the scid matches a payment we are expected
+	/// and the payment amount is correct and the offer has not expired.
+	///
+	/// Will do nothing if the scid does not match any of the ones we gave out, or if the intercepted
+	/// HTLC was already processed, e.g., as the event is replayed after a restart. See
+	/// [`Self::restore_processed_intercepts`].
+	///
+	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
+	/// [`LSPS2Event::InsufficientInboundAmount`]: crate::jit_channel::LSPS2Event::InsufficientInboundAmount
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn htlc_intercepted(
+		&self, scid: u64, intercept_id: InterceptId, inbound_amount_msat: u64,
+		expected_outbound_amount_msat: u64,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.htlc_intercepted(
+				scid,
+				intercept_id,
+				inbound_amount_msat,
+				expected_outbound_amount_msat,
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Forward [`Event::ChannelReady`] event parameters into this function.
+	///
+	/// Will forward the intercepted HTLC if it matches a channel
+	/// we need to forward a payment over otherwise it will be ignored, as signalled by
+	/// [`ChannelReadyOutcome::Ignored`]. Hence, events for all channels may be forwarded, including
+	/// normally opened ones.
+	///
+	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+	/// [`ChannelReadyOutcome::Ignored`]: crate::jit_channel::ChannelReadyOutcome::Ignored
+	pub fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<ChannelReadyOutcome, APIError> {
+		match &self.lsps2_message_handler {
+			Some(lsps2_message_handler) => lsps2_message_handler.channel_ready(
+				user_channel_id,
+				channel_id,
+				counterparty_node_id,
+			),
+			None => Ok(ChannelReadyOutcome::Ignored),
+		}
+	}
+
+	/// Used by LSP to report that opening a JIT channel requested via an
+	/// [`LSPS2Event::OpenChannel`] event failed, e.g., as funding it failed.
+	///
+	/// The HTLC intercepted for the channel is failed back and the next deferred channel open, if
+	/// any, is requested, see [`JITChannelsConfig::max_pending_channel_opens`].
+	///
+	/// [`LSPS2Event::OpenChannel`]: crate::jit_channel::LSPS2Event::OpenChannel
+	pub fn channel_open_failed(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+	) -> Result<(), APIError> {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.channel_open_failed(counterparty_node_id, user_channel_id)
+		} else {
+			Err(APIError::APIMisuseError {
+				err: "JIT Channels were not configured when LSPManager was instantiated"
+					.to_string(),
+			})
+		}
+	}
+
+	/// Forward [`Event::HTLCHandlingFailed`] event parameters into this function.
+	///
+	/// If an HTLC intercepted for a JIT channel not opened yet failed, e.g., as it expired, the
+	/// channel is abandoned and the next deferred channel open, if any, is requested, see
+	/// [`JITChannelsConfig::max_pending_channel_opens`]. Events for other HTLCs are ignored.
+	///
+	/// [`Event::HTLCHandlingFailed`]: lightning::events::Event::HTLCHandlingFailed
+	pub fn htlc_handling_failed(&self, failed_next_destination: HTLCDestination) {
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.htlc_handling_failed(failed_next_destination);
+		}
+	}
+
+	/// Forward [`Event::PaymentForwarded`] event parameters into this function.
+	///
+	/// The opening fee of a JIT channel is accounted as earned once the first payment was
+	/// forwarded over it, see [`Self::total_fees_earned_msat`].
+	///
+	/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
+	pub fn payment_forwarded(&self, next_channel_id: Option<ChannelId>) {
+		if let (Some(lsps2_message_handler), Some(next_channel_id)) =
+			(&self.lsps2_message_handler, next_channel_id)
+		{
+			lsps2_message_handler.payment_forwarded(&next_channel_id);
+		}
+	}
+
+	/// Returns the total of the opening fees in msat earned with JIT channels since this
+	/// [`LiquidityManager`] was created.
+	///
+	/// A fee is earned once a payment was forwarded over the channel, as reported to
+	/// [`Self::payment_forwarded`].
+	pub fn total_fees_earned_msat(&self) -> u64 {
+		self.lsps2_message_handler
+			.as_ref()
+			.map_or(0, |lsps2_message_handler| lsps2_message_handler.total_fees_earned_msat())
+	}
+
+	fn handle_lsps_message(
+		&self, msg: LSPSMessage, sender_node_id: &PublicKey,
+	) -> Result<(), lightning::ln::msgs::LightningError> {
+		match msg {
+			LSPSMessage::Invalid
+			| LSPSMessage::MethodNotFound(_)
+			| LSPSMessage::ProtocolUnavailable(_) => {
+				return Err(LightningError { err: format!("{} did not understand a message we previously sent, maybe they don't support a protocol we are trying to use?", sender_node_id), action: ErrorAction::IgnoreAndLog(Level::Error)});
+			}
+			LSPSMessage::LSPS0(msg) => {
+				self.lsps0_message_handler.handle_message(msg, sender_node_id)?;
+			}
+			LSPSMessage::LSPS2(msg) => match &self.lsps2_message_handler {
+				Some(lsps2_message_handler) => {
+					if let LSPS2Message::Request(request_id, _) = &msg {
+						// Unlike for a missing handler, signal that requests may be retried later.
+						if self.jit_channels_paused.load(Ordering::Acquire) {
+							if let LSPS2Message::Request(_, LSPS2Request::GetInfo(_)) = &msg {
+								lsps2_message_handler.get_info_rejected(
+									sender_node_id,
+									GetInfoRejectionReason::Maintenance,
+								);
+							}
+							self.enqueue_message(
+								*sender_node_id,
+								LSPSMessage::ProtocolUnavailable(request_id.clone()),
+							);
+							return Ok(());
+						}
+					}
+					lsps2_message_handler.handle_message(msg, sender_node_id)?;
+				}
+				None => {
+					if let LSPS2Message::Request(request_id, _) = msg {
+						self.handle_unknown_protocol_request(request_id, 2, sender_node_id);
+						return Ok(());
+					}
+					return Err(LightningError { err: format!("Received LSPS2 message without LSPS2 message handler configured. From node = {:?}", sender_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
+				}
+			},
+		}
+		Ok(())
+	}
+
+	fn implements_protocol(&self, protocol_number: u16) -> bool {
+		protocol_number == 0
+			|| self.lsps2_message_handler.as_ref().and_then(handler_protocol_number)
+				== Some(protocol_number)
+	}
+
+	fn handle_unknown_protocol_request(
+		&self, request_id: RequestId, protocol_number: u16, sender_node_id: &PublicKey,
+	) {
+		let policy = self
+			.provider_config
+			.as_ref()
+			.map_or(UnknownProtocolPolicy::RespondWithError, |config| {
+				config.unknown_protocol_policy
+			});
+		match policy {
+			UnknownProtocolPolicy::Ignore => {}
+			UnknownProtocolPolicy::Log => {
+				log_info!(
+					self.logger,
+					"Ignoring request {:?} from {} for unsupported protocol LSPS{}",
+					request_id,
+					sender_node_id,
+					protocol_number
+				);
+			}
+			UnknownProtocolPolicy::RespondWithError => {
+				self.enqueue_message(*sender_node_id, LSPSMessage::MethodNotFound(request_id));
+			}
+		}
+	}
+
+	fn max_peer_misbehaviors(&self) -> Option<u32> {
+		self.provider_config.as_ref().and_then(|config| config.max_peer_misbehaviors)
+	}
+
+	fn is_misbehaving(&self, counterparty_node_id: &PublicKey) -> bool {
+		match self.max_peer_misbehaviors() {
+			Some(max_peer_misbehaviors) => self
+				.misbehavior_counts
+				.lock()
+				.unwrap()
+				.get(counterparty_node_id)
+				.map_or(false, |count| *count >= max_peer_misbehaviors),
+			None => false,
+		}
+	}
+
+	fn record_misbehavior(&self, counterparty_node_id: &PublicKey) {
+		let max_peer_misbehaviors = match self.max_peer_misbehaviors() {
+			Some(max_peer_misbehaviors) => max_peer_misbehaviors,
+			None => return,
+		};
+
+		let count = {
+			let mut misbehavior_counts = self.misbehavior_counts.lock().unwrap();
+			let count = misbehavior_counts.entry(*counterparty_node_id).or_insert(0);
+			*count += 1;
+			*count
+		};
+
+		if count == max_peer_misbehaviors {
+			log_error!(
+				self.logger,
+				"Peer {} misbehaved {} times, refusing further messages",
+				counterparty_node_id,
+				count
+			);
+			if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+				lsps2_message_handler.clear_negotiation_state(counterparty_node_id);
+			}
+			self.pending_events
+				.enqueue(Event::PeerMisbehaved { counterparty_node_id: *counterparty_node_id });
+		}
+	}
+
+	fn enqueue_message(&self, node_id: PublicKey, msg: LSPSMessage) {
+		let mut pending_msgs = self.pending_messages.lock().unwrap();
+		pending_msgs.push((node_id, msg));
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> CustomMessageReader
+	for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	type CustomMessage = RawLSPSMessage;
+
+	fn read<RD: lightning::io::Read>(
+		&self, message_type: u16, buffer: &mut RD,
+	) -> Result<Option<Self::CustomMessage>, lightning::ln::msgs::DecodeError> {
+		match message_type {
+			// The payload is length-prefixed, so a truncated buffer fails cleanly with
+			// `DecodeError::ShortRead` rather than yielding a partial message.
+			LSPS_MESSAGE_TYPE_ID => {
+				// Check the length prefix of the payload before reading, and hence allocating, it.
+				let mut length_prefix = [0; 2];
+				buffer.read_exact(&mut length_prefix)?;
+				if u16::from_be_bytes(length_prefix)
+					> self.max_payload_length.load(Ordering::Acquire)
+				{
+					return Err(lightning::ln::msgs::DecodeError::InvalidValue);
+				}
+				let mut reader = io::Read::chain(&length_prefix[..], buffer);
+				Ok(Some(RawLSPSMessage::read(&mut reader)?))
+			}
+			_ => Ok(None),
+		}
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> CustomMessageHandler
+	for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	fn handle_custom_message(
+		&self, msg: Self::CustomMessage, sender_node_id: &PublicKey,
+	) -> Result<(), lightning::ln::msgs::LightningError> {
+		if self.is_misbehaving(sender_node_id) {
+			if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+				if is_get_info_request(&msg.payload) {
+					lsps2_message_handler
+						.get_info_rejected(sender_node_id, GetInfoRejectionReason::RateLimited);
+				}
+			}
+			return Err(LightningError {
+				err: format!("Refusing message from misbehaving peer {}", sender_node_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		let mut unknown_fields = Vec::new();
+		let message = {
+			let mut request_method_store = self.request_method_store.lock().unwrap();
+			LSPSMessage::from_str_with_id_map_and_unknown_fields(
+				&msg.payload,
+				request_method_store.as_mut(),
+				&mut unknown_fields,
+			)
+		};
+
+		if !unknown_fields.is_empty() {
+			log_debug!(
+				self.logger,
+				"Ignored unknown fields in message from {}: {}",
+				sender_node_id,
+				unknown_fields.join(", ")
+			);
+		}
+
+		match message {
+			Ok(msg) => {
+				#[cfg(feature = "tracing")]
+				let _span = message_span(
+					tracing::debug_span!(
+						"lsps_handle_message",
+						peer = %sender_node_id,
+						method = tracing::field::Empty,
+						request_id = tracing::field::Empty
+					),
+					&msg,
+				)
+				.entered();
+
+				if let Some(metrics) = self.metrics.lock().unwrap().as_ref() {
+					metrics.message_received(msg.method(), msg.error_code());
+				}
+
+				self.handle_lsps_message(msg, sender_node_id)
+			}
+			Err(_) => {
+				if let Some((request_id, protocol_number)) = request_protocol_number(&msg.payload) {
+					if !self.implements_protocol(protocol_number) {
+						self.handle_unknown_protocol_request(
+							request_id,
+							protocol_number,
+							sender_node_id,
+						);
+						return Ok(());
+					}
+				}
+
+				let response_request_id = response_request_id(&msg.payload);
+				let expected_method = response_request_id.as_ref().and_then(|request_id| {
+					self.request_method_store.lock().unwrap().get_method(request_id)
+				});
+				// Responses to requests we don't know (anymore), e.g., late or duplicate responses to
+				// requests we gave up on or re-sent, are due to our own state rather than malformed.
+				if response_request_id.is_none() || expected_method.is_some() {
+					self.record_misbehavior(sender_node_id);
+				}
+
+				let strict_request_matching = self
+					.provider_config
+					.as_ref()
+					.map_or(false, |config| config.strict_request_matching);
+				if strict_request_matching {
+					if let Some(request_id) = response_request_id {
+						log_error!(
+							self.logger,
+							"Received response from {} with id {:?} not matching an outstanding request. Expected method: {:?}",
+							sender_node_id,
+							request_id,
+							expected_method
+						);
+						self.pending_events.enqueue(Event::MismatchedResponse {
+							counterparty_node_id: *sender_node_id,
+							request_id,
+							expected_method,
+						});
+						return Ok(());
+					}
+				}
+
+				self.enqueue_message(*sender_node_id, LSPSMessage::Invalid);
+				Ok(())
+			}
+		}
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, Self::CustomMessage)> {
+		// Messages are queued synchronously by the calls producing them, so anything resulting from
+		// a call that already returned is guaranteed to be included here.
+		// LSPS0 forbids JSON-RPC batching, so we can't combine messages queued for the same peer
+		// into a single JSON array and rather send each of them individually.
+		let metrics = self.metrics.lock().unwrap().clone();
+		if let Some(metrics) = &metrics {
+			metrics.set_active_negotiation_peers(self.active_peer_count());
+		}
+		let mut request_method_store = self.request_method_store.lock().unwrap();
+		self.pending_messages
+			.lock()
+			.unwrap()
+			.drain(..)
+			.map(|(public_key, lsps_message)| {
+				#[cfg(feature = "tracing")]
+				let _span = message_span(
+					tracing::debug_span!(
+						"lsps_send_message",
+						peer = %public_key,
+						method = tracing::field::Empty,
+						request_id = tracing::field::Empty
+					),
+					&lsps_message,
+				)
+				.entered();
+
+				if let Some(metrics) = &metrics {
+					metrics.message_sent(lsps_message.method(), lsps_message.error_code());
+				}
+				if let Some((request_id, method_name)) = lsps_message.get_request_id_and_method() {
+					request_method_store.set_method(request_id, method_name);
+				}
+				(
+					public_key,
+					RawLSPSMessage { payload: serde_json::to_string(&lsps_message).unwrap() },
+				)
+			})
+			.collect()
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		let mut features = NodeFeatures::empty();
+
+		if self.provider_config.is_some() {
+			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
+		}
+
+		features
+	}
+
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
+		let mut features = InitFeatures::empty();
+
+		if self.provider_config.is_some() {
+			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
+		}
+
+		features
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> Listen for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	fn filtered_block_connected(
+		&self, header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
+		height: u32,
+	) {
+		{
+			let best_block = self.best_block.read().unwrap();
+			assert_eq!(best_block.block_hash(), header.prev_blockhash,
+			"Blocks must be connected in chain-order - the connected header must build on the last connected header");
+			assert_eq!(best_block.height(), height - 1,
+			"Blocks must be connected in chain-order - the connected block height must be one greater than the previous height");
+		}
+
+		self.transactions_confirmed(header, txdata, height);
+		self.best_block_updated(header, height);
+	}
+
+	fn block_disconnected(&self, header: &bitcoin::BlockHeader, height: u32) {
+		let new_height = height - 1;
+		{
+			let mut best_block = self.best_block.write().unwrap();
+			assert_eq!(best_block.block_hash(), header.block_hash(),
+				"Blocks must be disconnected in chain-order - the disconnected header must be the last connected header");
+			assert_eq!(best_block.height(), height,
+				"Blocks must be disconnected in chain-order - the disconnected block must have the correct height");
+			*best_block = BestBlock::new(header.prev_blockhash, new_height)
+		}
+
+		// TODO: Call block_disconnected on all sub-modules that require it, e.g., CRManager.
+		// Internally this should call transaction_unconfirmed for all transactions that were
+		// confirmed at a height <= the one we now disconnected.
+	}
+}
+
+impl<
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> Confirm for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	fn transactions_confirmed(
+		&self, _header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
+		height: u32,
+	) {
+		// TODO: Call transactions_confirmed on all sub-modules that require it, e.g., CRManager.
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.transactions_confirmed(txdata, height);
+		}
+	}
+
+	fn transaction_unconfirmed(&self, txid: &bitcoin::Txid) {
+		// TODO: Call transaction_unconfirmed on all sub-modules that require it, e.g., CRManager.
+		// Internally this should call transaction_unconfirmed for all transactions that were
+		// confirmed at a height <= the one we now unconfirmed.
 		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
-			lsps2_message_handler.channel_ready(
-				user_channel_id,
-				channel_id,
+			lsps2_message_handler.transaction_unconfirmed(txid);
+		}
+	}
+
+	fn best_block_updated(&self, header: &bitcoin::BlockHeader, height: u32) {
+		*self.best_block.write().unwrap() = BestBlock::new(header.block_hash(), height);
+
+		// TODO: Call best_block_updated on all sub-modules that require it, e.g., CRManager.
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			lsps2_message_handler.best_block_updated(height);
+		}
+	}
+
+	fn get_relevant_txids(&self) -> Vec<(bitcoin::Txid, Option<bitcoin::BlockHash>)> {
+		// TODO: Collect relevant txids from all sub-modules that, e.g., CRManager.
+		let mut relevant_txids = Vec::new();
+		if let Some(lsps2_message_handler) = &self.lsps2_message_handler {
+			relevant_txids.append(&mut lsps2_message_handler.get_relevant_txids());
+		}
+		relevant_txids
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jit_channel::LSPS2Event;
+	use crate::test_utils::{
+		buy_jit_channel, create_node, create_node_with_provider_config,
+		create_node_with_request_method_store, default_jit_channels_config, get_events,
+		pass_messages, raw_opening_fee_params, request_opening_fee_params_menu, try_create_node,
+		Node,
+	};
+
+	use lightning::util::config::UserConfig;
+
+	use bitcoin::hashes::Hash;
+	use bitcoin::{PackedLockTime, Transaction, TxOut, WScriptHash};
+
+	#[test]
+	fn pending_messages_are_counted_and_sent_individually() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp_a = create_node(2, Some(default_jit_channels_config()));
+		let lsp_b = create_node(3, Some(default_jit_channels_config()));
+		assert!(client.liquidity_manager.pending_message_counts().is_empty());
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp_a.node_id, None, None, 1, None)
+			.unwrap();
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp_a.node_id, None, None, 2, None)
+			.unwrap();
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp_b.node_id, None, None, 3, None)
+			.unwrap();
+
+		let pending_message_counts = client.liquidity_manager.pending_message_counts();
+		assert_eq!(pending_message_counts.len(), 2);
+		assert_eq!(pending_message_counts[&lsp_a.node_id], 2);
+		assert_eq!(pending_message_counts[&lsp_b.node_id], 1);
+
+		let msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 3);
+		assert_eq!(msgs.iter().filter(|(node_id, _)| *node_id == lsp_a.node_id).count(), 2);
+		assert!(client.liquidity_manager.pending_message_counts().is_empty());
+	}
+
+	fn send_unknown_protocol_request(policy: UnknownProtocolPolicy) -> (Node, Node) {
+		let client = create_node(1, None);
+		let lsp = create_node_with_provider_config(
+			2,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				strict_request_matching: false,
+				max_peer_misbehaviors: Some(1),
+				unknown_protocol_policy: policy,
+			}),
+		);
+
+		let msg = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","id":"abc","method":"lsps1.get_info","params":{}}"#
+				.to_string(),
+		};
+		lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+		assert!(!lsp.liquidity_manager.is_misbehaving(&client.node_id));
+		(client, lsp)
+	}
+
+	fn list_protocols(lsp: &Node, client: &Node) -> String {
+		let msg = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","id":"abc","method":"lsps0.list_protocols","params":{}}"#
+				.to_string(),
+		};
+		lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+		let mut msgs = lsp.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		msgs.pop().unwrap().1.payload
+	}
+
+	#[test]
+	fn list_protocols_advertises_enabled_protocols() {
+		let client = create_node(1, None);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert_eq!(
+			list_protocols(&lsp, &client),
+			r#"{"jsonrpc":"2.0","id":"abc","result":{"protocols":[2]}}"#
+		);
+
+		let lsp = create_node_with_provider_config(
+			3,
+			Some(LiquidityProviderConfig {
+				jit_channels: None,
+				strict_request_matching: false,
+				max_peer_misbehaviors: None,
+				unknown_protocol_policy: UnknownProtocolPolicy::Log,
+			}),
+		);
+		assert_eq!(
+			list_protocols(&lsp, &client),
+			r#"{"jsonrpc":"2.0","id":"abc","result":{"protocols":[]}}"#
+		);
+	}
+
+	#[test]
+	fn peer_protocols_are_recorded_until_disconnect() {
+		let client = create_node(1, None);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert_eq!(client.liquidity_manager.peer_protocols(&lsp.node_id), None);
+
+		client.liquidity_manager.list_protocols(lsp.node_id);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(client.liquidity_manager.peer_protocols(&lsp.node_id), Some(vec![2]));
+
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+		assert_eq!(client.liquidity_manager.peer_protocols(&lsp.node_id), None);
+	}
+
+	#[test]
+	fn unknown_protocol_request_is_answered_with_method_not_found() {
+		let (client, lsp) = send_unknown_protocol_request(UnknownProtocolPolicy::RespondWithError);
+
+		let msgs = lsp.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, client.node_id);
+		let response: serde_json::Value = serde_json::from_str(&msgs[0].1.payload).unwrap();
+		assert_eq!(response["id"], "abc");
+		assert_eq!(response["error"]["code"], -32601);
+	}
+
+	#[test]
+	fn unknown_protocol_request_is_dropped_if_configured() {
+		let (_, lsp) = send_unknown_protocol_request(UnknownProtocolPolicy::Ignore);
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert!(lsp.logger.lines.lock().unwrap().is_empty());
+
+		let (_, lsp) = send_unknown_protocol_request(UnknownProtocolPolicy::Log);
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert!(lsp
+			.logger
+			.lines
+			.lock()
+			.unwrap()
+			.iter()
+			.any(|(level, line)| *level == Level::Info && line.contains("LSPS1")));
+	}
+
+	#[test]
+	fn lsps2_request_is_answered_with_protocol_unavailable_while_paused() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		lsp.liquidity_manager.set_jit_channels_paused(true);
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let response: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(response["error"]["code"], -32000);
+		assert_eq!(response["error"]["message"], "protocol temporarily unavailable");
+		assert!(!lsp.liquidity_manager.is_misbehaving(&client.node_id));
+
+		lsp.liquidity_manager.set_jit_channels_paused(false);
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 2, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+	}
+
+	#[test]
+	fn lsps2_request_without_handler_is_answered_with_method_not_found() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, None);
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let (_, response) = lsp.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		let response: serde_json::Value = serde_json::from_str(&response.payload).unwrap();
+		assert_eq!(response["error"]["code"], -32601);
+	}
+
+	fn deliver_buy_response_for_get_info_request(strict_request_matching: bool) -> (Node, Node) {
+		let client = create_node_with_provider_config(
+			1,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				strict_request_matching,
+				max_peer_misbehaviors: None,
+				unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+			}),
+		);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		let buy_response = RawLSPSMessage {
+			payload: format!(
+				r#"{{"jsonrpc":"2.0","id":"{}","result":{{"jit_channel_scid":"0x0x42","lsp_cltv_expiry_delta":144,"client_trusts_lsp":false}}}}"#,
+				request_id.as_str()
+			),
+		};
+		client.liquidity_manager.handle_custom_message(buy_response, &lsp.node_id).unwrap();
+
+		(client, lsp)
+	}
+
+	#[test]
+	fn strict_request_matching_surfaces_mismatched_response() {
+		let (client, lsp) = deliver_buy_response_for_get_info_request(true);
+
+		match get_events(&client).pop() {
+			Some(Event::MismatchedResponse { counterparty_node_id, expected_method, .. }) => {
+				assert_eq!(counterparty_node_id, lsp.node_id);
+				assert_eq!(expected_method, Some("lsps2.get_info".to_string()));
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert!(client.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert!(client
+			.logger
+			.lines
+			.lock()
+			.unwrap()
+			.iter()
+			.any(|(level, line)| *level == Level::Error && line.contains("lsps2.get_info")));
+	}
+
+	#[test]
+	fn mismatched_response_is_answered_with_parse_error_by_default() {
+		let (client, lsp) = deliver_buy_response_for_get_info_request(false);
+
+		assert!(get_events(&client).is_empty());
+		let msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, lsp.node_id);
+		assert!(msgs[0].1.payload.contains("parse error"));
+	}
+
+	fn request_menu_entry(
+		client: &Node, lsp: &Node, user_channel_id: u128,
+	) -> (u128, OpeningFeeParams) {
+		match request_opening_fee_params_menu(
+			client,
+			lsp,
+			None,
+			user_channel_id,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	/// Has `client` request a menu from `lsp`, returning the id of the LSP's `get_info` request.
+	fn receive_get_info_request(client: &Node, lsp: &Node) -> RequestId {
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(client, lsp), 1);
+		assert_eq!(pass_messages(lsp, client), 1);
+		assert_eq!(pass_messages(client, lsp), 1);
+		match get_events(lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn zero_promise_secret_is_rejected() {
+		let mut jit_channels_config = default_jit_channels_config();
+		jit_channels_config.promise_secret = [0; 32];
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(jit_channels_config));
+
+		let request_id = receive_get_info_request(&client, &lsp);
+		let menu = vec![raw_opening_fee_params(100, 21)];
+		match lsp.liquidity_manager.opening_fee_params_generated(
+			client.node_id,
+			request_id.clone(),
+			menu.clone(),
+		) {
+			Err(APIError::APIMisuseError { err }) => assert!(err.contains("all zeros")),
+			res => panic!("Unexpected result: {:?}", res),
+		}
+
+		// Neither may a zero secret be rotated in, but a random one makes the node a valid LSP.
+		assert!(lsp.liquidity_manager.rotate_promise_secret([0; 32]).is_err());
+		lsp.liquidity_manager.rotate_promise_secret([42; 32]).unwrap();
+		lsp.liquidity_manager
+			.opening_fee_params_generated(client.node_id, request_id, menu)
+			.unwrap();
+	}
+
+	#[test]
+	fn jit_channels_require_intercept_htlcs() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let provider_config = LiquidityProviderConfig {
+			jit_channels: Some(default_jit_channels_config()),
+			..Default::default()
+		};
+		let lsp = try_create_node(
+			2,
+			Some(provider_config),
+			Box::new(HashMap::new()),
+			UserConfig::default(),
+		)
+		.unwrap();
+
+		let request_id = receive_get_info_request(&client, &lsp);
+		match lsp.liquidity_manager.opening_fee_params_generated(
+			client.node_id,
+			request_id,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			Err(APIError::APIMisuseError { err }) => {
+				assert!(err.contains("accept_intercept_htlcs"))
+			}
+			res => panic!("Unexpected result: {:?}", res),
+		}
+	}
+
+	#[test]
+	fn clients_need_neither_promise_secret_nor_intercept_htlcs() {
+		let provider_config = LiquidityProviderConfig {
+			jit_channels: Some(JITChannelsConfig::default()),
+			..Default::default()
+		};
+		let client = try_create_node(
+			1,
+			Some(provider_config),
+			Box::new(HashMap::new()),
+			UserConfig::default(),
+		)
+		.unwrap();
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { opening_fee_params_menu, .. } => {
+				assert_eq!(opening_fee_params_menu.len(), 1)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+	}
+
+	#[test]
+	fn zero_max_pending_channel_opens_is_rejected() {
+		let mut jit_channels_config = default_jit_channels_config();
+		jit_channels_config.max_pending_channel_opens = Some(0);
+		let provider_config = LiquidityProviderConfig {
+			jit_channels: Some(jit_channels_config),
+			..Default::default()
+		};
+		let user_config = UserConfig { accept_intercept_htlcs: true, ..Default::default() };
+		match try_create_node(2, Some(provider_config), Box::new(HashMap::new()), user_config) {
+			Err(APIError::APIMisuseError { err }) => {
+				assert!(err.contains("max_pending_channel_opens"))
+			}
+			res => panic!("Unexpected result: {:?}", res.map(|node| node.node_id)),
+		}
+	}
+
+	#[test]
+	fn rotated_promise_secret_is_accepted_during_grace_period() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		let (first_channel_id, first_params) = request_menu_entry(&client, &lsp, 1);
+		let (second_channel_id, second_params) = request_menu_entry(&client, &lsp, 2);
+
+		lsp.liquidity_manager.rotate_promise_secret([43; 32]).unwrap();
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(lsp.node_id, first_channel_id, first_params, None, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { .. })) => {}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		lsp.liquidity_manager
+			.lsps2_message_handler
+			.as_ref()
+			.unwrap()
+			.prune_promise_secrets(u64::MAX);
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(lsp.node_id, second_channel_id, second_params, None, None)
+			.unwrap();
+		let mut msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let (_, buy_request) = msgs.remove(0);
+		assert!(lsp.liquidity_manager.handle_custom_message(buy_request, &client.node_id).is_err());
+		assert!(get_events(&lsp).is_empty());
+	}
+
+	#[test]
+	fn peer_connected_records_lsp_support() {
+		let client = create_node(1, None);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let other = create_node(3, None);
+		assert_eq!(client.liquidity_manager.peer_supports_lsp(&lsp.node_id), None);
+
+		let lsp_features = lsp.liquidity_manager.provided_init_features(&client.node_id);
+		client.liquidity_manager.peer_connected(&lsp.node_id, &lsp_features);
+		assert_eq!(client.liquidity_manager.peer_supports_lsp(&lsp.node_id), Some(true));
+
+		let other_features = other.liquidity_manager.provided_init_features(&client.node_id);
+		client.liquidity_manager.peer_connected(&other.node_id, &other_features);
+		assert_eq!(client.liquidity_manager.peer_supports_lsp(&other.node_id), Some(false));
+	}
+
+	#[test]
+	fn outstanding_scids_lists_pending_channels() {
+		let client_a = create_node(1, Some(default_jit_channels_config()));
+		let client_b = create_node(3, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		assert!(lsp.liquidity_manager.outstanding_scids().is_empty());
+
+		buy_jit_channel(&client_a, &lsp, None, 1, 42);
+		buy_jit_channel(&client_b, &lsp, None, 1, 43);
+
+		assert_eq!(
+			lsp.liquidity_manager.outstanding_scids(),
+			vec![(42, client_a.node_id, 42), (43, client_b.node_id, 43)]
+		);
+	}
+
+	#[test]
+	fn jit_channel_funding_created_registers_funding_transaction() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let scid = 42;
+
+		buy_jit_channel(&client, &lsp, None, 1, scid);
+
+		let funding_txid = Txid::from_inner([7; 32]);
+		lsp.liquidity_manager
+			.jit_channel_funding_created(scid as u128, funding_txid, 1, &funding_script_pubkey())
+			.unwrap();
+
+		let registered_txs = lsp.filter.registered_txs.lock().unwrap();
+		assert_eq!(*registered_txs, vec![(funding_txid, funding_script_pubkey())]);
+		assert_eq!(lsp.liquidity_manager.get_relevant_txids(), vec![(funding_txid, None)]);
+	}
+
+	#[test]
+	fn jit_channel_funding_created_fails_for_unknown_channel() {
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let funding_txid = Txid::from_inner([7; 32]);
+
+		assert!(lsp
+			.liquidity_manager
+			.jit_channel_funding_created(42, funding_txid, 1, &funding_script_pubkey())
+			.is_err());
+		assert!(lsp.filter.registered_txs.lock().unwrap().is_empty());
+		assert!(lsp.liquidity_manager.get_relevant_txids().is_empty());
+	}
+
+	#[test]
+	fn get_info_rejections_are_logged_and_counted_by_reason() {
+		let client = create_node(1, None);
+		let mut jit_channels_config = default_jit_channels_config();
+		jit_channels_config.reject_requests_with_token = true;
+		let lsp = create_node_with_provider_config(
+			2,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(jit_channels_config),
+				strict_request_matching: false,
+				max_peer_misbehaviors: Some(1),
+				unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+			}),
+		);
+		let get_info = |id: &str, token: &str| {
+			RawLSPSMessage {
+			payload: format!(
+				"{{\"jsonrpc\":\"2.0\",\"id\":\"{}\",\"method\":\"lsps2.get_info\",\"params\":{{\"version\":1,\"token\":\"{}\"}}}}",
+				id, token
+			),
+		}
+		};
+		let rejection_logged = |reason: GetInfoRejectionReason| {
+			lsp.logger.lines.lock().unwrap().iter().any(|(level, line)| {
+				*level == Level::Info
+					&& line.contains(&format!("Rejected get_info request from {}", client.node_id))
+					&& line.ends_with(&format!("reason: {}", reason))
+			})
+		};
+
+		lsp.liquidity_manager.set_jit_channels_paused(true);
+		lsp.liquidity_manager
+			.handle_custom_message(get_info("1", "coupon"), &client.node_id)
+			.unwrap();
+		lsp.liquidity_manager.set_jit_channels_paused(false);
+
+		assert!(lsp
+			.liquidity_manager
+			.handle_custom_message(get_info("2", "coupon"), &client.node_id)
+			.is_err());
+		assert!(!lsp.liquidity_manager.is_misbehaving(&client.node_id));
+
+		let garbage = RawLSPSMessage { payload: "garbage".to_string() };
+		lsp.liquidity_manager.handle_custom_message(garbage, &client.node_id).unwrap();
+		assert!(lsp.liquidity_manager.is_misbehaving(&client.node_id));
+
+		assert!(lsp
+			.liquidity_manager
+			.handle_custom_message(get_info("3", "coupon"), &client.node_id)
+			.is_err());
+		assert!(get_events(&lsp)
+			.iter()
+			.all(|event| !matches!(event, Event::LSPS2(LSPS2Event::GetInfo { .. }))));
+
+		for reason in [
+			GetInfoRejectionReason::Maintenance,
+			GetInfoRejectionReason::InvalidToken,
+			GetInfoRejectionReason::RateLimited,
+		]
+		.iter()
+		{
+			assert_eq!(lsp.liquidity_manager.get_info_rejection_count(*reason), 1);
+			assert!(rejection_logged(*reason));
+		}
+		assert_ne!(
+			GetInfoRejectionReason::Maintenance.as_str(),
+			GetInfoRejectionReason::InvalidToken.as_str()
+		);
+		assert_ne!(
+			GetInfoRejectionReason::InvalidToken.as_str(),
+			GetInfoRejectionReason::RateLimited.as_str()
+		);
+	}
+
+	#[test]
+	fn peer_abuse_score_can_be_read_and_reset() {
+		let client = create_node(1, None);
+		let lsp = create_node_with_provider_config(
+			2,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				strict_request_matching: false,
+				max_peer_misbehaviors: Some(2),
+				unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+			}),
+		);
+		assert_eq!(lsp.liquidity_manager.peer_abuse_score(&client.node_id), 0);
+
+		for _ in 0..2 {
+			let garbage = RawLSPSMessage { payload: "garbage".to_string() };
+			lsp.liquidity_manager.handle_custom_message(garbage, &client.node_id).unwrap();
+		}
+		assert_eq!(lsp.liquidity_manager.peer_abuse_score(&client.node_id), 2);
+		assert!(lsp.liquidity_manager.is_misbehaving(&client.node_id));
+
+		lsp.liquidity_manager.reset_peer_abuse(&client.node_id);
+		assert_eq!(lsp.liquidity_manager.peer_abuse_score(&client.node_id), 0);
+		assert!(!lsp.liquidity_manager.is_misbehaving(&client.node_id));
+	}
+
+	#[test]
+	fn responses_to_our_own_requests_are_not_counted_as_misbehavior() {
+		let client = create_node_with_provider_config(
+			1,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				max_peer_misbehaviors: Some(1),
+				..Default::default()
+			}),
+		);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		// A second response to the same request, e.g., as it was re-sent after a reconnect.
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		let mut msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let (_, get_versions) = msgs.remove(0);
+		lsp.liquidity_manager.handle_custom_message(get_versions.clone(), &client.node_id).unwrap();
+		lsp.liquidity_manager.handle_custom_message(get_versions, &client.node_id).unwrap();
+		for (_, response) in lsp.liquidity_manager.get_and_clear_pending_msg() {
+			let _ = client.liquidity_manager.handle_custom_message(response, &lsp.node_id);
+		}
+
+		// A late response to a request we dropped once the LSP disconnected.
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		client.liquidity_manager.peer_disconnected(&lsp.node_id);
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(1_000, 1_000)],
+			)
+			.unwrap();
+		for (_, response) in lsp.liquidity_manager.get_and_clear_pending_msg() {
+			let _ = client.liquidity_manager.handle_custom_message(response, &lsp.node_id);
+		}
+
+		// An error response to a request of ours.
+		client.liquidity_manager.list_protocols(lsp.node_id);
+		let mut msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		let request: serde_json::Value = serde_json::from_str(&msgs.remove(0).1.payload).unwrap();
+		let method_not_found = RawLSPSMessage {
+			payload: format!(
+				r#"{{"jsonrpc":"2.0","id":{},"error":{{"code":-32601,"message":"Method not found"}}}}"#,
+				request["id"]
+			),
+		};
+		assert!(client
+			.liquidity_manager
+			.handle_custom_message(method_not_found, &lsp.node_id)
+			.is_err());
+
+		assert_eq!(client.liquidity_manager.peer_abuse_score(&lsp.node_id), 0);
+
+		let garbage = RawLSPSMessage { payload: "garbage".to_string() };
+		client.liquidity_manager.handle_custom_message(garbage, &lsp.node_id).unwrap();
+		assert!(client.liquidity_manager.is_misbehaving(&lsp.node_id));
+	}
+
+	#[test]
+	fn misbehaving_peer_is_refused_after_threshold() {
+		let client = create_node(1, None);
+		let other_client = create_node(3, None);
+		let lsp = create_node_with_provider_config(
+			2,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				strict_request_matching: false,
+				max_peer_misbehaviors: Some(3),
+				unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+			}),
+		);
+
+		for _ in 0..3 {
+			assert!(get_events(&lsp).is_empty());
+			let garbage = RawLSPSMessage { payload: "garbage".to_string() };
+			lsp.liquidity_manager.handle_custom_message(garbage, &client.node_id).unwrap();
+		}
+		match get_events(&lsp).pop() {
+			Some(Event::PeerMisbehaved { counterparty_node_id }) => {
+				assert_eq!(counterparty_node_id, client.node_id);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		lsp.liquidity_manager.get_and_clear_pending_msg();
+
+		let get_versions = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","id":"abc","method":"lsps2.get_versions","params":{}}"#
+				.to_string(),
+		};
+		assert!(lsp
+			.liquidity_manager
+			.handle_custom_message(get_versions.clone(), &client.node_id)
+			.is_err());
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+		assert!(get_events(&lsp).is_empty());
+
+		lsp.liquidity_manager.handle_custom_message(get_versions, &other_client.node_id).unwrap();
+		assert_eq!(lsp.liquidity_manager.get_and_clear_pending_msg().len(), 1);
+	}
+
+	fn funding_script_pubkey() -> Script {
+		Script::new_v0_p2wsh(&WScriptHash::from_inner([3; 32]))
+	}
+
+	fn funding_transaction() -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut { value: 100_000, script_pubkey: funding_script_pubkey() }],
+		}
+	}
+
+	#[test]
+	fn reading_truncated_message_fails_with_short_read() {
+		let node = create_node(1, None);
+		let message = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","method":"lsps0.list_protocols","id":"abc","params":{}}"#
+				.to_string(),
+		};
+		let encoded = message.encode();
+
+		let mut reader = lightning::io::Cursor::new(&encoded);
+		assert_eq!(
+			node.liquidity_manager.read(LSPS_MESSAGE_TYPE_ID, &mut reader).unwrap(),
+			Some(message)
+		);
+
+		for len in [0, 1, 2, encoded.len() / 2, encoded.len() - 1].iter() {
+			let mut reader = lightning::io::Cursor::new(&encoded[..*len]);
+			assert_eq!(
+				node.liquidity_manager.read(LSPS_MESSAGE_TYPE_ID, &mut reader),
+				Err(lightning::ln::msgs::DecodeError::ShortRead)
+			);
+		}
+	}
+
+	#[test]
+	fn reading_oversized_message_fails_before_reading_payload() {
+		let node = create_node(1, None);
+		node.liquidity_manager.set_max_payload_length(1024);
+
+		let message = RawLSPSMessage {
+			payload: r#"{"jsonrpc":"2.0","method":"lsps0.list_protocols","id":"abc","params":{}}"#
+				.to_string(),
+		};
+		let encoded = message.encode();
+		let mut reader = lightning::io::Cursor::new(&encoded);
+		assert_eq!(
+			node.liquidity_manager.read(LSPS_MESSAGE_TYPE_ID, &mut reader).unwrap(),
+			Some(message)
+		);
+
+		// Only the length prefix is present, so a read of the payload would fail with `ShortRead`.
+		let oversized = 60_000u16.to_be_bytes();
+		let mut reader = lightning::io::Cursor::new(&oversized[..]);
+		assert_eq!(
+			node.liquidity_manager.read(LSPS_MESSAGE_TYPE_ID, &mut reader),
+			Err(lightning::ln::msgs::DecodeError::InvalidValue)
+		);
+		assert_eq!(reader.position(), 2);
+	}
+
+	#[test]
+	fn metrics_count_messages_and_error_responses() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		let client_metrics = Arc::new(AtomicMetrics::new());
+		client.liquidity_manager.set_metrics(Arc::clone(&client_metrics));
+		let lsp_metrics = Arc::new(AtomicMetrics::new());
+		lsp.liquidity_manager.set_metrics(Arc::clone(&lsp_metrics));
+
+		let (jit_channel_id, mut opening_fee_params) = match request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		) {
+			LSPS2Event::GetInfoResponse { jit_channel_id, mut opening_fee_params_menu, .. } => {
+				(jit_channel_id, opening_fee_params_menu.remove(0))
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		// Tampering with the params invalidates their promise.
+		opening_fee_params.min_fee_msat -= 1;
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		let (_, msg) = client.liquidity_manager.get_and_clear_pending_msg().pop().unwrap();
+		assert!(lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).is_err());
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		let rendered = lsp_metrics.render_prometheus();
+		for line in [
+			"# TYPE lsps_messages_received_total counter",
+			"lsps_messages_received_total{method=\"lsps2.get_versions\"} 1",
+			"lsps_messages_received_total{method=\"lsps2.get_info\"} 1",
+			"lsps_messages_received_total{method=\"lsps2.buy\"} 1",
+			"lsps_messages_sent_total{method=\"lsps2.buy\"} 1",
+			"lsps_error_responses_sent_total{code=\"2\"} 1",
+			"# TYPE lsps_active_negotiation_peers gauge",
+			"lsps_active_negotiation_peers 0",
+		]
+		.iter()
+		{
+			assert!(rendered.lines().any(|l| l == *line), "missing {} in:\n{}", line, rendered);
+		}
+
+		let rendered = client_metrics.render_prometheus();
+		for line in [
+			"lsps_messages_sent_total{method=\"lsps2.get_info\"} 1",
+			"lsps_messages_received_total{method=\"lsps2.buy\"} 1",
+			"lsps_error_responses_received_total{code=\"2\"} 1",
+			"lsps_active_negotiation_peers 1",
+		]
+		.iter()
+		{
+			assert!(rendered.lines().any(|l| l == *line), "missing {} in:\n{}", line, rendered);
+		}
+	}
+
+	#[test]
+	fn untrusting_client_claims_payment_after_funding_confirmed() {
+		let mut client_config = default_jit_channels_config();
+		client_config.await_funding_confirmation = true;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		match buy_jit_channel(&client, &lsp, None, 1, 42) {
+			LSPS2Event::InvoiceGenerationReady { client_trusts_lsp, .. } => {
+				assert!(!client_trusts_lsp)
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		let funding_tx = funding_transaction();
+		client
+			.liquidity_manager
+			.jit_channel_payment_received(1, funding_tx.txid(), 0, &funding_script_pubkey())
+			.unwrap();
+		assert!(get_events(&client).is_empty());
+		assert_eq!(
+			*client.filter.registered_txs.lock().unwrap(),
+			vec![(funding_tx.txid(), funding_script_pubkey())]
+		);
+		assert_eq!(client.liquidity_manager.get_relevant_txids(), vec![(funding_tx.txid(), None)]);
+
+		let header = genesis_block(Network::Regtest).header;
+		client.liquidity_manager.transactions_confirmed(&header, &[(0, &funding_transaction())], 1);
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::ClaimPayment {
 				counterparty_node_id,
-			)?;
+				user_channel_id,
+				..
+			})) => {
+				assert_eq!(counterparty_node_id, lsp.node_id);
+				assert_eq!(user_channel_id, 1);
+			}
+			event => panic!("Unexpected event: {:?}", event),
 		}
+		assert!(client.liquidity_manager.get_relevant_txids().is_empty());
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Ready));
+	}
 
-		Ok(())
+	#[test]
+	fn untrusting_client_claims_payment_at_configured_confirmation_depth() {
+		let mut client_config = default_jit_channels_config();
+		client_config.await_funding_confirmation = true;
+		client_config.min_funding_confirmations = 3;
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+
+		let funding_tx = funding_transaction();
+		client
+			.liquidity_manager
+			.jit_channel_payment_received(1, funding_tx.txid(), 0, &funding_script_pubkey())
+			.unwrap();
+
+		let header = genesis_block(Network::Regtest).header;
+		client.liquidity_manager.transactions_confirmed(&header, &[(0, &funding_tx)], 1);
+		assert!(get_events(&client).is_empty());
+
+		// A reorg resets the confirmation depth.
+		client.liquidity_manager.transaction_unconfirmed(&funding_tx.txid());
+		client.liquidity_manager.best_block_updated(&header, 3);
+		assert!(get_events(&client).is_empty());
+		client.liquidity_manager.transactions_confirmed(&header, &[(0, &funding_tx)], 2);
+
+		client.liquidity_manager.best_block_updated(&header, 3);
+		assert!(get_events(&client).is_empty());
+		assert_ne!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Ready));
+
+		client.liquidity_manager.best_block_updated(&header, 4);
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::ClaimPayment { user_channel_id, .. })) => {
+				assert_eq!(user_channel_id, 1);
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert!(client.liquidity_manager.get_relevant_txids().is_empty());
+		assert_eq!(client.liquidity_manager.jit_channel_state(1), Some(JitChannelState::Ready));
 	}
 
-	fn handle_lsps_message(
-		&self, msg: LSPSMessage, sender_node_id: &PublicKey,
-	) -> Result<(), lightning::ln::msgs::LightningError> {
-		match msg {
-			LSPSMessage::Invalid => {
-				return Err(LightningError { err: format!("{} did not understand a message we previously sent, maybe they don't support a protocol we are trying to use?", sender_node_id), action: ErrorAction::IgnoreAndLog(Level::Error)});
+	#[test]
+	fn client_claims_payment_immediately_by_default() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		buy_jit_channel(&client, &lsp, None, 1, 42);
+
+		let funding_tx = funding_transaction();
+		client
+			.liquidity_manager
+			.jit_channel_payment_received(1, funding_tx.txid(), 0, &funding_script_pubkey())
+			.unwrap();
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::ClaimPayment { user_channel_id, .. })) => {
+				assert_eq!(user_channel_id, 1);
 			}
-			LSPSMessage::LSPS0(msg) => {
-				self.lsps0_message_handler.handle_message(msg, sender_node_id)?;
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert!(client.filter.registered_txs.lock().unwrap().is_empty());
+		assert!(client
+			.liquidity_manager
+			.jit_channel_payment_received(1, funding_tx.txid(), 0, &funding_script_pubkey())
+			.is_err());
+	}
+
+	#[test]
+	fn label_is_echoed_in_all_client_events() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		lsp.scid_allocator.queue_scid(42);
+		let label = Some("ticket-42".to_string());
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, label.clone())
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfo { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.opening_fee_params_generated(
+				client.node_id,
+				request_id,
+				vec![raw_opening_fee_params(100, 21)],
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+
+		let (jit_channel_id, opening_fee_params) = match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::GetInfoResponse {
+				jit_channel_id,
+				mut opening_fee_params_menu,
+				label: event_label,
+				..
+			})) => {
+				assert_eq!(event_label, label);
+				(jit_channel_id, opening_fee_params_menu.remove(0))
 			}
-			LSPSMessage::LSPS2(msg) => match &self.lsps2_message_handler {
-				Some(lsps2_message_handler) => {
-					lsps2_message_handler.handle_message(msg, sender_node_id)?;
-				}
-				None => {
-					return Err(LightningError { err: format!("Received LSPS2 message without LSPS2 message handler configured. From node = {:?}", sender_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
-				}
-			},
+			event => panic!("Unexpected event: {:?}", event),
+		};
+
+		client
+			.liquidity_manager
+			.opening_fee_params_selected(
+				lsp.node_id,
+				jit_channel_id,
+				opening_fee_params,
+				None,
+				None,
+			)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match get_events(&lsp).pop() {
+			Some(Event::LSPS2(LSPS2Event::BuyRequest { request_id, .. })) => request_id,
+			event => panic!("Unexpected event: {:?}", event),
+		};
+		lsp.liquidity_manager
+			.invoice_parameters_generated(client.node_id, request_id, 42, 144, false)
+			.unwrap();
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::InvoiceGenerationReady {
+				label: event_label, ..
+			})) => assert_eq!(event_label, label),
+			event => panic!("Unexpected event: {:?}", event),
+		}
+
+		let funding_tx = funding_transaction();
+		client
+			.liquidity_manager
+			.jit_channel_payment_received(1, funding_tx.txid(), 0, &funding_script_pubkey())
+			.unwrap();
+		match get_events(&client).pop() {
+			Some(Event::LSPS2(LSPS2Event::ClaimPayment { label: event_label, .. })) => {
+				assert_eq!(event_label, label)
+			}
+			event => panic!("Unexpected event: {:?}", event),
 		}
-		Ok(())
 	}
 
-	fn enqueue_message(&self, node_id: PublicKey, msg: LSPSMessage) {
-		let mut pending_msgs = self.pending_messages.lock().unwrap();
-		pending_msgs.push((node_id, msg));
+	#[derive(Clone, Debug, PartialEq, Eq)]
+	enum StoreCall {
+		Set(RequestId, String),
+		Get(RequestId),
+		Remove(RequestId),
 	}
-}
 
-impl<
-		ES: Deref + Clone,
-		M: Deref,
-		T: Deref,
-		F: Deref,
-		R: Deref,
-		SP: Deref,
-		L: Deref,
-		Descriptor: SocketDescriptor,
-		RM: Deref,
-		CM: Deref,
-		OM: Deref,
-		CMH: Deref,
-		NS: Deref,
-		C: Deref,
-	> CustomMessageReader
-	for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
-where
-	ES::Target: EntropySource,
-	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
-	T::Target: BroadcasterInterface,
-	F::Target: FeeEstimator,
-	R::Target: Router,
-	SP::Target: SignerProvider,
-	L::Target: Logger,
-	RM::Target: RoutingMessageHandler,
-	CM::Target: ChannelMessageHandler,
-	OM::Target: OnionMessageHandler,
-	CMH::Target: CustomMessageHandler,
-	NS::Target: NodeSigner,
-	C::Target: Filter,
-{
-	type CustomMessage = RawLSPSMessage;
+	struct MockRequestMethodStore {
+		methods: HashMap<RequestId, String>,
+		calls: Arc<Mutex<Vec<StoreCall>>>,
+	}
 
-	fn read<RD: lightning::io::Read>(
-		&self, message_type: u16, buffer: &mut RD,
-	) -> Result<Option<Self::CustomMessage>, lightning::ln::msgs::DecodeError> {
-		match message_type {
-			LSPS_MESSAGE_TYPE_ID => Ok(Some(RawLSPSMessage::read(buffer)?)),
-			_ => Ok(None),
+	impl RequestMethodStore for MockRequestMethodStore {
+		fn set_method(&mut self, request_id: RequestId, method: String) {
+			self.calls.lock().unwrap().push(StoreCall::Set(request_id.clone(), method.clone()));
+			self.methods.insert(request_id, method);
+		}
+
+		fn get_method(&self, request_id: &RequestId) -> Option<String> {
+			self.calls.lock().unwrap().push(StoreCall::Get(request_id.clone()));
+			self.methods.get(request_id).cloned()
+		}
+
+		fn remove_method(&mut self, request_id: &RequestId) -> Option<String> {
+			self.calls.lock().unwrap().push(StoreCall::Remove(request_id.clone()));
+			self.methods.remove(request_id)
 		}
 	}
-}
 
-impl<
-		ES: Deref + Clone,
-		M: Deref,
-		T: Deref,
-		F: Deref,
-		R: Deref,
-		SP: Deref,
-		L: Deref,
-		Descriptor: SocketDescriptor,
-		RM: Deref,
-		CM: Deref,
-		OM: Deref,
-		CMH: Deref,
-		NS: Deref,
-		C: Deref,
-	> CustomMessageHandler
-	for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
-where
-	ES::Target: EntropySource,
-	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
-	T::Target: BroadcasterInterface,
-	F::Target: FeeEstimator,
-	R::Target: Router,
-	SP::Target: SignerProvider,
-	L::Target: Logger,
-	RM::Target: RoutingMessageHandler,
-	CM::Target: ChannelMessageHandler,
-	OM::Target: OnionMessageHandler,
-	CMH::Target: CustomMessageHandler,
-	NS::Target: NodeSigner,
-	C::Target: Filter,
-{
-	fn handle_custom_message(
-		&self, msg: Self::CustomMessage, sender_node_id: &PublicKey,
-	) -> Result<(), lightning::ln::msgs::LightningError> {
-		let message = {
-			let mut request_id_to_method_map = self.request_id_to_method_map.lock().unwrap();
-			LSPSMessage::from_str_with_id_map(&msg.payload, &mut request_id_to_method_map)
-		};
+	fn recorded_calls(calls: &Mutex<Vec<StoreCall>>) -> Vec<StoreCall> {
+		calls.lock().unwrap().clone()
+	}
 
-		match message {
-			Ok(msg) => self.handle_lsps_message(msg, sender_node_id),
-			Err(_) => {
-				self.enqueue_message(*sender_node_id, LSPSMessage::Invalid);
-				Ok(())
+	#[test]
+	fn request_method_store_is_used_for_outgoing_requests_and_responses() {
+		let calls = Arc::new(Mutex::new(Vec::new()));
+		let store = MockRequestMethodStore { methods: HashMap::new(), calls: Arc::clone(&calls) };
+		let client = create_node_with_request_method_store(
+			1,
+			Some(LiquidityProviderConfig {
+				jit_channels: Some(default_jit_channels_config()),
+				strict_request_matching: false,
+				max_peer_misbehaviors: None,
+				unknown_protocol_policy: UnknownProtocolPolicy::RespondWithError,
+			}),
+			Box::new(store),
+		);
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert!(calls.lock().unwrap().is_empty());
+
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		let request_id = match recorded_calls(&calls).as_slice() {
+			[StoreCall::Set(request_id, method)] => {
+				assert_eq!(method, "lsps2.get_versions");
+				request_id.clone()
 			}
+			calls => panic!("Unexpected calls: {:?}", calls),
+		};
+
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(recorded_calls(&calls).last(), Some(&StoreCall::Get(request_id)));
+
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		match recorded_calls(&calls).last() {
+			Some(StoreCall::Set(_, method)) => assert_eq!(method, "lsps2.get_info"),
+			call => panic!("Unexpected call: {:?}", call),
 		}
 	}
 
-	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, Self::CustomMessage)> {
-		let mut request_id_to_method_map = self.request_id_to_method_map.lock().unwrap();
-		self.pending_messages
+	#[test]
+	fn purged_request_ignores_subsequent_response() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		let request_id = match client.liquidity_manager.get_and_clear_pending_msg().pop() {
+			Some((_, msg)) => {
+				let payload: serde_json::Value = serde_json::from_str(&msg.payload).unwrap();
+				let request_id =
+					RequestId::new(payload["id"].as_str().unwrap().to_string()).unwrap();
+				lsp.liquidity_manager.handle_custom_message(msg, &client.node_id).unwrap();
+				request_id
+			}
+			None => panic!("Expected get_versions request"),
+		};
+		assert!(client
+			.liquidity_manager
+			.request_method_store
 			.lock()
 			.unwrap()
-			.drain(..)
-			.map(|(public_key, lsps_message)| {
-				if let Some((request_id, method_name)) = lsps_message.get_request_id_and_method() {
-					request_id_to_method_map.insert(request_id, method_name);
-				}
-				(
-					public_key,
-					RawLSPSMessage { payload: serde_json::to_string(&lsps_message).unwrap() },
-				)
-			})
-			.collect()
+			.get_method(&request_id)
+			.is_some());
+
+		assert!(client.liquidity_manager.purge_request(&lsp.node_id, &request_id));
+		assert!(client
+			.liquidity_manager
+			.request_method_store
+			.lock()
+			.unwrap()
+			.get_method(&request_id)
+			.is_none());
+		assert!(!client.liquidity_manager.purge_request(&lsp.node_id, &request_id));
+
+		// The LSP's response is not processed, so no get_info request follows.
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert!(get_events(&client).is_empty());
+		let msgs = client.liquidity_manager.get_and_clear_pending_msg();
+		assert!(msgs.iter().all(|(_, msg)| !msg.payload.contains("lsps2.get_info")));
+		assert_eq!(
+			client.liquidity_manager.jit_channel_state(1),
+			Some(JitChannelState::AwaitingGetInfo)
+		);
 	}
 
-	fn provided_node_features(&self) -> NodeFeatures {
-		let mut features = NodeFeatures::empty();
+	#[test]
+	fn response_to_handled_event_is_immediately_drainable() {
+		let client = create_node(1, Some(default_jit_channels_config()));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
 
-		if self.provider_config.is_some() {
-			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
+		client
+			.liquidity_manager
+			.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+			.unwrap();
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert_eq!(pass_messages(&lsp, &client), 1);
+		assert_eq!(pass_messages(&client, &lsp), 1);
+		assert!(lsp.liquidity_manager.get_and_clear_pending_msg().is_empty());
+
+		for event in get_events(&lsp) {
+			match event {
+				Event::LSPS2(LSPS2Event::GetInfo { request_id, counterparty_node_id, .. }) => {
+					lsp.liquidity_manager
+						.opening_fee_params_generated(
+							counterparty_node_id,
+							request_id,
+							vec![raw_opening_fee_params(100, 21)],
+						)
+						.unwrap();
+				}
+				event => panic!("Unexpected event: {:?}", event),
+			}
 		}
 
-		features
+		let msgs = lsp.liquidity_manager.get_and_clear_pending_msg();
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, client.node_id);
+		assert!(msgs[0].1.payload.contains("opening_fee_params_menu"));
 	}
 
-	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
-		let mut features = InitFeatures::empty();
+	#[test]
+	fn fee_params_expiring_soon_is_emitted_once_within_lead_time() {
+		let mut client_config = default_jit_channels_config();
+		client_config.fee_params_expiry_warning_lead_time = Some(Duration::from_secs(60 * 60));
+		let client = create_node(1, Some(client_config));
+		let lsp = create_node(2, Some(default_jit_channels_config()));
 
-		if self.provider_config.is_some() {
-			features.set_optional_custom_bit(LSPS_FEATURE_BIT).unwrap();
-		}
+		let valid_until = raw_opening_fee_params(100, 21).valid_until;
+		request_opening_fee_params_menu(
+			&client,
+			&lsp,
+			None,
+			1,
+			vec![raw_opening_fee_params(100, 21)],
+		);
+		let lsps2_message_handler =
+			client.liquidity_manager.lsps2_message_handler.as_ref().unwrap();
+		let valid_until_secs = valid_until.timestamp() as u64;
 
-		features
-	}
-}
+		lsps2_message_handler.check_fee_params_expiry(valid_until_secs - 2 * 60 * 60);
+		assert!(get_events(&client).is_empty());
 
-impl<
-		ES: Deref + Clone,
-		M: Deref,
-		T: Deref,
-		F: Deref,
-		R: Deref,
-		SP: Deref,
-		L: Deref,
-		Descriptor: SocketDescriptor,
-		RM: Deref,
-		CM: Deref,
-		OM: Deref,
-		CMH: Deref,
-		NS: Deref,
-		C: Deref,
-	> Listen for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
-where
-	ES::Target: EntropySource,
-	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
-	T::Target: BroadcasterInterface,
-	F::Target: FeeEstimator,
-	R::Target: Router,
-	SP::Target: SignerProvider,
-	L::Target: Logger,
-	RM::Target: RoutingMessageHandler,
-	CM::Target: ChannelMessageHandler,
-	OM::Target: OnionMessageHandler,
-	CMH::Target: CustomMessageHandler,
-	NS::Target: NodeSigner,
-	C::Target: Filter,
-{
-	fn filtered_block_connected(
-		&self, header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
-		height: u32,
-	) {
-		{
-			let best_block = self.best_block.read().unwrap();
-			assert_eq!(best_block.block_hash(), header.prev_blockhash,
-			"Blocks must be connected in chain-order - the connected header must build on the last connected header");
-			assert_eq!(best_block.height(), height - 1,
-			"Blocks must be connected in chain-order - the connected block height must be one greater than the previous height");
+		lsps2_message_handler.check_fee_params_expiry(valid_until_secs - 30 * 60);
+		match get_events(&client).as_slice() {
+			[Event::LSPS2(LSPS2Event::FeeParamsExpiringSoon {
+				counterparty_node_id,
+				valid_until: expiring,
+				..
+			})] => {
+				assert_eq!(*counterparty_node_id, lsp.node_id);
+				assert_eq!(*expiring, valid_until);
+			}
+			events => panic!("Unexpected events: {:?}", events),
 		}
 
-		self.transactions_confirmed(header, txdata, height);
-		self.best_block_updated(header, height);
+		lsps2_message_handler.check_fee_params_expiry(valid_until_secs - 10 * 60);
+		assert!(get_events(&client).is_empty());
 	}
 
-	fn block_disconnected(&self, header: &bitcoin::BlockHeader, height: u32) {
-		let new_height = height - 1;
-		{
-			let mut best_block = self.best_block.write().unwrap();
-			assert_eq!(best_block.block_hash(), header.block_hash(),
-				"Blocks must be disconnected in chain-order - the disconnected header must be the last connected header");
-			assert_eq!(best_block.height(), height,
-				"Blocks must be disconnected in chain-order - the disconnected block must have the correct height");
-			*best_block = BestBlock::new(header.prev_blockhash, new_height)
-		}
+	#[test]
+	#[cfg(debug_assertions)]
+	#[should_panic(expected = "illegal JIT channel state transition from None to Ready")]
+	fn illegal_jit_channel_state_transition_panics_in_debug_builds() {
+		let lsp = create_node(1, Some(default_jit_channels_config()));
+		let jit_channel_manager = lsp.liquidity_manager.lsps2_message_handler.as_ref().unwrap();
 
-		// TODO: Call block_disconnected on all sub-modules that require it, e.g., CRManager.
-		// Internally this should call transaction_unconfirmed for all transactions that were
-		// confirmed at a height <= the one we now disconnected.
+		jit_channel_manager.transition_channel_state(42, JitChannelState::Ready);
 	}
-}
 
-impl<
-		ES: Deref + Clone,
-		M: Deref,
-		T: Deref,
-		F: Deref,
-		R: Deref,
-		SP: Deref,
-		L: Deref,
-		Descriptor: SocketDescriptor,
-		RM: Deref,
-		CM: Deref,
-		OM: Deref,
-		CMH: Deref,
-		NS: Deref,
-		C: Deref,
-	> Confirm for LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
-where
-	ES::Target: EntropySource,
-	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
-	T::Target: BroadcasterInterface,
-	F::Target: FeeEstimator,
-	R::Target: Router,
-	SP::Target: SignerProvider,
-	L::Target: Logger,
-	RM::Target: RoutingMessageHandler,
-	CM::Target: ChannelMessageHandler,
-	OM::Target: OnionMessageHandler,
-	CMH::Target: CustomMessageHandler,
-	NS::Target: NodeSigner,
-	C::Target: Filter,
-{
-	fn transactions_confirmed(
-		&self, header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
-		height: u32,
-	) {
-		// TODO: Call transactions_confirmed on all sub-modules that require it, e.g., CRManager.
-	}
+	#[test]
+	fn genesis_hash_matches_network() {
+		let node = create_node(1, None);
 
-	fn transaction_unconfirmed(&self, txid: &bitcoin::Txid) {
-		// TODO: Call transaction_unconfirmed on all sub-modules that require it, e.g., CRManager.
-		// Internally this should call transaction_unconfirmed for all transactions that were
-		// confirmed at a height <= the one we now unconfirmed.
+		assert_eq!(node.liquidity_manager.network(), Network::Regtest);
+		assert_eq!(
+			node.liquidity_manager.genesis_hash(),
+			genesis_block(Network::Regtest).header.block_hash()
+		);
+		assert_ne!(
+			node.liquidity_manager.genesis_hash(),
+			genesis_block(Network::Bitcoin).header.block_hash()
+		);
 	}
 
-	fn best_block_updated(&self, header: &bitcoin::BlockHeader, height: u32) {
-		// TODO: Call best_block_updated on all sub-modules that require it, e.g., CRManager.
-	}
+	#[cfg(feature = "tracing")]
+	mod spans {
+		use super::*;
 
-	fn get_relevant_txids(&self) -> Vec<(bitcoin::Txid, Option<bitcoin::BlockHash>)> {
-		// TODO: Collect relevant txids from all sub-modules that, e.g., CRManager.
-		Vec::new()
+		use tracing::field::{Field, Visit};
+		use tracing::span::{Attributes, Id, Record};
+		use tracing::{Event as TracingEvent, Metadata, Subscriber};
+
+		struct RecordedSpan {
+			name: &'static str,
+			fields: HashMap<String, String>,
+		}
+
+		struct FieldRecorder<'a>(&'a mut HashMap<String, String>);
+
+		impl<'a> Visit for FieldRecorder<'a> {
+			fn record_str(&mut self, field: &Field, value: &str) {
+				self.0.insert(field.name().to_string(), value.to_string());
+			}
+
+			fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+				self.0.insert(field.name().to_string(), format!("{:?}", value));
+			}
+		}
+
+		struct RecordingSubscriber {
+			spans: Arc<Mutex<Vec<RecordedSpan>>>,
+		}
+
+		impl Subscriber for RecordingSubscriber {
+			fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+				true
+			}
+
+			fn new_span(&self, span: &Attributes<'_>) -> Id {
+				let mut recorded =
+					RecordedSpan { name: span.metadata().name(), fields: HashMap::new() };
+				span.record(&mut FieldRecorder(&mut recorded.fields));
+				let mut spans = self.spans.lock().unwrap();
+				spans.push(recorded);
+				Id::from_u64(spans.len() as u64)
+			}
+
+			fn record(&self, span: &Id, values: &Record<'_>) {
+				let mut spans = self.spans.lock().unwrap();
+				let recorded = &mut spans[span.into_u64() as usize - 1];
+				values.record(&mut FieldRecorder(&mut recorded.fields));
+			}
+
+			fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+			fn event(&self, _event: &TracingEvent<'_>) {}
+
+			fn enter(&self, _span: &Id) {}
+
+			fn exit(&self, _span: &Id) {}
+		}
+
+		#[test]
+		fn sending_and_handling_messages_is_wrapped_in_spans() {
+			let client = create_node(1, Some(default_jit_channels_config()));
+			let lsp = create_node(2, Some(default_jit_channels_config()));
+
+			let spans = Arc::new(Mutex::new(Vec::new()));
+			let subscriber = RecordingSubscriber { spans: Arc::clone(&spans) };
+			tracing::subscriber::with_default(subscriber, || {
+				client
+					.liquidity_manager
+					.jit_channel_create_invoice(lsp.node_id, None, None, 1, None)
+					.unwrap();
+				assert_eq!(pass_messages(&client, &lsp), 1);
+			});
+
+			let spans = spans.lock().unwrap();
+			let sent = spans.iter().find(|span| span.name == "lsps_send_message").unwrap();
+			assert_eq!(sent.fields["peer"], lsp.node_id.to_string());
+			assert_eq!(sent.fields["method"], "lsps2.get_versions");
+
+			let handled = spans.iter().find(|span| span.name == "lsps_handle_message").unwrap();
+			assert_eq!(handled.fields["peer"], client.node_id.to_string());
+			assert_eq!(handled.fields["method"], "lsps2.get_versions");
+			assert_eq!(handled.fields["request_id"], sent.fields["request_id"]);
+		}
 	}
 }
@@ -4,12 +4,17 @@ use crate::jit_channel::msgs::{
 };
 
 use lightning::impl_writeable_msg;
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
 use lightning::ln::wire;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{_init_and_read_len_prefixed_tlv_fields, write_tlv_fields};
 use serde::de;
+use serde::de::DeserializeOwned;
 use serde::de::{MapAccess, Visitor};
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt;
@@ -24,6 +29,11 @@ const JSONRPC_RESULT_FIELD_KEY: &str = "result";
 const JSONRPC_ERROR_FIELD_KEY: &str = "error";
 const JSONRPC_INVALID_MESSAGE_ERROR_CODE: i32 = -32700;
 const JSONRPC_INVALID_MESSAGE_ERROR_MESSAGE: &str = "parse error";
+const JSONRPC_METHOD_NOT_FOUND_ERROR_CODE: i32 = -32601;
+const JSONRPC_METHOD_NOT_FOUND_ERROR_MESSAGE: &str = "Method not found";
+/// Taken from the JSON-RPC range reserved for implementation-defined server errors.
+const JSONRPC_PROTOCOL_UNAVAILABLE_ERROR_CODE: i32 = -32000;
+const JSONRPC_PROTOCOL_UNAVAILABLE_ERROR_MESSAGE: &str = "protocol temporarily unavailable";
 const LSPS0_LISTPROTOCOLS_METHOD_NAME: &str = "lsps0.list_protocols";
 
 /// The Lightning message type id for LSPS messages.
@@ -44,16 +54,115 @@ impl wire::Type for RawLSPSMessage {
 	}
 }
 
+/// The maximum length in bytes of the payload of a [`RawLSPSMessage`] we accept by default.
+///
+/// Corresponds to the maximum a Lightning message can carry, i.e., doesn't restrict payloads any
+/// further.
+pub const DEFAULT_MAX_PAYLOAD_LENGTH: u16 = u16::MAX;
+
+/// The maximum length of a [`RequestId`] we accept.
+pub const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// A JSON-RPC request id.
+///
+/// Request ids are guaranteed to be non-empty and at most [`MAX_REQUEST_ID_LENGTH`] characters
+/// long.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct RequestId(pub String);
+pub struct RequestId(String);
+
+impl Writeable for RequestId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for RequestId {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let id: String = Readable::read(reader)?;
+		RequestId::new(id).map_err(|()| DecodeError::InvalidValue)
+	}
+}
+
+impl RequestId {
+	/// Creates a new [`RequestId`], validating that `id` is non-empty and not longer than
+	/// [`MAX_REQUEST_ID_LENGTH`].
+	#[allow(clippy::result_unit_err)]
+	pub fn new(id: String) -> Result<Self, ()> {
+		if id.is_empty() || id.len() > MAX_REQUEST_ID_LENGTH {
+			return Err(());
+		}
+		Ok(Self(id))
+	}
+
+	/// Returns the request id as a string slice.
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+}
+
+/// Stores the method names of the requests we sent, allowing to interpret the responses to them.
+///
+/// By default, the method names are held in memory. Implementing this trait allows to back them
+/// by an external store, e.g., if responses might be handled by a different process than the one
+/// that sent the request.
+pub trait RequestMethodStore {
+	/// Records that the request with the given id was sent for the given method.
+	fn set_method(&mut self, request_id: RequestId, method: String);
+
+	/// Returns the method the request with the given id was sent for, if known.
+	fn get_method(&self, request_id: &RequestId) -> Option<String>;
+
+	/// Forgets the request with the given id, returning the method it was sent for, if known.
+	fn remove_method(&mut self, request_id: &RequestId) -> Option<String>;
+}
+
+impl RequestMethodStore for HashMap<RequestId, String> {
+	fn set_method(&mut self, request_id: RequestId, method: String) {
+		self.insert(request_id, method);
+	}
 
+	fn get_method(&self, request_id: &RequestId) -> Option<String> {
+		self.get(request_id).cloned()
+	}
+
+	fn remove_method(&mut self, request_id: &RequestId) -> Option<String> {
+		self.remove(request_id)
+	}
+}
+
+/// An error returned in response to a request.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ResponseError {
+	/// A number indicating the error type that occurred.
 	pub code: i32,
+	/// A short description of the error.
 	pub message: String,
+	/// Optional additional information about the error.
 	pub data: Option<String>,
 }
 
+impl Writeable for ResponseError {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		write_tlv_fields!(writer, {
+			(0, self.code, required),
+			(2, self.message, required),
+			(4, self.data, option),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for ResponseError {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		_init_and_read_len_prefixed_tlv_fields!(reader, {
+			(0, code, required),
+			(2, message, required),
+			(4, data, option),
+		});
+		Ok(Self { code: code.0.unwrap(), message: message.0.unwrap(), data })
+	}
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct ListProtocolsRequest {}
 
@@ -81,6 +190,16 @@ pub enum LSPS0Response {
 	ListProtocolsError(ResponseError),
 }
 
+impl LSPS0Response {
+	pub fn method(&self) -> &str {
+		match self {
+			LSPS0Response::ListProtocols(_) | LSPS0Response::ListProtocolsError(_) => {
+				LSPS0_LISTPROTOCOLS_METHOD_NAME
+			}
+		}
+	}
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LSPS0Message {
 	Request(RequestId, LSPS0Request),
@@ -93,6 +212,8 @@ impl TryFrom<LSPSMessage> for LSPS0Message {
 	fn try_from(message: LSPSMessage) -> Result<Self, Self::Error> {
 		match message {
 			LSPSMessage::Invalid => Err(()),
+			LSPSMessage::MethodNotFound(_) => Err(()),
+			LSPSMessage::ProtocolUnavailable(_) => Err(()),
 			LSPSMessage::LSPS0(message) => Ok(message),
 			LSPSMessage::LSPS2(_) => Err(()),
 		}
@@ -108,102 +229,203 @@ impl From<LSPS0Message> for LSPSMessage {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LSPSMessage {
 	Invalid,
+	/// An error response to a request for a method of a protocol we don't implement, as mandated
+	/// by LSPS0.
+	MethodNotFound(RequestId),
+	/// An error response to a request for a protocol we implement but which is currently paused.
+	ProtocolUnavailable(RequestId),
 	LSPS0(LSPS0Message),
 	LSPS2(LSPS2Message),
 }
 
 impl LSPSMessage {
 	pub fn from_str_with_id_map(
-		json_str: &str, request_id_to_method: &mut HashMap<String, String>,
+		json_str: &str, request_id_to_method: &mut dyn RequestMethodStore,
+	) -> Result<Self, serde_json::Error> {
+		Self::from_str_with_id_map_and_unknown_fields(json_str, request_id_to_method, &mut vec![])
+	}
+
+	/// Parses the message like [`Self::from_str_with_id_map`], but additionally appends the
+	/// paths of any fields of a response `result` we don't know about to `unknown_fields`.
+	///
+	/// Unknown fields are ignored for forward compatibility, but collecting them allows to
+	/// detect if the counterparty speaks a newer revision of the spec.
+	pub fn from_str_with_id_map_and_unknown_fields(
+		json_str: &str, request_id_to_method: &mut dyn RequestMethodStore,
+		unknown_fields: &mut Vec<String>,
 	) -> Result<Self, serde_json::Error> {
 		let deserializer = &mut serde_json::Deserializer::from_str(json_str);
-		let visitor = LSPSMessageVisitor { request_id_to_method };
+		let visitor = LSPSMessageVisitor { request_id_to_method, unknown_fields };
 		deserializer.deserialize_any(visitor)
 	}
 
-	pub fn get_request_id_and_method(&self) -> Option<(String, String)> {
+	pub fn get_request_id_and_method(&self) -> Option<(RequestId, String)> {
 		match self {
 			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, request)) => {
-				Some((request_id.0.clone(), request.method().to_string()))
+				Some((request_id.clone(), request.method().to_string()))
 			}
 			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
-				Some((request_id.0.clone(), request.method().to_string()))
+				Some((request_id.clone(), request.method().to_string()))
+			}
+			_ => None,
+		}
+	}
+
+	/// Returns the method of the request, or of the request responded to, if known.
+	pub fn method(&self) -> Option<&str> {
+		match self {
+			LSPSMessage::Invalid
+			| LSPSMessage::MethodNotFound(_)
+			| LSPSMessage::ProtocolUnavailable(_) => None,
+			LSPSMessage::LSPS0(LSPS0Message::Request(_, request)) => Some(request.method()),
+			LSPSMessage::LSPS0(LSPS0Message::Response(_, response)) => Some(response.method()),
+			LSPSMessage::LSPS2(LSPS2Message::Request(_, request)) => Some(request.method()),
+			LSPSMessage::LSPS2(LSPS2Message::Response(_, response)) => Some(response.method()),
+		}
+	}
+
+	/// Returns the id of the request, or of the request responded to, if any.
+	pub fn request_id(&self) -> Option<&RequestId> {
+		match self {
+			LSPSMessage::Invalid => None,
+			LSPSMessage::MethodNotFound(request_id)
+			| LSPSMessage::ProtocolUnavailable(request_id)
+			| LSPSMessage::LSPS0(LSPS0Message::Request(request_id, _))
+			| LSPSMessage::LSPS0(LSPS0Message::Response(request_id, _))
+			| LSPSMessage::LSPS2(LSPS2Message::Request(request_id, _))
+			| LSPSMessage::LSPS2(LSPS2Message::Response(request_id, _)) => Some(request_id),
+		}
+	}
+
+	/// Returns the error code of the message, if it is an error response.
+	pub fn error_code(&self) -> Option<i32> {
+		match self {
+			LSPSMessage::Invalid => Some(JSONRPC_INVALID_MESSAGE_ERROR_CODE),
+			LSPSMessage::MethodNotFound(_) => Some(JSONRPC_METHOD_NOT_FOUND_ERROR_CODE),
+			LSPSMessage::ProtocolUnavailable(_) => Some(JSONRPC_PROTOCOL_UNAVAILABLE_ERROR_CODE),
+			LSPSMessage::LSPS0(LSPS0Message::Response(
+				_,
+				LSPS0Response::ListProtocolsError(error),
+			))
+			| LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::GetInfoError(error)))
+			| LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::BuyError(error))) => {
+				Some(error.code)
 			}
 			_ => None,
 		}
 	}
 }
 
-impl Serialize for LSPSMessage {
+/// The JSON-RPC 2.0 envelope all LSPS messages are serialized through.
+///
+/// Keeps the envelope fields consistently placed, i.e., `jsonrpc` first, followed by `method` and
+/// `id` for requests, and `id` followed by either `result` or `error` for responses.
+pub(crate) enum JsonRpcEnvelope<'a, T: Serialize> {
+	Request {
+		id: &'a RequestId,
+		method: &'a str,
+		params: &'a T,
+	},
+	Response {
+		id: &'a RequestId,
+		result: &'a T,
+	},
+	/// An error response. The `id` is `null` if the request it responds to couldn't be parsed.
+	Error {
+		id: Option<&'a RequestId>,
+		error: &'a ResponseError,
+	},
+}
+
+impl<'a> JsonRpcEnvelope<'a, ()> {
+	pub(crate) fn error(id: Option<&'a RequestId>, error: &'a ResponseError) -> Self {
+		JsonRpcEnvelope::Error { id, error }
+	}
+}
+
+impl<'a, T: Serialize> Serialize for JsonRpcEnvelope<'a, T> {
 	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
 	where
 		S: serde::Serializer,
 	{
 		let mut jsonrpc_object =
-			serializer.serialize_struct(LSPS_MESSAGE_SERIALIZED_STRUCT_NAME, 3)?;
+			serializer.serialize_struct(LSPS_MESSAGE_SERIALIZED_STRUCT_NAME, 4)?;
 
 		jsonrpc_object.serialize_field(JSONRPC_FIELD_KEY, JSONRPC_FIELD_VALUE)?;
 
 		match self {
-			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, request)) => {
-				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, request.method())?;
-				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
+			JsonRpcEnvelope::Request { id, method, params } => {
+				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, method)?;
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &id.0)?;
+				jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?;
+			}
+			JsonRpcEnvelope::Response { id, result } => {
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &id.0)?;
+				jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?;
+			}
+			JsonRpcEnvelope::Error { id, error } => {
+				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &id.map(|id| &id.0))?;
+				jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?;
+			}
+		}
+
+		jsonrpc_object.end()
+	}
+}
 
+impl Serialize for LSPSMessage {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		match self {
+			LSPSMessage::LSPS0(LSPS0Message::Request(id, request)) => {
+				let method = request.method();
 				match request {
 					LSPS0Request::ListProtocols(params) => {
-						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
-					}
-				};
-			}
-			LSPSMessage::LSPS0(LSPS0Message::Response(request_id, response)) => {
-				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
-
-				match response {
-					LSPS0Response::ListProtocols(result) => {
-						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?;
-					}
-					LSPS0Response::ListProtocolsError(error) => {
-						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?;
+						JsonRpcEnvelope::Request { id, method, params }.serialize(serializer)
 					}
 				}
 			}
-			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
-				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
-				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, request.method())?;
-
+			LSPSMessage::LSPS0(LSPS0Message::Response(id, response)) => match response {
+				LSPS0Response::ListProtocols(result) => {
+					JsonRpcEnvelope::Response { id, result }.serialize(serializer)
+				}
+				LSPS0Response::ListProtocolsError(error) => {
+					JsonRpcEnvelope::error(Some(id), error).serialize(serializer)
+				}
+			},
+			LSPSMessage::LSPS2(LSPS2Message::Request(id, request)) => {
+				let method = request.method();
 				match request {
 					LSPS2Request::GetVersions(params) => {
-						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+						JsonRpcEnvelope::Request { id, method, params }.serialize(serializer)
 					}
 					LSPS2Request::GetInfo(params) => {
-						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+						JsonRpcEnvelope::Request { id, method, params }.serialize(serializer)
 					}
 					LSPS2Request::Buy(params) => {
-						jsonrpc_object.serialize_field(JSONRPC_PARAMS_FIELD_KEY, params)?
+						JsonRpcEnvelope::Request { id, method, params }.serialize(serializer)
 					}
 				}
 			}
-			LSPSMessage::LSPS2(LSPS2Message::Response(request_id, response)) => {
-				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
-
-				match response {
-					LSPS2Response::GetVersions(result) => {
-						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
-					}
-					LSPS2Response::GetInfo(result) => {
-						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
-					}
-					LSPS2Response::GetInfoError(error) => {
-						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
-					}
-					LSPS2Response::Buy(result) => {
-						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
-					}
-					LSPS2Response::BuyError(error) => {
-						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
-					}
+			LSPSMessage::LSPS2(LSPS2Message::Response(id, response)) => match response {
+				LSPS2Response::GetVersions(result) => {
+					JsonRpcEnvelope::Response { id, result }.serialize(serializer)
 				}
-			}
+				LSPS2Response::GetInfo(result) => {
+					JsonRpcEnvelope::Response { id, result }.serialize(serializer)
+				}
+				LSPS2Response::GetInfoError(error) => {
+					JsonRpcEnvelope::error(Some(id), error).serialize(serializer)
+				}
+				LSPS2Response::Buy(result) => {
+					JsonRpcEnvelope::Response { id, result }.serialize(serializer)
+				}
+				LSPS2Response::BuyError(error) => {
+					JsonRpcEnvelope::error(Some(id), error).serialize(serializer)
+				}
+			},
 			LSPSMessage::Invalid => {
 				let error = ResponseError {
 					code: JSONRPC_INVALID_MESSAGE_ERROR_CODE,
@@ -211,17 +433,100 @@ impl Serialize for LSPSMessage {
 					data: None,
 				};
 
-				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &serde_json::Value::Null)?;
-				jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, &error)?;
+				JsonRpcEnvelope::error(None, &error).serialize(serializer)
+			}
+			LSPSMessage::MethodNotFound(id) => {
+				let error = ResponseError {
+					code: JSONRPC_METHOD_NOT_FOUND_ERROR_CODE,
+					message: JSONRPC_METHOD_NOT_FOUND_ERROR_MESSAGE.to_string(),
+					data: None,
+				};
+
+				JsonRpcEnvelope::error(Some(id), &error).serialize(serializer)
+			}
+			LSPSMessage::ProtocolUnavailable(id) => {
+				let error = ResponseError {
+					code: JSONRPC_PROTOCOL_UNAVAILABLE_ERROR_CODE,
+					message: JSONRPC_PROTOCOL_UNAVAILABLE_ERROR_MESSAGE.to_string(),
+					data: None,
+				};
+
+				JsonRpcEnvelope::error(Some(id), &error).serialize(serializer)
 			}
 		}
+	}
+}
 
-		jsonrpc_object.end()
+/// Returns the given JSON-RPC `id` as string, normalizing numeric ids, as some implementations
+/// send them as numbers rather than strings.
+fn json_rpc_id(id: &Value) -> Option<String> {
+	match id {
+		Value::String(id) => Some(id.clone()),
+		Value::Number(id) => Some(id.to_string()),
+		_ => None,
 	}
 }
 
+/// Returns the id of the given JSON-RPC object if it is a response, i.e., has no method.
+pub(crate) fn response_request_id(json_str: &str) -> Option<RequestId> {
+	let object = serde_json::from_str::<Value>(json_str).ok()?;
+	if object.get(JSONRPC_METHOD_FIELD_KEY).is_some() {
+		return None;
+	}
+	let id = json_rpc_id(object.get(JSONRPC_ID_FIELD_KEY)?)?;
+	RequestId::new(id).ok()
+}
+
+/// Returns the id and the LSPS protocol number of the given JSON-RPC object if it is a request for
+/// a method named `lsps<protocol number>.<name>`.
+pub(crate) fn request_protocol_number(json_str: &str) -> Option<(RequestId, u16)> {
+	let object = serde_json::from_str::<Value>(json_str).ok()?;
+	let method = object.get(JSONRPC_METHOD_FIELD_KEY)?.as_str()?;
+	let protocol_number = method.strip_prefix("lsps")?.split('.').next()?.parse().ok()?;
+	let id = json_rpc_id(object.get(JSONRPC_ID_FIELD_KEY)?)?;
+	Some((RequestId::new(id).ok()?, protocol_number))
+}
+
 struct LSPSMessageVisitor<'a> {
-	request_id_to_method: &'a mut HashMap<String, String>,
+	request_id_to_method: &'a mut dyn RequestMethodStore,
+	unknown_fields: &'a mut Vec<String>,
+}
+
+/// Deserializes a response `result`, recording any fields that were ignored while doing so.
+fn deserialize_result<T: DeserializeOwned + Serialize>(
+	result: Value, unknown_fields: &mut Vec<String>,
+) -> Result<T, serde_json::Error> {
+	let response: T = serde_json::from_value(result.clone())?;
+	let known = serde_json::to_value(&response)?;
+	collect_unknown_fields(&result, &known, JSONRPC_RESULT_FIELD_KEY, unknown_fields);
+	Ok(response)
+}
+
+fn collect_unknown_fields(
+	received: &Value, known: &Value, path: &str, unknown_fields: &mut Vec<String>,
+) {
+	match (received, known) {
+		(Value::Object(received), Value::Object(known)) => {
+			for (key, value) in received {
+				let field_path = format!("{}.{}", path, key);
+				match known.get(key) {
+					Some(known_value) => {
+						collect_unknown_fields(value, known_value, &field_path, unknown_fields)
+					}
+					// Fields that are skipped when serializing `None` may be sent as `null`.
+					None if value.is_null() => {}
+					None => unknown_fields.push(field_path),
+				}
+			}
+		}
+		(Value::Array(received), Value::Array(known)) => {
+			for (index, (value, known_value)) in received.iter().zip(known.iter()).enumerate() {
+				let element_path = format!("{}[{}]", path, index);
+				collect_unknown_fields(value, known_value, &element_path, unknown_fields);
+			}
+		}
+		_ => {}
+	}
 }
 
 impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
@@ -244,7 +549,12 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 		while let Some(key) = map.next_key()? {
 			match key {
 				"id" => {
-					id = Some(map.next_value()?);
+					let value: Value = map.next_value()?;
+					id = Some(json_rpc_id(&value).ok_or_else(|| {
+						de::Error::custom(
+							"Received invalid request id: must be a string or a number",
+						)
+					})?);
 				}
 				"method" => {
 					method = Some(map.next_value()?);
@@ -264,13 +574,23 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 			}
 		}
 
+		let id = match id {
+			Some(id) => Some(RequestId::new(id).map_err(|_| {
+				de::Error::custom(format!(
+					"Received invalid request id: must be non-empty and at most {} characters long",
+					MAX_REQUEST_ID_LENGTH
+				))
+			})?),
+			None => None,
+		};
+
 		match (id, method) {
 			(Some(id), Some(method)) => match method {
 				LSPS0_LISTPROTOCOLS_METHOD_NAME => {
-					self.request_id_to_method.insert(id.clone(), method.to_string());
+					self.request_id_to_method.set_method(id.clone(), method.to_string());
 
 					Ok(LSPSMessage::LSPS0(LSPS0Message::Request(
-						RequestId(id),
+						id,
 						LSPS0Request::ListProtocols(ListProtocolsRequest {}),
 					)))
 				}
@@ -278,7 +598,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
 					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
-						RequestId(id),
+						id,
 						LSPS2Request::GetVersions(request),
 					)))
 				}
@@ -286,36 +606,34 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
 					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
-						RequestId(id),
+						id,
 						LSPS2Request::GetInfo(request),
 					)))
 				}
 				LSPS2_BUY_METHOD_NAME => {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
-					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(
-						RequestId(id),
-						LSPS2Request::Buy(request),
-					)))
+					Ok(LSPSMessage::LSPS2(LSPS2Message::Request(id, LSPS2Request::Buy(request))))
 				}
 				_ => Err(de::Error::custom(format!(
 					"Received request with unknown method: {}",
 					method
 				))),
 			},
-			(Some(id), None) => match self.request_id_to_method.get(&id) {
+			(Some(id), None) => match self.request_id_to_method.get_method(&id) {
 				Some(method) => match method.as_str() {
 					LSPS0_LISTPROTOCOLS_METHOD_NAME => {
 						if let Some(error) = error {
 							Ok(LSPSMessage::LSPS0(LSPS0Message::Response(
-								RequestId(id),
+								id,
 								LSPS0Response::ListProtocolsError(error),
 							)))
 						} else if let Some(result) = result {
 							let list_protocols_response =
-								serde_json::from_value(result).map_err(de::Error::custom)?;
+								deserialize_result(result, self.unknown_fields)
+									.map_err(de::Error::custom)?;
 							Ok(LSPSMessage::LSPS0(LSPS0Message::Response(
-								RequestId(id),
+								id,
 								LSPS0Response::ListProtocols(list_protocols_response),
 							)))
 						} else {
@@ -324,10 +642,10 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					}
 					LSPS2_GET_VERSIONS_METHOD_NAME => {
 						if let Some(result) = result {
-							let response =
-								serde_json::from_value(result).map_err(de::Error::custom)?;
+							let response = deserialize_result(result, self.unknown_fields)
+								.map_err(de::Error::custom)?;
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
-								RequestId(id),
+								id,
 								LSPS2Response::GetVersions(response),
 							)))
 						} else {
@@ -337,14 +655,14 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					LSPS2_GET_INFO_METHOD_NAME => {
 						if let Some(error) = error {
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
-								RequestId(id),
+								id,
 								LSPS2Response::GetInfoError(error),
 							)))
 						} else if let Some(result) = result {
-							let response =
-								serde_json::from_value(result).map_err(de::Error::custom)?;
+							let response = deserialize_result(result, self.unknown_fields)
+								.map_err(de::Error::custom)?;
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
-								RequestId(id),
+								id,
 								LSPS2Response::GetInfo(response),
 							)))
 						} else {
@@ -354,14 +672,14 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					LSPS2_BUY_METHOD_NAME => {
 						if let Some(error) = error {
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
-								RequestId(id),
+								id,
 								LSPS2Response::BuyError(error),
 							)))
 						} else if let Some(result) = result {
-							let response =
-								serde_json::from_value(result).map_err(de::Error::custom)?;
+							let response = deserialize_result(result, self.unknown_fields)
+								.map_err(de::Error::custom)?;
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
-								RequestId(id),
+								id,
 								LSPS2Response::Buy(response),
 							)))
 						} else {
@@ -374,7 +692,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					))),
 				},
 				None => Err(de::Error::custom(format!(
-					"Received response for unknown request id: {}",
+					"Received response for unknown request id: {:?}",
 					id
 				))),
 			},
@@ -391,6 +709,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::jit_channel::msgs::{GetVersionsRequest, GetVersionsResponse};
 
 	#[test]
 	fn deserializes_request() {
@@ -408,16 +727,54 @@ mod tests {
 		assert_eq!(
 			msg,
 			LSPSMessage::LSPS0(LSPS0Message::Request(
-				RequestId("request:id:xyz123".to_string()),
+				RequestId::new("request:id:xyz123".to_string()).unwrap(),
 				LSPS0Request::ListProtocols(ListProtocolsRequest {})
 			))
 		);
 	}
 
+	#[test]
+	fn request_id_rejects_empty_and_oversized_ids() {
+		assert!(RequestId::new(String::new()).is_err());
+		assert!(RequestId::new("a".repeat(MAX_REQUEST_ID_LENGTH + 1)).is_err());
+		assert!(RequestId::new("a".repeat(MAX_REQUEST_ID_LENGTH)).is_ok());
+	}
+
+	#[test]
+	fn request_id_can_be_used_as_map_key() {
+		let mut request_id_to_method_map = HashMap::new();
+		let request_id = RequestId::new("request:id:xyz123".to_string()).unwrap();
+		request_id_to_method_map.insert(request_id.clone(), "lsps0.list_protocols".to_string());
+
+		let same_request_id = RequestId::new("request:id:xyz123".to_string()).unwrap();
+		assert_eq!(
+			request_id_to_method_map.get(&same_request_id),
+			Some(&"lsps0.list_protocols".to_string())
+		);
+		assert_eq!(
+			request_id_to_method_map.remove(&request_id),
+			Some("lsps0.list_protocols".to_string())
+		);
+		assert!(request_id_to_method_map.is_empty());
+	}
+
+	#[test]
+	fn deserialize_fails_with_empty_request_id() {
+		let json = r#"{
+			"jsonrpc": "2.0",
+			"id": "",
+			"method": "lsps0.list_protocols"
+		}"#;
+
+		let mut request_id_method_map = HashMap::new();
+		assert!(LSPSMessage::from_str_with_id_map(json, &mut request_id_method_map).is_err());
+		assert!(request_id_method_map.is_empty());
+	}
+
 	#[test]
 	fn serializes_request() {
 		let request = LSPSMessage::LSPS0(LSPS0Message::Request(
-			RequestId("request:id:xyz123".to_string()),
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
 			LSPS0Request::ListProtocols(ListProtocolsRequest {}),
 		));
 		let json = serde_json::to_string(&request).unwrap();
@@ -437,8 +794,10 @@ mod tests {
 	        }
 	    }"#;
 		let mut request_id_to_method_map = HashMap::new();
-		request_id_to_method_map
-			.insert("request:id:xyz123".to_string(), "lsps0.list_protocols".to_string());
+		request_id_to_method_map.insert(
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
+			"lsps0.list_protocols".to_string(),
+		);
 
 		let response =
 			LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map).unwrap();
@@ -446,12 +805,48 @@ mod tests {
 		assert_eq!(
 			response,
 			LSPSMessage::LSPS0(LSPS0Message::Response(
-				RequestId("request:id:xyz123".to_string()),
+				RequestId::new("request:id:xyz123".to_string()).unwrap(),
 				LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![1, 2, 3] })
 			))
 		);
 	}
 
+	#[test]
+	fn deserializes_responses_with_string_and_numeric_ids() {
+		let mut request_id_to_method_map = HashMap::new();
+		for id in ["xyz123", "42"].iter() {
+			request_id_to_method_map.insert(
+				RequestId::new(id.to_string()).unwrap(),
+				"lsps0.list_protocols".to_string(),
+			);
+		}
+
+		// Responses are only parsed if they can be correlated with an outstanding request.
+		for (json_id, expected_id) in [(r#""xyz123""#, "xyz123"), ("42", "42")].iter() {
+			let json =
+				format!(r#"{{"jsonrpc":"2.0","id":{},"result":{{"protocols":[1,2,3]}}}}"#, json_id);
+			assert_eq!(
+				response_request_id(&json),
+				Some(RequestId::new(expected_id.to_string()).unwrap())
+			);
+
+			let response =
+				LSPSMessage::from_str_with_id_map(&json, &mut request_id_to_method_map).unwrap();
+			assert_eq!(
+				response,
+				LSPSMessage::LSPS0(LSPS0Message::Response(
+					RequestId::new(expected_id.to_string()).unwrap(),
+					LSPS0Response::ListProtocols(ListProtocolsResponse {
+						protocols: vec![1, 2, 3]
+					})
+				))
+			);
+		}
+
+		let json = r#"{"jsonrpc":"2.0","id":["42"],"result":{"protocols":[1,2,3]}}"#;
+		assert!(LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map).is_err());
+	}
+
 	#[test]
 	fn deserializes_error_response() {
 		let json = r#"{
@@ -463,8 +858,10 @@ mod tests {
 	        }
 	    }"#;
 		let mut request_id_to_method_map = HashMap::new();
-		request_id_to_method_map
-			.insert("request:id:xyz123".to_string(), "lsps0.list_protocols".to_string());
+		request_id_to_method_map.insert(
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
+			"lsps0.list_protocols".to_string(),
+		);
 
 		let response =
 			LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map).unwrap();
@@ -472,7 +869,7 @@ mod tests {
 		assert_eq!(
 			response,
 			LSPSMessage::LSPS0(LSPS0Message::Response(
-				RequestId("request:id:xyz123".to_string()),
+				RequestId::new("request:id:xyz123".to_string()).unwrap(),
 				LSPS0Response::ListProtocolsError(ResponseError {
 					code: -32617,
 					message: "Unknown Error".to_string(),
@@ -482,6 +879,56 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn deserializes_get_info_response_with_unknown_fields() {
+		let json = r#"{
+			"jsonrpc": "2.0",
+			"id": "request:id:xyz123",
+			"result": {
+				"opening_fee_params_menu": [{
+					"min_fee_msat": 546000,
+					"proportional": 1200,
+					"valid_until": "2023-02-23T08:47:30.511Z",
+					"min_lifetime": 1008,
+					"max_client_to_self_delay": 2016,
+					"promise": "abcdefghijklmnopqrstuvwxyz",
+					"future_fee_field": 42
+				}],
+				"min_payment_size_msat": 1000,
+				"max_payment_size_msat": 100000000,
+				"future_field": "xyz"
+			}
+		}"#;
+		let mut request_id_to_method_map = HashMap::new();
+		request_id_to_method_map.insert(
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
+			LSPS2_GET_INFO_METHOD_NAME.to_string(),
+		);
+
+		let mut unknown_fields = Vec::new();
+		let response = LSPSMessage::from_str_with_id_map_and_unknown_fields(
+			json,
+			&mut request_id_to_method_map,
+			&mut unknown_fields,
+		)
+		.unwrap();
+
+		match response {
+			LSPSMessage::LSPS2(LSPS2Message::Response(_, LSPS2Response::GetInfo(response))) => {
+				assert_eq!(response.opening_fee_params_menu.len(), 1);
+				assert_eq!(response.min_payment_size_msat, 1000);
+			}
+			_ => panic!("Unexpected message: {:?}", response),
+		}
+		assert_eq!(
+			unknown_fields,
+			vec![
+				"result.future_field".to_string(),
+				"result.opening_fee_params_menu[0].future_fee_field".to_string()
+			]
+		);
+	}
+
 	#[test]
 	fn deserialize_fails_with_unknown_request_id() {
 		let json = r#"{
@@ -492,8 +939,10 @@ mod tests {
 	        }
 	    }"#;
 		let mut request_id_to_method_map = HashMap::new();
-		request_id_to_method_map
-			.insert("request:id:xyz123".to_string(), "lsps0.list_protocols".to_string());
+		request_id_to_method_map.insert(
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
+			"lsps0.list_protocols".to_string(),
+		);
 
 		let response = LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map);
 		assert!(response.is_err());
@@ -502,7 +951,7 @@ mod tests {
 	#[test]
 	fn serializes_response() {
 		let response = LSPSMessage::LSPS0(LSPS0Message::Response(
-			RequestId("request:id:xyz123".to_string()),
+			RequestId::new("request:id:xyz123".to_string()).unwrap(),
 			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![1, 2, 3] }),
 		));
 		let json = serde_json::to_string(&response).unwrap();
@@ -511,4 +960,63 @@ mod tests {
 			r#"{"jsonrpc":"2.0","id":"request:id:xyz123","result":{"protocols":[1,2,3]}}"#
 		);
 	}
+
+	fn envelope_keys(message: &LSPSMessage) -> (Value, Vec<String>) {
+		let json = serde_json::to_value(message).unwrap();
+		let mut keys: Vec<String> = json.as_object().unwrap().keys().cloned().collect();
+		keys.sort();
+		(json, keys)
+	}
+
+	#[test]
+	fn serializes_lsps2_request_envelope() {
+		let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+			RequestId::new("abc".to_string()).unwrap(),
+			LSPS2Request::GetVersions(GetVersionsRequest {}),
+		));
+		assert_eq!(
+			serde_json::to_string(&request).unwrap(),
+			r#"{"jsonrpc":"2.0","method":"lsps2.get_versions","id":"abc","params":{}}"#
+		);
+
+		let (json, keys) = envelope_keys(&request);
+		assert_eq!(keys, vec!["id", "jsonrpc", "method", "params"]);
+		assert_eq!(json["jsonrpc"], "2.0");
+	}
+
+	#[test]
+	fn serializes_lsps2_response_envelopes() {
+		let id = RequestId::new("abc".to_string()).unwrap();
+
+		let response = LSPSMessage::LSPS2(LSPS2Message::Response(
+			id.clone(),
+			LSPS2Response::GetVersions(GetVersionsResponse { versions: vec![1] }),
+		));
+		assert_eq!(
+			serde_json::to_string(&response).unwrap(),
+			r#"{"jsonrpc":"2.0","id":"abc","result":{"versions":[1]}}"#
+		);
+
+		let error = LSPSMessage::LSPS2(LSPS2Message::Response(
+			id,
+			LSPS2Response::BuyError(ResponseError {
+				code: 1,
+				message: "unsupported version".to_string(),
+				data: None,
+			}),
+		));
+		let (json, keys) = envelope_keys(&error);
+		assert_eq!(keys, vec!["error", "id", "jsonrpc"]);
+		assert_eq!(json["id"], "abc");
+		assert_eq!(json["error"]["code"], 1);
+		assert_eq!(json["error"]["message"], "unsupported version");
+	}
+
+	#[test]
+	fn serializes_invalid_message_envelope_with_null_id() {
+		let (json, keys) = envelope_keys(&LSPSMessage::Invalid);
+		assert_eq!(keys, vec!["error", "id", "jsonrpc"]);
+		assert_eq!(json["id"], Value::Null);
+		assert_eq!(json["error"]["code"], JSONRPC_INVALID_MESSAGE_ERROR_CODE);
+	}
 }
@@ -2,8 +2,9 @@ use bitcoin::secp256k1::PublicKey;
 use lightning::ln::msgs::{ErrorAction, LightningError};
 use lightning::sign::EntropySource;
 use lightning::util::logger::Level;
+use std::collections::HashMap;
 use std::ops::Deref;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use crate::transport::message_handler::ProtocolMessageHandler;
 use crate::transport::msgs::{
@@ -19,6 +20,7 @@ where
 	entropy_source: ES,
 	pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 	protocols: Vec<u16>,
+	peer_protocols: RwLock<HashMap<PublicKey, Vec<u16>>>,
 }
 
 impl<ES: Deref> LSPS0MessageHandler<ES>
@@ -29,7 +31,8 @@ where
 		entropy_source: ES, protocols: Vec<u16>,
 		pending_messages: Arc<Mutex<Vec<(PublicKey, LSPSMessage)>>>,
 	) -> Self {
-		Self { entropy_source, protocols, pending_messages }
+		let peer_protocols = RwLock::new(HashMap::new());
+		Self { entropy_source, protocols, pending_messages, peer_protocols }
 	}
 
 	pub fn list_protocols(&self, counterparty_node_id: PublicKey) {
@@ -41,6 +44,14 @@ where
 		self.enqueue_message(counterparty_node_id, msg);
 	}
 
+	pub fn peer_protocols(&self, counterparty_node_id: &PublicKey) -> Option<Vec<u16>> {
+		self.peer_protocols.read().unwrap().get(counterparty_node_id).cloned()
+	}
+
+	pub fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		self.peer_protocols.write().unwrap().remove(counterparty_node_id);
+	}
+
 	fn enqueue_message(&self, counterparty_node_id: PublicKey, message: LSPS0Message) {
 		self.pending_messages.lock().unwrap().push((counterparty_node_id, message.into()));
 	}
@@ -66,7 +77,10 @@ where
 		&self, response: LSPS0Response, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
 		match response {
-			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols }) => Ok(()),
+			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols }) => {
+				self.peer_protocols.write().unwrap().insert(*counterparty_node_id, protocols);
+				Ok(())
+			}
 			LSPS0Response::ListProtocolsError(ResponseError { code, message, data, .. }) => {
 				Err(LightningError {
 					err: format!(
@@ -107,6 +121,7 @@ mod tests {
 	use std::sync::Arc;
 
 	use super::*;
+	use crate::test_utils;
 
 	struct TestEntropy {}
 	impl EntropySource for TestEntropy {
@@ -125,10 +140,10 @@ mod tests {
 			Arc::new(LSPS0MessageHandler::new(entropy, protocols, pending_messages.clone()));
 
 		let list_protocols_request = LSPS0Message::Request(
-			RequestId("xyz123".to_string()),
+			RequestId::new("xyz123".to_string()).unwrap(),
 			LSPS0Request::ListProtocols(ListProtocolsRequest {}),
 		);
-		let counterparty_node_id = utils::parse_pubkey(
+		let counterparty_node_id = test_utils::parse_pubkey(
 			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
 		)
 		.unwrap();
@@ -144,7 +159,7 @@ mod tests {
 		assert_eq!(
 			*message,
 			LSPSMessage::LSPS0(LSPS0Message::Response(
-				RequestId("xyz123".to_string()),
+				RequestId::new("xyz123".to_string()).unwrap(),
 				LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![] })
 			))
 		);
@@ -160,7 +175,7 @@ mod tests {
 			pending_messages.clone(),
 		));
 
-		let counterparty_node_id = utils::parse_pubkey(
+		let counterparty_node_id = test_utils::parse_pubkey(
 			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
 		)
 		.unwrap();
@@ -176,7 +191,7 @@ mod tests {
 		assert_eq!(
 			*message,
 			LSPSMessage::LSPS0(LSPS0Message::Request(
-				RequestId("00000000000000000000000000000000".to_string()),
+				RequestId::new("00000000000000000000000000000000".to_string()).unwrap(),
 				LSPS0Request::ListProtocols(ListProtocolsRequest {})
 			))
 		);
@@ -0,0 +1,516 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Role-scoped views on a [`LiquidityManager`].
+
+use crate::jit_channel::channel_manager::{
+	ChannelReadyOutcome, GetInfoRejectionReason, JitChannelState, RouteHintBundle,
+};
+use crate::jit_channel::msgs::{OpeningFeeParams, RawOpeningFeeParams};
+use crate::transport::message_handler::LiquidityManager;
+use crate::transport::msgs::RequestId;
+
+use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use lightning::chain::{self, Filter};
+use lightning::ln::channelmanager::InterceptId;
+use lightning::ln::msgs::{ChannelMessageHandler, OnionMessageHandler, RoutingMessageHandler};
+use lightning::ln::peer_handler::{CustomMessageHandler, SocketDescriptor};
+use lightning::ln::ChannelId;
+use lightning::routing::router::Router;
+use lightning::sign::{EntropySource, NodeSigner, SignerProvider};
+use lightning::util::config::ChannelConfig;
+use lightning::util::errors::APIError;
+use lightning::util::logger::Logger;
+
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Script, Txid};
+
+use std::ops::Deref;
+
+/// A view on a [`LiquidityManager`] exposing only the methods used by a client buying JIT
+/// channels from an LSP.
+///
+/// Returned by [`LiquidityManager::as_client`].
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+/// # use lightning::chain::{self, Filter};
+/// # use lightning::ln::peer_handler::{
+/// #     ErroringMessageHandler, IgnoringMessageHandler, SocketDescriptor,
+/// # };
+/// # use lightning::routing::router::Router;
+/// # use lightning::sign::{InMemorySigner, KeysManager};
+/// # use lightning::util::logger::Logger;
+/// # use ldk_lsp_client::LiquidityManager;
+/// # #[derive(Clone, PartialEq, Eq, Hash)]
+/// # struct Socket;
+/// # impl SocketDescriptor for Socket {
+/// #     fn send_data(&mut self, data: &[u8], _resume_read: bool) -> usize { data.len() }
+/// #     fn disconnect_socket(&mut self) {}
+/// # }
+/// # type MyLiquidityManager = LiquidityManager<
+/// #     Arc<KeysManager>, Arc<dyn chain::Watch<InMemorySigner>>, Arc<dyn BroadcasterInterface>,
+/// #     Arc<dyn FeeEstimator>, Arc<dyn Router>, Arc<KeysManager>, Arc<dyn Logger>, Socket,
+/// #     Arc<IgnoringMessageHandler>, Arc<ErroringMessageHandler>, Arc<IgnoringMessageHandler>,
+/// #     Arc<IgnoringMessageHandler>, Arc<KeysManager>, Arc<dyn Filter>,
+/// # >;
+/// # use bitcoin::secp256k1::PublicKey;
+/// # fn example(liquidity_manager: &MyLiquidityManager, lsp_node_id: PublicKey) {
+/// let client = liquidity_manager.as_client();
+/// client.jit_channel_create_invoice(lsp_node_id, Some(42_000), None, 1, None).unwrap();
+/// // Later, once the `InvoiceGenerationReady` event was generated:
+/// let route_hint = client.invoice_route_hint(1);
+/// let expiry_secs = client.recommended_invoice_expiry_secs(1);
+/// # }
+/// ```
+#[allow(clippy::type_complexity)]
+pub struct LspClient<
+	'a,
+	ES: Deref + Clone,
+	M: Deref,
+	T: Deref,
+	F: Deref,
+	R: Deref,
+	SP: Deref,
+	L: Deref + Clone,
+	Descriptor: SocketDescriptor,
+	RM: Deref,
+	CM: Deref,
+	OM: Deref,
+	CMH: Deref,
+	NS: Deref,
+	C: Deref,
+> where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	pub(crate) liquidity_manager:
+		&'a LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>,
+}
+
+impl<
+		'a,
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> LspClient<'a, ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	/// Initiates the creation of an invoice for a JIT channel with the given LSP.
+	///
+	/// See [`LiquidityManager::jit_channel_create_invoice`].
+	pub fn jit_channel_create_invoice(
+		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
+		token: Option<String>, user_channel_id: u128, label: Option<String>,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.jit_channel_create_invoice(
+			counterparty_node_id,
+			payment_size_msat,
+			token,
+			user_channel_id,
+			label,
+		)
+	}
+
+	/// Initiates the creation of an invoice for a JIT channel with the cheapest of the given LSPs.
+	///
+	/// See [`LiquidityManager::jit_channel_create_invoice_multi`].
+	pub fn jit_channel_create_invoice_multi(
+		&self, lsp_candidates: Vec<PublicKey>, payment_size_msat: Option<u64>,
+		user_channel_id: u128,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.jit_channel_create_invoice_multi(
+			lsp_candidates,
+			payment_size_msat,
+			user_channel_id,
+		)
+	}
+
+	/// Returns the total cost of a JIT channel opened with the given `opening_fee_params`.
+	///
+	/// See [`LiquidityManager::estimate_total_client_cost`].
+	pub fn estimate_total_client_cost(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> Option<u64> {
+		self.liquidity_manager.estimate_total_client_cost(opening_fee_params, payment_size_msat)
+	}
+
+	/// Returns the liquidity a JIT channel opened with the given `opening_fee_params` will provide.
+	///
+	/// See [`LiquidityManager::expected_inbound_liquidity_msat`].
+	pub fn expected_inbound_liquidity_msat(
+		&self, opening_fee_params: &OpeningFeeParams, payment_size_msat: u64,
+	) -> Option<u64> {
+		self.liquidity_manager
+			.expected_inbound_liquidity_msat(opening_fee_params, payment_size_msat)
+	}
+
+	/// Confirms which opening fee params to buy the JIT channel with.
+	///
+	/// See [`LiquidityManager::opening_fee_params_selected`].
+	pub fn opening_fee_params_selected(
+		&self, counterparty_node_id: PublicKey, channel_id: u128,
+		opening_fee_params: OpeningFeeParams, refund_onchain_address: Option<String>,
+		announce_channel: Option<bool>,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.opening_fee_params_selected(
+			counterparty_node_id,
+			channel_id,
+			opening_fee_params,
+			refund_onchain_address,
+			announce_channel,
+		)
+	}
+
+	/// Returns the parameters required to include a route hint for the JIT channel in an invoice.
+	///
+	/// See [`LiquidityManager::invoice_route_hint`].
+	pub fn invoice_route_hint(&self, user_channel_id: u128) -> Option<RouteHintBundle> {
+		self.liquidity_manager.invoice_route_hint(user_channel_id)
+	}
+
+	/// Returns the expiry in seconds the invoice for the JIT channel should have.
+	///
+	/// See [`LiquidityManager::recommended_invoice_expiry_secs`].
+	pub fn recommended_invoice_expiry_secs(&self, user_channel_id: u128) -> Option<u32> {
+		self.liquidity_manager.recommended_invoice_expiry_secs(user_channel_id)
+	}
+
+	/// Returns the node id of the LSP the JIT channel is negotiated with.
+	///
+	/// See [`LiquidityManager::lsp_for_jit_channel`].
+	pub fn lsp_for_jit_channel(&self, user_channel_id: u128) -> Option<PublicKey> {
+		self.liquidity_manager.lsp_for_jit_channel(user_channel_id)
+	}
+
+	/// Returns the stage the JIT channel negotiation is in.
+	///
+	/// See [`LiquidityManager::jit_channel_state`].
+	pub fn jit_channel_state(&self, user_channel_id: u128) -> Option<JitChannelState> {
+		self.liquidity_manager.jit_channel_state(user_channel_id)
+	}
+
+	/// Signals a payment was received over the JIT channel.
+	///
+	/// See [`LiquidityManager::jit_channel_payment_received`].
+	pub fn jit_channel_payment_received(
+		&self, user_channel_id: u128, funding_txid: Txid, funding_output_index: u16,
+		funding_script_pubkey: &Script,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.jit_channel_payment_received(
+			user_channel_id,
+			funding_txid,
+			funding_output_index,
+			funding_script_pubkey,
+		)
+	}
+}
+
+/// A view on a [`LiquidityManager`] exposing only the methods used by an LSP selling JIT
+/// channels to clients.
+///
+/// Returned by [`LiquidityManager::as_server`].
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use lightning::chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+/// # use lightning::chain::{self, Filter};
+/// # use lightning::ln::peer_handler::{
+/// #     ErroringMessageHandler, IgnoringMessageHandler, SocketDescriptor,
+/// # };
+/// # use lightning::routing::router::Router;
+/// # use lightning::sign::{InMemorySigner, KeysManager};
+/// # use lightning::util::logger::Logger;
+/// # use ldk_lsp_client::LiquidityManager;
+/// # #[derive(Clone, PartialEq, Eq, Hash)]
+/// # struct Socket;
+/// # impl SocketDescriptor for Socket {
+/// #     fn send_data(&mut self, data: &[u8], _resume_read: bool) -> usize { data.len() }
+/// #     fn disconnect_socket(&mut self) {}
+/// # }
+/// # type MyLiquidityManager = LiquidityManager<
+/// #     Arc<KeysManager>, Arc<dyn chain::Watch<InMemorySigner>>, Arc<dyn BroadcasterInterface>,
+/// #     Arc<dyn FeeEstimator>, Arc<dyn Router>, Arc<KeysManager>, Arc<dyn Logger>, Socket,
+/// #     Arc<IgnoringMessageHandler>, Arc<ErroringMessageHandler>, Arc<IgnoringMessageHandler>,
+/// #     Arc<IgnoringMessageHandler>, Arc<KeysManager>, Arc<dyn Filter>,
+/// # >;
+/// # use bitcoin::secp256k1::PublicKey;
+/// # use ldk_lsp_client::RequestId;
+/// # use ldk_lsp_client::jit_channel::RawOpeningFeeParams;
+/// # fn example(
+/// #     liquidity_manager: &MyLiquidityManager, client_node_id: PublicKey, request_id: RequestId,
+/// #     opening_fee_params_menu: Vec<RawOpeningFeeParams>,
+/// # ) {
+/// let server = liquidity_manager.as_server();
+/// // Upon a `GetInfo` event:
+/// server
+///     .opening_fee_params_generated(client_node_id, request_id, opening_fee_params_menu)
+///     .unwrap();
+/// // Keeping track of the scids handed out:
+/// let outstanding_scids = server.outstanding_scids();
+/// # }
+/// ```
+#[allow(clippy::type_complexity)]
+pub struct LspServer<
+	'a,
+	ES: Deref + Clone,
+	M: Deref,
+	T: Deref,
+	F: Deref,
+	R: Deref,
+	SP: Deref,
+	L: Deref + Clone,
+	Descriptor: SocketDescriptor,
+	RM: Deref,
+	CM: Deref,
+	OM: Deref,
+	CMH: Deref,
+	NS: Deref,
+	C: Deref,
+> where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	pub(crate) liquidity_manager:
+		&'a LiquidityManager<ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>,
+}
+
+impl<
+		'a,
+		ES: Deref + Clone,
+		M: Deref,
+		T: Deref,
+		F: Deref,
+		R: Deref,
+		SP: Deref,
+		L: Deref + Clone,
+		Descriptor: SocketDescriptor,
+		RM: Deref,
+		CM: Deref,
+		OM: Deref,
+		CMH: Deref,
+		NS: Deref,
+		C: Deref,
+	> LspServer<'a, ES, M, T, F, R, SP, L, Descriptor, RM, CM, OM, CMH, NS, C>
+where
+	ES::Target: EntropySource,
+	M::Target: chain::Watch<<SP::Target as SignerProvider>::Signer>,
+	T::Target: BroadcasterInterface,
+	F::Target: FeeEstimator,
+	R::Target: Router,
+	SP::Target: SignerProvider,
+	L::Target: Logger,
+	RM::Target: RoutingMessageHandler,
+	CM::Target: ChannelMessageHandler,
+	OM::Target: OnionMessageHandler,
+	CMH::Target: CustomMessageHandler,
+	NS::Target: NodeSigner,
+	C::Target: Filter,
+{
+	/// Provides the opening fee params menu to a client requesting a JIT channel.
+	///
+	/// See [`LiquidityManager::opening_fee_params_generated`].
+	pub fn opening_fee_params_generated(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId,
+		opening_fee_params_menu: Vec<RawOpeningFeeParams>,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.opening_fee_params_generated(
+			counterparty_node_id,
+			request_id,
+			opening_fee_params_menu,
+		)
+	}
+
+	/// Returns a `min_fee_msat` covering the estimated cost of a channel funding transaction.
+	///
+	/// See [`LiquidityManager::suggested_min_fee_msat`].
+	pub fn suggested_min_fee_msat(&self) -> Option<u64> {
+		self.liquidity_manager.suggested_min_fee_msat()
+	}
+
+	/// Provides the client with the scid and `cltv_expiry_delta` to use in their invoice.
+	///
+	/// See [`LiquidityManager::invoice_parameters_generated`].
+	pub fn invoice_parameters_generated(
+		&self, counterparty_node_id: PublicKey, request_id: RequestId, scid: u64,
+		cltv_expiry_delta: u32, client_trusts_lsp: bool,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.invoice_parameters_generated(
+			counterparty_node_id,
+			request_id,
+			scid,
+			cltv_expiry_delta,
+			client_trusts_lsp,
+		)
+	}
+
+	/// Lists all scids handed out to clients that are still awaiting an intercepted payment.
+	///
+	/// See [`LiquidityManager::outstanding_scids`].
+	pub fn outstanding_scids(&self) -> Vec<(u64, PublicKey, u128)> {
+		self.liquidity_manager.outstanding_scids()
+	}
+
+	/// Returns the stage the JIT channel with the given intercept scid is in.
+	///
+	/// See [`LiquidityManager::jit_channel_state_by_scid`].
+	pub fn jit_channel_state(&self, scid: u64) -> Option<JitChannelState> {
+		self.liquidity_manager.jit_channel_state_by_scid(scid)
+	}
+
+	/// Sets the [`ChannelConfig`] the JIT channel will be opened with.
+	///
+	/// See [`LiquidityManager::set_jit_channel_config`].
+	pub fn set_jit_channel_config(
+		&self, user_channel_id: u128, channel_config: ChannelConfig,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.set_jit_channel_config(user_channel_id, channel_config)
+	}
+
+	/// Registers the funding outpoint of a JIT channel opened for a client.
+	///
+	/// See [`LiquidityManager::jit_channel_funding_created`].
+	pub fn jit_channel_funding_created(
+		&self, user_channel_id: u128, funding_txid: Txid, funding_output_index: u16,
+		funding_script_pubkey: &Script,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.jit_channel_funding_created(
+			user_channel_id,
+			funding_txid,
+			funding_output_index,
+			funding_script_pubkey,
+		)
+	}
+
+	/// Handles the parameters of an [`Event::HTLCIntercepted`] event.
+	///
+	/// See [`LiquidityManager::htlc_intercepted`].
+	///
+	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
+	pub fn htlc_intercepted(
+		&self, scid: u64, intercept_id: InterceptId, inbound_amount_msat: u64,
+		expected_outbound_amount_msat: u64,
+	) -> Result<(), APIError> {
+		self.liquidity_manager.htlc_intercepted(
+			scid,
+			intercept_id,
+			inbound_amount_msat,
+			expected_outbound_amount_msat,
+		)
+	}
+
+	/// Handles the parameters of an [`Event::ChannelReady`] event.
+	///
+	/// See [`LiquidityManager::channel_ready`].
+	///
+	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+	pub fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<ChannelReadyOutcome, APIError> {
+		self.liquidity_manager.channel_ready(user_channel_id, channel_id, counterparty_node_id)
+	}
+
+	/// Handles the parameters of an [`Event::PaymentForwarded`] event.
+	///
+	/// See [`LiquidityManager::payment_forwarded`].
+	///
+	/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
+	pub fn payment_forwarded(&self, next_channel_id: Option<ChannelId>) {
+		self.liquidity_manager.payment_forwarded(next_channel_id)
+	}
+
+	/// Returns the total of the opening fees earned with JIT channels.
+	///
+	/// See [`LiquidityManager::total_fees_earned_msat`].
+	pub fn total_fees_earned_msat(&self) -> u64 {
+		self.liquidity_manager.total_fees_earned_msat()
+	}
+
+	/// Rotates the secret used to sign opening fee params promises.
+	///
+	/// See [`LiquidityManager::rotate_promise_secret`].
+	pub fn rotate_promise_secret(&self, new_secret: [u8; 32]) -> Result<(), APIError> {
+		self.liquidity_manager.rotate_promise_secret(new_secret)
+	}
+
+	/// Pauses or resumes serving JIT channel requests.
+	///
+	/// See [`LiquidityManager::set_jit_channels_paused`].
+	pub fn set_jit_channels_paused(&self, paused: bool) {
+		self.liquidity_manager.set_jit_channels_paused(paused)
+	}
+
+	/// Returns the abuse score of the given peer.
+	///
+	/// See [`LiquidityManager::peer_abuse_score`].
+	pub fn peer_abuse_score(&self, counterparty_node_id: &PublicKey) -> u32 {
+		self.liquidity_manager.peer_abuse_score(counterparty_node_id)
+	}
+
+	/// Resets the abuse score of the given peer.
+	///
+	/// See [`LiquidityManager::reset_peer_abuse`].
+	pub fn reset_peer_abuse(&self, counterparty_node_id: &PublicKey) {
+		self.liquidity_manager.reset_peer_abuse(counterparty_node_id)
+	}
+
+	/// Returns how many `get_info` requests were rejected for the given reason.
+	///
+	/// See [`LiquidityManager::get_info_rejection_count`].
+	pub fn get_info_rejection_count(&self, reason: GetInfoRejectionReason) -> u64 {
+		self.liquidity_manager.get_info_rejection_count(reason)
+	}
+}
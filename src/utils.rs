@@ -1,9 +1,47 @@
-use bitcoin::secp256k1::PublicKey;
 use lightning::sign::EntropySource;
+#[cfg(test)]
+use std::cell::Cell;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fmt::Write, ops::Deref};
 
 use crate::transport::msgs::RequestId;
 
+#[cfg(test)]
+thread_local! {
+	static TIME_OFFSET: Cell<Duration> = Cell::new(Duration::from_secs(0));
+}
+
+fn time_offset() -> Duration {
+	#[cfg(test)]
+	return TIME_OFFSET.with(|offset| offset.get());
+	#[cfg(not(test))]
+	Duration::from_secs(0)
+}
+
+/// Moves the clock observed through [`now`] and [`duration_since_epoch`] forward by the given
+/// duration on the current thread.
+#[cfg(test)]
+pub(crate) fn advance_time(duration: Duration) {
+	TIME_OFFSET.with(|offset| offset.set(offset.get() + duration));
+}
+
+/// Returns the current monotonic time.
+pub(crate) fn now() -> Instant {
+	Instant::now() + time_offset()
+}
+
+/// Returns the time elapsed since the unix epoch.
+pub(crate) fn duration_since_epoch() -> Duration {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.expect("system clock to be ahead of the unix epoch")
+		+ time_offset()
+}
+
+/// Maximum block height that can be used in a `short_channel_id`. This
+/// value is based on the 3-bytes available for the block height.
+pub const MAX_SCID_BLOCK: u64 = 0x00ffffff;
+
 /// Maximum transaction index that can be used in a `short_channel_id`.
 /// This value is based on the 3-bytes available for tx index.
 pub const MAX_SCID_TX_INDEX: u64 = 0x00ffffff;
@@ -27,22 +65,43 @@ pub fn vout_from_scid(short_channel_id: &u64) -> u16 {
 	((short_channel_id) & MAX_SCID_VOUT_INDEX) as u16
 }
 
+/// Parses a `short_channel_id` given in the `<block>x<tx_index>x<vout>` format.
+///
+/// Fails if any component is not a decimal number or exceeds the range of its field.
 pub fn scid_from_human_readable_string(human_readable_scid: &str) -> Result<u64, ()> {
 	let mut parts = human_readable_scid.split('x');
 
-	let block: u64 = parts.next().ok_or(())?.parse().map_err(|_e| ())?;
-	let tx_index: u64 = parts.next().ok_or(())?.parse().map_err(|_e| ())?;
-	let vout_index: u64 = parts.next().ok_or(())?.parse().map_err(|_e| ())?;
+	let block = parse_scid_component(parts.next(), MAX_SCID_BLOCK)?;
+	let tx_index = parse_scid_component(parts.next(), MAX_SCID_TX_INDEX)?;
+	let vout_index = parse_scid_component(parts.next(), MAX_SCID_VOUT_INDEX)?;
+
+	if parts.next().is_some() {
+		return Err(());
+	}
 
 	Ok((block << 40) | (tx_index << 16) | vout_index)
 }
 
+fn parse_scid_component(component: Option<&str>, max_value: u64) -> Result<u64, ()> {
+	let component = component.ok_or(())?;
+	if component.is_empty() || !component.bytes().all(|b| b.is_ascii_digit()) {
+		return Err(());
+	}
+
+	let value: u64 = component.parse().map_err(|_e| ())?;
+	if value > max_value {
+		return Err(());
+	}
+
+	Ok(value)
+}
+
 pub(crate) fn generate_request_id<ES: Deref>(entropy_source: &ES) -> RequestId
 where
 	ES::Target: EntropySource,
 {
 	let bytes = entropy_source.get_secure_random_bytes();
-	RequestId(hex_str(&bytes[0..16]))
+	RequestId::new(hex_str(&bytes[0..16])).expect("hex encoded random bytes to be a valid id")
 }
 
 #[inline]
@@ -54,53 +113,6 @@ pub fn hex_str(value: &[u8]) -> String {
 	res
 }
 
-pub fn to_vec(hex: &str) -> Option<Vec<u8>> {
-	let mut out = Vec::with_capacity(hex.len() / 2);
-
-	let mut b = 0;
-	for (idx, c) in hex.as_bytes().iter().enumerate() {
-		b <<= 4;
-		match *c {
-			b'A'..=b'F' => b |= c - b'A' + 10,
-			b'a'..=b'f' => b |= c - b'a' + 10,
-			b'0'..=b'9' => b |= c - b'0',
-			_ => return None,
-		}
-		if (idx & 1) == 1 {
-			out.push(b);
-			b = 0;
-		}
-	}
-
-	Some(out)
-}
-
-pub fn to_compressed_pubkey(hex: &str) -> Option<PublicKey> {
-	if hex.len() != 33 * 2 {
-		return None;
-	}
-	let data = match to_vec(&hex[0..33 * 2]) {
-		Some(bytes) => bytes,
-		None => return None,
-	};
-	match PublicKey::from_slice(&data) {
-		Ok(pk) => Some(pk),
-		Err(_) => None,
-	}
-}
-
-pub fn parse_pubkey(pubkey_str: &str) -> Result<PublicKey, std::io::Error> {
-	let pubkey = to_compressed_pubkey(pubkey_str);
-	if pubkey.is_none() {
-		return Err(std::io::Error::new(
-			std::io::ErrorKind::Other,
-			"ERROR: unable to parse given pubkey for node",
-		));
-	}
-
-	Ok(pubkey.unwrap())
-}
-
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -119,4 +131,40 @@ mod tests {
 		assert_eq!(tx_index_from_scid(&scid), tx_index);
 		assert_eq!(vout_from_scid(&scid), vout);
 	}
+
+	#[test]
+	fn rejects_non_numeric_scid_components() {
+		assert!(scid_from_human_readable_string("1xAxB").is_err());
+		assert!(scid_from_human_readable_string("Ax2x3").is_err());
+		assert!(scid_from_human_readable_string("+1x2x3").is_err());
+		assert!(scid_from_human_readable_string("1x-2x3").is_err());
+		assert!(scid_from_human_readable_string("1xx3").is_err());
+		assert!(scid_from_human_readable_string("1x2").is_err());
+		assert!(scid_from_human_readable_string("1x2x3x4").is_err());
+		assert!(scid_from_human_readable_string("").is_err());
+	}
+
+	#[test]
+	fn rejects_out_of_range_scid_components() {
+		assert!(scid_from_human_readable_string("99999999x2x3").is_err());
+		assert!(scid_from_human_readable_string(&format!("{}x2x3", MAX_SCID_BLOCK + 1)).is_err());
+		assert!(scid_from_human_readable_string(&format!("1x{}x3", MAX_SCID_TX_INDEX + 1)).is_err());
+		assert!(
+			scid_from_human_readable_string(&format!("1x2x{}", MAX_SCID_VOUT_INDEX + 1)).is_err()
+		);
+		assert!(scid_from_human_readable_string("1x2x99999999999999999999").is_err());
+	}
+
+	#[test]
+	fn parses_scid_components_at_field_maxima() {
+		let human_readable_scid =
+			format!("{}x{}x{}", MAX_SCID_BLOCK, MAX_SCID_TX_INDEX, MAX_SCID_VOUT_INDEX);
+		let scid = scid_from_human_readable_string(&human_readable_scid).unwrap();
+		assert_eq!(scid, u64::MAX);
+		assert_eq!(block_from_scid(&scid) as u64, MAX_SCID_BLOCK);
+		assert_eq!(tx_index_from_scid(&scid) as u64, MAX_SCID_TX_INDEX);
+		assert_eq!(vout_from_scid(&scid) as u64, MAX_SCID_VOUT_INDEX);
+
+		assert_eq!(scid_from_human_readable_string("0x0x0"), Ok(0));
+	}
 }